@@ -0,0 +1,123 @@
+// Memory map
+
+// 16kB ROM bank #0
+pub const ROM0_START:       usize   = 0x0000;
+pub const ROM0_END:         usize   = 0x3FFF;
+// 16kB switchable ROM bank
+pub const ROMX_START:       usize   = 0x4000;
+pub const ROMX_END:         usize   = 0x7FFF;
+// 8kB Video RAM
+pub const VRAM_START:       usize   = 0x8000;
+pub const VRAM_END:         usize   = 0x9FFF;
+// 8kB switchable RAM bank on the cartridge
+pub const SRAM_START:       usize   = 0xA000;
+pub const SRAM_END:         usize   = 0xBFFF;
+// 8kB Internal RAM
+pub const WRAM_START:       usize   = 0xC000;
+pub const WRAM_END:         usize   = 0xDFFF;
+// Echo of 8kB Internal RAM
+pub const ECHO_START:       usize   = 0xE000;
+pub const ECHO_END:         usize   = 0xFDFF;
+// Sprite Attribute Memory (OAM)
+pub const OAM_START:        usize   = 0xFE00;
+pub const OAM_END:          usize   = 0xFE9F;
+// Empty but unusable for I/O
+pub const UNUSABLE_START:   usize   = 0xFEA0;
+pub const UNUSABLE_END:     usize   = 0xFEFF;
+// I/O ports
+pub const IO_START:         usize   = 0xFF00;
+pub const IO_END:           usize   = 0xFF7F;
+// High RAM
+pub const HRAM_START:       usize   = 0xFF80;
+pub const HRAM_END:         usize   = 0xFFFE;
+
+// I/O registers
+
+// Joypad
+pub const JOYP:     usize   = 0xFF00;
+// Serial Data Transfer
+pub const SB:       usize   = 0xFF01;
+pub const SC:       usize   = 0xFF02;
+// Timer
+pub const DIV:      usize   = 0xFF04;
+pub const TIMA:     usize   = 0xFF05;
+pub const TMA:      usize   = 0xFF06;
+pub const TAC:      usize   = 0xFF07;
+// Interrupt Flag Register
+pub const IF:       usize   = 0xFF0F;
+// Sound Channel 1 - Tone & Sweep
+pub const NR10:     usize   = 0xFF10;
+pub const NR11:     usize   = 0xFF11;
+pub const NR12:     usize   = 0xFF12;
+pub const NR13:     usize   = 0xFF13;
+pub const NR14:     usize   = 0xFF14;
+// Sound Channel 2 - Tone
+pub const NR21:     usize   = 0xFF16;
+pub const NR22:     usize   = 0xFF17;
+pub const NR23:     usize   = 0xFF18;
+pub const NR24:     usize   = 0xFF19;
+// Sound Channel 3 - Wave Output
+pub const NR30:     usize   = 0xFF1A;
+pub const NR31:     usize   = 0xFF1B;
+pub const NR32:     usize   = 0xFF1C;
+pub const NR33:     usize   = 0xFF1D;
+pub const NR34:     usize   = 0xFF1E;
+// Sound Channel 4 - Noise
+pub const NR41:     usize   = 0xFF20;
+pub const NR42:     usize   = 0xFF21;
+pub const NR43:     usize   = 0xFF22;
+pub const NR44:     usize   = 0xFF23;
+// Sound Control
+pub const NR50:     usize   = 0xFF24;
+pub const NR51:     usize   = 0xFF25;
+pub const NR52:     usize   = 0xFF26;
+// Wave Pattern RAM
+pub const WAVE_START:   usize   = 0xFF30;
+pub const WAVE_END:     usize   = 0xFF3F;
+// LCD Registers
+pub const LCDC:     usize   = 0xFF40;
+pub const STAT:     usize   = 0xFF41;
+pub const SCY:      usize   = 0xFF42;
+pub const SCX:      usize   = 0xFF43;
+pub const LY:       usize   = 0xFF44;
+pub const LYC:      usize   = 0xFF45;
+pub const DMA:      usize   = 0xFF46;
+pub const BGP:      usize   = 0xFF47;
+pub const OBP0:     usize   = 0xFF48;
+pub const OBP1:     usize   = 0xFF49;
+pub const WY:       usize   = 0xFF4A;
+pub const WX:       usize   = 0xFF4B;
+// CPU Speed Switch (CGB only)
+pub const KEY1:     usize   = 0xFF4D;
+// VRAM Bank (CGB only)
+pub const VBK:      usize   = 0xFF4F;
+// Boot ROM disable
+pub const BOOT:     usize   = 0xFF50;
+// VRAM DMA (CGB only)
+pub const HDMA1:    usize   = 0xFF51;
+pub const HDMA2:    usize   = 0xFF52;
+pub const HDMA3:    usize   = 0xFF53;
+pub const HDMA4:    usize   = 0xFF54;
+pub const HDMA5:    usize   = 0xFF55;
+// Infrared Port (CGB only)
+pub const RP:       usize   = 0xFF56;
+// Color Palettes (CGB only)
+pub const BCPS:     usize   = 0xFF68;
+pub const BCPD:     usize   = 0xFF69;
+pub const OCPS:     usize   = 0xFF6A;
+pub const OCPD:     usize   = 0xFF6B;
+// Object Priority Mode (CGB only)
+pub const OPRI:     usize   = 0xFF6C;
+// WRAM Bank (CGB only)
+pub const SVBK:     usize   = 0xFF70;
+// Interrupt Enable Register
+pub const IE:       usize   = 0xFFFF;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Doesn't compile if one of them is off
+    const _: () = assert!(JOYP == 0xFF00 && DMA == 0xFF46 && BOOT == 0xFF50 && IE == 0xFFFF);
+    const _: () = assert!(NR52 == 0xFF26 && SVBK == 0xFF70 && HRAM_END - HRAM_START == 0x7E);
+}
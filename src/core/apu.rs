@@ -1,73 +1,629 @@
+use crate::core::addr::*;
 use crate::core::io::Io;
+use crate::core::cpu::CLOCK_HZ;
+use crate::core::model::Model;
+use crate::core::error::GbError;
+use crate::core::state::{ StateReader, StateWriter };
 use std::sync::{Arc, Mutex};
-use cpal::{Stream, SampleFormat};
+use cpal::{Stream, SampleFormat, SampleRate};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use log::{error, warn};
+
+// The frame sequencer is clocked at 512Hz (4194304Hz / 8192)
+const FRAME_SEQUENCER_PERIOD: u16 = 8192;
+// Until an output stream is open, and for captured samples without one
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+// The capacitors on the sound outputs filter out the DC offset, which otherwise
+// clicks whenever a channel starts or stops
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HighPass {
+    Off,
+    Dmg,
+    // Charges faster, so it removes more of the bass too
+    Cgb,
+}
+
+impl HighPass {
+    pub fn for_model(model: Model) -> Self {
+        match model.is_color() {
+            false   =>  HighPass::Dmg,
+            true    =>  HighPass::Cgb,
+        }
+    }
+
+    // How much of the capacitor's charge is left after a clock
+    fn charge_factor(&self) -> f32 {
+        match self {
+            HighPass::Off   =>  1.0,
+            HighPass::Dmg   =>  0.999958,
+            HighPass::Cgb   =>  0.998943,
+        }
+    }
+}
+
+// One step of the filter. `charge` is the charge factor over a whole sample.
+fn high_pass(capacitor: &mut f32, input: f32, charge: f32) -> f32 {
+    let output = input - *capacitor;
+    *capacitor = input - output * charge;
+    output
+}
 
 #[derive(Copy, Clone, Debug)]
 struct Channel {
     no:                     u8,     // number of channel (for debug)
+    enabled:                bool,
+    dac_enabled:            bool,
+    freq_reg:               u32,
     freq:                   f32,
-    amplitude:              f32,
-    sample_rate:            f32,
 
-    duration:               i32,
     length:                 u32,
+    length_enabled:         bool,
 
-    envelope_time:          f32,
-    envelope_samples:       f32,
-    envelope_volume:        u32,
-    envelope_steps:         u32,
-    envelope_steps_init:    u32,
+    volume:                 u8,
+    envelope_volume:        u8,
+    envelope_period:        u8,
+    envelope_timer:         u8,
     envelope_increasing:    bool,
+
+    sweep_enabled:          bool,
+    sweep_period:           u8,
+    sweep_timer:            u8,
+    sweep_shift:            u8,
+    sweep_decreasing:       bool,
+    sweep_shadow:           u32,
+
+    phase:                  f32,
 }
 
 impl Channel {
     pub fn new(no: u8) -> Self {
-        println!("sampling rate: {}", get_sample_rate());
         Channel {
             no:                     no,
+            enabled:                false,
+            dac_enabled:            false,
+            freq_reg:               0u32,
             freq:                   0f32,
-            amplitude:              1f32,
-            sample_rate:            get_sample_rate(),
-            duration:               0i32,
             length:                 0u32,
-            envelope_time:          0f32,
-            envelope_samples:       0f32,
-            envelope_volume:        0u32,
-            envelope_steps:         0u32,
-            envelope_steps_init:    0u32,
-            envelope_increasing:    true,
+            length_enabled:         false,
+            volume:                 0u8,
+            envelope_volume:        0u8,
+            envelope_period:        0u8,
+            envelope_timer:         0u8,
+            envelope_increasing:    false,
+            sweep_enabled:          false,
+            sweep_period:           0u8,
+            sweep_timer:            0u8,
+            sweep_shift:            0u8,
+            sweep_decreasing:       false,
+            sweep_shadow:           0u32,
+            phase:                  0f32,
         }
     }
 
-    pub fn reset(&mut self) {
-        self.amplitude = 1f32;
-        self.envelope_time = 0f32;
-    }
-
-    pub fn update_envelope(&mut self) {
-        if self.envelope_samples > 0f32 {
-            self.envelope_time += 1f32 / self.sample_rate;
-            if self.envelope_steps > 0 && self.envelope_time >= self.envelope_samples {
-                self.envelope_time = 0f32;
-                self.envelope_steps -= 1;
-                if self.envelope_steps == 0 {
-                    self.amplitude = 0f32;
-                } else if self.envelope_increasing {
-                    self.amplitude = 1f32 - (self.envelope_steps as f32)/(self.envelope_steps_init as f32);
-                } else {
-                    self.amplitude = (self.envelope_steps as f32)/(self.envelope_steps_init as f32);
-                }
+    pub fn set_freq(&mut self, freq_reg: u32) {
+        self.freq_reg = freq_reg & 0x7FF;
+        self.freq = (131072 / (2048 - self.freq_reg)) as f32;
+    }
+
+    pub fn set_envelope(&mut self, data: u8) {
+        self.envelope_volume     = (data & 0xF0) >> 4;
+        self.envelope_increasing = data & 0x08 != 0;
+        self.envelope_period     = data & 0x07;
+        self.dac_enabled         = data & 0xF8 != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    pub fn set_sweep(&mut self, data: u8) {
+        self.sweep_period       = (data & 0x70) >> 4;
+        self.sweep_decreasing   = data & 0x08 != 0;
+        self.sweep_shift        = data & 0x07;
+    }
+
+    pub fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length == 0 {
+            self.length = 64;
+        }
+        self.volume = self.envelope_volume;
+        self.envelope_timer = self.envelope_period;
+
+        self.sweep_shadow = self.freq_reg;
+        self.sweep_timer = self.sweep_reload();
+        self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+        if self.sweep_shift != 0 {
+            self.sweep_freq();
+        }
+    }
+
+    // 256Hz
+    pub fn clock_length(&mut self) {
+        if self.length_enabled && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // 64Hz
+    pub fn clock_envelope(&mut self) {
+        if self.envelope_period == 0 {
+            return;
+        }
+        self.envelope_timer = self.envelope_timer.saturating_sub(1);
+        if self.envelope_timer == 0 {
+            self.envelope_timer = self.envelope_period;
+            if self.envelope_increasing && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.envelope_increasing && self.volume > 0 {
+                self.volume -= 1;
             }
         }
     }
 
-    pub fn update_sweep(&mut self) {
+    // 128Hz (channel 1 only)
+    pub fn clock_sweep(&mut self) {
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+        if self.sweep_timer > 0 {
+            return;
+        }
+        self.sweep_timer = self.sweep_reload();
+        if !self.sweep_enabled || self.sweep_period == 0 {
+            return;
+        }
+
+        let freq = self.sweep_freq();
+        if freq <= 2047 && self.sweep_shift != 0 {
+            self.sweep_shadow = freq;
+            self.set_freq(freq);
+            // overflow check again with the new frequency
+            self.sweep_freq();
+        }
+    }
+
+    fn sweep_reload(&self) -> u8 {
+        match self.sweep_period {
+            0   =>  8,
+            n   =>  n,
+        }
+    }
+
+    fn sweep_freq(&mut self) -> u32 {
+        let delta = self.sweep_shadow >> self.sweep_shift;
+        let freq = match self.sweep_decreasing {
+            true    =>  self.sweep_shadow.wrapping_sub(delta),
+            false   =>  self.sweep_shadow + delta,
+        };
+        if freq > 2047 {
+            self.enabled = false;
+        }
+        freq
+    }
+
+    pub fn amplitude(&self) -> f32 {
+        self.volume as f32 / 15f32
+    }
+
+    pub fn should_play(&self) -> bool {
+        self.enabled && self.dac_enabled
     }
 
-    pub fn should_play(&mut self) -> bool {
-        (self.duration == -1 || self.duration > 0) &&
-         self.envelope_steps_init > 0
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+        w.u32(self.freq_reg);
+        w.f32(self.freq);
+        w.u32(self.length);
+        w.bool(self.length_enabled);
+        w.u8(self.volume);
+        w.u8(self.envelope_volume);
+        w.u8(self.envelope_period);
+        w.u8(self.envelope_timer);
+        w.bool(self.envelope_increasing);
+        w.bool(self.sweep_enabled);
+        w.u8(self.sweep_period);
+        w.u8(self.sweep_timer);
+        w.u8(self.sweep_shift);
+        w.bool(self.sweep_decreasing);
+        w.u32(self.sweep_shadow);
+        w.f32(self.phase);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        self.enabled                = r.bool()?;
+        self.dac_enabled            = r.bool()?;
+        self.freq_reg               = r.u32()?;
+        self.freq                   = r.f32()?;
+        self.length                 = r.u32()?;
+        self.length_enabled         = r.bool()?;
+        self.volume                 = r.u8()?;
+        self.envelope_volume        = r.u8()?;
+        self.envelope_period        = r.u8()?;
+        self.envelope_timer         = r.u8()?;
+        self.envelope_increasing    = r.bool()?;
+        self.sweep_enabled          = r.bool()?;
+        self.sweep_period           = r.u8()?;
+        self.sweep_timer            = r.u8()?;
+        self.sweep_shift            = r.u8()?;
+        self.sweep_decreasing       = r.bool()?;
+        self.sweep_shadow           = r.u32()?;
+        self.phase                  = r.f32()?;
+        Ok(())
+    }
+
+    pub fn next_sample(&mut self, sample_rate: f32) -> f32 {
+        if !self.should_play() {
+            return 0f32;
+        }
+
+        self.phase = (self.phase + self.freq / sample_rate) % 1f32;
+        match self.phase < 0.5 {
+            true    =>  self.amplitude(),
+            false   =>  0f32,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct WaveChannel {
+    enabled:        bool,
+    dac_enabled:    bool,
+    freq:           f32,    // samples per second (32 samples per period)
+    position:       f32,
+    volume_shift:   u8,
+
+    length:         u32,
+    length_enabled: bool,
+
+    ram:            [u8; 0x10],
+}
+
+impl WaveChannel {
+    pub fn new() -> Self {
+        WaveChannel {
+            enabled:        false,
+            dac_enabled:    false,
+            freq:           0f32,
+            position:       0f32,
+            volume_shift:   4,
+            length:         0u32,
+            length_enabled: false,
+            ram:            [0; 0x10],
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.enabled && self.dac_enabled
+    }
+
+    pub fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length == 0 {
+            self.length = 256;
+        }
+        self.position = 0f32;
+    }
+
+    // 256Hz
+    pub fn clock_length(&mut self) {
+        if self.length_enabled && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // While the channel is playing, the CPU can only reach
+    // the byte which is currently being played (DMG)
+    fn ram_index(&self, addr: usize) -> usize {
+        match self.is_playing() {
+            true    =>  (self.position as usize % 32) / 2,
+            false   =>  addr - WAVE_START,
+        }
+    }
+
+    pub fn read_ram(&self, addr: usize) -> u8 {
+        self.ram[self.ram_index(addr)]
+    }
+
+    pub fn write_ram(&mut self, addr: usize, data: u8) {
+        let index = self.ram_index(addr);
+        self.ram[index] = data;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+        w.f32(self.freq);
+        w.f32(self.position);
+        w.u8(self.volume_shift);
+        w.u32(self.length);
+        w.bool(self.length_enabled);
+        w.bytes(&self.ram);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        self.enabled        = r.bool()?;
+        self.dac_enabled    = r.bool()?;
+        self.freq           = r.f32()?;
+        self.position       = r.f32()?;
+        self.volume_shift   = r.u8()?;
+        self.length         = r.u32()?;
+        self.length_enabled = r.bool()?;
+        r.bytes_into(&mut self.ram)
+    }
+
+    pub fn next_sample(&mut self, sample_rate: f32) -> f32 {
+        if !self.is_playing() {
+            return 0f32;
+        }
+
+        let index = self.position as usize % 32;
+        let sample = match index % 2 {
+            0   =>  self.ram[index/2] >> 4,
+            _   =>  self.ram[index/2] & 0x0F,
+        };
+        self.position = (self.position + self.freq / sample_rate) % 32f32;
+
+        (sample >> self.volume_shift) as f32 / 15f32
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct NoiseChannel {
+    enabled:                bool,
+    dac_enabled:            bool,
+    freq:                   f32,
+    width7:                 bool,
+    lfsr:                   u16,
+    phase:                  f32,
+
+    length:                 u32,
+    length_enabled:         bool,
+
+    volume:                 u8,
+    envelope_volume:        u8,
+    envelope_period:        u8,
+    envelope_timer:         u8,
+    envelope_increasing:    bool,
+}
+
+impl NoiseChannel {
+    pub fn new() -> Self {
+        NoiseChannel {
+            enabled:                false,
+            dac_enabled:            false,
+            freq:                   0f32,
+            width7:                 false,
+            lfsr:                   0x7FFF,
+            phase:                  0f32,
+            length:                 0u32,
+            length_enabled:         false,
+            volume:                 0u8,
+            envelope_volume:        0u8,
+            envelope_period:        0u8,
+            envelope_timer:         0u8,
+            envelope_increasing:    false,
+        }
+    }
+
+    pub fn set_envelope(&mut self, data: u8) {
+        self.envelope_volume     = (data & 0xF0) >> 4;
+        self.envelope_increasing = data & 0x08 != 0;
+        self.envelope_period     = data & 0x07;
+        self.dac_enabled         = data & 0xF8 != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    pub fn set_polynomial(&mut self, data: u8) {
+        let shift = (data >> 4) as u32;
+        let divisor = match data & 0x07 {
+            0   =>  0.5f32,
+            r   =>  r as f32,
+        };
+        self.freq = 524288f32 / divisor / (2u32.pow(shift + 1) as f32);
+        self.width7 = data & 0x08 != 0;
+    }
+
+    pub fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length == 0 {
+            self.length = 64;
+        }
+        self.volume = self.envelope_volume;
+        self.envelope_timer = self.envelope_period;
+        self.lfsr = 0x7FFF;
+    }
+
+    // 256Hz
+    pub fn clock_length(&mut self) {
+        if self.length_enabled && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // 64Hz
+    pub fn clock_envelope(&mut self) {
+        if self.envelope_period == 0 {
+            return;
+        }
+        self.envelope_timer = self.envelope_timer.saturating_sub(1);
+        if self.envelope_timer == 0 {
+            self.envelope_timer = self.envelope_period;
+            if self.envelope_increasing && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.envelope_increasing && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn shift_lfsr(&mut self) {
+        let bit = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+        self.lfsr = (self.lfsr >> 1) | (bit << 14);
+        if self.width7 {
+            self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+        w.f32(self.freq);
+        w.bool(self.width7);
+        w.u16(self.lfsr);
+        w.f32(self.phase);
+        w.u32(self.length);
+        w.bool(self.length_enabled);
+        w.u8(self.volume);
+        w.u8(self.envelope_volume);
+        w.u8(self.envelope_period);
+        w.u8(self.envelope_timer);
+        w.bool(self.envelope_increasing);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        self.enabled                = r.bool()?;
+        self.dac_enabled            = r.bool()?;
+        self.freq                   = r.f32()?;
+        self.width7                 = r.bool()?;
+        self.lfsr                   = r.u16()?;
+        self.phase                  = r.f32()?;
+        self.length                 = r.u32()?;
+        self.length_enabled         = r.bool()?;
+        self.volume                 = r.u8()?;
+        self.envelope_volume        = r.u8()?;
+        self.envelope_period        = r.u8()?;
+        self.envelope_timer         = r.u8()?;
+        self.envelope_increasing    = r.bool()?;
+        Ok(())
+    }
+
+    pub fn next_sample(&mut self, sample_rate: f32) -> f32 {
+        if !(self.enabled && self.dac_enabled) {
+            return 0f32;
+        }
+
+        self.phase += self.freq / sample_rate;
+        while self.phase >= 1f32 {
+            self.phase -= 1f32;
+            self.shift_lfsr();
+        }
+        match self.lfsr & 0x01 {
+            0   =>  self.volume as f32 / 15f32,
+            _   =>  0f32,
+        }
+    }
+}
+
+struct Mixer {
+    channel1:       Channel,
+    channel2:       Channel,
+    channel3:       WaveChannel,
+    channel4:       NoiseChannel,
+    nr50:           u8,
+    nr51:           u8,
+    sample_rate:    f32,
+    // While the APU captures samples itself, the output stream plays silence
+    capturing:      bool,
+    // Samples the output stream has asked for, to see whether the device keeps up
+    played:         u64,
+    // Debug mutes, independent of the sound registers
+    channel_enabled:    [bool; 4],
+    high_pass:      HighPass,
+    // Charge factor per output sample
+    charge:         f32,
+    // Left and right
+    capacitors:     [f32; 2],
+}
+
+impl Mixer {
+    // The sample rate is set once the output stream is open
+    pub fn new() -> Self {
+        Mixer {
+            channel1:       Channel::new(1),
+            channel2:       Channel::new(2),
+            channel3:       WaveChannel::new(),
+            channel4:       NoiseChannel::new(),
+            nr50:           0x77,
+            nr51:           0xF3,
+            sample_rate:    0.0,
+            capturing:      false,
+            played:         0,
+            channel_enabled:    [true; 4],
+            high_pass:      HighPass::Off,
+            charge:         1.0,
+            capacitors:     [0.0; 2],
+        }
+    }
+
+    // The channel status bits of NR52
+    fn status(&self) -> u8 {
+        let on = [
+            self.channel1.enabled,
+            self.channel2.enabled,
+            self.channel3.enabled,
+            self.channel4.enabled,
+        ];
+        on.iter().enumerate().fold(0, |status, (i, on)| status | (*on as u8) << i)
+    }
+
+    fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate as f32;
+        self.set_high_pass(self.high_pass);
+    }
+
+    fn set_high_pass(&mut self, filter: HighPass) {
+        self.high_pass = filter;
+        self.charge = filter.charge_factor().powf(CLOCK_HZ as f32 / self.sample_rate);
+        self.capacitors = [0.0; 2];
+    }
+
+    // Returns a (left, right) sample.
+    // NR51 routes each channel to the left (bits 4-7) and/or right (bits 0-3) output
+    // and NR50 scales each output by (volume + 1) / 8.
+    pub fn next_sample(&mut self) -> (f32, f32) {
+        let outputs = [
+            self.channel1.next_sample(self.sample_rate),
+            self.channel2.next_sample(self.sample_rate),
+            self.channel3.next_sample(self.sample_rate),
+            self.channel4.next_sample(self.sample_rate),
+        ];
+        let mut left = 0f32;
+        let mut right = 0f32;
+
+        for (i, output) in outputs.iter().enumerate() {
+            if !self.channel_enabled[i] {
+                continue;
+            }
+            if self.nr51 & (0x10 << i) != 0 {
+                left += output;
+            }
+            if self.nr51 & (0x01 << i) != 0 {
+                right += output;
+            }
+        }
+
+        let left_volume = (((self.nr50 >> 4) & 0x07) + 1) as f32 / 8f32;
+        let right_volume = ((self.nr50 & 0x07) + 1) as f32 / 8f32;
+
+        let (left, right) = (left * left_volume / 20.0, right * right_volume / 20.0);
+        if self.high_pass == HighPass::Off {
+            return (left, right);
+        }
+        (
+            high_pass(&mut self.capacitors[0], left, self.charge),
+            high_pass(&mut self.capacitors[1], right, self.charge),
+        )
     }
 }
 
@@ -78,16 +634,12 @@ pub struct Apu {
     nr12: u8,
     nr13: u8,
     nr14: u8,
-    channel1:    Arc<Mutex<Channel>>,
-    stream1:    Stream,
     
     // Sound Channel 2
     nr21: u8,
     nr22: u8,
     nr23: u8,
     nr24: u8,
-    channel2:    Arc<Mutex<Channel>>,
-    stream2:    Stream,
 
     // Sound Channel 3
     nr30: u8,
@@ -95,7 +647,6 @@ pub struct Apu {
     nr32: u8,
     nr33: u8,
     nr34: u8,
-    wavepattern_ram: [u8; 0x10],
     
     // Sound Channel 4
     nr41: u8,
@@ -107,229 +658,659 @@ pub struct Apu {
     nr50: u8,
     nr51: u8,
     nr52: u8,
+
+    mixer:  Arc<Mutex<Mixer>>,
+    // None until open_output(), or if the device can't play
+    stream: Option<Stream>,
+    sample_rate:    u32,
+    // What set_sample_rate() asked for. The device's highest rate otherwise.
+    wanted_rate:    Option<u32>,
+    // Samples taken every CLOCK_HZ / sample_rate clocks of emulated time while capturing
+    capture:        Option<Vec<(f32, f32)>>,
+    sample_clock:   u64,
+
+    sequencer_clock:    u16,
+    sequencer_step:     u8,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
 }
 
 impl Apu {
+    // Silent until open_output(), so headless runs and tests don't need an audio device
     pub fn new() -> Self {
-        let channel1 = Arc::new(Mutex::new(Channel::new(1)));
-        let stream1 = get_stream(channel1.clone());
-        stream1.play().unwrap();
-        
-        let channel2 = Arc::new(Mutex::new(Channel::new(2)));
-        let stream2 = get_stream(channel2.clone());
-        stream2.play().unwrap();
-
-        Apu {
-         nr10:      0x80,
-         nr11:      0xBF,
-         nr12:      0xF3,
-         nr13:      0x00,
-         nr14:      0xBF,
-         channel1:   channel1,
-         stream1:   stream1,
-
-         nr21:      0x3F,
-         nr22:      0x00,
-         nr23:      0x00,
-         nr24:      0xBF,
-         channel2:   channel2,
-         stream2:   stream2,
+        let mixer = Arc::new(Mutex::new(Mixer::new()));
+        if let Ok(mut mixer) = mixer.lock() {
+            mixer.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        }
+
+        let mut apu = Apu {
+         nr10:  0,
+         nr11:  0,
+         nr12:  0,
+         nr13:  0,
+         nr14:  0,
+
+         nr21:  0,
+         nr22:  0,
+         nr23:  0,
+         nr24:  0,
          
-         nr30:  0x7F,
-         nr31:  0xFF,
-         nr32:  0x9F,
-         nr33:  0xBF,
-         nr34:  0x00,
-         wavepattern_ram:    [0; 0x10],
+         nr30:  0,
+         nr31:  0,
+         nr32:  0,
+         nr33:  0,
+         nr34:  0,
          
-         nr41:  0xFF,
-         nr42:  0x00,
-         nr43:  0x00,
-         nr44:  0x00,
+         nr41:  0,
+         nr42:  0,
+         nr43:  0,
+         nr44:  0,
          
-         nr50:  0x77,
-         nr51:  0xF3,
-         nr52:  0xF1,
+         nr50:  0,
+         nr51:  0,
+         nr52:  0,
+
+         mixer,
+         stream:    None,
+         sample_rate:   DEFAULT_SAMPLE_RATE,
+         wanted_rate:   None,
+         capture:       None,
+         sample_clock:  0,
+
+         sequencer_clock:   0,
+         sequencer_step:    0,
+        };
+        apu.reset();
+
+        apu
+    }
+
+    // Starts playing on the default output device, at the rate set_sample_rate() asked for
+    // if it supports it and otherwise at its highest. Without a usable device the APU stays silent.
+    pub fn open_output(&mut self) {
+        self.stream = get_stream(self.mixer.clone(), self.wanted_rate).map(|(stream, rate)| {
+            self.set_mixer_rate(rate);
+            stream
+        });
+        self.start();
+    }
+
+    pub fn has_output(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn set_mixer_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+        if let Ok(mut mixer) = self.mixer.lock() {
+            mixer.set_sample_rate(rate);
+        }
+    }
+
+    // Silences the audio stream, e.g. before exiting. The APU keeps running.
+    pub fn stop(&mut self) {
+        if let Some(Err(e)) = self.stream.as_ref().map(|stream| stream.pause()) {
+            error!("can't stop the audio stream: {}", e);
+        }
+    }
+
+    // Plays the audio stream again after stop()
+    pub fn start(&mut self) {
+        if let Some(Err(e)) = self.stream.as_ref().map(|stream| stream.play()) {
+            error!("can't start the audio stream: {}", e);
+        }
+    }
+
+    // Restores the post-boot register values without reopening the audio stream
+    pub fn reset(&mut self) {
+        self.power_off();
+
+        self.nr10 = 0x80;
+        self.nr11 = 0xBF;
+        self.nr12 = 0xF3;
+        self.nr13 = 0x00;
+        self.nr14 = 0xBF;
+
+        self.nr21 = 0x3F;
+        self.nr22 = 0x00;
+        self.nr23 = 0x00;
+        self.nr24 = 0xBF;
+
+        self.nr30 = 0x7F;
+        self.nr31 = 0xFF;
+        self.nr32 = 0x9F;
+        self.nr33 = 0xBF;
+        self.nr34 = 0x00;
+
+        self.nr41 = 0xFF;
+        self.nr42 = 0x00;
+        self.nr43 = 0x00;
+        self.nr44 = 0x00;
+
+        self.nr50 = 0x77;
+        self.nr51 = 0xF3;
+        self.nr52 = 0xF1;
+
+        if let Ok(mut mixer) = self.mixer.lock() {
+            mixer.nr50 = self.nr50;
+            mixer.nr51 = self.nr51;
+            // The boot sound leaves channel 1 on, faded out to silence
+            mixer.channel1.set_envelope(self.nr12);
+            mixer.channel1.enabled = true;
+        };
+
+        self.sequencer_clock = 0;
+        self.sequencer_step = 0;
+    }
+
+    // Reopens the output stream, if there is one, at `rate` and returns the rate it got,
+    // which is the device's highest if it doesn't support `rate`
+    pub fn set_sample_rate(&mut self, rate: u32) -> u32 {
+        self.wanted_rate = Some(rate);
+        self.set_mixer_rate(rate);
+        if self.stream.is_some() {
+            self.open_output();
+        }
+        self.sample_rate
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Makes the APU produce the samples itself, in step with emulated time instead of
+    // the output device, e.g. to check or record the sound headless. The output stream
+    // plays silence meanwhile.
+    pub fn set_capture(&mut self, on: bool) {
+        self.capture = match on {
+            true    =>  Some(self.capture.take().unwrap_or_default()),
+            false   =>  None,
+        };
+        self.sample_clock = 0;
+        if let Ok(mut mixer) = self.mixer.lock() {
+            mixer.capturing = on;
+        }
+    }
+
+    pub fn samples_played(&self) -> u64 {
+        self.mixer.lock().map(|mixer| mixer.played).unwrap_or(0)
+    }
+
+    // The (left, right) samples captured since the last call
+    pub fn take_samples(&mut self) -> Vec<(f32, f32)> {
+        match &mut self.capture {
+            Some(samples)   =>  std::mem::take(samples),
+            None            =>  Vec::new(),
+        }
+    }
+
+    fn capture_samples(&mut self) {
+        let samples = match &mut self.capture {
+            Some(samples)   =>  samples,
+            None            =>  return,
+        };
+        self.sample_clock += 4 * self.sample_rate as u64;
+        if self.sample_clock < CLOCK_HZ {
+            return;
+        }
+        if let Ok(mut mixer) = self.mixer.lock() {
+            while self.sample_clock >= CLOCK_HZ {
+                self.sample_clock -= CLOCK_HZ;
+                samples.push(mixer.next_sample());
+            }
         }
     }
+
+    pub fn tick(&mut self) {
+        self.capture_samples();
+
+        self.sequencer_clock += 4;
+        if self.sequencer_clock < FRAME_SEQUENCER_PERIOD {
+            return;
+        }
+        self.sequencer_clock -= FRAME_SEQUENCER_PERIOD;
+
+        let step = self.sequencer_step;
+        self.sequencer_step = (step + 1) % 8;
+
+        if let Ok(mut mixer) = self.mixer.lock() {
+            // Length counters (256Hz)
+            if step.is_multiple_of(2) {
+                mixer.channel1.clock_length();
+                mixer.channel2.clock_length();
+                mixer.channel3.clock_length();
+                mixer.channel4.clock_length();
+            }
+            // Sweep (128Hz)
+            if step == 2 || step == 6 {
+                mixer.channel1.clock_sweep();
+            }
+            // Volume envelopes (64Hz)
+            if step == 7 {
+                mixer.channel1.clock_envelope();
+                mixer.channel2.clock_envelope();
+                mixer.channel4.clock_envelope();
+            }
+        }
+    }
+
+    // Mutes (or unmutes) channel 1-4 in the final mix, for debugging
+    pub fn set_channel_enabled(&mut self, ch: u8, on: bool) {
+        if let Ok(mut mixer) = self.mixer.lock() {
+            if let Some(enabled) = mixer.channel_enabled.get_mut((ch as usize).wrapping_sub(1)) {
+                *enabled = on;
+            }
+        };
+    }
+
+    // Filters the DC offset out of the output like the DMG or CGB hardware, or not at all
+    pub fn set_high_pass(&mut self, filter: HighPass) {
+        if let Ok(mut mixer) = self.mixer.lock() {
+            mixer.set_high_pass(filter);
+        };
+    }
+
+    // The output sample rate, the filter and the debug mutes belong to the host and aren't saved
+    pub fn save_state(&self, w: &mut StateWriter) {
+        let registers = [
+            self.nr10, self.nr11, self.nr12, self.nr13, self.nr14,
+            self.nr21, self.nr22, self.nr23, self.nr24,
+            self.nr30, self.nr31, self.nr32, self.nr33, self.nr34,
+            self.nr41, self.nr42, self.nr43, self.nr44,
+            self.nr50, self.nr51, self.nr52,
+        ];
+        w.bytes(&registers);
+        w.u16(self.sequencer_clock);
+        w.u8(self.sequencer_step);
+
+        // The channels must be written even if the audio thread died
+        let mixer = self.mixer.lock().unwrap_or_else(|e| e.into_inner());
+        mixer.channel1.save_state(w);
+        mixer.channel2.save_state(w);
+        mixer.channel3.save_state(w);
+        mixer.channel4.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        let mut registers = [0; 21];
+        r.bytes_into(&mut registers)?;
+        self.nr10 = registers[0];
+        self.nr11 = registers[1];
+        self.nr12 = registers[2];
+        self.nr13 = registers[3];
+        self.nr14 = registers[4];
+        self.nr21 = registers[5];
+        self.nr22 = registers[6];
+        self.nr23 = registers[7];
+        self.nr24 = registers[8];
+        self.nr30 = registers[9];
+        self.nr31 = registers[10];
+        self.nr32 = registers[11];
+        self.nr33 = registers[12];
+        self.nr34 = registers[13];
+        self.nr41 = registers[14];
+        self.nr42 = registers[15];
+        self.nr43 = registers[16];
+        self.nr44 = registers[17];
+        self.nr50 = registers[18];
+        self.nr51 = registers[19];
+        self.nr52 = registers[20];
+        self.sequencer_clock = r.u16()?;
+        self.sequencer_step = r.u8()?;
+
+        let mut mixer = self.mixer.lock().unwrap_or_else(|e| e.into_inner());
+        mixer.channel1.load_state(r)?;
+        mixer.channel2.load_state(r)?;
+        mixer.channel3.load_state(r)?;
+        mixer.channel4.load_state(r)?;
+        mixer.nr50 = self.nr50;
+        mixer.nr51 = self.nr51;
+        Ok(())
+    }
+
+    fn is_powered(&self) -> bool {
+        self.nr52 & 0x80 != 0
+    }
+
+    fn power_off(&mut self) {
+        self.nr10 = 0;
+        self.nr11 = 0;
+        self.nr12 = 0;
+        self.nr13 = 0;
+        self.nr14 = 0;
+        self.nr21 = 0;
+        self.nr22 = 0;
+        self.nr23 = 0;
+        self.nr24 = 0;
+        self.nr30 = 0;
+        self.nr31 = 0;
+        self.nr32 = 0;
+        self.nr33 = 0;
+        self.nr34 = 0;
+        self.nr41 = 0;
+        self.nr42 = 0;
+        self.nr43 = 0;
+        self.nr44 = 0;
+        self.nr50 = 0;
+        self.nr51 = 0;
+        self.nr52 = 0;
+
+        if let Ok(mut mixer) = self.mixer.lock() {
+            // Wave RAM is not affected by the power state
+            let ram = mixer.channel3.ram;
+            mixer.channel1 = Channel::new(1);
+            mixer.channel2 = Channel::new(2);
+            mixer.channel3 = WaveChannel::new();
+            mixer.channel3.ram = ram;
+            mixer.channel4 = NoiseChannel::new();
+            mixer.nr50 = 0;
+            mixer.nr51 = 0;
+        };
+    }
+
+    fn update_wave_freq(&mut self) {
+        let freq = (2097152 / (2048 - ((self.nr33 as u32) + (((self.nr34 & 0b111) as u32) << 8)))) as f32;
+        if let Ok(mut mixer) = self.mixer.lock() {
+            mixer.channel3.freq = freq;
+        };
+    }
 }
 
 impl Io for Apu {
     fn read8(&self, addr: usize) -> u8 {
+        // Write-only bits, e.g. lengths, frequencies and triggers, read as 1
         match addr {
-            0xFF10              =>  self.nr10,
-            0xFF11              =>  self.nr11,
-            0xFF12              =>  self.nr12,
-            0xFF13              =>  self.nr13,
-            0xFF14              =>  self.nr14,
-            0xFF16              =>  self.nr21,
-            0xFF17              =>  self.nr22,
-            0xFF18              =>  self.nr23,
-            0xFF19              =>  self.nr24,
-            0xFF1A              =>  self.nr30,
-            0xFF1B              =>  self.nr31,
-            0xFF1C              =>  self.nr32,
-            0xFF1D              =>  self.nr33,
-            0xFF1E              =>  self.nr34,
-            0xFF30 ..= 0xFF3F   =>  self.wavepattern_ram[addr-0xFF30],
-            0xFF20              =>  self.nr41,
-            0xFF21              =>  self.nr42,
-            0xFF22              =>  self.nr43,
-            0xFF23              =>  self.nr44,
-            0xFF24              =>  self.nr50,
-            0xFF25              =>  self.nr51,
-            0xFF26              =>  self.nr52,
+            NR10                =>  self.nr10 | 0x80,
+            NR11                =>  self.nr11 | 0x3F,
+            NR12                =>  self.nr12,
+            NR13                =>  0xFF,
+            NR14                =>  self.nr14 | 0xBF,
+            NR21                =>  self.nr21 | 0x3F,
+            NR22                =>  self.nr22,
+            NR23                =>  0xFF,
+            NR24                =>  self.nr24 | 0xBF,
+            NR30                =>  self.nr30 | 0x7F,
+            NR31                =>  0xFF,
+            NR32                =>  self.nr32 | 0x9F,
+            NR33                =>  0xFF,
+            NR34                =>  self.nr34 | 0xBF,
+            WAVE_START ..= WAVE_END =>  match self.mixer.lock() {
+                Ok(mixer)   =>  mixer.channel3.read_ram(addr),
+                Err(_)      =>  0xFF,
+            },
+            NR41                =>  0xFF,
+            NR42                =>  self.nr42,
+            NR43                =>  self.nr43,
+            NR44                =>  self.nr44 | 0xBF,
+            NR50                =>  self.nr50,
+            NR51                =>  self.nr51,
+            // Bits 0-3: channel 1-4 on, i.e. triggered and neither its length
+            // nor its DAC turned it off. Bits 4-6 are unused.
+            NR52                =>  match self.mixer.lock() {
+                Ok(mixer)   =>  self.nr52 & 0x80 | 0x70 | mixer.status(),
+                Err(_)      =>  self.nr52 & 0x80 | 0x70,
+            },
             _                   =>  panic!("can't read from: {:04x}", addr),
         }
     }
 
     fn write8(&mut self, addr: usize, data: u8) {
+        // While the APU is powered off, NR10-NR51 ignore writes
+        if !self.is_powered() && (NR10 ..= NR51).contains(&addr) {
+            return;
+        }
+
         match addr {
-            0xFF10              =>  self.nr10 = data,
-            0xFF11              =>  {
+            NR10                =>  {
+                self.nr10 = data;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    let channel = &mut mixer.channel1;
+                    channel.set_sweep(self.nr10);
+                };
+            },
+            NR11                =>  {
                 self.nr11 = data;
-                if let Ok(mut channel) = self.channel1.lock() {
-                    channel.length = (self.nr11 & 0x3F) as u32;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    let channel = &mut mixer.channel1;
+                    channel.length = 64 - (self.nr11 & 0x3F) as u32;
                 };
             },
-            0xFF12              =>  {
+            NR12                =>  {
                 self.nr12 = data;
-                if let Ok(mut channel) = self.channel1.lock() {
-                    channel.envelope_volume     = ((self.nr12 & 0xF0) >> 4) as u32;
-                    channel.envelope_samples    = ((self.nr12 & 0x07) as f32) / 64f32;
-                    channel.envelope_increasing = (((self.nr12 & 0x08) >> 3) == 1) as bool;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    let channel = &mut mixer.channel1;
+                    channel.set_envelope(self.nr12);
                 };
             },
-            0xFF13              =>  {
+            NR13                =>  {
                 self.nr13 = data;
-                let freq = (131072 / (2048 - ((self.nr13 as u32) + (((self.nr14 & 0b111) as u32) << 8)))) as f32;
-                if let Ok(mut channel) = self.channel1.lock() {
-                    channel.freq = freq;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    let channel = &mut mixer.channel1;
+                    channel.set_freq((self.nr13 as u32) + (((self.nr14 & 0b111) as u32) << 8));
                 };
             },
-            0xFF14              =>  {
+            NR14                =>  {
                 self.nr14 = data;
-                let freq = (131072 / (2048 - ((self.nr13 as u32) + (((self.nr14 & 0b111) as u32) << 8)))) as f32;
-                if let Ok(mut channel) = self.channel1.lock() {
-                    channel.freq = freq;
-                    if self.nr14 & 0x80 != 0{
-                        if channel.length == 0 {
-                            channel.length = 64;
-                        }
-                        let mut duration = -1;
-                        if self.nr14 & 0x40 != 0 {
-                            duration = ((channel.length as f32) * (1f32/64f32)) as i32 * channel.sample_rate as i32;
-                        }
-                        channel.duration = duration;
-                        channel.reset();
-                        channel.envelope_steps = channel.envelope_volume;
-                        channel.envelope_steps_init = channel.envelope_volume;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    let channel = &mut mixer.channel1;
+                    channel.set_freq((self.nr13 as u32) + (((self.nr14 & 0b111) as u32) << 8));
+                    channel.length_enabled = self.nr14 & 0x40 != 0;
+                    if self.nr14 & 0x80 != 0 {
+                        channel.trigger();
                     }
                 };
             },
-            0xFF16              =>  self.nr21 = data,
-            0xFF17              =>  {
+            NR21                =>  {
+                self.nr21 = data;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    let channel = &mut mixer.channel2;
+                    channel.length = 64 - (self.nr21 & 0x3F) as u32;
+                };
+            },
+            NR22                =>  {
                 self.nr22 = data;
-                if let Ok(mut channel) = self.channel2.lock() {
-                    channel.envelope_volume     = ((self.nr22 & 0xF0) >> 4) as u32;
-                    channel.envelope_samples    = ((self.nr22 & 0x07) as f32) / 64f32;
-                    channel.envelope_increasing = (((self.nr22 & 0x08) >> 3) == 1) as bool;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    let channel = &mut mixer.channel2;
+                    channel.set_envelope(self.nr22);
                 };
             },
-            0xFF18              =>  {
+            NR23                =>  {
                 self.nr23 = data;
-                let freq = (131072 / (2048 - ((self.nr23 as u32) + (((self.nr24 & 0b111) as u32) << 8)))) as f32;
-                if let Ok(mut channel) = self.channel2.lock() {
-                    channel.freq = freq;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    let channel = &mut mixer.channel2;
+                    channel.set_freq((self.nr23 as u32) + (((self.nr24 & 0b111) as u32) << 8));
                 };
             },
-            0xFF19              =>  {
+            NR24                =>  {
                 self.nr24 = data;
-                let freq = (131072 / (2048 - ((self.nr23 as u32) + (((self.nr24 & 0b111) as u32) << 8)))) as f32;
-                if let Ok(mut channel) = self.channel2.lock() {
-                    channel.freq = freq;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    let channel = &mut mixer.channel2;
+                    channel.set_freq((self.nr23 as u32) + (((self.nr24 & 0b111) as u32) << 8));
+                    channel.length_enabled = self.nr24 & 0x40 != 0;
                     if self.nr24 & 0x80 != 0 {
-                        if channel.length == 0 {
-                            channel.length = 64;
-                        }
-                        let mut duration = -1;
-                        if self.nr24 & 0x40 != 0 {
-                            duration = ((channel.length as f32) * (1f32/64f32)) as i32 * channel.sample_rate as i32;
-                        }
-                        channel.duration = duration;
-                        channel.reset();
-                        channel.envelope_steps = channel.envelope_volume;
-                        channel.envelope_steps_init = channel.envelope_volume;
+                        channel.trigger();
                     }
                 };
             },
-            0xFF1A              =>  self.nr30 = data,
-            0xFF1B              =>  self.nr31 = data,
-            0xFF1C              =>  self.nr32 = data,
-            0xFF1D              =>  self.nr33 = data,
-            0xFF1E              =>  self.nr34 = data,
-            0xFF30 ..= 0xFF3F   =>  self.wavepattern_ram[addr-0xFF30] = data,
-            0xFF20              =>  self.nr41 = data,
-            0xFF21              =>  self.nr42 = data,
-            0xFF22              =>  self.nr43 = data,
-            0xFF23              =>  self.nr44 = data,
-            0xFF24              =>  self.nr50 = data,
-            0xFF25              =>  self.nr51 = data,
-            0xFF26              =>  self.nr52 = data,
+            NR30                =>  {
+                self.nr30 = data;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    let channel = &mut mixer.channel3;
+                    channel.dac_enabled = self.nr30 & 0x80 != 0;
+                    if !channel.dac_enabled {
+                        channel.enabled = false;
+                    }
+                };
+            },
+            NR31                =>  {
+                self.nr31 = data;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    let channel = &mut mixer.channel3;
+                    channel.length = 256 - self.nr31 as u32;
+                };
+            },
+            NR32                =>  {
+                self.nr32 = data;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    let channel = &mut mixer.channel3;
+                    channel.volume_shift = match (self.nr32 >> 5) & 0x03 {
+                        0   =>  4,  // mute
+                        1   =>  0,  // 100%
+                        2   =>  1,  // 50%
+                        _   =>  2,  // 25%
+                    };
+                };
+            },
+            NR33                =>  {
+                self.nr33 = data;
+                self.update_wave_freq();
+            },
+            NR34                =>  {
+                self.nr34 = data;
+                self.update_wave_freq();
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    let channel = &mut mixer.channel3;
+                    channel.length_enabled = self.nr34 & 0x40 != 0;
+                    if self.nr34 & 0x80 != 0 {
+                        channel.trigger();
+                    }
+                };
+            },
+            WAVE_START ..= WAVE_END =>  {
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    let channel = &mut mixer.channel3;
+                    channel.write_ram(addr, data);
+                };
+            },
+            NR41                =>  {
+                self.nr41 = data;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    mixer.channel4.length = 64 - (self.nr41 & 0x3F) as u32;
+                };
+            },
+            NR42                =>  {
+                self.nr42 = data;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    mixer.channel4.set_envelope(self.nr42);
+                };
+            },
+            NR43                =>  {
+                self.nr43 = data;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    mixer.channel4.set_polynomial(self.nr43);
+                };
+            },
+            NR44                =>  {
+                self.nr44 = data;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    mixer.channel4.length_enabled = self.nr44 & 0x40 != 0;
+                    if self.nr44 & 0x80 != 0 {
+                        mixer.channel4.trigger();
+                    }
+                };
+            },
+            NR50                =>  {
+                self.nr50 = data;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    mixer.nr50 = self.nr50;
+                };
+            },
+            NR51                =>  {
+                self.nr51 = data;
+                if let Ok(mut mixer) = self.mixer.lock() {
+                    mixer.nr51 = self.nr51;
+                };
+            },
+            NR52                =>  {
+                if data & 0x80 == 0 {
+                    self.power_off();
+                }
+                // Only the power bit is writable
+                self.nr52 = data & 0x80;
+            },
             _       => panic!("can't write to: {:04x}", addr),
         }
     }
 }
 
-fn write_data<T>(output: &mut [T], channels: usize, next_sample: &mut dyn FnMut() -> f32)
+fn write_data<T>(output: &mut [T], channels: usize, next_sample: &mut dyn FnMut() -> (f32, f32))
 where
     T: cpal::Sample
 {
     for frame in output.chunks_mut(channels) {
-        let value: T = cpal::Sample::from::<f32>(&next_sample());
-        for sample in frame.iter_mut() {
-            *sample = value;
+        let (left, right) = next_sample();
+        if channels == 1 {
+            frame[0] = cpal::Sample::from::<f32>(&((left + right) / 2f32));
+            continue;
+        }
+        for (i, sample) in frame.iter_mut().enumerate() {
+            *sample = match i {
+                0   =>  cpal::Sample::from::<f32>(&left),
+                1   =>  cpal::Sample::from::<f32>(&right),
+                _   =>  cpal::Sample::from::<f32>(&0f32),
+            };
         }
     }
 }
 
-fn get_stream(channel_arc: Arc<Mutex<Channel>>) -> Stream {
+// Opens the output stream at `sample_rate` if the device has a config for it,
+// otherwise at the highest rate of its first config. Returns the stream and its rate,
+// or None with a warning if there is no device or it can't play.
+fn get_stream(mixer: Arc<Mutex<Mixer>>, sample_rate: Option<u32>) -> Option<(Stream, u32)> {
     let host = cpal::default_host();
-    let device = host.default_output_device().expect("no output device available");
-    let err_fn = |err| eprintln!("an error occurred on the output audio stream: {}", err);
-    let mut supported_configs_range = device.supported_output_configs()
-        .expect("error while querying configs");
-    let supported_config = supported_configs_range.next()
-        .expect("no supported config?!")
-        .with_max_sample_rate();
+    let device = match host.default_output_device() {
+        Some(device)    =>  device,
+        None            =>  {
+            warn!("no audio output device, the sound is off");
+            return None;
+        },
+    };
+    let err_fn = |err| error!("an error occurred on the output audio stream: {}", err);
+    let supported_configs: Vec<_> = match device.supported_output_configs() {
+        Ok(configs) =>  configs.collect(),
+        Err(e)      =>  {
+            warn!("can't query the audio device, the sound is off: {}", e);
+            return None;
+        },
+    };
+    let wanted = sample_rate.and_then(|rate| supported_configs.iter()
+        .find(|c| c.min_sample_rate().0 <= rate && rate <= c.max_sample_rate().0)
+        .map(|c| c.clone().with_sample_rate(SampleRate(rate))));
+    let supported_config = match wanted {
+        Some(config)    =>  config,
+        None            =>  {
+            let config = match supported_configs.into_iter().next() {
+                Some(config)    =>  config.with_max_sample_rate(),
+                None            =>  {
+                    warn!("the audio device has no output config, the sound is off");
+                    return None;
+                },
+            };
+            if let Some(rate) = sample_rate {
+                warn!("the audio device can't play at {} Hz, using {} Hz", rate, config.sample_rate().0);
+            }
+            config
+        },
+    };
     let sample_format = supported_config.sample_format();
     let config: cpal::StreamConfig = supported_config.into();
     let channels = config.channels as usize;
-    let sample_rate = config.sample_rate.0 as f32;
-    let mut sample_clock = 0f32;
-    let mut prev = 0f32;
+    let rate = config.sample_rate.0;
 
     let mut call_back = move || {
-        sample_clock = (sample_clock + 1f32) % sample_rate;
-        let mut output = prev;
-        
-        if let Ok(mut channel) = channel_arc.lock() {
-            if channel.should_play() {
-                output = channel.amplitude * ((sample_clock * channel.freq * 2.0 * std::f32::consts::PI / sample_rate)
-                            .sin().ceil()) / 20.0;
-                prev = output;
-                if channel.duration > 0 {
-                    channel.duration -= 1;
-                }
-            }
-            channel.update_envelope();
-            channel.update_sweep();
+        match mixer.lock() {
+            Ok(mut mixer) if !mixer.capturing   =>  {
+                mixer.played += 1;
+                mixer.next_sample()
+            },
+            _                                   =>  (0f32, 0f32),
         }
-
-        output
     };
 
-    match sample_format {
+    let stream = match sample_format {
         SampleFormat::F32 => device.build_output_stream(
             &config,
             move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
@@ -337,18 +1318,215 @@ fn get_stream(channel_arc: Arc<Mutex<Channel>>) -> Stream {
             },
             err_fn
         ),
-        _   => panic!(),
-    }.unwrap()
+        format  =>  {
+            warn!("unsupported audio sample format {:?}, the sound is off", format);
+            return None;
+        },
+    };
+    match stream {
+        Ok(stream)  =>  Some((stream, rate)),
+        Err(e)      =>  {
+            warn!("can't open the audio stream, the sound is off: {}", e);
+            None
+        },
+    }
 }
 
-fn get_sample_rate() -> f32 {
-    let host = cpal::default_host();
-    let device = host.default_output_device().expect("no output device available");
-    let mut supported_configs_range = device.supported_output_configs()
-        .expect("error while querying configs");
-    let supported_config = supported_configs_range.next()
-        .expect("no supported config?!")
-        .with_max_sample_rate();
-    let config: cpal::StreamConfig = supported_config.into();
-    config.sample_rate.0 as f32
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_apu_has_no_output() {
+        let apu = Apu::new();
+        assert!(!apu.has_output());
+        assert_eq!(apu.sample_rate(), DEFAULT_SAMPLE_RATE);
+    }
+
+    #[test]
+    fn sample_rate_without_output() {
+        let mut apu = Apu::new();
+        assert_eq!(apu.set_sample_rate(44100), 44100);
+        assert!(!apu.has_output());
+        assert_eq!(apu.sample_rate(), 44100);
+    }
+
+    #[test]
+    fn captures_without_output() {
+        let mut apu = Apu::new();
+        apu.set_capture(true);
+        // A second, ticked once per machine cycle
+        for _ in 0..CLOCK_HZ / 4 {
+            apu.tick();
+        }
+        assert_eq!(apu.take_samples().len(), DEFAULT_SAMPLE_RATE as usize);
+    }
+
+    #[test]
+    fn power_off_clears_and_ignores_registers() {
+        let mut apu = Apu::new();
+        apu.write8(WAVE_START, 0x5A);
+        apu.write8(NR52, 0x00);
+        assert_eq!(apu.read8(NR12), 0x00);
+
+        apu.write8(NR11, 0x80);
+        apu.write8(NR12, 0xF3);
+        // Only the write-only length bits read as 1
+        assert_eq!(apu.read8(NR11), 0x3F);
+        assert_eq!(apu.read8(NR12), 0x00);
+        assert_eq!(apu.read8(NR52), 0x70);
+        // Wave RAM keeps its contents
+        assert_eq!(apu.read8(WAVE_START), 0x5A);
+
+        apu.write8(NR52, 0x80);
+        apu.write8(NR12, 0xF3);
+        assert_eq!(apu.read8(NR12), 0xF3);
+    }
+
+    #[test]
+    fn wave_channel_plays_ramp() {
+        let mut apu = Apu::new();
+        // Samples 0-15, twice
+        for (i, addr) in (WAVE_START ..= WAVE_END).enumerate() {
+            let sample = (i * 2) % 16;
+            apu.write8(addr, ((sample << 4) | (sample + 1)) as u8);
+        }
+        apu.write8(NR30, 0x80);
+        // 100% volume
+        apu.write8(NR32, 0x20);
+        // 2097152 / (2048 - 1984) = 32768 samples per second
+        apu.write8(NR33, 0xC0);
+        apu.write8(NR34, 0x87);
+
+        // One wave sample per output sample
+        let mut mixer = apu.mixer.lock().unwrap();
+        for i in 0..32 {
+            assert_eq!(mixer.channel3.next_sample(32768.0), (i % 16) as f32 / 15.0);
+        }
+    }
+
+    #[test]
+    fn length_counter_silences_channel() {
+        let mut apu = Apu::new();
+        // Channel 2, a length of 2
+        apu.write8(NR21, 0x3E);
+        apu.write8(NR22, 0xF0);
+        apu.write8(NR24, 0xC0);
+        assert_eq!(apu.read8(NR52) & 0x02, 0x02);
+
+        // Length counters are clocked on steps 0, 2, 4 and 6, every 8192 clocks
+        for _ in 0..2 * FRAME_SEQUENCER_PERIOD / 4 {
+            apu.tick();
+        }
+        assert_eq!(apu.read8(NR52) & 0x02, 0x02);
+        for _ in 0..FRAME_SEQUENCER_PERIOD / 4 {
+            apu.tick();
+        }
+        assert_eq!(apu.read8(NR52) & 0x02, 0x00);
+    }
+
+    #[test]
+    fn nr51_pans_channel_left() {
+        let mut apu = Apu::new();
+        apu.write8(NR50, 0x77);
+        // Channel 2 to the left only
+        apu.write8(NR51, 0x20);
+        apu.write8(NR22, 0xF0);
+        apu.write8(NR23, 0x00);
+        apu.write8(NR24, 0x87);
+
+        apu.set_capture(true);
+        for _ in 0..CLOCK_HZ / 4 / 100 {
+            apu.tick();
+        }
+        let samples = apu.take_samples();
+        assert!(samples.iter().any(|&(left, _)| left > 0.0));
+        assert!(samples.iter().all(|&(_, right)| right == 0.0));
+    }
+
+    #[test]
+    fn muted_channel_leaves_mix() {
+        let mut apu = Apu::new();
+        apu.write8(NR50, 0x77);
+        // Channel 1 to the right, channel 2 to the left
+        apu.write8(NR51, 0x21);
+        apu.write8(NR12, 0xF0);
+        apu.write8(NR14, 0x87);
+        apu.write8(NR22, 0xF0);
+        apu.write8(NR24, 0x87);
+        apu.set_channel_enabled(2, false);
+
+        apu.set_capture(true);
+        for _ in 0..CLOCK_HZ / 4 / 100 {
+            apu.tick();
+        }
+        let samples = apu.take_samples();
+        assert!(samples.iter().all(|&(left, _)| left == 0.0));
+        assert!(samples.iter().any(|&(_, right)| right > 0.0));
+        assert_eq!(apu.read8(NR52) & 0x03, 0x03);
+    }
+
+    #[test]
+    fn high_pass_removes_dc_offset() {
+        let charge = HighPass::Dmg.charge_factor().powf(CLOCK_HZ as f32 / 44100.0);
+        let mut capacitor = 0.0;
+        let outputs: Vec<f32> = (0..44100).map(|_| high_pass(&mut capacitor, 1.0, charge)).collect();
+        assert_eq!(outputs[0], 1.0);
+        assert!(outputs.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(outputs[44099].abs() < 0.01);
+
+        // Off lets the offset through
+        let mut capacitor = 0.0;
+        let charge = HighPass::Off.charge_factor();
+        assert!((0..100).all(|_| high_pass(&mut capacitor, 1.0, charge) == 1.0));
+    }
+
+    #[test]
+    fn nr52_reports_status_bits() {
+        // The boot sound leaves channel 1 on. Power cycling stops it.
+        let mut apu = Apu::new();
+        assert_eq!(apu.read8(NR52), 0xF1);
+        apu.write8(NR52, 0x00);
+        apu.write8(NR52, 0x80);
+        assert_eq!(apu.read8(NR52), 0xF0);
+
+        // Channel 1 with its DAC on
+        apu.write8(NR12, 0xF0);
+        apu.write8(NR14, 0x80);
+        assert_eq!(apu.read8(NR52), 0xF1);
+
+        // Only the power bit is writable
+        apu.write8(NR52, 0x8E);
+        assert_eq!(apu.read8(NR52), 0xF1);
+
+        // Turning the DAC off stops the channel
+        apu.write8(NR12, 0x00);
+        assert_eq!(apu.read8(NR52), 0xF0);
+        apu.write8(NR52, 0x00);
+        assert_eq!(apu.read8(NR52), 0x70);
+    }
+
+    #[test]
+    fn nr_registers_read_with_masks() {
+        // Register and the bits that always read as 1
+        let masks = [
+            (0xFF10, 0x80), (0xFF11, 0x3F), (0xFF12, 0x00), (0xFF13, 0xFF), (0xFF14, 0xBF),
+                            (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF18, 0xFF), (0xFF19, 0xBF),
+            (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1D, 0xFF), (0xFF1E, 0xBF),
+                            (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00), (0xFF23, 0xBF),
+            (0xFF24, 0x00), (0xFF25, 0x00),
+        ];
+        let mut apu = Apu::new();
+        for &(addr, mask) in masks.iter() {
+            apu.write8(addr, 0x00);
+            assert_eq!(apu.read8(addr), mask, "0x{:04x}", addr);
+        }
+        // Bits that can be read back keep what was written
+        apu.write8(0xFF12, 0xF3);
+        assert_eq!(apu.read8(0xFF12), 0xF3);
+        apu.write8(0xFF11, 0x80);
+        assert_eq!(apu.read8(0xFF11), 0xBF);
+        apu.write8(0xFF1C, 0x40);
+        assert_eq!(apu.read8(0xFF1C), 0xDF);
+    }
+}
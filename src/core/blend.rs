@@ -0,0 +1,45 @@
+use std::collections::VecDeque;
+
+// Averages the last frames, to smooth fades that flip the palette every frame.
+// The LCD doesn't do this, so it's off unless asked for.
+pub struct FrameBlend {
+    frames: VecDeque<Vec<u8>>,
+    len:    usize,
+}
+
+impl FrameBlend {
+    pub fn new(len: usize) -> Self {
+        FrameBlend {
+            frames: VecDeque::with_capacity(len),
+            len:    len.max(1),
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.len
+    }
+
+    // Keeps a completed frame, dropping the oldest one once there are `len`
+    pub fn push(&mut self, rgba: &[u8]) {
+        let mut frame = match self.frames.len() == self.len {
+            true    =>  self.frames.pop_front().unwrap_or_default(),
+            false   =>  Vec::with_capacity(rgba.len()),
+        };
+        frame.clear();
+        frame.extend_from_slice(rgba);
+        self.frames.push_back(frame);
+    }
+
+    // Overwrites `rgba` with the average of the kept frames, rounded to nearest.
+    // Left as is until a frame has been kept.
+    pub fn blend(&self, rgba: &mut [u8]) {
+        let n = self.frames.len() as u32;
+        if n == 0 {
+            return;
+        }
+        for (i, out) in rgba.iter_mut().enumerate() {
+            let sum: u32 = self.frames.iter().map(|frame| frame[i] as u32).sum();
+            *out = ((sum + n / 2) / n) as u8;
+        }
+    }
+}
@@ -1,56 +1,441 @@
-use crate::core::io::Io;
+use crate::core::addr::*;
+use crate::core::io::{ Io, MemDevice };
 use crate::core::ram::Ram;
 use crate::core::cartridge::Cartridge;
 use crate::core::interrupt::*;
 use crate::core::pad::{ Pad, Key };
+use crate::core::sgb::SgbPacket;
 use crate::core::ppu::*;
 use crate::core::hram::HRam;
-use crate::core::apu::Apu;
+use crate::core::apu::{ Apu, HighPass };
 use crate::core::timer::Timer;
+use crate::core::serial::{ Serial, SerialLink, SerialDevice };
+use crate::core::infrared::{ Infrared, InfraredDevice };
+use crate::core::error::GbError;
+use crate::core::model::Model;
+use crate::core::state::{ StateReader, StateWriter };
 
+use log::debug;
+use std::cell::{ Cell, RefCell };
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
 use std::path::Path;
 
-const DMA_START_ADDR: usize = 0xFF46;
-const OAM_START_ADDR: usize = 0xFE00;
+const WRAM_BANK_SIZE: usize = 0x1000;
+// OAM DMA copies 160 bytes, one per machine cycle
+const DMA_LENGTH: usize = 0xA0;
+// Number of regions in memory_regions()
+const REGIONS: usize = 11;
+const DMG_BOOT_ROM_SIZE: usize = 0x100;
+const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
+// I/O registers as the DMG boot ROM leaves them.
+// The APU restores its own registers in Apu::reset().
+const POST_BOOT_IO: [(usize, u8); 14] = [
+    (TIMA, 0x00), (TMA, 0x00), (TAC, 0x00), (IF, 0xE1),
+    (LCDC, 0x91), (SCY, 0x00), (SCX, 0x00), (LYC, 0x00),
+    (BGP, 0xFC), (OBP0, 0xFF), (OBP1, 0xFF), (WY, 0x00),
+    (WX, 0x00), (IE, 0x00),
+];
+const POST_BOOT_DIV: u8 = 0xAB;
+// The (R) mark next to the logo
+const REGISTERED_TILE: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemRegion {
+    pub name:   &'static str,
+    pub start:  u16,
+    pub end:    u16,
+    // Bank currently mapped into the region, for banked regions
+    pub bank:   Option<usize>,
+}
+
+impl MemRegion {
+    fn new(name: &'static str, start: usize, end: usize, bank: Option<usize>) -> Self {
+        MemRegion {
+            name,
+            start:  start as u16,
+            end:    end as u16,
+            bank,
+        }
+    }
+}
+
+// Reads and writes the CPU made to a region of the memory map
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionAccesses {
+    pub region: MemRegion,
+    pub reads:  u64,
+    pub writes: u64,
+}
+
+#[derive(Default)]
+struct AccessCounter {
+    // Reads only borrow the bus
+    reads:  [Cell<u64>; REGIONS],
+    writes: [u64; REGIONS],
+}
+
+// Memory a step back can restore by writing the old value back. IF and IE
+// are plain latches, unlike the other I/O registers.
+fn is_journaled(addr: usize) -> bool {
+    matches!(addr,
+        VRAM_START ..= VRAM_END | WRAM_START ..= WRAM_END | ECHO_START ..= ECHO_END |
+        OAM_START ..= OAM_END | HRAM_START ..= HRAM_END | IF | IE)
+}
+
+// Addresses in the IO range no component handles. Most are unused and read 0xFF
+// on hardware too; the rest (HDMA) aren't emulated yet.
+fn is_unhandled_io(addr: usize) -> bool {
+    match addr {
+        0xFF03 | 0xFF08 ..= 0xFF0E | 0xFF15 | 0xFF1F | 0xFF27 ..= 0xFF2F  =>  true,
+        KEY1 | VBK | BOOT | RP | BCPS ..= OPRI | SVBK                    =>  false,
+        0xFF4C ..= IO_END                                                =>  true,
+        _                                                                =>  false,
+    }
+}
+
+// Index into memory_regions()
+fn region_index(addr: usize) -> usize {
+    match addr {
+        ROM0_START ..= ROM0_END           =>  0,
+        ROMX_START ..= ROMX_END           =>  1,
+        VRAM_START ..= VRAM_END           =>  2,
+        SRAM_START ..= SRAM_END           =>  3,
+        WRAM_START ..= WRAM_END           =>  4,
+        ECHO_START ..= ECHO_END           =>  5,
+        OAM_START ..= OAM_END             =>  6,
+        UNUSABLE_START ..= UNUSABLE_END   =>  7,
+        IO_START ..= IO_END               =>  8,
+        HRAM_START ..= HRAM_END           =>  9,
+        _                                 =>  10,
+    }
+}
 
 pub struct Bus {
+    model:      Model,
     cartridge:  Cartridge,
     ram:        Ram,
+    svbk:       u8,
+    // KEY1 (CGB only): the current speed and a switch armed for the next STOP
+    double_speed:   bool,
+    speed_armed:    bool,
     hram:       HRam,
     ppu:        Ppu,
     apu:        Apu,
     interrupt:  Interrupt,
     pad:        Pad,
+    serial:     Serial,
+    infrared:   Infrared,
+    boot_rom:   Option<Vec<u8>>,
+    // The boot ROM is mapped over the cartridge until 0xFF50 is written
+    booting:    bool,
+    // Next byte of a running OAM DMA
+    dma_index:  Option<usize>,
+    // Only counted while profiling
+    accesses:   Option<AccessCounter>,
+    // IO registers we don't emulate that the game has touched
+    unhandled:  RefCell<BTreeSet<u16>>,
+    // Old values of the bytes the CPU overwrote, while step back is on
+    journal:    Option<Vec<(u16, u8)>>,
+    // Mapped over the default routing, the latest first
+    devices:    Vec<(RangeInclusive<u16>, Box<dyn MemDevice>)>,
     pub timer:      Timer,
 }
 
 impl Bus {
     pub fn _no_cartridge() -> Self {
         Bus {
+            model:      Model::Dmg,
             cartridge:  Cartridge::_no_cartridge(),
             ram:        Ram::new(),
+            svbk:       0,
+            double_speed:   false,
+            speed_armed:    false,
             hram:       HRam::new(),
-            ppu:        Ppu::new(),
+            ppu:        Ppu::new(Model::Dmg),
             apu:        Apu::new(),
             interrupt:  Interrupt::new(),
             pad:        Pad::new(),
+            serial:     Serial::new(Model::Dmg),
+            infrared:   Infrared::new(),
+            boot_rom:   None,
+            booting:    false,
+            dma_index:  None,
+            accesses:   None,
+            unhandled:  RefCell::new(BTreeSet::new()),
+            journal:    None,
+            devices:    Vec::new(),
             timer:      Timer::new(),
         }
     }
 
-    pub fn from_path(path: &Path) -> Self {
-        Bus {
-            cartridge:  Cartridge::from_path(path),
-            ram:        Ram::new(),
+    pub fn from_path(path: &Path) -> Result<Self, GbError> {
+        Bus::from_cartridge(Cartridge::from_path(path)?)
+    }
+
+    pub fn from_bytes(bin: Vec<u8>) -> Result<Self, GbError> {
+        Bus::load(bin, false)
+    }
+
+    pub fn load(bin: Vec<u8>, ignore_checksums: bool) -> Result<Self, GbError> {
+        Bus::from_cartridge(Cartridge::load(bin, ignore_checksums)?)
+    }
+
+    fn from_cartridge(cartridge: Cartridge) -> Result<Self, GbError> {
+        let model = cartridge.model();
+        let mut bus = Bus {
+            model,
+            cartridge,
+            ram:        Ram::with_size(wram_size(model)),
+            svbk:       0,
+            double_speed:   false,
+            speed_armed:    false,
             hram:       HRam::new(),
-            ppu:        Ppu::new(),
+            ppu:        Ppu::new(model),
             apu:        Apu::new(),
             interrupt:  Interrupt::new(),
             pad:        Pad::new(),
+            serial:     Serial::new(model),
+            infrared:   Infrared::new(),
+            boot_rom:   None,
+            booting:    false,
+            dma_index:  None,
+            accesses:   None,
+            unhandled:  RefCell::new(BTreeSet::new()),
+            journal:    None,
+            devices:    Vec::new(),
             timer:      Timer::new(),
+        };
+        bus.apu.set_high_pass(HighPass::for_model(model));
+        bus.skip_boot_rom();
+
+        Ok(bus)
+    }
+
+    // Returns every component to its post-boot state.
+    // The cartridge (ROM and external RAM) is preserved.
+    pub fn reset(&mut self) {
+        self.cartridge.reset();
+        self.ram        = Ram::with_size(wram_size(self.model));
+        self.svbk       = 0;
+        self.double_speed   = false;
+        self.speed_armed    = false;
+        self.hram       = HRam::new();
+        self.ppu        = Ppu::new(self.model);
+        self.apu.reset();
+        self.interrupt  = Interrupt::new();
+        self.pad.reset();
+        self.serial.reset();
+        self.infrared.reset();
+        self.dma_index  = None;
+        self.timer      = Timer::new();
+
+        match self.boot_rom {
+            Some(_) =>  self.booting = true,
+            None    =>  self.skip_boot_rom(),
+        }
+    }
+
+    // Sets up what the boot ROM would have left behind:
+    // the I/O registers and, on DMG, the logo in VRAM that some games check.
+    fn skip_boot_rom(&mut self) {
+        self.booting = false;
+        for (addr, data) in POST_BOOT_IO.iter() {
+            self.write_raw(*addr, *data);
+        }
+        self.timer.set_div(POST_BOOT_DIV);
+
+        if !self.model.is_color() {
+            self.load_logo();
+        }
+    }
+
+    // The logo is the cartridge's own, so games comparing the header with VRAM
+    // find what they would after the real boot ROM
+    fn load_logo(&mut self) {
+        // Every nibble of the logo becomes two rows of a tile with doubled pixels
+        let logo = self.cartridge.logo().to_vec();
+        let mut addr = 0x8010;
+        for byte in logo {
+            for nibble in [byte >> 4, byte & 0x0F].iter() {
+                let mut row = 0;
+                for bit in 0..4 {
+                    if nibble & (0x08 >> bit) != 0 {
+                        row |= 0xC0 >> (bit * 2);
+                    }
+                }
+                self.write_raw(addr, row);
+                self.write_raw(addr+2, row);
+                addr += 4;
+            }
+        }
+        for row in REGISTERED_TILE.iter() {
+            self.write_raw(addr, *row);
+            addr += 2;
+        }
+
+        // Tiles 1-12 and 13-24 on two rows, with the (R) mark at the end of the first
+        for i in 0..12 {
+            self.write_raw(0x9904 + i, 0x01 + i as u8);
+            self.write_raw(0x9924 + i, 0x0D + i as u8);
+        }
+        self.write_raw(0x9910, 0x19);
+    }
+
+    // The boot ROM isn't saved. States taken while booting run the boot ROM
+    // loaded now, or skip to the game if there is none.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(match self.model {
+            Model::Dmg  =>  0,
+            Model::Cgb  =>  1,
+            Model::Mgb  =>  2,
+            Model::Agb  =>  3,
+        });
+        self.cartridge.save_state(w);
+        self.ram.save_state(w);
+        w.u8(self.svbk);
+        w.bool(self.double_speed);
+        w.bool(self.speed_armed);
+        self.hram.save_state(w);
+        self.ppu.save_state(w);
+        self.apu.save_state(w);
+        self.interrupt.save_state(w);
+        self.pad.save_state(w);
+        self.serial.save_state(w);
+        self.infrared.save_state(w);
+        w.bool(self.booting);
+        w.bool(self.dma_index.is_some());
+        w.u8(self.dma_index.unwrap_or(0) as u8);
+        self.timer.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        let model = match r.u8()? {
+            0   =>  Model::Dmg,
+            1   =>  Model::Cgb,
+            2   =>  Model::Mgb,
+            3   =>  Model::Agb,
+            n   =>  return Err(GbError::BadState(format!("unknown model: {}", n))),
+        };
+        if model != self.model {
+            self.set_model(model);
+        }
+        self.cartridge.load_state(r)?;
+        self.ram.load_state(r)?;
+        self.svbk = r.u8()?;
+        self.double_speed   = r.bool()?;
+        self.speed_armed    = r.bool()?;
+        self.hram.load_state(r)?;
+        self.ppu.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.interrupt.load_state(r)?;
+        self.pad.load_state(r)?;
+        self.serial.load_state(r)?;
+        self.infrared.load_state(r)?;
+        self.booting = r.bool()? && self.boot_rom.is_some();
+        let dma = r.bool()?;
+        let index = r.u8()? as usize;
+        self.dma_index = if dma && index < DMA_LENGTH { Some(index) } else { None };
+        self.timer.load_state(r)
+    }
+
+    pub fn booting(&self) -> bool {
+        self.booting
+    }
+
+    // Runs `bin` at power on instead of skipping straight to the game. The machine is reset.
+    pub fn set_boot_rom(&mut self, bin: Vec<u8>) -> Result<(), GbError> {
+        if bin.len() != DMG_BOOT_ROM_SIZE && bin.len() != CGB_BOOT_ROM_SIZE {
+            return Err(GbError::BadBootRom(bin.len()));
+        }
+        self.boot_rom = Some(bin);
+        self.reset();
+
+        Ok(())
+    }
+
+    fn boot_rom_read(&self, addr: usize) -> Option<u8> {
+        let boot_rom = match &self.boot_rom {
+            Some(boot_rom) if self.booting  =>  boot_rom,
+            _                               =>  return None,
+        };
+
+        match addr {
+            // The CGB boot ROM leaves a hole for the cartridge header
+            0x0000 ..= 0x00FF   |
+            0x0200 ..= 0x08FF   =>  boot_rom.get(addr).copied(),
+            _                   =>  None,
+        }
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    // Switches the hardware being emulated. The machine is reset.
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        self.apu.set_high_pass(HighPass::for_model(model));
+        self.serial.set_model(model);
+        self.reset();
+    }
+
+    // WRAM bank mapped at 0xD000-0xDFFF (always 1 on DMG)
+    fn wram_bank(&self) -> usize {
+        match self.model.is_color() {
+            false   =>  1,
+            true    =>  (self.svbk as usize & 0x07).max(1),
+        }
+    }
+
+    fn wram_addr(&self, addr: usize) -> usize {
+        match addr {
+            0xC000 ..= 0xCFFF   =>  addr & 0x0FFF,
+            _                   =>  self.wram_bank() * WRAM_BANK_SIZE + (addr & 0x0FFF),
         }
     }
 
+    // 16-bit INC/DEC put the register on the address bus. On DMG this
+    // corrupts OAM when it points at 0xFE00-0xFEFF during the OAM scan.
+    pub fn oam_bug(&mut self, addr: u16) {
+        if let (false, OAM_START ..= UNUSABLE_END) = (self.model.is_color(), addr as usize) {
+            self.ppu.corrupt_oam();
+        }
+    }
+
+    pub fn connect_link(&mut self, link: Box<dyn SerialLink>) {
+        self.serial.connect(link);
+    }
+
+    pub fn connect_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.serial.connect_device(device);
+    }
+
+    pub fn connect_infrared(&mut self, device: Box<dyn InfraredDevice>) {
+        self.infrared.connect(device);
+    }
+
+    pub fn map_device(&mut self, range: RangeInclusive<u16>, device: Box<dyn MemDevice>) {
+        self.devices.insert(0, (range, device));
+    }
+
+    pub fn unmap_devices(&mut self) {
+        self.devices.clear();
+    }
+
+    fn device_at(&self, addr: usize) -> Option<&dyn MemDevice> {
+        self.devices.iter()
+            .find(|(range, _)| range.contains(&(addr as u16)))
+            .map(|(_, device)| device.as_ref())
+    }
+
+    fn device_at_mut(&mut self, addr: usize) -> Option<&mut (dyn MemDevice + 'static)> {
+        self.devices.iter_mut()
+            .find(|(range, _)| range.contains(&(addr as u16)))
+            .map(|(_, device)| device.as_mut())
+    }
+
     pub fn enable_irq(&mut self) {
         self.interrupt.enable();
     }
@@ -63,6 +448,35 @@ impl Bus {
         self.interrupt.is_enabled_irq()
     }
 
+    // STOP performs a speed switch armed through KEY1.
+    // The CPU doesn't run any faster yet, only KEY1 reports the new speed.
+    pub fn stop(&mut self) {
+        if self.speed_armed {
+            self.double_speed = !self.double_speed;
+            self.speed_armed = false;
+        }
+    }
+
+    // Starts recording the old value of each byte the CPU writes to RAM, VRAM, OAM,
+    // HRAM, IF or IE, so the writes can be undone. Other writes aren't recorded: putting
+    // back an I/O register would fire its side effects again (OAM DMA, the DIV reset,
+    // sound triggers), and cartridge writes can hit MBC or RTC registers. IF comes first
+    // as taking an interrupt clears its flag without a write.
+    pub fn start_journal(&mut self) {
+        self.journal = Some(vec![(IF as u16, self.read_raw(IF))]);
+    }
+
+    pub fn take_journal(&mut self) -> Vec<(u16, u8)> {
+        self.journal.take().unwrap_or_default()
+    }
+
+    // Puts back the old values from a journal, newest first
+    pub fn undo_writes(&mut self, writes: &[(u16, u8)]) {
+        for (addr, data) in writes.iter().rev() {
+            self.write_raw(*addr as usize, *data);
+        }
+    }
+
     pub fn isr_addr(&mut self) -> Option<usize> {
         self.interrupt.isr_addr()
     }
@@ -71,6 +485,10 @@ impl Bus {
         self.interrupt.has_irq()
     }
 
+    pub fn request_interrupt(&mut self, kind: InterruptKind) {
+        self.interrupt.set_irq(kind);
+    }
+
     pub fn push_key(&mut self, key: Key) {
         self.pad.push_key(key);
         self.interrupt.set_irq(InterruptKind::Joypad);
@@ -80,26 +498,197 @@ impl Bus {
         self.pad.release_key(key);
     }
 
+    pub fn set_buttons(&mut self, mask: u8) {
+        if self.pad.set_buttons(mask) {
+            self.interrupt.set_irq(InterruptKind::Joypad);
+        }
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.pad.buttons()
+    }
+
+    pub fn set_turbo(&mut self, key: Key, on: bool) {
+        self.pad.set_turbo(key, on);
+    }
+
+    pub fn is_turbo(&self, key: Key) -> bool {
+        self.pad.is_turbo(key)
+    }
+
+    pub fn set_turbo_rate(&mut self, frames: u8) {
+        self.pad.set_turbo_rate(frames);
+    }
+
+    pub fn turbo_rate(&self) -> u8 {
+        self.pad.turbo_rate()
+    }
+
     pub fn get_pixels(&self) -> [u8; SCREEN_WIDTH*SCREEN_HEIGHT] {
         self.ppu.get_pixels()
     }
 
+    pub fn line_done(&self, ly: u8) -> bool {
+        self.ppu.line_done(ly)
+    }
+
+    pub fn get_colors(&self) -> &[u16] {
+        self.ppu.get_colors()
+    }
+
+    pub fn get_layers(&self) -> &[u8] {
+        self.ppu.get_layers()
+    }
+
+    pub fn ppu_registers(&self) -> PpuRegisters {
+        self.ppu.registers()
+    }
+
+    pub fn ppu_timing(&self) -> PpuTiming {
+        self.ppu.timing_stats()
+    }
+
+    pub fn pop_sgb_packet(&mut self) -> Option<SgbPacket> {
+        self.pad.pop_sgb_packet()
+    }
+
+    pub fn set_channel_enabled(&mut self, ch: u8, on: bool) {
+        self.apu.set_channel_enabled(ch, on);
+    }
+
+    pub fn set_high_pass(&mut self, filter: HighPass) {
+        self.apu.set_high_pass(filter);
+    }
+
+    pub fn open_audio(&mut self) {
+        self.apu.open_output();
+    }
+
+    pub fn has_audio(&self) -> bool {
+        self.apu.has_output()
+    }
+
+    pub fn stop_audio(&mut self) {
+        self.apu.stop();
+    }
+
+    pub fn start_audio(&mut self) {
+        self.apu.start();
+    }
+
+    pub fn set_sample_rate(&mut self, rate: u32) -> u32 {
+        self.apu.set_sample_rate(rate)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.apu.sample_rate()
+    }
+
+    pub fn set_audio_capture(&mut self, on: bool) {
+        self.apu.set_capture(on);
+    }
+
+    pub fn take_audio_samples(&mut self) -> Vec<(f32, f32)> {
+        self.apu.take_samples()
+    }
+
+    pub fn audio_samples_played(&self) -> u64 {
+        self.apu.samples_played()
+    }
+
+    // Overview of the memory map, as laid out in read8()/write8()
+    pub fn memory_regions(&self) -> Vec<MemRegion> {
+        vec![
+            MemRegion::new("ROM bank 0",        ROM0_START, ROM0_END,       Some(0)),
+            MemRegion::new("Switchable ROM",    ROMX_START, ROMX_END,       Some(self.cartridge.rom_bank())),
+            MemRegion::new("VRAM",              VRAM_START, VRAM_END,       None),
+            MemRegion::new("External RAM",      SRAM_START, SRAM_END,       self.cartridge.ram_bank()),
+            MemRegion::new("WRAM",              WRAM_START, WRAM_END,       None),
+            MemRegion::new("Echo RAM",          ECHO_START, ECHO_END,       None),
+            MemRegion::new("OAM",               OAM_START,  OAM_END,        None),
+            MemRegion::new("Unusable",          UNUSABLE_START, UNUSABLE_END, None),
+            MemRegion::new("I/O",               IO_START,   IO_END,         None),
+            MemRegion::new("HRAM",              HRAM_START, HRAM_END,       None),
+            MemRegion::new("IE",                IE,         IE,             None),
+        ]
+    }
+
+    // Everything the CPU can read, 0x0000-0xFFFF, with the banks that are switched in
+    // Reads without counting as an access, for checks made between instructions
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.read_raw(addr as usize)
+    }
+
+    pub fn dump_memory(&self) -> Vec<u8> {
+        (ROM0_START..=IE).map(|addr| self.read_raw(addr)).collect()
+    }
+
+    // Counts the CPU's reads and writes per region of the memory map.
+    // Off by default so the bus doesn't pay for it. Turning it on starts from zero.
+    pub fn set_profiling(&mut self, on: bool) {
+        self.accesses = match on {
+            true    =>  Some(AccessCounter::default()),
+            false   =>  None,
+        };
+    }
+
+    // None unless profiling is on
+    pub fn memory_accesses(&self) -> Option<Vec<RegionAccesses>> {
+        let accesses = self.accesses.as_ref()?;
+        let regions = self.memory_regions().into_iter().enumerate().map(|(i, region)| RegionAccesses {
+            region,
+            reads:  accesses.reads[i].get(),
+            writes: accesses.writes[i],
+        });
+        Some(regions.collect())
+    }
+
+    // IO registers the game accessed that we don't handle, lowest first
+    pub fn unhandled_io(&self) -> Vec<u16> {
+        self.unhandled.borrow().iter().copied().collect()
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    // Advances OAM DMA by one machine cycle, copying one byte.
+    // Returns true while a DMA is running: the CPU is stalled for its 160 cycles.
+    // A write to DMA during a transfer restarts it.
     pub fn transfer(&mut self) -> bool {
         if self.ppu.dma_started() {
-            for i in 0..0xA0 {
-                let addr = (self.read8(DMA_START_ADDR) as usize * 0x100 + i) as usize;
-                let data = self.read8(addr);
-                self.write8(OAM_START_ADDR + i, data);
-            }
             self.ppu.stop_dma();
-
-            return true;
+            self.dma_index = Some(0);
         }
-        false
+
+        let i = match self.dma_index {
+            Some(i) =>  i,
+            None    =>  return false,
+        };
+        let addr = self.read_raw(DMA) as usize * 0x100 + i;
+        let data = self.read_raw(addr);
+        self.write_raw(OAM_START + i, data);
+        self.dma_index = match i + 1 {
+            DMA_LENGTH  =>  None,
+            next        =>  Some(next),
+        };
+
+        true
     }
 
-    pub fn tick(&mut self) {
-        match self.ppu.tick() {
+    // Returns true when the PPU enters VBlank
+    pub fn tick(&mut self) -> bool {
+        let (vblank, lcdc) = self.ppu.tick();
+        let frame = vblank.is_some();
+        // Auto-fire follows the frames of the game
+        if vblank.is_some() && self.pad.next_frame() {
+            self.interrupt.set_irq(InterruptKind::Joypad);
+        }
+        match (vblank, lcdc) {
             (None, Some(_))  =>  {
                 self.interrupt.set_irq(InterruptKind::LcdcStatus);
             },
@@ -115,89 +704,501 @@ impl Bus {
         if self.timer.tick() {
             self.interrupt.set_irq(InterruptKind::Timer);
         };
+        if self.serial.tick() {
+            self.interrupt.set_irq(InterruptKind::Serial);
+        };
+        self.apu.tick();
+
+        frame
+    }
+}
+
+fn wram_size(model: Model) -> usize {
+    match model.is_color() {
+        false   =>  2 * WRAM_BANK_SIZE,
+        true    =>  8 * WRAM_BANK_SIZE,
     }
 }
 
 impl Io for Bus {
     fn read8(&self, addr: usize) -> u8 {
+        if let Some(accesses) = &self.accesses {
+            let reads = &accesses.reads[region_index(addr)];
+            reads.set(reads.get() + 1);
+        }
+        self.note_unhandled(addr);
+        self.read_raw(addr)
+    }
+
+    fn write8(&mut self, addr: usize, data: u8) {
+        if let Some(accesses) = &mut self.accesses {
+            accesses.writes[region_index(addr)] += 1;
+        }
+        self.note_unhandled(addr);
+        if self.journal.is_some() && is_journaled(addr) {
+            let old = self.read_raw(addr);
+            if let Some(journal) = &mut self.journal {
+                journal.push((addr as u16, old));
+            }
+        }
+        self.write_raw(addr, data);
+    }
+}
+
+impl Bus {
+    // Logs the first access to each IO register we don't handle
+    fn note_unhandled(&self, addr: usize) {
+        if is_unhandled_io(addr) && self.unhandled.borrow_mut().insert(addr as u16) {
+            debug!("unhandled IO register: 0x{:04x}", addr);
+        }
+    }
+
+    // Memory accesses of the bus itself (OAM DMA, setting up the boot state)
+    // go through here and aren't profiled
+    fn read_raw(&self, addr: usize) -> u8 {
+        if let Some(device) = self.device_at(addr) {
+            return device.read(addr as u16);
+        }
         match addr {
-            // 16kB ROM bank #0
-            0x0000 ..= 0x3FFF   =>  self.cartridge.read8(addr),
+            // 16kB ROM bank #0 (the boot ROM while booting)
+            ROM0_START ..= ROM0_END           =>  match self.boot_rom_read(addr) {
+                Some(data)  =>  data,
+                None        =>  self.cartridge.read8(addr),
+            },
             // 16kB switchable ROM bank
-            0x4000 ..= 0x7FFF   =>  self.cartridge.read8(addr),
+            ROMX_START ..= ROMX_END           =>  self.cartridge.read8(addr),
             // 8kB Video RAM
-            0x8000 ..= 0x9FFF   =>  self.ppu.read8(addr),
+            VRAM_START ..= VRAM_END           =>  self.ppu.read8(addr),
             // 8kB switchable RAM bank
-            0xA000 ..= 0xBFFF   =>  self.cartridge.read8(addr),
+            SRAM_START ..= SRAM_END           =>  self.cartridge.read8(addr),
             // 8kB Internal RAM
-            0xC000 ..= 0xDFFF   =>  self.ram.read8(addr&0x1FFF),
-            // Echo of 8kB Internal RAM
-            0xE000 ..= 0xFDFF   =>  self.ram.read8(addr&0x1FFF),
+            WRAM_START ..= WRAM_END           =>  self.ram.read8(self.wram_addr(addr)),
+            // Echo of 8kB Internal RAM (mirrors 0xC000-0xDDFF)
+            ECHO_START ..= ECHO_END           =>  self.read_raw(addr-0x2000),
             // Sprite Attribute Memory (OAM)
-            0xFE00 ..= 0xFE9F   =>  self.ppu.read8(addr),
-            // Empty but unusable for I/O
-            0xFEA0 ..= 0xFEFF   =>  0,
+            OAM_START ..= OAM_END             =>  self.ppu.read8(addr),
+            // Empty but unusable for I/O. CGB returns 0xFF,
+            // DMG 0x00, or 0xFF while the PPU holds OAM
+            UNUSABLE_START ..= UNUSABLE_END   =>  match (self.model.is_color(), self.ppu.oam_accessible()) {
+                (false, true)   =>  0x00,
+                _               =>  0xFF,
+            },
             // I/O ports
-            0xFF00              =>  self.pad.read8(addr),
+            JOYP                              =>  self.pad.read8(addr),
+            // Serial Data Transfer
+            SB ..= SC                         =>  self.serial.read8(addr),
             // Timer
-            0xFF04 ..= 0xFF07   =>  self.timer.read8(addr),
+            DIV ..= TAC                       =>  self.timer.read8(addr),
+            // Sound Channel 1 - Tone & Sweep
+            NR10 ..= NR14                     |
+            // Sound Channel 2 - Tone
+            NR21 ..= NR24                     |
+            // Sound Channel 3 - Wave Output
+            NR30 ..= NR34                     |
+            // Sound Channel 4 - Noise
+            NR41 ..= NR52                     |
+            // Wabe Pattern RAM
+            WAVE_START ..= WAVE_END           =>  self.apu.read8(addr),
             // Interrupt Flag Register
-            0xFF0F              =>  self.interrupt.read8(addr),
+            IF                                =>  self.interrupt.read8(addr),
             // LCD Registers
-            0xFF40 ..= 0xFF4B   => self.ppu.read8(addr),
-            // Empty but unusable for I/O
-            0xFF4C ..= 0xFF7F   =>  0,
+            LCDC ..= WX                       =>  self.ppu.read8(addr),
+            // VRAM Bank, Color Palettes and Object Priority (CGB only)
+            VBK                               |
+            BCPS ..= OPRI                     =>  self.ppu.read8(addr),
+            // Infrared Port (CGB only)
+            RP                                =>  match self.model.is_color() {
+                false   =>  0xFF,
+                true    =>  self.infrared.read8(addr),
+            },
+            // WRAM Bank (CGB only)
+            SVBK                              =>  match self.model.is_color() {
+                false   =>  0xFF,
+                true    =>  self.svbk | 0xF8,
+            },
+            // Speed Switch (CGB only)
+            KEY1                              =>  match self.model.is_color() {
+                false   =>  0xFF,
+                true    =>  (self.double_speed as u8) << 7 | 0x7E | self.speed_armed as u8,
+            },
+            // VRAM DMA (CGB only), not emulated
+            HDMA1 ..= HDMA5                   =>  0xFF,
+            // Unused
+            0xFF4C ..= IO_END                 =>  0xFF,
             // Internal RAM
-            0xFF80 ..= 0xFFFE   =>  self.hram.read8(addr&0x7F),
+            HRAM_START ..= HRAM_END           =>  self.hram.read8(addr),
             // Interrupt Enable Register
-            0xFFFF              =>  self.interrupt.read8(addr),
-            _                   =>  0,
+            IE                                =>  self.interrupt.read8(addr),
+            _                                 =>  0xFF,
         }
     }
 
-    fn write8(&mut self, addr: usize, data: u8) {
+    fn write_raw(&mut self, addr: usize, data: u8) {
+        if let Some(device) = self.device_at_mut(addr) {
+            device.write(addr as u16, data);
+            return;
+        }
         match addr {
             // 16kB ROM bank #0
-            0x0000 ..= 0x3FFF   =>  self.cartridge.write8(addr, data),
+            ROM0_START ..= ROM0_END           =>  self.cartridge.write8(addr, data),
             // 16kB switchable ROM bank
-            0x4000 ..= 0x7FFF   =>  self.cartridge.write8(addr, data),
+            ROMX_START ..= ROMX_END           =>  self.cartridge.write8(addr, data),
             // 8kB Video RAM
-            0x8000 ..= 0x9FFF   =>  self.ppu.write8(addr, data),
+            VRAM_START ..= VRAM_END           =>  self.ppu.write8(addr, data),
             // 8kB switchable RAM bank
-            0xA000 ..= 0xBFFF   =>  self.cartridge.write8(addr, data),
+            SRAM_START ..= SRAM_END           =>  self.cartridge.write8(addr, data),
             // 8kB Internal RAM
-            0xC000 ..= 0xDFFF   =>  self.ram.write8(addr&0x1FFF, data),
-            // Echo of 8kB Internal RAM
-            0xE000 ..= 0xFDFF   =>  self.ram.write8(addr&0x1FFF, data),
+            WRAM_START ..= WRAM_END           =>  {
+                let addr = self.wram_addr(addr);
+                self.ram.write8(addr, data);
+            },
+            // Echo of 8kB Internal RAM (mirrors 0xC000-0xDDFF)
+            ECHO_START ..= ECHO_END           =>  self.write_raw(addr-0x2000, data),
             // Sprite Attribute Memory (OAM)
-            0xFE00 ..= 0xFE9F   =>  self.ppu.write8(addr, data),
+            OAM_START ..= OAM_END             =>  self.ppu.write8(addr, data),
             // Empty but unusable for I/O
-            0xFEA0 ..= 0xFEFF   =>  (),
+            UNUSABLE_START ..= UNUSABLE_END   =>  (),
             // I/O ports
-            0xFF00              =>  self.pad.write8(addr, data),
+            JOYP                              =>  self.pad.write8(addr, data),
+            // Serial Data Transfer
+            SB ..= SC                         =>  self.serial.write8(addr, data),
             // Timer
-            0xFF04 ..= 0xFF07   =>  self.timer.write8(addr, data),
+            DIV ..= TAC                       =>  self.timer.write8(addr, data),
             // Sound Channel 1 - Tone & Sweep
-            0xFF10 ..= 0xFF14   |
+            NR10 ..= NR14                     |
             // Sound Channel 2 - Tone
-            0xFF16 ..= 0xFF19   |
+            NR21 ..= NR24                     |
             // Sound Channel 3 - Wave Output
-            0xFF1A ..= 0xFF1E   |
+            NR30 ..= NR34                     |
             // Sound Channel 4 - Noise
-            0xFF20 ..= 0xFF26   |
+            NR41 ..= NR52                     |
             // Wabe Pattern RAM
-            0xFF30 ..= 0xFF3F   =>  self.apu.write8(addr, data),
+            WAVE_START ..= WAVE_END           =>  self.apu.write8(addr, data),
             // Interrupt Flag Register
-            0xFF0F              =>  self.interrupt.write8(addr, data),
+            IF                                =>  self.interrupt.write8(addr, data),
             // LCD Registers
-            0xFF40 ..= 0xFF4B   =>  self.ppu.write8(addr, data),
-            // Empty but unusable for I/O
-            0xFF4C ..= 0xFF7F   =>  (),
+            LCDC ..= WX                       =>  self.ppu.write8(addr, data),
+            // VRAM Bank, Color Palettes and Object Priority (CGB only)
+            VBK                               |
+            BCPS ..= OPRI                     =>  self.ppu.write8(addr, data),
+            // Boot ROM disable
+            BOOT if data != 0                 =>  self.booting = false,
+            // Speed Switch (CGB only). Only bit 0 is writable
+            KEY1 if self.model.is_color()     =>  self.speed_armed = data & 0x01 != 0,
+            // Infrared Port (CGB only)
+            RP if self.model.is_color()       =>  self.infrared.write8(addr, data),
+            // WRAM Bank (CGB only)
+            SVBK if self.model.is_color()     =>  self.svbk = data & 0x07,
+            // VRAM DMA (CGB only), not emulated
+            HDMA1 ..= HDMA5                   =>  (),
+            // Unused
+            0xFF4C ..= IO_END                 =>  (),
             // Internal RAM
-            0xFF80 ..= 0xFFFE   =>  self.hram.write8(addr&0x7F, data),
+            HRAM_START ..= HRAM_END           =>  self.hram.write8(addr, data),
             // Interrupt Enable Register
-            0xFFFF              =>  self.interrupt.write8(addr, data),
-            _                   =>  (),
+            IE                                =>  self.interrupt.write8(addr, data),
+            _                                 =>  (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::cartridge::tests::{ rom, fix_header, capture_log };
+    use crate::core::serial::{ Loopback, Scripted };
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn dmg() -> Bus {
+        Bus::from_bytes(rom(&[])).unwrap()
+    }
+
+    fn cgb() -> Bus {
+        let mut bin = rom(&[]);
+        bin[0x143] = 0xC0;
+        fix_header(&mut bin);
+        Bus::from_bytes(bin).unwrap()
+    }
+
+    #[test]
+    fn echo_ram_mirrors_wram() {
+        let mut bus = dmg();
+        bus.write8(0xC123, 0x42);
+        assert_eq!(bus.read8(0xE123), 0x42);
+        bus.write8(0xE456, 0x24);
+        assert_eq!(bus.read8(0xC456), 0x24);
+        bus.write8(0xFDFF, 0x99);
+        assert_eq!(bus.read8(0xDDFF), 0x99);
+    }
+
+    #[test]
+    fn echo_ram_follows_svbk() {
+        let mut bus = cgb();
+        bus.write8(SVBK, 0x02);
+        bus.write8(0xD010, 0x22);
+        bus.write8(SVBK, 0x03);
+        bus.write8(0xF010, 0x33);
+        assert_eq!(bus.read8(0xD010), 0x33);
+        bus.write8(SVBK, 0x02);
+        assert_eq!(bus.read8(0xF010), 0x22);
+    }
+
+    #[test]
+    fn unusable_region_per_model() {
+        let mut bus = dmg();
+        bus.write8(LCDC, 0x00);
+        assert_eq!(bus.read8(0xFEA0), 0x00);
+        // OAM search
+        bus.write8(LCDC, 0x91);
+        bus.tick();
+        assert_eq!(bus.read8(STAT) & 0x03, 0x02);
+        assert_eq!(bus.read8(0xFEA0), 0xFF);
+
+        let mut bus = cgb();
+        bus.write8(LCDC, 0x00);
+        assert_eq!(bus.read8(0xFEA0), 0xFF);
+        assert_eq!(bus.read8(0xFEFF), 0xFF);
+    }
+
+    #[test]
+    fn scripted_device_answers_external_clock() {
+        let mut bus = dmg();
+        bus.connect_device(Box::new(Scripted(vec![0x5A])));
+        bus.write8(IF, 0x00);
+        // Waits for the other side's clock, like a game on the slave side
+        bus.write8(SB, 0x11);
+        bus.write8(SC, 0x80);
+        while bus.read8(SC) & 0x80 != 0 {
+            bus.tick();
         }
+        assert_eq!(bus.read8(SB), 0x5A);
+        assert_eq!(bus.read8(IF) & 0x08, 0x08);
+
+        bus.connect_device(Box::new(Loopback));
+        bus.write8(SB, 0x22);
+        bus.write8(SC, 0x80);
+        while bus.read8(SC) & 0x80 != 0 {
+            bus.tick();
+        }
+        assert_eq!(bus.read8(SB), 0x22);
+    }
+
+    #[test]
+    fn memory_regions_show_rom_bank() {
+        // MBC1 with 4 banks
+        let mut bin = rom(&[]);
+        bin.resize(0x10000, 0);
+        bin[0x147] = 0x01;
+        bin[0x148] = 0x01;
+        fix_header(&mut bin);
+        let mut bus = Bus::from_bytes(bin).unwrap();
+        bus.write8(0x2000, 0x03);
+
+        let regions = bus.memory_regions();
+        let romx = regions.iter().find(|region| region.start == ROMX_START as u16).unwrap();
+        assert_eq!(romx.name, "Switchable ROM");
+        assert_eq!(romx.bank, Some(3));
+    }
+
+    // Writes STAT in HBlank and returns whether that requested a STAT interrupt
+    fn stat_write_irq(mut bus: Bus) -> bool {
+        bus.write8(LCDC, 0x91);
+        while bus.read8(STAT) & 0x03 != 0x00 {
+            bus.tick();
+        }
+        bus.write8(IF, 0x00);
+        bus.write8(STAT, 0x00);
+        bus.tick();
+        bus.read8(IF) & 0x02 != 0
+    }
+
+    #[test]
+    fn stat_write_bug_on_dmg_only() {
+        assert!(stat_write_irq(dmg()));
+        assert!(!stat_write_irq(cgb()));
+    }
+
+    #[test]
+    fn skipped_boot_rom_leaves_post_boot_io() {
+        let bus = dmg();
+        let io = [
+            (JOYP, 0xCF), (DIV, 0xAB), (TAC, 0xF8), (IF, 0xE1),
+            (NR50, 0x77), (NR51, 0xF3), (NR52, 0xF1),
+            (LCDC, 0x91), (BGP, 0xFC), (OBP0, 0xFF), (OBP1, 0xFF), (IE, 0x00),
+        ];
+        for &(addr, data) in io.iter() {
+            assert_eq!(bus.read8(addr), data, "0x{:04X}", addr);
+        }
+        // The top of the "N" of the logo, and its tiles on the map
+        assert_eq!(bus.peek(0x8010), 0xF0);
+        assert_eq!(bus.peek(0x9904), 0x01);
+        assert_eq!(bus.peek(0x9910), 0x19);
+    }
+
+    // OAM after INC/DEC of a pointer to 0xFE00 in the second M-cycle of OAM search
+    fn corrupted_oam(mut bus: Bus) -> Vec<u8> {
+        bus.write8(LCDC, 0x00);
+        let rows = [
+            0x0F, 0xF0, 0x33, 0xCC, 0x55, 0xAA, 0x66, 0x99,
+            0xFF, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66,
+        ];
+        for (i, data) in rows.iter().enumerate() {
+            bus.write8(OAM_START + i, *data);
+        }
+        bus.write8(LCDC, 0x91);
+        bus.tick();
+        bus.oam_bug(OAM_START as u16);
+        bus.write8(LCDC, 0x00);
+        (OAM_START..OAM_START + 16).map(|addr| bus.read8(addr)).collect()
+    }
+
+    #[test]
+    fn oam_bug_corrupts_row_on_dmg() {
+        // The first word of row 1 is ((a ^ c) & (b ^ c)) ^ c of its own first word (a)
+        // and the first (b) and third (c) words of row 0. The rest is copied from row 0.
+        assert_eq!(corrupted_oam(dmg())[8..], [0x5F, 0xA0, 0x33, 0xCC, 0x55, 0xAA, 0x66, 0x99]);
+        assert_eq!(corrupted_oam(cgb())[8..], [0xFF, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn dma_stalls_for_160_cycles() {
+        let mut bus = dmg();
+        for i in 0..0xA0 {
+            bus.write8(0xC000 + i, i as u8);
+        }
+        bus.write8(DMA, 0xC0);
+
+        let mut stalled = 0;
+        while bus.transfer() {
+            stalled += 1;
+            bus.write8(HRAM_START, stalled as u8);
+            assert_eq!(bus.read8(HRAM_START), stalled as u8);
+        }
+        assert_eq!(stalled, 160);
+        assert_eq!(bus.read_raw(OAM_START + 0x9F), 0x9F);
+        assert!(!bus.transfer());
+    }
+
+    #[test]
+    fn unhandled_io_is_logged_once() {
+        let log = capture_log();
+        let bus = dmg();
+        assert_eq!(bus.read8(0xFF03), 0xFF);
+        assert_eq!(bus.read8(0xFF03), 0xFF);
+        assert_eq!(bus.unhandled_io(), vec![0xFF03]);
+
+        let messages = log.lock().unwrap();
+        let logged = messages.iter().filter(|message| *message == "unhandled IO register: 0xff03").count();
+        assert_eq!(logged, 1);
+    }
+
+    #[test]
+    fn state_keeps_model_and_rejects_unknown() {
+        for &model in [Model::Dmg, Model::Mgb, Model::Cgb, Model::Agb].iter() {
+            let mut bus = dmg();
+            bus.set_model(model);
+            let mut w = StateWriter::new();
+            bus.save_state(&mut w);
+            let state = w.finish();
+
+            let mut other = dmg();
+            other.load_state(&mut StateReader::new(&state).unwrap()).unwrap();
+            assert_eq!(other.model, model);
+        }
+
+        let mut w = StateWriter::new();
+        dmg().save_state(&mut w);
+        let mut state = w.finish();
+        // The model comes first, after the magic and version
+        let header = StateWriter::new().finish().len();
+        state[header] = 9;
+        let result = dmg().load_state(&mut StateReader::new(&state).unwrap());
+        assert!(matches!(result, Err(GbError::BadState(_))));
+    }
+
+    #[test]
+    fn ie_sits_above_hram() {
+        let mut bus = dmg();
+        bus.write8(0xFFFF, 0x1F);
+        assert_eq!(bus.read8(0xFFFF), 0x1F);
+        bus.write8(0xFFFE, 0xA5);
+        assert_eq!(bus.read8(0xFFFE), 0xA5);
+        assert_eq!(bus.read8(0xFFFF), 0x1F);
+        bus.write8(0xFFFF, 0x00);
+        assert_eq!(bus.read8(0xFFFE), 0xA5);
+    }
+
+    #[test]
+    fn unused_stat_and_tac_bits_read_as_one() {
+        let mut bus = dmg();
+        bus.write8(LCDC, 0x00);
+        bus.write8(STAT, 0x00);
+        assert_eq!(bus.read8(STAT), 0x80);
+        bus.write8(TAC, 0x05);
+        assert_eq!(bus.read8(TAC), 0xFD);
+        bus.write8(TAC, 0x00);
+        assert_eq!(bus.read8(TAC), 0xF8);
+    }
+
+    #[test]
+    fn unplugged_transfer_completes_with_interrupt() {
+        let mut bus = dmg();
+        bus.write8(IF, 0x00);
+        bus.write8(SB, 0x12);
+        bus.write8(SC, 0x81);
+        for _ in 0..1023 {
+            bus.tick();
+        }
+        assert_eq!(bus.read8(SC) & 0x80, 0x80);
+        assert_eq!(bus.read8(IF) & 0x08, 0x00);
+        bus.tick();
+        assert_eq!(bus.read8(SC) & 0x80, 0x00);
+        assert_eq!(bus.read8(IF) & 0x08, 0x08);
+        assert_eq!(bus.read8(SB), 0xFF);
+    }
+
+    // 16 bytes of memory that also counts the writes it gets
+    struct Scratch {
+        data:   [u8; 16],
+        writes: Rc<RefCell<u32>>,
+    }
+
+    impl MemDevice for Scratch {
+        fn read(&self, addr: u16) -> u8 {
+            self.data[(addr & 0x0F) as usize]
+        }
+
+        fn write(&mut self, addr: u16, data: u8) {
+            self.data[(addr & 0x0F) as usize] = data;
+            *self.writes.borrow_mut() += 1;
+        }
+    }
+
+    #[test]
+    fn mapped_device_takes_its_range() {
+        let mut bus = dmg();
+        let writes = Rc::new(RefCell::new(0));
+        bus.map_device(0xA000..=0xA00F, Box::new(Scratch { data: [0x11; 16], writes: writes.clone() }));
+        assert_eq!(bus.read8(0xA000), 0x11);
+        bus.write8(0xA00F, 0x42);
+        assert_eq!(bus.read8(0xA00F), 0x42);
+        assert_eq!(*writes.borrow(), 1);
+        // The cartridge has no RAM
+        bus.write8(0xA010, 0x42);
+        assert_eq!(bus.read8(0xA010), 0xFF);
+        assert_eq!(*writes.borrow(), 1);
+
+        bus.unmap_devices();
+        assert_eq!(bus.read8(0xA00F), 0xFF);
+    }
+
+    #[test]
+    fn unused_sound_registers_read_ff() {
+        let mut bus = dmg();
+        for addr in [0xFF15, 0xFF1F].iter().copied().chain(0xFF27..=0xFF2F) {
+            bus.write8(addr, 0x00);
+            assert_eq!(bus.read8(addr), 0xFF, "0x{:04x}", addr);
+        }
+    }
+}
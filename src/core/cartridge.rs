@@ -1,18 +1,300 @@
+use log::{debug, warn};
+
 use crate::core::io::Io;
+use crate::core::error::GbError;
+use crate::core::model::Model;
+use crate::core::state::{ StateReader, StateWriter };
+use crate::core::rtc::{ Rtc, RTC_SAVE_SIZE, unix_now };
 
+use std::fmt;
 use std::path::Path;
 use std::fs::read;
 
-const _ROM_SIZE:             usize   = 32768;
+const ROM_SIZE:             usize   = 32768;
+const HEADER_END:           usize   = 0x150;
+const LOGO_START:           usize   = 0x104;
+const LOGO_END:             usize   = 0x134;
 const TITLE_START:          usize   = 0x134;
 const TITLE_END:            usize   = 0x142;
-// const LICENSEE_CODE_START:  usize   = 0x144;
-// const LICENSEE_CODE_END:    usize   = 0x145;
-// const SGB_FLAG:             usize   = 0x146;
+const MANUFACTURER_START:   usize   = 0x13F;
+const MANUFACTURER_END:     usize   = 0x143;
+const CGB_FLAG:             usize   = 0x143;
+const LICENSEE_CODE_START:  usize   = 0x144;
+const LICENSEE_CODE_END:    usize   = 0x146;
+const SGB_FLAG:             usize   = 0x146;
 const CARTRIDGE_TYPE:       usize   = 0x147;
-// const ROM_SIZE_ADDR:        usize   = 0x148;
-// const RAM_SIZE_ADDR:        usize   = 0x149;
-// const DESTINATION_CODE:     usize   = 0x14A;
+const ROM_SIZE_ADDR:        usize   = 0x148;
+const RAM_SIZE_ADDR:        usize   = 0x149;
+const DESTINATION_CODE:     usize   = 0x14A;
+const OLD_LICENSEE_CODE:    usize   = 0x14B;
+const VERSION:              usize   = 0x14C;
+const HEADER_CHECKSUM:      usize   = 0x14D;
+const GLOBAL_CHECKSUM:      usize   = 0x14E;
+
+// What the boot ROM compares the header's logo with. It locks up on a mismatch.
+const NINTENDO_LOGO: [u8; LOGO_END - LOGO_START] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+fn rom_size(code: u8) -> Result<usize, GbError> {
+    match code {
+        // 32kB << n
+        n @ 0x00 ..= 0x08   =>  Ok((32*1024) << n),
+        n                   =>  Err(GbError::BadHeader(format!("unknown rom size: 0x{:02x}", n))),
+    }
+}
+
+fn ram_size(code: u8) -> Result<usize, GbError> {
+    match code {
+        0   =>  Ok(0),
+        1   =>  Ok(2*1024),     // 16kbit
+        2   =>  Ok(8*1024),     // 64kbit
+        3   =>  Ok(32*1024),    // 256kbit
+        4   =>  Ok(128*1024),   // 1Mbit
+        5   =>  Ok(64*1024),    // 512kbit
+        n   =>  Err(GbError::BadHeader(format!("unknown ram size: 0x{:02x}", n))),
+    }
+}
+
+pub fn cartridge_type_name(code: u8) -> &'static str {
+    match code {
+        0x00    =>  "ROM ONLY",
+        0x01    =>  "MBC1",
+        0x02    =>  "MBC1+RAM",
+        0x03    =>  "MBC1+RAM+BATTERY",
+        0x05    =>  "MBC2",
+        0x06    =>  "MBC2+BATTERY",
+        0x08    =>  "ROM+RAM",
+        0x09    =>  "ROM+RAM+BATTERY",
+        0x0B    =>  "MMM01",
+        0x0C    =>  "MMM01+RAM",
+        0x0D    =>  "MMM01+RAM+BATTERY",
+        0x0F    =>  "MBC3+TIMER+BATTERY",
+        0x10    =>  "MBC3+TIMER+RAM+BATTERY",
+        0x11    =>  "MBC3",
+        0x12    =>  "MBC3+RAM",
+        0x13    =>  "MBC3+RAM+BATTERY",
+        0x19    =>  "MBC5",
+        0x1A    =>  "MBC5+RAM",
+        0x1B    =>  "MBC5+RAM+BATTERY",
+        0x1C    =>  "MBC5+RUMBLE",
+        0x1D    =>  "MBC5+RUMBLE+RAM",
+        0x1E    =>  "MBC5+RUMBLE+RAM+BATTERY",
+        0x20    =>  "MBC6",
+        0x22    =>  "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+        0xFC    =>  "POCKET CAMERA",
+        0xFD    =>  "BANDAI TAMA5",
+        0xFE    =>  "HuC3",
+        0xFF    =>  "HuC1+RAM+BATTERY",
+        _       =>  "UNKNOWN",
+    }
+}
+
+// The memory bank controller a cartridge type byte asks for, whether we emulate it or not
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MapperKind {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    RomRam,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+    Unknown(u8),
+}
+
+impl MapperKind {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x00            =>  MapperKind::RomOnly,
+            0x01 ..= 0x03   =>  MapperKind::Mbc1,
+            0x05 | 0x06     =>  MapperKind::Mbc2,
+            0x08 | 0x09     =>  MapperKind::RomRam,
+            0x0B ..= 0x0D   =>  MapperKind::Mmm01,
+            0x0F ..= 0x13   =>  MapperKind::Mbc3,
+            0x19 ..= 0x1E   =>  MapperKind::Mbc5,
+            0x20            =>  MapperKind::Mbc6,
+            0x22            =>  MapperKind::Mbc7,
+            0xFC            =>  MapperKind::PocketCamera,
+            0xFD            =>  MapperKind::Tama5,
+            0xFE            =>  MapperKind::HuC3,
+            0xFF            =>  MapperKind::HuC1,
+            n               =>  MapperKind::Unknown(n),
+        }
+    }
+}
+
+// MBC1 multicarts are 1MB and hold a game with its own header every 256kB (bank 0x10, 0x20, ...)
+fn is_multicart(rom: &[u8]) -> bool {
+    const GAME_BANKS: usize = 0x10;
+    rom.len() == 64 * 0x4000 && (1..4).any(|game| {
+        let base = game * GAME_BANKS * 0x4000;
+        rom[base+LOGO_START..base+LOGO_END] == rom[LOGO_START..LOGO_END]
+    })
+}
+
+fn bank2_shift(multicart: bool) -> u8 {
+    match multicart {
+        true    =>  4,
+        false   =>  5,
+    }
+}
+
+// ROM bank at 0x4000-0x7FFF. Multicarts ignore bit 4 of bank1, after the 0 to 1 fixup.
+fn mbc1_rom_bank(bank1: u8, bank2: u8, multicart: bool) -> usize {
+    let low = match multicart {
+        true    =>  bank1 & 0x0F,
+        false   =>  bank1,
+    };
+    (bank2 << bank2_shift(multicart) | low) as usize
+}
+
+// ROM bank at 0x0000-0x3FFF: 0, or what bank2 selects in RamBank mode
+fn mbc1_rom0_bank(bank2: u8, mode: &BankMode, multicart: bool) -> usize {
+    match mode {
+        BankMode::RamBank   =>  (bank2 << bank2_shift(multicart)) as usize,
+        BankMode::RomBank   =>  0,
+    }
+}
+
+fn mbc1_ram_bank(bank2: u8, mode: &BankMode) -> usize {
+    match mode {
+        BankMode::RamBank   =>  bank2 as usize,
+        BankMode::RomBank   =>  0,
+    }
+}
+
+// What the boot ROM computes over 0x134-0x14C and compares with the byte at 0x14D
+fn header_checksum(bin: &[u8]) -> u8 {
+    bin[TITLE_START..HEADER_CHECKSUM].iter()
+        .fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1))
+}
+
+// The big-endian sum of every ROM byte except its own two. Nothing on the hardware checks it
+fn global_checksum(bin: &[u8]) -> u16 {
+    bin.iter().enumerate()
+        .filter(|(i, _)| *i != GLOBAL_CHECKSUM && *i != GLOBAL_CHECKSUM+1)
+        .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16))
+}
+
+// Checked over the file as given, before a short ROM is padded, to catch bad dumps
+fn check_checksums(bin: &[u8], ignore_checksums: bool) -> Result<(), GbError> {
+    let header = (bin[HEADER_CHECKSUM], header_checksum(bin));
+    let global = ((bin[GLOBAL_CHECKSUM] as u16) << 8 | bin[GLOBAL_CHECKSUM+1] as u16, global_checksum(bin));
+    if header.0 != header.1 {
+        if !ignore_checksums {
+            return Err(GbError::HeaderChecksum(header.0, header.1));
+        }
+        warn!("header checksum is 0x{:02x}, should be 0x{:02x}", header.0, header.1);
+    }
+    if global.0 != global.1 {
+        if !ignore_checksums {
+            return Err(GbError::GlobalChecksum(global.0, global.1));
+        }
+        warn!("global checksum is 0x{:04x}, should be 0x{:04x}", global.0, global.1);
+    }
+    Ok(())
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim_end_matches('\0').to_string()
+}
+
+// Every field of the cartridge header, decoded without loading the game
+pub struct Header {
+    pub title:              String,
+    pub manufacturer:       String,
+    pub cgb_flag:           u8,
+    pub sgb_flag:           u8,
+    pub cartridge_type:     u8,
+    pub rom_size:           Option<usize>,
+    pub ram_size:           Option<usize>,
+    pub destination:        u8,
+    pub old_licensee:       u8,
+    pub new_licensee:       String,
+    pub version:            u8,
+    pub header_checksum:    u8,
+    pub global_checksum:    u16,
+    pub logo_ok:            bool,
+    // Checksums computed over the ROM, to compare with the ones above
+    pub actual_header_checksum: u8,
+    pub actual_global_checksum: u16,
+}
+
+impl Header {
+    pub fn parse(bin: &[u8]) -> Result<Self, GbError> {
+        if bin.len() < HEADER_END {
+            return Err(GbError::TruncatedRom(bin.len()));
+        }
+
+        let actual_header_checksum = header_checksum(bin);
+        let actual_global_checksum = global_checksum(bin);
+
+        Ok(Header {
+            title:              ascii(&bin[TITLE_START..CGB_FLAG]),
+            manufacturer:       ascii(&bin[MANUFACTURER_START..MANUFACTURER_END]),
+            cgb_flag:           bin[CGB_FLAG],
+            sgb_flag:           bin[SGB_FLAG],
+            cartridge_type:     bin[CARTRIDGE_TYPE],
+            rom_size:           rom_size(bin[ROM_SIZE_ADDR]).ok(),
+            ram_size:           ram_size(bin[RAM_SIZE_ADDR]).ok(),
+            destination:        bin[DESTINATION_CODE],
+            old_licensee:       bin[OLD_LICENSEE_CODE],
+            new_licensee:       ascii(&bin[LICENSEE_CODE_START..LICENSEE_CODE_END]),
+            version:            bin[VERSION],
+            header_checksum:    bin[HEADER_CHECKSUM],
+            global_checksum:    (bin[GLOBAL_CHECKSUM] as u16) << 8 | bin[GLOBAL_CHECKSUM+1] as u16,
+            logo_ok:            bin[LOGO_START..LOGO_END] == NINTENDO_LOGO,
+            actual_header_checksum,
+            actual_global_checksum,
+        })
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let size = |size: Option<usize>| match size {
+            Some(size)  =>  format!("{} KB", size / 1024),
+            None        =>  "unknown".to_string(),
+        };
+        let check = |ok: bool| if ok { "ok" } else { "BAD" };
+
+        writeln!(f, "Title:            {}", self.title)?;
+        writeln!(f, "Manufacturer:     {}", self.manufacturer)?;
+        writeln!(f, "CGB flag:         0x{:02x} ({})", self.cgb_flag, match self.cgb_flag {
+            0x80    =>  "CGB enhanced",
+            0xC0    =>  "CGB only",
+            _       =>  "DMG",
+        })?;
+        writeln!(f, "SGB flag:         0x{:02x} ({})", self.sgb_flag, match self.sgb_flag {
+            0x03    =>  "SGB functions",
+            _       =>  "none",
+        })?;
+        writeln!(f, "Cartridge type:   0x{:02x} ({})", self.cartridge_type, cartridge_type_name(self.cartridge_type))?;
+        writeln!(f, "ROM size:         {}", size(self.rom_size))?;
+        writeln!(f, "RAM size:         {}", size(self.ram_size))?;
+        writeln!(f, "Destination:      0x{:02x} ({})", self.destination, match self.destination {
+            0x00    =>  "Japan",
+            _       =>  "overseas",
+        })?;
+        writeln!(f, "Old licensee:     0x{:02x}", self.old_licensee)?;
+        writeln!(f, "New licensee:     {}", self.new_licensee)?;
+        writeln!(f, "Version:          {}", self.version)?;
+        writeln!(f, "Nintendo logo:    {}", check(self.logo_ok))?;
+        writeln!(f, "Header checksum:  0x{:02x} ({})", self.header_checksum,
+                 check(self.header_checksum == self.actual_header_checksum))?;
+        write!(f, "Global checksum:  0x{:04x} ({})", self.global_checksum,
+               check(self.global_checksum == self.actual_global_checksum))
+    }
+}
 
 pub enum BankMode {
     RamBank = 0,
@@ -27,55 +309,325 @@ pub enum Cartridge {
 
     Mbc1 {
         rom:            Vec<u8>,
-        rombank:        u8,
+        // Low 5 bits of the ROM bank (0x2000-0x3FFF)
+        bank1:          u8,
         title:          String,
         ram:            Vec<u8>,
-        rambank:        u8,
+        // 2 more bits (0x4000-0x5FFF): the upper ROM bank bits, and in RamBank mode
+        // the RAM bank and the bank mapped at 0x0000-0x3FFF too
+        bank2:          u8,
         ram_enabled:    bool,
         mode:           BankMode,
+        battery:        bool,
+        dirty:          bool,
+        // Multicarts (MBC1M) wire bank2 one bit lower, so only 4 bits of bank1 are used
+        multicart:      bool,
+    },
+
+    Mbc3 {
+        rom:            Vec<u8>,
+        // 7-bit ROM bank at 0x4000-0x7FFF, 0 selects 1
+        rom_bank:       u8,
+        title:          String,
+        ram:            Vec<u8>,
+        // 0x00-0x03 maps a RAM bank at 0xA000-0xBFFF, 0x08-0x0C an RTC register
+        ram_select:     u8,
+        ram_enabled:    bool,
+        battery:        bool,
+        dirty:          bool,
+        // MBC3+TIMER carts only
+        rtc:            Option<Rtc>,
+        // The last write to 0x6000-0x7FFF was 0x00, so 0x01 latches the clock
+        latch_armed:    bool,
     },
 }
 
 impl Cartridge {
     pub fn _no_cartridge() -> Self {
         Cartridge::NoMbc {
-            rom:        vec![0; _ROM_SIZE],
+            rom:        vec![0; ROM_SIZE],
             title:      "NO CARTRIDGE".to_string(),
         }
     }
 
-    pub fn from_path(path: &Path) -> Self {
-        let bin = read(path).unwrap();
+    pub fn from_path(path: &Path) -> Result<Self, GbError> {
+        Cartridge::from_bytes(read(path)?)
+    }
+
+    pub fn from_bytes(bin: Vec<u8>) -> Result<Self, GbError> {
+        Cartridge::load(bin, false)
+    }
+
+    // With ignore_checksums a wrong header or global checksum is only a warning, so
+    // homebrew that never fixed them still boots
+    pub fn load(mut bin: Vec<u8>, ignore_checksums: bool) -> Result<Self, GbError> {
+        if bin.len() < HEADER_END {
+            return Err(GbError::TruncatedRom(bin.len()));
+        }
         let title = String::from_utf8(bin[TITLE_START..TITLE_END]
                     .to_vec())
-                    .unwrap();
-        let ramsize = match bin[0x149] {
-            0   =>  0,
-            1   =>  16*1024,    // 16kbit
-            2   =>  64*1024,    // 64kbit
-            3   =>  256*1024,   // 256kbit
-            4   =>  1024*1024,  // 1Mbit
-            _   =>  panic!(),
-        };
+                    .map_err(|_| GbError::BadHeader("title is not valid ascii".to_string()))?;
+        let romsize = rom_size(bin[ROM_SIZE_ADDR])?;
+        check_checksums(&bin, ignore_checksums)?;
 
-        match bin[CARTRIDGE_TYPE] {
+        // Homebrew and test ROMs can be smaller than the smallest real ROM.
+        // The rest reads as open bus.
+        if romsize == ROM_SIZE && bin.len() < romsize {
+            bin.resize(romsize, 0xFF);
+        }
+        // A short file can't hold every bank. An over-dump still runs.
+        if bin.len() < romsize {
+            return Err(GbError::RomSizeMismatch(romsize, bin.len()));
+        }
+        if bin.len() > romsize {
+            warn!("rom is {} bytes but the header says {} bytes (over-dump?)", bin.len(), romsize);
+        }
+
+        let ramsize = ram_size(bin[RAM_SIZE_ADDR])?;
+
+        if bin[LOGO_START..LOGO_END] != NINTENDO_LOGO {
+            warn!("the nintendo logo in the header is wrong, a boot rom would lock up");
+        }
+
+        let code = bin[CARTRIDGE_TYPE];
+        let cartridge = match MapperKind::from_code(code) {
             // No MBC(ROM only)
-            0x00    =>  Cartridge::NoMbc {
+            MapperKind::RomOnly =>  Cartridge::NoMbc {
                             rom:    bin,
                             title:  title,
                         },
-            0x01    =>  Cartridge::Mbc1 {
+            // MBC1, MBC1+RAM, MBC1+RAM+BATTERY
+            MapperKind::Mbc1    =>  Cartridge::Mbc1 {
+                            battery:        code == 0x03,
+                            multicart:      is_multicart(&bin),
                             rom:            bin,
-                            rombank:        1,
-                            title:          title,
+                            bank1:          1,
+                            title,
                             ram:            vec![0; ramsize],
-                            rambank:        0,
+                            bank2:          0,
                             ram_enabled:    false,
                             mode:           BankMode::RomBank,
+                            dirty:          false,
+                        },
+            // MBC3, with RAM, battery and the real-time clock in any combination
+            MapperKind::Mbc3    =>  Cartridge::Mbc3 {
+                            battery:        code == 0x0F || code == 0x10 || code == 0x13,
+                            rtc:            match code {
+                                0x0F | 0x10 =>  Some(Rtc::new(unix_now())),
+                                _           =>  None,
+                            },
+                            rom:            bin,
+                            rom_bank:       1,
+                            title,
+                            ram:            vec![0; ramsize],
+                            ram_select:     0,
+                            ram_enabled:    false,
+                            dirty:          false,
+                            latch_armed:    false,
                         },
-            _       =>  unimplemented!("can't load: mbc type={}", bin[CARTRIDGE_TYPE]),
+            // Known but not emulated yet, or not a cartridge type at all
+            _                   =>  return Err(GbError::UnsupportedMapper(code)),
+        };
+
+        Ok(cartridge)
+    }
+
+    // Resets the MBC registers. External RAM is kept intact.
+    pub fn reset(&mut self) {
+        match self {
+            Cartridge::NoMbc { .. }   =>  (),
+            Cartridge::Mbc1 { bank1, bank2, ram_enabled, mode, .. }   =>  {
+                *bank1 = 1;
+                *bank2 = 0;
+                *ram_enabled = false;
+                *mode = BankMode::RomBank;
+            },
+            Cartridge::Mbc3 { rom_bank, ram_select, ram_enabled, latch_armed, .. }  =>  {
+                *rom_bank = 1;
+                *ram_select = 0;
+                *ram_enabled = false;
+                *latch_armed = false;
+            },
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        match self {
+            Cartridge::NoMbc { rom, .. }    =>  rom,
+            Cartridge::Mbc1 { rom, .. }     =>  rom,
+            Cartridge::Mbc3 { rom, .. }     =>  rom,
+        }
+    }
+
+    // The Nintendo logo in the header
+    pub fn logo(&self) -> &[u8] {
+        &self.rom()[LOGO_START..LOGO_END]
+    }
+
+    // Sum of the 16 title bytes, which the CGB boot ROM looks games up by
+    pub fn title_checksum(&self) -> u8 {
+        self.rom()[TITLE_START..=CGB_FLAG].iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+    }
+
+    // Whether the old licensee code, or the new one it points to, is Nintendo's
+    pub fn is_nintendo(&self) -> bool {
+        match self.rom()[OLD_LICENSEE_CODE] {
+            0x01    =>  true,
+            0x33    =>  &self.rom()[LICENSEE_CODE_START..LICENSEE_CODE_END] == b"01",
+            _       =>  false,
+        }
+    }
+
+    // The hardware the game asks for in its header.
+    // 0x80 (CGB enhanced) and 0xC0 (CGB only) select the Game Boy Color.
+    pub fn model(&self) -> Model {
+        match self.rom()[CGB_FLAG] {
+            0x80 | 0xC0 =>  Model::Cgb,
+            _           =>  Model::Dmg,
         }
     }
+
+    // ROM bank mapped at 0x4000-0x7FFF
+    pub fn rom_bank(&self) -> usize {
+        match self {
+            Cartridge::NoMbc { .. }             =>  1,
+            Cartridge::Mbc1 { bank1, bank2, multicart, .. }     =>  mbc1_rom_bank(*bank1, *bank2, *multicart),
+            Cartridge::Mbc3 { rom_bank, .. }    =>  *rom_bank as usize,
+        }
+    }
+
+    // External RAM bank mapped at 0xA000-0xBFFF, if the cartridge has RAM
+    pub fn ram_bank(&self) -> Option<usize> {
+        match self {
+            Cartridge::Mbc1 { ram, bank2, mode, .. } if !ram.is_empty() =>  Some(mbc1_ram_bank(*bank2, mode)),
+            Cartridge::Mbc3 { ram, ram_select, .. } if !ram.is_empty() && *ram_select < 0x04    =>
+                Some(*ram_select as usize),
+            _                                                           =>  None,
+        }
+    }
+
+    // What the battery keeps, if the cartridge has one: the external RAM, followed by
+    // the clock on MBC3+TIMER carts
+    pub fn battery_ram(&self) -> Option<Vec<u8>> {
+        match self {
+            Cartridge::Mbc1 { ram, battery: true, .. }  =>  Some(ram.clone()),
+            Cartridge::Mbc3 { ram, rtc, battery: true, .. } =>  {
+                let mut data = ram.clone();
+                if let Some(rtc) = rtc {
+                    data.extend_from_slice(&rtc.save());
+                }
+                Some(data)
+            },
+            _                                           =>  None,
+        }
+    }
+
+    // Restores external RAM from a save. Short saves are loaded as far as they go.
+    // The clock catches up with the time that passed since the save was written.
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        self.load_battery_ram_at(data, unix_now());
+    }
+
+    pub fn load_battery_ram_at(&mut self, data: &[u8], now: u64) {
+        match self {
+            Cartridge::Mbc1 { ram, battery: true, .. }  =>  {
+                let len = ram.len().min(data.len());
+                ram[..len].copy_from_slice(&data[..len]);
+            },
+            Cartridge::Mbc3 { ram, rtc, battery: true, .. } =>  {
+                let len = ram.len().min(data.len());
+                ram[..len].copy_from_slice(&data[..len]);
+                if let (Some(rtc), Some(saved)) = (rtc, data.get(ram.len()..)) {
+                    if saved.len() >= RTC_SAVE_SIZE {
+                        rtc.load(saved, now);
+                    }
+                }
+            },
+            _                                           =>  (),
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        match self {
+            Cartridge::Mbc1 { dirty, battery: true, .. }    =>  *dirty,
+            Cartridge::Mbc3 { dirty, battery: true, .. }    =>  *dirty,
+            _                                               =>  false,
+        }
+    }
+
+    pub fn clear_dirty(&mut self) {
+        match self {
+            Cartridge::Mbc1 { dirty, .. }   =>  *dirty = false,
+            Cartridge::Mbc3 { dirty, .. }   =>  *dirty = false,
+            _                               =>  (),
+        }
+    }
+
+    // FNV-1a hash of the whole ROM. Unlike the header's global checksum it also tells
+    // apart revisions and hacks of a game that left the checksum stale.
+    fn rom_hash(&self) -> u64 {
+        self.rom().iter().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    // The ROM itself isn't saved, only its hash to catch states of other games
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u64(self.rom_hash());
+        if let Cartridge::Mbc1 { bank1, ram, bank2, ram_enabled, mode, .. } = self {
+            w.u8(*bank1);
+            w.u8(*bank2);
+            w.bool(*ram_enabled);
+            w.bool(match mode {
+                BankMode::RamBank   =>  true,
+                BankMode::RomBank   =>  false,
+            });
+            w.bytes(ram);
+        }
+        if let Cartridge::Mbc3 { rom_bank, ram, ram_select, ram_enabled, rtc, latch_armed, .. } = self {
+            w.u8(*rom_bank);
+            w.u8(*ram_select);
+            w.bool(*ram_enabled);
+            w.bool(*latch_armed);
+            w.bytes(ram);
+            if let Some(rtc) = rtc {
+                rtc.save_state(w);
+            }
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        let hash = r.u64()?;
+        if hash != self.rom_hash() {
+            return Err(GbError::BadState(format!(
+                "saved with another rom (hash {:016x}, this rom's is {:016x})", hash, self.rom_hash())));
+        }
+        if let Cartridge::Mbc1 { bank1, ram, bank2, ram_enabled, mode, dirty, .. } = self {
+            *bank1          = r.u8()?;
+            *bank2          = r.u8()?;
+            *ram_enabled    = r.bool()?;
+            *mode = match r.bool()? {
+                true    =>  BankMode::RamBank,
+                false   =>  BankMode::RomBank,
+            };
+            r.bytes_into(ram)?;
+            // The battery save follows the loaded RAM
+            *dirty = true;
+        }
+        if let Cartridge::Mbc3 { rom_bank, ram, ram_select, ram_enabled, rtc, latch_armed, dirty, .. } = self {
+            *rom_bank       = r.u8()?;
+            *ram_select     = r.u8()?;
+            *ram_enabled    = r.bool()?;
+            *latch_armed    = r.bool()?;
+            r.bytes_into(ram)?;
+            // The clock counts on from the state's time, as if the game had been left running
+            if let Some(rtc) = rtc {
+                rtc.load_state(r, unix_now())?;
+            }
+            *dirty = true;
+        }
+        Ok(())
+    }
 }
 
 
@@ -84,12 +636,37 @@ impl Io for Cartridge {
         match self {
             Cartridge::NoMbc { rom, .. }  =>  match addr {
                 0x0000 ..= 0x7FFF   =>  rom[addr],
+                // No external RAM, the bus is open
+                0xA000 ..= 0xBFFF   =>  0xFF,
+                _                   =>  panic!(),
+            },
+            Cartridge::Mbc1 { rom, bank1, ram, bank2, mode, multicart, .. }  =>  match addr {
+                // Banks past the end of the ROM wrap around
+                0x0000 ..= 0x3FFF   =>  {
+                    let bank = mbc1_rom0_bank(*bank2, mode, *multicart);
+                    rom[(addr+0x4000*bank) % rom.len()]
+                },
+                0x4000 ..= 0x7FFF   =>  {
+                    let bank = mbc1_rom_bank(*bank1, *bank2, *multicart);
+                    rom[(addr-0x4000+0x4000*bank) % rom.len()]
+                },
+                // Carts with less than 8kB of RAM (or none) leave the rest open
+                0xA000 ..= 0xBFFF   =>  {
+                    let bank = mbc1_ram_bank(*bank2, mode);
+                    *ram.get(addr-0xA000+0x2000*bank).unwrap_or(&0xFF)
+                },
                 _                   =>  panic!(),
             },
-            Cartridge::Mbc1 { rom, rombank, ram, rambank, .. }  =>  match addr {
+            Cartridge::Mbc3 { rom, rom_bank, ram, ram_select, ram_enabled, rtc, .. }  =>  match addr {
                 0x0000 ..= 0x3FFF   =>  rom[addr],
-                0x4000 ..= 0x7FFF   =>  rom[addr+0x4000*(*rombank as usize - 1)],
-                0xA000 ..= 0xBFFF   =>  ram[addr-0xA000+0x2000*(*rambank as usize)],
+                0x4000 ..= 0x7FFF   =>  rom[(addr-0x4000+0x4000*(*rom_bank as usize)) % rom.len()],
+                0xA000 ..= 0xBFFF   =>  match (*ram_enabled, *ram_select, rtc) {
+                    (false, _, _)           =>  0xFF,
+                    (true, 0x00 ..= 0x03, _)    =>
+                        *ram.get(addr-0xA000+0x2000*(*ram_select as usize)).unwrap_or(&0xFF),
+                    (true, reg, Some(rtc))  =>  rtc.read(reg),
+                    (true, _, None)         =>  0xFF,
+                },
                 _                   =>  panic!(),
             },
         }
@@ -98,29 +675,306 @@ impl Io for Cartridge {
 
     fn write8(&mut self, addr: usize, data: u8) {
         match self {
-            Cartridge::NoMbc { rom, .. }  =>  match addr {
-                0x0000 ..= 0x7FFF   =>  rom[addr] = data,
+            Cartridge::NoMbc { .. }  =>  match addr {
+                // No MBC to take the write, and ROM can't change
+                0x0000 ..= 0x7FFF   =>  (),
+                0xA000 ..= 0xBFFF   =>  (),
                 _                   =>  panic!(),
             },
-            Cartridge::Mbc1 { rombank, ram, rambank, ram_enabled, mode, .. }  =>  match addr {
+            Cartridge::Mbc1 { bank1, ram, bank2, ram_enabled, mode, dirty, .. }  =>  match addr {
                 0x0000 ..= 0x1FFF   =>  *ram_enabled = data&0x0F == 0x0A,
-                0x2000 ..= 0x3FFF   =>  *rombank = data&0x1F,
-                0x4000 ..= 0x5FFF   =>  match mode {
-                    BankMode::RamBank   => *rambank = data&0x03,
-                    BankMode::RomBank   => *rombank |= (data&0x03) << 5,
-                }
+                // The low 5 bits of the bank. 0 selects bank 1 as bank 0 is always mapped.
+                0x2000 ..= 0x3FFF   =>  {
+                    *bank1 = match data&0x1F {
+                        0   =>  1,
+                        n   =>  n,
+                    };
+                    debug!("ROM bank 1: {}", *bank1);
+                },
+                0x4000 ..= 0x5FFF   =>  {
+                    *bank2 = data&0x03;
+                    debug!("ROM bank 2: {}", *bank2);
+                },
                 0x6000 ..= 0x7FFF   =>  match data&0x01 == 0x00 {
                     true    =>  *mode = BankMode::RomBank,
                     false   =>  *mode = BankMode::RamBank,
                 },
                 0xA000 ..= 0xBFFF   =>  if *ram_enabled {
-                    match mode {
-                        BankMode::RamBank   =>  ram[addr-0xA000+0x2000*(*rambank as usize)] = data,
-                        BankMode::RomBank   =>  ram[addr-0xA000] = data,
+                    let index = addr-0xA000+0x2000*mbc1_ram_bank(*bank2, mode);
+                    if let Some(byte) = ram.get_mut(index) {
+                        *byte = data;
+                        *dirty = true;
+                    }
+                },
+                _                   =>  panic!(),
+            },
+            Cartridge::Mbc3 { rom_bank, ram, ram_select, ram_enabled, rtc, dirty, latch_armed, .. }  =>  match addr {
+                0x0000 ..= 0x1FFF   =>  *ram_enabled = data&0x0F == 0x0A,
+                0x2000 ..= 0x3FFF   =>  {
+                    *rom_bank = match data&0x7F {
+                        0   =>  1,
+                        n   =>  n,
+                    };
+                    debug!("ROM bank: {}", *rom_bank);
+                },
+                0x4000 ..= 0x5FFF   =>  *ram_select = data&0x0F,
+                0x6000 ..= 0x7FFF   =>  {
+                    if *latch_armed && data == 0x01 {
+                        if let Some(rtc) = rtc {
+                            rtc.latch(unix_now());
+                        }
                     }
+                    *latch_armed = data == 0x00;
+                },
+                0xA000 ..= 0xBFFF   =>  match (*ram_enabled, *ram_select, rtc) {
+                    (false, _, _)           =>  (),
+                    (true, 0x00 ..= 0x03, _)    =>  {
+                        let index = addr-0xA000+0x2000*(*ram_select as usize);
+                        if let Some(byte) = ram.get_mut(index) {
+                            *byte = data;
+                            *dirty = true;
+                        }
+                    },
+                    (true, reg, Some(rtc))  =>  {
+                        rtc.write(reg, data, unix_now());
+                        *dirty = true;
+                    },
+                    (true, _, None)         =>  (),
                 },
                 _                   =>  panic!(),
             },
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use log::{ Log, Metadata, Record, LevelFilter };
+    use std::sync::Mutex;
+
+    // A 32kB cartridge without an MBC, with a valid header. It jumps over the header
+    // to `program` at 0x150.
+    pub fn rom(program: &[u8]) -> Vec<u8> {
+        let mut bin = vec![0; ROM_SIZE];
+        bin[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        bin[LOGO_START..LOGO_END].copy_from_slice(&NINTENDO_LOGO);
+        bin[HEADER_END..HEADER_END+program.len()].copy_from_slice(program);
+        fix_header(&mut bin);
+        bin
+    }
+
+    // Recomputes both checksums after a test changed the rom
+    pub fn fix_header(bin: &mut [u8]) {
+        bin[HEADER_CHECKSUM] = header_checksum(bin);
+        let global = global_checksum(bin);
+        bin[GLOBAL_CHECKSUM] = (global >> 8) as u8;
+        bin[GLOBAL_CHECKSUM+1] = global as u8;
+    }
+
+    #[test]
+    fn truncated_rom() {
+        let bin = rom(&[])[..0x14F].to_vec();
+        assert!(matches!(Cartridge::from_bytes(bin), Err(GbError::TruncatedRom(0x14F))));
+    }
+
+    #[test]
+    fn short_rom_reads_open_bus() {
+        let bin = rom(&[0x18, 0xFE])[..0x1000].to_vec();
+        let cartridge = Cartridge::from_bytes(bin).unwrap();
+        assert_eq!(cartridge.read8(0x0150), 0x18);
+        assert_eq!(cartridge.read8(0x0FFF), 0x00);
+        assert_eq!(cartridge.read8(0x1000), 0xFF);
+        assert_eq!(cartridge.read8(0x7FFF), 0xFF);
+    }
+
+    #[test]
+    fn unsupported_mapper() {
+        let mut bin = rom(&[]);
+        bin[CARTRIDGE_TYPE] = 0x20;
+        fix_header(&mut bin);
+        assert!(matches!(Cartridge::from_bytes(bin), Err(GbError::UnsupportedMapper(0x20))));
+    }
+
+    // Keeps the message of every record logged
+    struct CaptureLogger(Mutex<Vec<String>>);
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            if let Ok(mut messages) = self.0.lock() {
+                messages.push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger(Mutex::new(Vec::new()));
+
+    // Starts capturing debug logs, for the tests of every module.
+    // Returns the messages logged so far.
+    pub fn capture_log() -> &'static Mutex<Vec<String>> {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+        &LOGGER.0
+    }
+
+    #[test]
+    fn bank_switch_is_logged() {
+        let log = capture_log();
+        // MBC1 with 8 banks
+        let mut bin = rom(&[]);
+        bin.resize(0x20000, 0);
+        bin[CARTRIDGE_TYPE] = 0x01;
+        bin[ROM_SIZE_ADDR] = 0x02;
+        fix_header(&mut bin);
+        let mut cartridge = Cartridge::from_bytes(bin).unwrap();
+        cartridge.write8(0x2000, 0x06);
+
+        let messages = log.lock().unwrap();
+        assert!(messages.iter().any(|message| message == "ROM bank 1: 6"));
+    }
+
+    #[test]
+    fn rom_size_mismatch() {
+        // The header claims 1MB, but the file only holds 32kB
+        let mut bin = rom(&[]);
+        bin[CARTRIDGE_TYPE] = 0x01;
+        bin[ROM_SIZE_ADDR] = 0x05;
+        fix_header(&mut bin);
+        assert!(matches!(Cartridge::from_bytes(bin), Err(GbError::RomSizeMismatch(0x100000, 0x8000))));
+    }
+
+    #[test]
+    fn header_info() {
+        let mut bin = rom(&[]);
+        bin[TITLE_START..TITLE_START + 8].copy_from_slice(b"GBR TEST");
+        bin[CARTRIDGE_TYPE] = 0x03;
+        fix_header(&mut bin);
+        let info = Header::parse(&bin).unwrap().to_string();
+        assert!(info.contains("Title:            GBR TEST\n"));
+        assert!(info.contains("Cartridge type:   0x03 (MBC1+RAM+BATTERY)\n"));
+        assert!(info.lines().any(|line| line.starts_with("Header checksum:") && line.ends_with("(ok)")));
+    }
+
+    #[test]
+    fn rom_writes_select_bank_or_are_ignored() {
+        // Each bank starts with its number
+        let mut bin = rom(&[]);
+        bin.resize(0x20000, 0);
+        for bank in 1..8 {
+            bin[bank * 0x4000] = bank as u8;
+        }
+        bin[CARTRIDGE_TYPE] = 0x01;
+        bin[ROM_SIZE_ADDR] = 0x02;
+        fix_header(&mut bin);
+        let mut mbc1 = Cartridge::from_bytes(bin).unwrap();
+        assert_eq!(mbc1.read8(0x4000), 1);
+        mbc1.write8(0x2000, 0x03);
+        assert_eq!(mbc1.read8(0x4000), 3);
+        assert_eq!(mbc1.read8(0x2000), 0);
+
+        let mut bin = rom(&[]);
+        bin[0x4000] = 0x42;
+        fix_header(&mut bin);
+        let mut rom_only = Cartridge::from_bytes(bin).unwrap();
+        rom_only.write8(0x2000, 0x03);
+        rom_only.write8(0x4000, 0x24);
+        assert_eq!(rom_only.read8(0x2000), 0);
+        assert_eq!(rom_only.read8(0x4000), 0x42);
+    }
+
+    #[test]
+    fn unknown_type_byte_is_named() {
+        assert_eq!(MapperKind::from_code(0x42), MapperKind::Unknown(0x42));
+        assert_eq!(MapperKind::from_code(0x13), MapperKind::Mbc3);
+        let mut bin = rom(&[]);
+        bin[CARTRIDGE_TYPE] = 0x42;
+        fix_header(&mut bin);
+        let err = Cartridge::from_bytes(bin).err().unwrap();
+        assert!(matches!(err, GbError::UnsupportedMapper(0x42)));
+        assert!(err.to_string().contains("0x42"));
+    }
+
+    // 1MB MBC1 ROM with each bank's number at offset 0x200, optionally with a
+    // second game header at bank 0x10 like a multicart
+    fn mbc1_1mb(multicart: bool) -> Cartridge {
+        let mut bin = rom(&[]);
+        bin.resize(64 * 0x4000, 0);
+        for bank in 0..64 {
+            bin[bank * 0x4000 + 0x200] = bank as u8;
+        }
+        bin[CARTRIDGE_TYPE] = 0x01;
+        bin[ROM_SIZE_ADDR] = 0x05;
+        fix_header(&mut bin);
+        if multicart {
+            let game = 0x10 * 0x4000;
+            let header = bin[..0x150].to_vec();
+            bin[game..game + 0x150].copy_from_slice(&header);
+            fix_header(&mut bin);
+        }
+        Cartridge::from_bytes(bin).unwrap()
+    }
+
+    #[test]
+    fn multicart_wires_bank2_one_bit_lower() {
+        let mut mbc1 = mbc1_1mb(false);
+        mbc1.write8(0x2000, 0x12);
+        mbc1.write8(0x4000, 0x01);
+        assert_eq!(mbc1.read8(0x4200), 0x32);
+
+        let mut mbc1m = mbc1_1mb(true);
+        // Bit 4 of bank1 is ignored
+        mbc1m.write8(0x2000, 0x12);
+        mbc1m.write8(0x4000, 0x01);
+        assert_eq!(mbc1m.read8(0x4200), 0x12);
+        assert_eq!(mbc1m.read8(0x0200), 0x00);
+        // In RAM banking mode bank2 picks the game at 0x0000-0x3FFF
+        mbc1m.write8(0x6000, 0x01);
+        mbc1m.write8(0x4000, 0x02);
+        assert_eq!(mbc1m.read8(0x0200), 0x20);
+        assert_eq!(mbc1m.read8(0x4200), 0x22);
+    }
+
+    #[test]
+    fn wrong_checksums_are_rejected_unless_ignored() {
+        let log = capture_log();
+        let mut bin = rom(&[]);
+        bin[HEADER_CHECKSUM] = header_checksum(&bin).wrapping_add(1);
+        let (header, actual) = (bin[HEADER_CHECKSUM], header_checksum(&bin));
+        assert!(matches!(Cartridge::from_bytes(bin.clone()),
+                         Err(GbError::HeaderChecksum(h, a)) if h == header && a == actual));
+
+        assert!(Cartridge::load(bin, true).is_ok());
+        assert!(log.lock().unwrap().iter().any(|message| message.starts_with("header checksum is ")));
+
+        // A byte changed past the header, e.g. a hand-edited homebrew build
+        let mut bin = rom(&[]);
+        bin[0x4000] = 0x42;
+        assert!(matches!(Cartridge::from_bytes(bin.clone()), Err(GbError::GlobalChecksum(_, _))));
+        let cartridge = Cartridge::load(bin, true).unwrap();
+        assert_eq!(cartridge.read8(0x4000), 0x42);
+        assert!(log.lock().unwrap().iter().any(|message| message.starts_with("global checksum is ")));
+    }
+
+    #[test]
+    fn logo_reads_back_from_header() {
+        let cartridge = Cartridge::from_bytes(rom(&[])).unwrap();
+        let logo: Vec<u8> = (LOGO_START..LOGO_END).map(|addr| cartridge.read8(addr)).collect();
+        assert_eq!(logo, NINTENDO_LOGO.to_vec());
+        assert!(Header::parse(&rom(&[])).unwrap().logo_ok);
+
+        // A bad logo is only reported, homebrew still boots
+        let log = capture_log();
+        let mut bin = rom(&[]);
+        bin[LOGO_START] ^= 0xFF;
+        fix_header(&mut bin);
+        assert!(!Header::parse(&bin).unwrap().logo_ok);
+        assert!(Cartridge::from_bytes(bin).is_ok());
+        assert!(log.lock().unwrap().iter().any(|message| message.starts_with("the nintendo logo")));
+    }
+}
@@ -1,12 +1,28 @@
 use bitflags::*;
+use log::error;
+use std::collections::VecDeque;
 use std::fmt;
+use std::ops::RangeInclusive;
 use std::path::Path;
 
-use crate::core::io::Io;
-use crate::core::bus::Bus;
+use crate::core::addr::{ IE, IF };
+use crate::core::io::{ Io, MemDevice };
+use crate::core::bus::{ Bus, MemRegion, RegionAccesses };
+use crate::core::interrupt::{ InterruptKind, InterruptState };
+use crate::core::cartridge::Cartridge;
 use crate::core::pad::Key;
+use crate::core::sgb::SgbPacket;
+use crate::core::serial::{ SerialLink, SerialDevice };
+use crate::core::infrared::InfraredDevice;
+use crate::core::apu::HighPass;
+use crate::core::trace::Trace;
 use crate::core::ppu::*;
+use crate::core::error::GbError;
+use crate::core::model::Model;
+use crate::core::state::{ StateReader, StateWriter };
 
+// Bits 0-3 of F don't exist and always read as 0.
+// Build Flags with from_bits_truncate() so stray low bits are dropped.
 bitflags! {
     struct Flags: u8 {
         const Z     = 0b10000000;
@@ -29,8 +45,67 @@ pub struct Cpu {
     pc:     u16,
     bus:    Bus,
     halt:   bool,
+    instructions:   u64,
+    cycles:         u64,
+    breakpoints:    Vec<u16>,
+    // Only counted while profiling
+    opcodes:        Option<Vec<OpcodeCount>>,
+    // The last instructions, newest last, while step back is on
+    undo:           Option<VecDeque<UndoStep>>,
+    trace:          Option<Trace>,
+    // Instructions per machine cycle of the rest of the system, 1.0 unless debugging
+    clock_scale:    f64,
+    // Instructions owed to the CPU by clock_scale
+    clock_debt:     f64,
 }
 
+// How many instructions step_back() can undo
+const STEP_BACK_DEPTH: usize = 256;
+
+// What an instruction changed: the registers before it and the memory it overwrote
+struct UndoStep {
+    regs:   Registers,
+    ime:    bool,
+    writes: Vec<(u16, u8)>,
+}
+
+// Copy of the registers, e.g. to check the result of a test ROM
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Registers {
+    pub a:  u8,
+    pub f:  u8,
+    pub b:  u8,
+    pub c:  u8,
+    pub d:  u8,
+    pub e:  u8,
+    pub h:  u8,
+    pub l:  u8,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+// How often an opcode ran and the clocks it took. CB-prefixed opcodes are 0xCB00-0xCBFF.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpcodeCount {
+    pub opcode: u16,
+    pub name:   &'static str,
+    pub count:  u64,
+    pub cycles: u64,
+}
+
+// Why step_frame() returned
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    VBlank,
+    CycleBudgetExceeded,
+    // PC reached a breakpoint. The instruction there hasn't run yet
+    Breakpoint,
+}
+
+pub const NLINES:           usize = 154;
+pub const CYCLE_PER_LINE:   usize = 114;
+pub const CLOCK_HZ:         u64   = 4194304;
+
 impl fmt::Display for Cpu {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Cpu {{\n\taf= 0x{:02x}{:02x}\n\tbc= 0x{:02x}{:02x}\n\
@@ -44,44 +119,354 @@ impl fmt::Display for Cpu {
 
 impl Cpu {
     pub fn _new() -> Self {
-        Cpu {
-            a:      0x11,
-            b:      0x00,
-            d:      0xFF,
-            h:      0x00,
-            c:      0x00,
-            e:      0x56,
-            l:      0x0D,
-            f:      Flags::from_bits_truncate(0x80),
-            sp:     0xFFFE,
-            pc:     0x100,
-            bus:    Bus::_no_cartridge(),
-            halt:   false,
-        }
+        Cpu::with_bus(Bus::_no_cartridge())
     }
     
-    pub fn from_path(path: &Path) -> Self {
-        Cpu {
-            a:      0x11,
+    pub fn from_path(path: &Path) -> Result<Self, GbError> {
+        Ok(Cpu::with_bus(Bus::from_path(path)?))
+    }
+
+    pub fn from_bytes(bin: Vec<u8>) -> Result<Self, GbError> {
+        Cpu::load(bin, false)
+    }
+
+    pub fn load(bin: Vec<u8>, ignore_checksums: bool) -> Result<Self, GbError> {
+        Ok(Cpu::with_bus(Bus::load(bin, ignore_checksums)?))
+    }
+
+    fn with_bus(bus: Bus) -> Self {
+        let mut cpu = Cpu {
+            a:      0x00,
             b:      0x00,
-            d:      0xFF,
+            d:      0x00,
             h:      0x00,
             c:      0x00,
-            e:      0x56,
-            l:      0x0D,
-            f:      Flags::from_bits_truncate(0x80),
-            sp:     0xFFFE,
-            pc:     0x100,
-            bus:    Bus::from_path(path),
+            e:      0x00,
+            l:      0x00,
+            f:      Flags::empty(),
+            sp:     0x0000,
+            pc:     0x0000,
+            bus,
             halt:   false,
+            instructions:   0,
+            cycles:         0,
+            breakpoints:    Vec::new(),
+            opcodes:        None,
+            undo:           None,
+            trace:          None,
+            clock_scale:    1.0,
+            clock_debt:     0.0,
+        };
+        cpu.reset_registers();
+        cpu
+    }
+
+    // Registers as the boot ROM leaves them, or cleared when running a boot ROM.
+    // Games tell the models apart by A (0x11 on CGB).
+    fn reset_registers(&mut self) {
+        if self.bus.booting() {
+            self.a      = 0x00;
+            self.f      = Flags::empty();
+            self.b      = 0x00;
+            self.c      = 0x00;
+            self.d      = 0x00;
+            self.e      = 0x00;
+            self.h      = 0x00;
+            self.l      = 0x00;
+            self.sp     = 0x0000;
+            self.pc     = 0x0000;
+            return;
+        }
+
+        match self.bus.model() {
+            Model::Dmg | Model::Mgb =>  {
+                // The Pocket's boot ROM leaves 0xFF in A
+                self.a      = match self.bus.model() {
+                    Model::Mgb  =>  0xFF,
+                    _           =>  0x01,
+                };
+                self.f      = Flags::from_bits_truncate(0xB0);
+                self.b      = 0x00;
+                self.c      = 0x13;
+                self.d      = 0x00;
+                self.e      = 0xD8;
+                self.h      = 0x01;
+                self.l      = 0x4D;
+            },
+            // Games detect a GBA by bit 0 of B
+            Model::Cgb | Model::Agb =>  {
+                self.a      = 0x11;
+                self.f      = Flags::from_bits_truncate(0x80);
+                self.b      = match self.bus.model() {
+                    Model::Agb  =>  0x01,
+                    _           =>  0x00,
+                };
+                self.c      = 0x00;
+                self.d      = 0xFF;
+                self.e      = 0x56;
+                self.h      = 0x00;
+                self.l      = 0x0D;
+            },
+        }
+        self.sp     = 0xFFFE;
+        self.pc     = 0x100;
+    }
+
+    pub fn reset(&mut self) {
+        self.bus.reset();
+        self.reset_registers();
+        self.halt   = false;
+        self.instructions   = 0;
+        self.cycles         = 0;
+        if let Some(undo) = &mut self.undo {
+            undo.clear();
+        }
+    }
+
+    pub fn set_boot_rom(&mut self, bin: Vec<u8>) -> Result<(), GbError> {
+        self.bus.set_boot_rom(bin)?;
+        self.reset();
+        Ok(())
+    }
+
+    pub fn model(&self) -> Model {
+        self.bus.model()
+    }
+
+    // Switches the hardware being emulated. The machine is reset.
+    pub fn set_model(&mut self, model: Model) {
+        self.bus.set_model(model);
+        self.reset();
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for reg in [self.a, self.f.bits(), self.b, self.c, self.d, self.e, self.h, self.l].iter() {
+            w.u8(*reg);
         }
+        w.u16(self.sp);
+        w.u16(self.pc);
+        w.bool(self.halt);
+        w.u64(self.instructions);
+        w.u64(self.cycles);
+        self.bus.save_state(w);
     }
 
-    pub fn tick(&mut self) {
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        self.a      = r.u8()?;
+        self.f      = Flags::from_bits_truncate(r.u8()?);
+        self.b      = r.u8()?;
+        self.c      = r.u8()?;
+        self.d      = r.u8()?;
+        self.e      = r.u8()?;
+        self.h      = r.u8()?;
+        self.l      = r.u8()?;
+        self.sp     = r.u16()?;
+        self.pc     = r.u16()?;
+        self.halt   = r.bool()?;
+        self.instructions   = r.u64()?;
+        self.cycles         = r.u64()?;
+        if let Some(undo) = &mut self.undo {
+            undo.clear();
+        }
+        self.bus.load_state(r)
+    }
+
+    // One machine cycle (4 clocks). A running OAM DMA copies a byte and stalls the CPU;
+    // otherwise the CPU takes a pending interrupt or runs the next instruction.
+    // Then the PPU, timer, serial port and APU advance, in that order.
+    // Returns true when the PPU enters VBlank.
+    pub fn tick(&mut self) -> bool {
         if !self.bus.transfer() {
-            self.step();
+            self.clock_debt += self.clock_scale;
+            while self.clock_debt >= 1.0 {
+                self.step();
+                self.clock_debt -= 1.0;
+            }
+        }
+        let vblank = self.bus.tick();
+        self.cycles = self.cycles.wrapping_add(4);
+        vblank
+    }
+
+    pub fn run_frame(&mut self) {
+        for _ in 0..NLINES*CYCLE_PER_LINE {
+            self.tick();
+        }
+    }
+
+    // Runs until the PPU enters VBlank, or until `budget` clocks have passed
+    // without that happening, e.g. with the LCD off or a game stuck in a loop.
+    // Stops at breakpoints too, except one at the current PC so a stopped game can resume.
+    pub fn step_frame(&mut self, budget: u64) -> StopReason {
+        let start = self.cycles;
+        while self.cycles.wrapping_sub(start) < budget {
+            if self.cycles != start && self.at_breakpoint() {
+                return StopReason::Breakpoint;
+            }
+            if self.tick() {
+                return StopReason::VBlank;
+            }
+        }
+        StopReason::CycleBudgetExceeded
+    }
+
+    // Runs `n` instructions. Interrupt dispatches and halted cycles don't count.
+    // Returns the clocks that passed, and why it stopped early if it did: at a breakpoint
+    // (except one at the current PC), or after `budget` clocks, e.g. halted with no interrupt.
+    pub fn step_instructions(&mut self, n: u64, budget: u64) -> (u64, Option<StopReason>) {
+        let start = self.cycles;
+        let end = self.instructions.wrapping_add(n);
+        while self.instructions != end {
+            let elapsed = self.cycles.wrapping_sub(start);
+            if elapsed >= budget {
+                return (elapsed, Some(StopReason::CycleBudgetExceeded));
+            }
+            if self.cycles != start && self.at_breakpoint() {
+                return (elapsed, Some(StopReason::Breakpoint));
+            }
+            self.tick();
+        }
+        (self.cycles.wrapping_sub(start), None)
+    }
+
+    pub fn run_until_mem(&mut self, addr: u16, value: u8, budget: u64) -> (u64, Option<StopReason>) {
+        let start = self.cycles;
+        while self.bus.peek(addr) != value {
+            let elapsed = self.cycles.wrapping_sub(start);
+            if elapsed >= budget {
+                return (elapsed, Some(StopReason::CycleBudgetExceeded));
+            }
+            if self.cycles != start && self.at_breakpoint() {
+                return (elapsed, Some(StopReason::Breakpoint));
+            }
+            self.tick();
+        }
+        (self.cycles.wrapping_sub(start), None)
+    }
+
+    // Runs one instruction, or an interrupt dispatch. Gives up after a frame
+    // when the CPU is halted and nothing wakes it.
+    pub fn step_instruction(&mut self) {
+        let pc = self.pc;
+        let instructions = self.instructions;
+        for _ in 0..NLINES*CYCLE_PER_LINE {
+            self.tick();
+            if self.instructions != instructions || self.pc != pc {
+                return;
+            }
+        }
+    }
+
+    // Keeps the last instructions so step_back() can undo them. Off by default.
+    pub fn set_step_back(&mut self, on: bool) {
+        self.undo = match on {
+            true    =>  Some(VecDeque::with_capacity(STEP_BACK_DEPTH)),
+            false   =>  None,
+        };
+    }
+
+    // Undoes the last instruction or interrupt dispatch: registers, IME and the RAM
+    // it wrote (see Bus::start_journal). The PPU, timer and APU aren't rewound.
+    // False if there's nothing to undo.
+    pub fn step_back(&mut self) -> bool {
+        let step = match self.undo.as_mut().and_then(|undo| undo.pop_back()) {
+            Some(step)  =>  step,
+            None        =>  return false,
+        };
+        self.bus.undo_writes(&step.writes);
+        self.set_registers(step.regs);
+        self.halt = false;
+        match step.ime {
+            true    =>  self.bus.enable_irq(),
+            false   =>  self.bus.disable_irq(),
+        }
+        true
+    }
+
+    fn undo_point(&mut self) -> Option<UndoStep> {
+        self.undo.as_ref()?;
+        self.bus.start_journal();
+        Some(UndoStep {
+            regs:   self.registers(),
+            ime:    self.bus.is_enabled_irq(),
+            writes: Vec::new(),
+        })
+    }
+
+    fn record_undo(&mut self, mut step: UndoStep) {
+        step.writes = self.bus.take_journal();
+        if let Some(undo) = &mut self.undo {
+            if undo.len() == STEP_BACK_DEPTH {
+                undo.pop_front();
+            }
+            undo.push_back(step);
+        }
+    }
+
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+        self.trace = trace;
+    }
+
+    pub fn trace(&self) -> Option<Trace> {
+        self.trace.clone()
+    }
+
+    // Runs `scale` instructions per machine cycle instead of one, carrying fractions over
+    // to the next cycle. Breakpoints are only checked between machine cycles.
+    pub fn set_clock_scale(&mut self, scale: f64) {
+        assert!(scale > 0.0 && scale.is_finite(), "invalid clock scale: {}", scale);
+        self.clock_scale = scale;
+        self.clock_debt = 0.0;
+    }
+
+    pub fn clock_scale(&self) -> f64 {
+        self.clock_scale
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        !self.halt && self.breakpoints.contains(&self.pc)
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.retain(|bp| *bp != addr);
+    }
+
+    pub fn breakpoints(&self) -> &[u16] {
+        &self.breakpoints
+    }
+
+    // Lets `clocks` pass (rounded down to whole machine cycles) for everything but the CPU:
+    // the PPU, timer, serial port, APU and OAM DMA. Interrupts are requested but not taken.
+    pub fn advance_cycles(&mut self, clocks: u64) {
+        for _ in 0..clocks / 4 {
+            self.bus.transfer();
+            self.bus.tick();
+            self.cycles = self.cycles.wrapping_add(4);
         }
-        self.bus.tick();
+    }
+
+    // Runs until the PPU has drawn line `ly`. Gives up after two frames if the LCD is off.
+    pub fn run_to_line(&mut self, ly: u8) {
+        for _ in 0..2*NLINES*CYCLE_PER_LINE {
+            if self.bus.line_done(ly) {
+                return;
+            }
+            self.tick();
+        }
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
     }
 
     pub fn push_key(&mut self, key: Key) {
@@ -92,10 +477,234 @@ impl Cpu {
         self.bus.release_key(key);
     }
 
+    pub fn set_buttons(&mut self, mask: u8) {
+        self.bus.set_buttons(mask);
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.bus.buttons()
+    }
+
+    pub fn set_turbo(&mut self, key: Key, on: bool) {
+        self.bus.set_turbo(key, on);
+    }
+
+    pub fn is_turbo(&self, key: Key) -> bool {
+        self.bus.is_turbo(key)
+    }
+
+    pub fn set_turbo_rate(&mut self, frames: u8) {
+        self.bus.set_turbo_rate(frames);
+    }
+
+    pub fn turbo_rate(&self) -> u8 {
+        self.bus.turbo_rate()
+    }
+
     pub fn get_pixels(&self) -> [u8; SCREEN_WIDTH*SCREEN_HEIGHT] {
         self.bus.get_pixels()
     }
 
+    pub fn get_colors(&self) -> &[u16] {
+        self.bus.get_colors()
+    }
+
+    pub fn get_layers(&self) -> &[u8] {
+        self.bus.get_layers()
+    }
+
+    pub fn ppu_registers(&self) -> PpuRegisters {
+        self.bus.ppu_registers()
+    }
+
+    pub fn ppu_timing(&self) -> PpuTiming {
+        self.bus.ppu_timing()
+    }
+
+    pub fn connect_link(&mut self, link: Box<dyn SerialLink>) {
+        self.bus.connect_link(link);
+    }
+
+    pub fn connect_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.bus.connect_device(device);
+    }
+
+    pub fn connect_infrared(&mut self, device: Box<dyn InfraredDevice>) {
+        self.bus.connect_infrared(device);
+    }
+
+    pub fn map_device(&mut self, range: RangeInclusive<u16>, device: Box<dyn MemDevice>) {
+        self.bus.map_device(range, device);
+    }
+
+    pub fn unmap_devices(&mut self) {
+        self.bus.unmap_devices();
+    }
+
+    pub fn pop_sgb_packet(&mut self) -> Option<SgbPacket> {
+        self.bus.pop_sgb_packet()
+    }
+
+    pub fn set_channel_enabled(&mut self, ch: u8, on: bool) {
+        self.bus.set_channel_enabled(ch, on);
+    }
+
+    pub fn set_high_pass(&mut self, filter: HighPass) {
+        self.bus.set_high_pass(filter);
+    }
+
+    pub fn open_audio(&mut self) {
+        self.bus.open_audio();
+    }
+
+    pub fn has_audio(&self) -> bool {
+        self.bus.has_audio()
+    }
+
+    pub fn stop_audio(&mut self) {
+        self.bus.stop_audio();
+    }
+
+    pub fn start_audio(&mut self) {
+        self.bus.start_audio();
+    }
+
+    pub fn set_sample_rate(&mut self, rate: u32) -> u32 {
+        self.bus.set_sample_rate(rate)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.bus.sample_rate()
+    }
+
+    pub fn set_audio_capture(&mut self, on: bool) {
+        self.bus.set_audio_capture(on);
+    }
+
+    pub fn take_audio_samples(&mut self) -> Vec<(f32, f32)> {
+        self.bus.take_audio_samples()
+    }
+
+    pub fn audio_samples_played(&self) -> u64 {
+        self.bus.audio_samples_played()
+    }
+
+    pub fn memory_regions(&self) -> Vec<MemRegion> {
+        self.bus.memory_regions()
+    }
+
+    pub fn set_profiling(&mut self, on: bool) {
+        self.bus.set_profiling(on);
+    }
+
+    pub fn memory_accesses(&self) -> Option<Vec<RegionAccesses>> {
+        self.bus.memory_accesses()
+    }
+
+    pub fn unhandled_io(&self) -> Vec<u16> {
+        self.bus.unhandled_io()
+    }
+
+    // Off by default so step() doesn't pay for it. Turning it on starts from zero.
+    pub fn set_opcode_profiling(&mut self, on: bool) {
+        self.opcodes = match on {
+            true    =>  Some((0..0x200).map(|i| OpcodeCount {
+                opcode: if i < 0x100 { i } else { 0xCB00 | (i & 0xFF) },
+                name:   "",
+                count:  0,
+                cycles: 0,
+            }).collect()),
+            false   =>  None,
+        };
+    }
+
+    // Opcodes that ran, the most frequent first. None unless profiling is on.
+    pub fn opcode_counts(&self) -> Option<Vec<OpcodeCount>> {
+        let mut counts: Vec<OpcodeCount> = self.opcodes.as_ref()?.iter()
+            .filter(|c| c.count > 0)
+            .cloned()
+            .collect();
+        counts.sort_by(|a, b| b.count.cmp(&a.count).then(a.opcode.cmp(&b.opcode)));
+        Some(counts)
+    }
+
+    fn count_opcode(&mut self, opcode: u8, inst: &Instruction) {
+        if let Some(counts) = &mut self.opcodes {
+            // CB-prefixed opcodes follow the others
+            let i = match opcode {
+                0xCB    =>  0x100 | inst.opcode as usize,
+                _       =>  opcode as usize,
+            };
+            let c = &mut counts[i];
+            c.name      = inst.name;
+            c.count     += 1;
+            c.cycles    += inst.cycles as u64;
+        }
+    }
+
+    pub fn dump_memory(&self) -> Vec<u8> {
+        self.bus.dump_memory()
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a:  self.a,
+            f:  self.f.bits(),
+            b:  self.b,
+            c:  self.c,
+            d:  self.d,
+            e:  self.e,
+            h:  self.h,
+            l:  self.l,
+            sp: self.sp,
+            pc: self.pc,
+        }
+    }
+
+    pub fn set_registers(&mut self, regs: Registers) {
+        self.a  = regs.a;
+        self.f  = Flags::from_bits_truncate(regs.f);
+        self.b  = regs.b;
+        self.c  = regs.c;
+        self.d  = regs.d;
+        self.e  = regs.e;
+        self.h  = regs.h;
+        self.l  = regs.l;
+        self.sp = regs.sp;
+        self.pc = regs.pc;
+    }
+
+    // Memory as the CPU sees it. Writes to ROM reach the cartridge's bank registers.
+    // Reads are peeks, so a debugger looking around isn't profiled or logged.
+    pub fn read_memory(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
+    }
+
+    pub fn write_memory(&mut self, addr: u16, data: u8) {
+        self.bus.write8(addr as usize, data);
+    }
+
+    pub fn request_interrupt(&mut self, kind: InterruptKind) {
+        self.bus.request_interrupt(kind);
+    }
+
+    pub fn interrupt_state(&self) -> InterruptState {
+        InterruptState {
+            ime:    self.bus.is_enabled_irq(),
+            ie:     self.bus.peek(IE as u16),
+            if_:    self.bus.peek(IF as u16),
+            halted: self.halt,
+        }
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        self.bus.cartridge()
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        self.bus.cartridge_mut()
+    }
+
     fn step(&mut self) {
         if self.halt {
             if self.bus.has_irq() {
@@ -103,13 +712,25 @@ impl Cpu {
             }
             return;
         }
+        // None unless step back is on
+        let undo = self.undo_point();
         if self.bus.has_irq() && self.bus.is_enabled_irq() {
             self.resolve_irq();
-            return;
+        } else {
+            let pc = self.pc;
+            let opcode = self.fetch();
+            // Before decoding, so an unsupported opcode ends the trace
+            if let Some(trace) = &self.trace {
+                trace.record(pc, opcode);
+            }
+            let inst = self.decode(opcode);
+            self.count_opcode(opcode, &inst);
+            self.execute(&inst);
+            self.instructions = self.instructions.wrapping_add(1);
+        }
+        if let Some(undo) = undo {
+            self.record_undo(undo);
         }
-        let opcode = self.fetch();
-        let inst = self.decode(opcode);
-        self.execute(&inst);
     }
 
     fn resolve_irq(&mut self) {
@@ -174,6 +795,8 @@ impl Cpu {
         self.l = (data & 0xFF) as u8;
     }
 
+    // The stack grows downward. 16-bit values push the high byte first,
+    // so pop() returns the low byte first.
     fn push(&mut self, data: u8) {
         self.sp = self.sp.wrapping_sub(1);
         self.bus.write8(self.sp as usize, data);
@@ -185,6 +808,28 @@ impl Cpu {
         self.bus.read8(addr as usize)
     }
 
+    // Flags of A - n without storing the result. H is the borrow out of bit 3,
+    // i.e. the low nibble of n is bigger than A's, and C the borrow out of bit 7.
+    fn cp(&mut self, n: u8) {
+        let a = self.a;
+        if a == n {
+            self.f.insert(Flags::Z);
+        } else {
+            self.f.remove(Flags::Z);
+        }
+        self.f.insert(Flags::N);
+        if a&0x0F < n&0x0F {
+            self.f.insert(Flags::H);
+        } else {
+            self.f.remove(Flags::H);
+        }
+        if a < n {
+            self.f.insert(Flags::C);
+        } else {
+            self.f.remove(Flags::C);
+        }
+    }
+
     fn decode(&mut self, opcode: u8) -> Instruction {
         match opcode {
             0x00    =>  Instruction {
@@ -221,6 +866,7 @@ impl Cpu {
                 cycles:     8,
                 operation:  |cpu| {
                     let bc = cpu.read_bc();
+                    cpu.bus.oam_bug(bc);
                     cpu.write_bc(bc.wrapping_add(1));
                     Ok(())
                 },
@@ -300,9 +946,9 @@ impl Cpu {
                 opcode:     0x08,
                 cycles:     20,
                 operation:  |cpu| {
-                    let addr = cpu.fetch16() as usize;
-                    cpu.bus.write8(addr, (cpu.sp&0xFF) as u8);
-                    cpu.bus.write8(addr+1, (cpu.sp >> 8) as u8);
+                    let addr = cpu.fetch16();
+                    cpu.bus.write8(addr as usize, (cpu.sp&0xFF) as u8);
+                    cpu.bus.write8(addr.wrapping_add(1) as usize, (cpu.sp >> 8) as u8);
                     Ok(())
                 },
             },
@@ -343,6 +989,7 @@ impl Cpu {
                 cycles:     8,
                 operation:  |cpu| {
                     let bc = cpu.read_bc();
+                    cpu.bus.oam_bug(bc);
                     cpu.write_bc(bc.wrapping_sub(1));
                     Ok(())
                 },
@@ -421,8 +1068,9 @@ impl Cpu {
                 name:       "STOP",
                 opcode:     0x10,
                 cycles:     4,
-                operation:  |_| {
-                    // TODO
+                operation:  |cpu| {
+                    // TODO: low power mode
+                    cpu.bus.stop();
                     Ok(())
                 },
             },
@@ -452,6 +1100,7 @@ impl Cpu {
                 cycles:     8,
                 operation:  |cpu| {
                     let de = cpu.read_de();
+                    cpu.bus.oam_bug(de);
                     cpu.write_de(de.wrapping_add(1));
                     Ok(())
                 },
@@ -532,7 +1181,7 @@ impl Cpu {
                 cycles:     8,
                 operation:  |cpu| {
                     let e = cpu.fetch() as i8 as i16;
-                    cpu.pc = ((cpu.pc as i16) + e) as u16;
+                    cpu.pc = cpu.pc.wrapping_add(e as u16);
                     Ok(())
                 },
             },
@@ -573,6 +1222,7 @@ impl Cpu {
                 cycles:     8,
                 operation:  |cpu| {
                     let de = cpu.read_de();
+                    cpu.bus.oam_bug(de);
                     cpu.write_de(de.wrapping_sub(1));
                     Ok(())
                 },
@@ -657,7 +1307,7 @@ impl Cpu {
                 operation:  |cpu| {
                     let e = cpu.fetch() as i8 as i16;
                     if !cpu.f.contains(Flags::Z) {
-                        cpu.pc = ((cpu.pc as i16) + e) as u16;
+                        cpu.pc = cpu.pc.wrapping_add(e as u16);
                     }
                     Ok(())
                 },
@@ -689,6 +1339,7 @@ impl Cpu {
                 cycles:     8,
                 operation:  |cpu| {
                     let hl = cpu.read_hl();
+                    cpu.bus.oam_bug(hl);
                     cpu.write_hl(hl.wrapping_add(1));
                     Ok(())
                 },
@@ -794,7 +1445,7 @@ impl Cpu {
                 operation:  |cpu| {
                     let e = cpu.fetch() as i8 as i16;
                     if cpu.f.contains(Flags::Z) {
-                        cpu.pc = ((cpu.pc as i16) + e) as u16;
+                        cpu.pc = cpu.pc.wrapping_add(e as u16);
                     }
                     Ok(())
                 },
@@ -838,6 +1489,7 @@ impl Cpu {
                 cycles:     8,
                 operation:  |cpu| {
                     let hl = cpu.read_hl();
+                    cpu.bus.oam_bug(hl);
                     cpu.write_hl(hl.wrapping_sub(1));
                     Ok(())
                 },
@@ -912,7 +1564,7 @@ impl Cpu {
                 operation:  |cpu| {
                     let e = cpu.fetch() as i8 as i16;
                     if !cpu.f.contains(Flags::C) {
-                        cpu.pc = ((cpu.pc as i16) + e)  as u16;
+                        cpu.pc = cpu.pc.wrapping_add(e as u16);
                     }
                     Ok(())
                 },
@@ -943,6 +1595,7 @@ impl Cpu {
                 opcode:     0x33,
                 cycles:     8,
                 operation:  |cpu| {
+                    cpu.bus.oam_bug(cpu.sp);
                     cpu.sp = cpu.sp.wrapping_add(1);
                     Ok(())
                 },
@@ -954,14 +1607,15 @@ impl Cpu {
                 operation:  |cpu| {
                     let addr = cpu.read_hl() as usize;
                     let n = cpu.bus.read8(addr);
-                    cpu.bus.write8(addr, n.wrapping_add(1));
-                    if cpu.bus.read8(addr) == 0 {
+                    let r = n.wrapping_add(1);
+                    cpu.bus.write8(addr, r);
+                    if r == 0 {
                         cpu.f.insert(Flags::Z);
                     } else {
                         cpu.f.remove(Flags::Z);
                     }
                     cpu.f.remove(Flags::N);
-                    if (cpu.bus.read8(addr)^n^1)&0x10 == 0x10 {
+                    if (r^n^1)&0x10 == 0x10 {
                         cpu.f.insert(Flags::H);
                     } else {
                         cpu.f.remove(Flags::H);
@@ -976,8 +1630,9 @@ impl Cpu {
                 operation:  |cpu| {
                     let addr = cpu.read_hl() as usize;
                     let n = cpu.bus.read8(addr);
-                    cpu.bus.write8(addr, n.wrapping_sub(1));
-                    if cpu.bus.read8(addr) == 0 {
+                    let r = n.wrapping_sub(1);
+                    cpu.bus.write8(addr, r);
+                    if r == 0 {
                         cpu.f.insert(Flags::Z);
                     } else {
                         cpu.f.remove(Flags::Z);
@@ -1019,7 +1674,7 @@ impl Cpu {
                 operation:  |cpu| {
                     let e = cpu.fetch() as i8 as i16;
                     if cpu.f.contains(Flags::C) {
-                        cpu.pc = ((cpu.pc as i16) + e) as u16;
+                        cpu.pc = cpu.pc.wrapping_add(e as u16);
                     }
                     Ok(())
                 },
@@ -1062,6 +1717,7 @@ impl Cpu {
                 opcode:     0x3B,
                 cycles:     8,
                 operation:  |cpu| {
+                    cpu.bus.oam_bug(cpu.sp);
                     cpu.sp = cpu.sp.wrapping_sub(1);
                     Ok(())
                 },
@@ -2122,6 +2778,7 @@ impl Cpu {
                     Ok(())
                 },
             },
+            // A becomes 2A + carry
             0x8F    =>  Instruction {
                 name:       "ADC A, A",
                 opcode:     0x8F,
@@ -2562,13 +3219,15 @@ impl Cpu {
                     Ok(())
                 },
             },
+            // A becomes 0x00 or 0xFF depending on the carry (the usual sign extension idiom),
+            // with H and C set by the borrow of the carry
             0x9F    =>  Instruction {
                 name:       "SBC A, A",
                 opcode:     0x9F,
                 cycles:     4,
                 operation:  |cpu| {
                     let a = cpu.a;
-                    let c = (cpu.f & Flags::C == Flags::C) as u8;
+                    let c = cpu.f.contains(Flags::C) as u8;
                     let n = cpu.a;
                     cpu.a = a.wrapping_sub(n).wrapping_sub(c);
                     if cpu.a == 0 {
@@ -3051,24 +3710,8 @@ impl Cpu {
                 opcode:     0xB8,
                 cycles:     4,
                 operation:  |cpu| {
-                    let a = cpu.a;
                     let n = cpu.b;
-                    if  a == n {
-                        cpu.f.insert(Flags::Z);
-                    } else {
-                        cpu.f.remove(Flags::Z);
-                    }
-                    cpu.f.insert(Flags::N);
-                    if a&0x0F < n&0x0F {
-                        cpu.f.insert(Flags::H);
-                    } else {
-                        cpu.f.remove(Flags::H);
-                    }
-                    if a < n {
-                        cpu.f.insert(Flags::C);
-                    } else {
-                        cpu.f.remove(Flags::C);
-                    }
+                    cpu.cp(n);
                     Ok(())
                 },
             },
@@ -3077,24 +3720,8 @@ impl Cpu {
                 opcode:     0xB9,
                 cycles:     4,
                 operation:  |cpu| {
-                    let a = cpu.a;
                     let n = cpu.c;
-                    if  a == n {
-                        cpu.f.insert(Flags::Z);
-                    } else {
-                        cpu.f.remove(Flags::Z);
-                    }
-                    cpu.f.insert(Flags::N);
-                    if a&0x0F < n&0x0F {
-                        cpu.f.insert(Flags::H);
-                    } else {
-                        cpu.f.remove(Flags::H);
-                    }
-                    if a < n {
-                        cpu.f.insert(Flags::C);
-                    } else {
-                        cpu.f.remove(Flags::C);
-                    }
+                    cpu.cp(n);
                     Ok(())
                 },
             },
@@ -3103,24 +3730,8 @@ impl Cpu {
                 opcode:     0xBA,
                 cycles:     4,
                 operation:  |cpu| {
-                    let a = cpu.a;
                     let n = cpu.d;
-                    if  a == n {
-                        cpu.f.insert(Flags::Z);
-                    } else {
-                        cpu.f.remove(Flags::Z);
-                    }
-                    cpu.f.insert(Flags::N);
-                    if a&0x0F < n&0x0F {
-                        cpu.f.insert(Flags::H);
-                    } else {
-                        cpu.f.remove(Flags::H);
-                    }
-                    if a < n {
-                        cpu.f.insert(Flags::C);
-                    } else {
-                        cpu.f.remove(Flags::C);
-                    }
+                    cpu.cp(n);
                     Ok(())
                 },
             },
@@ -3129,24 +3740,8 @@ impl Cpu {
                 opcode:     0xBB,
                 cycles:     4,
                 operation:  |cpu| {
-                    let a = cpu.a;
                     let n = cpu.e;
-                    if  a == n {
-                        cpu.f.insert(Flags::Z);
-                    } else {
-                        cpu.f.remove(Flags::Z);
-                    }
-                    cpu.f.insert(Flags::N);
-                    if a&0x0F < n&0x0F {
-                        cpu.f.insert(Flags::H);
-                    } else {
-                        cpu.f.remove(Flags::H);
-                    }
-                    if a < n {
-                        cpu.f.insert(Flags::C);
-                    } else {
-                        cpu.f.remove(Flags::C);
-                    }
+                    cpu.cp(n);
                     Ok(())
                 },
             },
@@ -3155,24 +3750,8 @@ impl Cpu {
                 opcode:     0xBC,
                 cycles:     4,
                 operation:  |cpu| {
-                    let a = cpu.a;
                     let n = cpu.h;
-                    if  a == n {
-                        cpu.f.insert(Flags::Z);
-                    } else {
-                        cpu.f.remove(Flags::Z);
-                    }
-                    cpu.f.insert(Flags::N);
-                    if a&0x0F < n&0x0F {
-                        cpu.f.insert(Flags::H);
-                    } else {
-                        cpu.f.remove(Flags::H);
-                    }
-                    if a < n {
-                        cpu.f.insert(Flags::C);
-                    } else {
-                        cpu.f.remove(Flags::C);
-                    }
+                    cpu.cp(n);
                     Ok(())
                 },
             },
@@ -3181,24 +3760,8 @@ impl Cpu {
                 opcode:     0xBD,
                 cycles:     4,
                 operation:  |cpu| {
-                    let a = cpu.a;
                     let n = cpu.l;
-                    if  a == n {
-                        cpu.f.insert(Flags::Z);
-                    } else {
-                        cpu.f.remove(Flags::Z);
-                    }
-                    cpu.f.insert(Flags::N);
-                    if a&0x0F < n&0x0F {
-                        cpu.f.insert(Flags::H);
-                    } else {
-                        cpu.f.remove(Flags::H);
-                    }
-                    if a < n {
-                        cpu.f.insert(Flags::C);
-                    } else {
-                        cpu.f.remove(Flags::C);
-                    }
+                    cpu.cp(n);
                     Ok(())
                 },
             },
@@ -3207,24 +3770,8 @@ impl Cpu {
                 opcode:     0xBE,
                 cycles:     8,
                 operation:  |cpu| {
-                    let a = cpu.a;
                     let n = cpu.bus.read8(cpu.read_hl() as usize);
-                    if  a == n {
-                        cpu.f.insert(Flags::Z);
-                    } else {
-                        cpu.f.remove(Flags::Z);
-                    }
-                    cpu.f.insert(Flags::N);
-                    if a&0x0F < n&0x0F {
-                        cpu.f.insert(Flags::H);
-                    } else {
-                        cpu.f.remove(Flags::H);
-                    }
-                    if a < n {
-                        cpu.f.insert(Flags::C);
-                    } else {
-                        cpu.f.remove(Flags::C);
-                    }
+                    cpu.cp(n);
                     Ok(())
                 },
             },            
@@ -3233,24 +3780,8 @@ impl Cpu {
                 opcode:     0xBF,
                 cycles:     4,
                 operation:  |cpu| {
-                    let a = cpu.a;
                     let n = cpu.a;
-                    if  a == n {
-                        cpu.f.insert(Flags::Z);
-                    } else {
-                        cpu.f.remove(Flags::Z);
-                    }
-                    cpu.f.insert(Flags::N);
-                    if a&0x0F < n&0x0F {
-                        cpu.f.insert(Flags::H);
-                    } else {
-                        cpu.f.remove(Flags::H);
-                    }
-                    if a < n {
-                        cpu.f.insert(Flags::C);
-                    } else {
-                        cpu.f.remove(Flags::C);
-                    }
+                    cpu.cp(n);
                     Ok(())
                 },
             },
@@ -3733,21 +4264,14 @@ impl Cpu {
                 cycles:     16,
                 operation:  |cpu| {
                     let sp = cpu.sp;
-                    let n = cpu.fetch() as i8 as i16;
-                    cpu.sp = (sp as i16).wrapping_add(n) as u16;
+                    let n = cpu.fetch();
+                    cpu.sp = sp.wrapping_add(n as i8 as u16);
                     cpu.f.remove(Flags::Z);
                     cpu.f.remove(Flags::N);
-                    let c = (sp ^ n as u16) ^ (sp.wrapping_add(n as u16));
-                    if c & 0x10 == 0x10 {
-                        cpu.f.insert(Flags::H);
-                    } else {
-                        cpu.f.remove(Flags::H);
-                    }
-                    if c & 0x100 == 0x100 {
-                        cpu.f.insert(Flags::C);
-                    } else {
-                        cpu.f.remove(Flags::C);
-                    }
+                    // H and C come from adding the raw byte to the low byte of SP,
+                    // even when the offset is negative
+                    cpu.f.set(Flags::H, (sp & 0x0F) + (n as u16 & 0x0F) > 0x0F);
+                    cpu.f.set(Flags::C, (sp & 0xFF) + n as u16 > 0xFF);
                     Ok(())
                 },
             },
@@ -3942,24 +4466,8 @@ impl Cpu {
                 opcode:     0xFE,
                 cycles:     8,
                 operation:  |cpu| {
-                    let a = cpu.a;
                     let n = cpu.fetch();
-                    if  a == n {
-                        cpu.f.insert(Flags::Z);
-                    } else {
-                        cpu.f.remove(Flags::Z);
-                    }
-                    cpu.f.insert(Flags::N);
-                    if a&0x0F < n&0x0F {
-                        cpu.f.insert(Flags::H);
-                    } else {
-                        cpu.f.remove(Flags::H);
-                    }
-                    if a < n {
-                        cpu.f.insert(Flags::C);
-                    } else {
-                        cpu.f.remove(Flags::C);
-                    }
+                    cpu.cp(n);
                     Ok(())
                 },
             },
@@ -3975,7 +4483,10 @@ impl Cpu {
                 },
             },
 
-            _       =>  unimplemented!("can't decode: 0x{:02x}\ncpu={}", opcode, self),
+            _       =>  {
+                error!("unsupported opcode 0x{:02x} at 0x{:04x}", opcode, self.pc.wrapping_sub(1));
+                unimplemented!("can't decode: 0x{:02x}\ncpu={}", opcode, self)
+            },
         }
     }
 
@@ -4119,9 +4630,11 @@ impl Cpu {
                 cycles:     16,
                 operation:  |cpu| {
                     let addr = cpu.read_hl() as usize;
-                    let carry = cpu.bus.read8(addr) & 0x80 != 0;
-                    cpu.bus.write8(addr, cpu.bus.read8(addr).rotate_left(1));
-                    if cpu.bus.read8(addr) == 0 {
+                    let n = cpu.bus.read8(addr);
+                    let carry = n & 0x80 != 0;
+                    let r = n.rotate_left(1);
+                    cpu.bus.write8(addr, r);
+                    if r == 0 {
                         cpu.f.insert(Flags::Z);
                     } else {
                         cpu.f.remove(Flags::Z);
@@ -4296,9 +4809,11 @@ impl Cpu {
                 cycles:     16,
                 operation:  |cpu| {
                     let addr = cpu.read_hl() as usize;
-                    let carry = cpu.bus.read8(addr) & 0x01 != 0;
-                    cpu.bus.write8(addr, cpu.bus.read8(addr).rotate_right(1));
-                    if cpu.bus.read8(addr) == 0 {
+                    let n = cpu.bus.read8(addr);
+                    let carry = n & 0x01 != 0;
+                    let r = n.rotate_right(1);
+                    cpu.bus.write8(addr, r);
+                    if r == 0 {
                         cpu.f.insert(Flags::Z);
                     } else {
                         cpu.f.remove(Flags::Z);
@@ -4474,10 +4989,11 @@ impl Cpu {
                 cycles:     8,
                 operation:  |cpu| {
                     let addr = cpu.read_hl() as usize;
-                    let carry = cpu.bus.read8(addr) & 0x80 == 0x80;
-                    cpu.bus.write8(addr, cpu.bus.read8(addr) << 1 |
-                                    cpu.f.contains(Flags::C) as u8);
-                    if cpu.bus.read8(addr) == 0 {
+                    let n = cpu.bus.read8(addr);
+                    let carry = n & 0x80 == 0x80;
+                    let r = n << 1 | cpu.f.contains(Flags::C) as u8;
+                    cpu.bus.write8(addr, r);
+                    if r == 0 {
                         cpu.f.insert(Flags::Z);
                     } else {
                         cpu.f.remove(Flags::Z);
@@ -4671,12 +5187,11 @@ impl Cpu {
                 cycles:     16,
                 operation:  |cpu| {
                     let addr = cpu.read_hl() as usize;
-                    let carry = cpu.bus.read8(addr) & 0x01 != 0;
-                    cpu.bus.write8(addr, cpu.bus.read8(addr) >> 1);
-                    if cpu.f.contains(Flags::C) {
-                        cpu.bus.write8(addr, cpu.bus.read8(addr) | 0x80);
-                    }
-                    if cpu.bus.read8(addr) == 0 {
+                    let n = cpu.bus.read8(addr);
+                    let carry = n & 0x01 != 0;
+                    let r = n >> 1 | (cpu.f.contains(Flags::C) as u8) << 7;
+                    cpu.bus.write8(addr, r);
+                    if r == 0 {
                         cpu.f.insert(Flags::Z);
                     } else {
                         cpu.f.remove(Flags::Z);
@@ -4855,9 +5370,11 @@ impl Cpu {
                 cycles:     16,
                 operation:  |cpu| {
                     let addr = cpu.read_hl() as usize;
-                    let carry = cpu.bus.read8(addr) & 0x80 != 0;
-                    cpu.bus.write8(addr, cpu.bus.read8(addr) << 1);
-                    if cpu.bus.read8(addr) == 0 {
+                    let n = cpu.bus.read8(addr);
+                    let carry = n & 0x80 != 0;
+                    let r = n << 1;
+                    cpu.bus.write8(addr, r);
+                    if r == 0 {
                         cpu.f.insert(Flags::Z);
                     } else {
                         cpu.f.remove(Flags::Z);
@@ -5032,10 +5549,11 @@ impl Cpu {
                 cycles:     16,
                 operation:  |cpu| {
                     let addr = cpu.read_hl() as usize;
-                    let carry = cpu.bus.read8(addr) & 0x01 != 0;
-                    cpu.bus.write8(addr, cpu.bus.read8(addr) >> 1
-                                | cpu.bus.read8(addr) & 0x80);
-                    if cpu.bus.read8(addr) == 0 {
+                    let n = cpu.bus.read8(addr);
+                    let carry = n & 0x01 != 0;
+                    let r = n >> 1 | n & 0x80;
+                    cpu.bus.write8(addr, r);
+                    if r == 0 {
                         cpu.f.insert(Flags::Z);
                     } else {
                         cpu.f.remove(Flags::Z);
@@ -5180,10 +5698,10 @@ impl Cpu {
                 cycles:     16,
                 operation:  |cpu| {
                     let addr = cpu.read_hl() as usize;
-                    let hi = cpu.bus.read8(addr) & 0xF0;
-                    let lo = cpu.bus.read8(addr) & 0x0F;
-                    cpu.bus.write8(addr, hi >> 4 | lo << 4);
-                    if cpu.bus.read8(addr) == 0 {
+                    let n = cpu.bus.read8(addr);
+                    let r = n.rotate_left(4);
+                    cpu.bus.write8(addr, r);
+                    if r == 0 {
                         cpu.f.insert(Flags::Z);
                     } else {
                         cpu.f.remove(Flags::Z);
@@ -5349,9 +5867,11 @@ impl Cpu {
                 cycles:     16,
                 operation:  |cpu| {
                     let addr = cpu.read_hl() as usize;
-                    let carry = cpu.bus.read8(addr) & 0x01 != 0;
-                    cpu.bus.write8(addr, cpu.bus.read8(addr) >> 1);
-                    if cpu.bus.read8(addr) == 0 {
+                    let n = cpu.bus.read8(addr);
+                    let carry = n & 0x01 != 0;
+                    let r = n >> 1;
+                    cpu.bus.write8(addr, r);
+                    if r == 0 {
                         cpu.f.insert(Flags::Z);
                     } else {
                         cpu.f.remove(Flags::Z);
@@ -7537,3 +8057,228 @@ impl fmt::Display for Instruction {
             self.name, self.cycles, self.opcode)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::cartridge::tests::{ rom, fix_header };
+
+    // A CPU about to run `program` at 0x150
+    fn cpu(program: &[u8]) -> Cpu {
+        let mut cpu = Cpu::from_bytes(rom(program)).unwrap();
+        cpu.pc = 0x150;
+        cpu
+    }
+
+    fn step(cpu: &mut Cpu, n: usize) {
+        for _ in 0..n {
+            cpu.step_instruction();
+        }
+    }
+
+    #[test]
+    fn pop_af_clears_low_flag_bits() {
+        // LD BC,0x12FF; PUSH BC; POP AF; PUSH AF; POP DE
+        let mut cpu = cpu(&[0x01, 0xFF, 0x12, 0xC5, 0xF1, 0xF5, 0xD1]);
+        step(&mut cpu, 3);
+        assert_eq!(cpu.registers().a, 0x12);
+        assert_eq!(cpu.registers().f, 0xF0);
+        step(&mut cpu, 2);
+        assert_eq!(cpu.registers().d, 0x12);
+        assert_eq!(cpu.registers().e, 0xF0);
+    }
+
+    #[test]
+    fn push_af_pops_as_bc() {
+        // LD A,0x34; SCF; PUSH AF; POP BC
+        let mut cpu = cpu(&[0x3E, 0x34, 0x37, 0xF5, 0xC1]);
+        step(&mut cpu, 4);
+        let regs = cpu.registers();
+        assert_eq!(regs.b, 0x34);
+        assert_eq!(regs.c, regs.f);
+        assert_eq!(regs.c & 0x10, 0x10);
+        assert_eq!(cpu.bus.read8(0xFFFD), 0x34);
+        assert_eq!(cpu.bus.read8(0xFFFC), regs.f);
+    }
+
+    // Runs `opcode` with A = `a` and the carry flag set or not, and returns A and F
+    fn alu_a_a(opcode: u8, a: u8, carry: bool) -> (u8, u8) {
+        let mut cpu = cpu(&[opcode]);
+        let mut regs = cpu.registers();
+        regs.a = a;
+        regs.f = if carry { 0x10 } else { 0x00 };
+        cpu.set_registers(regs);
+        step(&mut cpu, 1);
+        (cpu.registers().a, cpu.registers().f)
+    }
+
+    #[test]
+    fn adc_a_a() {
+        assert_eq!(alu_a_a(0x8F, 0x80, false), (0x00, 0x90));
+        assert_eq!(alu_a_a(0x8F, 0x80, true), (0x01, 0x10));
+        assert_eq!(alu_a_a(0x8F, 0x88, false), (0x10, 0x30));
+        assert_eq!(alu_a_a(0x8F, 0x08, true), (0x11, 0x20));
+    }
+
+    #[test]
+    fn sbc_a_a() {
+        assert_eq!(alu_a_a(0x9F, 0x42, false), (0x00, 0xC0));
+        assert_eq!(alu_a_a(0x9F, 0x42, true), (0xFF, 0x70));
+        assert_eq!(alu_a_a(0x9F, 0x00, true), (0xFF, 0x70));
+    }
+
+    // Runs ADD SP,n and returns SP and F
+    fn add_sp(sp: u16, n: u8) -> (u16, u8) {
+        let mut cpu = cpu(&[0xE8, n]);
+        let mut regs = cpu.registers();
+        regs.sp = sp;
+        regs.f = 0xF0;
+        cpu.set_registers(regs);
+        step(&mut cpu, 1);
+        (cpu.registers().sp, cpu.registers().f)
+    }
+
+    #[test]
+    fn add_sp_flags_come_from_low_byte() {
+        assert_eq!(add_sp(0x000F, 0x01), (0x0010, 0x20));
+        assert_eq!(add_sp(0x00FF, 0x01), (0x0100, 0x30));
+        // -1 carries out of the low byte even though SP goes down
+        assert_eq!(add_sp(0x0001, 0xFF), (0x0000, 0x30));
+        assert_eq!(add_sp(0x1000, 0xFF), (0x0FFF, 0x00));
+    }
+
+    #[test]
+    fn jr_wraps_around_address_space() {
+        // JR +2 at 0xFFFE lands past 0xFFFF
+        let mut cpu = cpu(&[]);
+        cpu.write_memory(0xFFFE, 0x18);
+        cpu.write_memory(0xFFFF, 0x02);
+        cpu.pc = 0xFFFE;
+        step(&mut cpu, 1);
+        assert_eq!(cpu.pc, 0x0002);
+
+        // JR -4 at 0x0000 lands below it
+        let mut bin = rom(&[]);
+        bin[0x0000] = 0x18;
+        bin[0x0001] = 0xFC;
+        fix_header(&mut bin);
+        let mut cpu = Cpu::from_bytes(bin).unwrap();
+        cpu.pc = 0x0000;
+        step(&mut cpu, 1);
+        assert_eq!(cpu.pc, 0xFFFE);
+    }
+
+    #[test]
+    fn call_wraps_around_address_space() {
+        // CALL 0x0012 with its opcode at 0xFFFE and its high byte at 0x0000
+        let mut cpu = cpu(&[]);
+        cpu.write_memory(0xFFFE, 0xCD);
+        cpu.write_memory(0xFFFF, 0x12);
+        cpu.pc = 0xFFFE;
+        cpu.sp = 0xD000;
+        step(&mut cpu, 1);
+        assert_eq!(cpu.pc, 0x0012);
+        assert_eq!(cpu.sp, 0xCFFE);
+        assert_eq!((cpu.read_memory(0xCFFE), cpu.read_memory(0xCFFF)), (0x01, 0x00));
+    }
+
+    #[test]
+    fn opcode_profile_counts_sequence() {
+        // NOP; INC A; NOP; SWAP A; NOP
+        let mut cpu = cpu(&[0x00, 0x3C, 0x00, 0xCB, 0x37, 0x00]);
+        assert_eq!(cpu.opcode_counts(), None);
+        cpu.set_opcode_profiling(true);
+        step(&mut cpu, 5);
+
+        let counts: Vec<(u16, u64, u64)> = cpu.opcode_counts().unwrap().iter()
+            .map(|c| (c.opcode, c.count, c.cycles))
+            .collect();
+        assert_eq!(counts, vec![(0x0000, 3, 12), (0x003C, 1, 4), (0xCB37, 1, 8)]);
+    }
+
+    #[test]
+    fn step_back_restores_registers_and_memory() {
+        // LD SP,0xD000; LD BC,0x1234; PUSH BC; LD A,0x07; LDH (SCX),A; LD (HL+),A
+        let mut cpu = cpu(&[0x31, 0x00, 0xD0, 0x01, 0x34, 0x12, 0xC5, 0x3E, 0x07, 0xE0, 0x43, 0x22]);
+        cpu.write_memory(0xCFFE, 0xAA);
+        cpu.write_memory(0xCFFF, 0xBB);
+        cpu.write_memory(0xC000, 0xCC);
+        cpu.h = 0xC0;
+        cpu.l = 0x00;
+        cpu.set_step_back(true);
+        step(&mut cpu, 2);
+
+        let before = cpu.registers();
+        step(&mut cpu, 1);
+        assert_eq!((cpu.read_memory(0xCFFE), cpu.read_memory(0xCFFF)), (0x34, 0x12));
+        assert!(cpu.step_back());
+        assert_eq!(cpu.registers(), before);
+        assert_eq!((cpu.read_memory(0xCFFE), cpu.read_memory(0xCFFF)), (0xAA, 0xBB));
+
+        // I/O registers keep their value, RAM goes back
+        step(&mut cpu, 4);
+        assert!(cpu.step_back());
+        assert_eq!((cpu.read_memory(0xC000), cpu.registers().l), (0xCC, 0x00));
+        assert!(cpu.step_back());
+        assert_eq!(cpu.read_memory(0xFF43), 0x07);
+        assert_eq!(cpu.registers().pc, 0x159);
+    }
+
+    #[test]
+    fn stop_switches_armed_speed() {
+        // LD A,0x01; LDH (KEY1),A; STOP
+        let mut bin = rom(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00]);
+        bin[0x143] = 0xC0;
+        fix_header(&mut bin);
+        let mut cpu = Cpu::from_bytes(bin).unwrap();
+        cpu.pc = 0x150;
+        assert_eq!(cpu.read_memory(0xFF4D), 0x7E);
+
+        step(&mut cpu, 2);
+        assert_eq!(cpu.read_memory(0xFF4D), 0x7F);
+        step(&mut cpu, 1);
+        assert_eq!(cpu.read_memory(0xFF4D), 0xFE);
+    }
+
+    #[test]
+    fn trace_ends_at_unsupported_opcode() {
+        // LD A,0x01; INC A; NOP; unsupported 0xD3
+        let mut cpu = cpu(&[0x3E, 0x01, 0x3C, 0x00, 0xD3]);
+        let trace = Trace::new(3);
+        cpu.set_trace(Some(trace.clone()));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| step(&mut cpu, 4)));
+        assert!(result.is_err());
+        let mut dump = Vec::new();
+        trace.write_to(&mut dump).unwrap();
+        assert_eq!(String::from_utf8(dump).unwrap(), "0152: 3c\n0153: 00\n0154: d3\n");
+    }
+
+    // Compares A = `a` with `n` through CP B, CP (HL) and CP n, and returns F for each
+    fn cp_forms(a: u8, n: u8) -> [u8; 3] {
+        let mut flags = [0; 3];
+        for (i, program) in [vec![0xB8], vec![0xBE], vec![0xFE, n]].iter().enumerate() {
+            let mut cpu = cpu(program);
+            cpu.write_memory(0xC000, n);
+            let mut regs = cpu.registers();
+            regs.a = a;
+            regs.b = n;
+            regs.h = 0xC0;
+            regs.l = 0x00;
+            cpu.set_registers(regs);
+            step(&mut cpu, 1);
+            assert_eq!(cpu.registers().a, a);
+            flags[i] = cpu.registers().f;
+        }
+        flags
+    }
+
+    #[test]
+    fn cp_borrows_alike_in_every_form() {
+        // N and H
+        assert_eq!(cp_forms(0x10, 0x01), [0x60; 3]);
+        // N, H and C
+        assert_eq!(cp_forms(0x00, 0x01), [0x70; 3]);
+        // Z and N
+        assert_eq!(cp_forms(0x42, 0x42), [0xC0; 3]);
+    }
+}
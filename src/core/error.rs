@@ -0,0 +1,64 @@
+use std::fmt;
+use std::io;
+
+use crate::core::cartridge::cartridge_type_name;
+
+#[derive(Debug)]
+pub enum GbError {
+    Io(io::Error),
+    BadHeader(String),
+    UnsupportedMapper(u8),
+    TruncatedRom(usize),
+    BadBootRom(usize),
+    // (size in the header, file size)
+    RomSizeMismatch(usize, usize),
+    // (checksum in the header, checksum of the rom)
+    HeaderChecksum(u8, u8),
+    GlobalChecksum(u16, u16),
+    BadState(String),
+    BadPatch(String),
+    // Save state slots live next to the ROM file
+    NoRomPath,
+}
+
+impl fmt::Display for GbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GbError::Io(e)                  =>  write!(f, "I/O error: {}", e),
+            GbError::BadHeader(msg)         =>  write!(f, "bad cartridge header: {}", msg),
+            GbError::UnsupportedMapper(t)   =>
+                write!(f, "unsupported cartridge type: 0x{:02x} ({})", t, cartridge_type_name(*t)),
+            GbError::TruncatedRom(len)      =>  write!(f, "rom is truncated: {} bytes", len),
+            GbError::RomSizeMismatch(header, len)   =>
+                write!(f, "rom is {} bytes but the header says {} bytes (bad dump?)", len, header),
+            GbError::HeaderChecksum(header, actual)   =>
+                write!(f, "header checksum is 0x{:02x}, should be 0x{:02x} (bad dump?)", header, actual),
+            GbError::GlobalChecksum(header, actual)   =>
+                write!(f, "global checksum is 0x{:04x}, should be 0x{:04x} (bad dump?)", header, actual),
+            GbError::BadBootRom(len)        =>  write!(f, "boot rom must be 256 or 2304 bytes: {} bytes", len),
+            GbError::BadState(msg)          =>  write!(f, "bad save state: {}", msg),
+            GbError::BadPatch(msg)          =>  write!(f, "bad patch: {}", msg),
+            GbError::NoRomPath              =>  write!(f, "the rom wasn't loaded from a file"),
+        }
+    }
+}
+
+impl std::error::Error for GbError {}
+
+impl From<io::Error> for GbError {
+    fn from(e: io::Error) -> Self {
+        GbError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_error_names_no_file_kind() {
+        // Used for .sav, save state and patch files as much as for ROMs
+        let e = GbError::from(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        assert_eq!(e.to_string(), "I/O error: no such file");
+    }
+}
@@ -0,0 +1,1434 @@
+use log::error;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::core::bus::{ MemRegion, RegionAccesses };
+use crate::core::cpu::{ Cpu, OpcodeCount, Registers, StopReason, NLINES, CYCLE_PER_LINE };
+use crate::core::error::GbError;
+use crate::core::interrupt::{ InterruptKind, InterruptState };
+use crate::core::model::Model;
+use crate::core::apu::HighPass;
+use crate::core::trace::Trace;
+use crate::core::save;
+use crate::core::patch;
+use crate::core::state::{ StateReader, StateWriter };
+use crate::core::sgb::SgbPacket;
+use crate::core::serial::{ SerialLink, SerialDevice };
+use crate::core::infrared::InfraredDevice;
+use crate::core::io::MemDevice;
+use crate::core::pad::{ Key, InputEvent, InputSource };
+use crate::core::blend::FrameBlend;
+use crate::core::palette::{ Palette, PaletteChoice, PALETTES, boot_palette };
+use crate::core::ppu::{ PpuRegisters, PpuTiming, SCREEN_WIDTH, SCREEN_HEIGHT };
+
+// How often dirty cartridge RAM is written back to disk
+const SAVE_INTERVAL: Duration = Duration::from_secs(3);
+// step_frame() gives up after four frames without a VBlank
+const DEFAULT_CYCLE_BUDGET: u64 = 4 * (NLINES * CYCLE_PER_LINE * 4) as u64;
+
+// Pixel layouts framebuffer() can produce
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    // 4 bytes per pixel: R, G, B, A
+    Rgba8888,
+    // 2 bytes per pixel, little endian: RRRRRGGG GGGBBBBB
+    Rgb565,
+}
+
+// How CGB colors are turned into RGB
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorCorrection {
+    // Each 5-bit channel scaled to 8 bits, as bright and saturated as a modern screen makes it
+    Raw,
+    // Mixes the channels and darkens them like the CGB's LCD
+    Lcd,
+}
+
+// The 15-bit CGB color as RGB888
+fn cgb_to_rgb(color: u16, correction: ColorCorrection) -> [u8; 3] {
+    let (r, g, b) = (color & 0x1F, (color >> 5) & 0x1F, (color >> 10) & 0x1F);
+    match correction {
+        ColorCorrection::Raw    =>  {
+            let scale = |c: u16| ((c << 3) | (c >> 2)) as u8;
+            [scale(r), scale(g), scale(b)]
+        },
+        // Each mix is at most 32 * 31 = 992, clamped to 960 (0-240)
+        ColorCorrection::Lcd    =>  {
+            let mix = |c: u16| (c.min(960) >> 2) as u8;
+            [mix(r*26 + g*4 + b*2), mix(g*24 + b*8), mix(r*6 + g*4 + b*22)]
+        },
+    }
+}
+
+pub struct GameBoy {
+    cpu:        Cpu,
+    rom_path:   Option<PathBuf>,
+    save_path:  Option<PathBuf>,
+    // The .sav file is only read while this is off
+    save_enabled:   bool,
+    // Set by set_model(), None while the header decides
+    forced_model:   Option<Model>,
+    last_save:  Instant,
+    rgba:       Vec<u8>,
+    rgb565:     Vec<u8>,
+    inputs:     Vec<Box<dyn InputSource>>,
+    // Inputs from queue_input() and the frame they are due on
+    queued:     Vec<(u64, InputEvent)>,
+    frames:     u64,
+    // frame_hash() of the last frame and how many frames in a row it has stayed the same
+    last_hash:  u64,
+    unchanged:  u64,
+    cycle_budget:   u64,
+    color_correction:   ColorCorrection,
+    palette:            PaletteChoice,
+    blend:              Option<FrameBlend>,
+}
+
+impl GameBoy {
+    pub fn from_path(path: &Path) -> Result<Self, GbError> {
+        GameBoy::open(path, None, false)
+    }
+
+    // Loads the ROM with an IPS or BPS patch applied if there is one, e.g. a translation.
+    // Saves and save states still go next to the original ROM.
+    pub fn open(path: &Path, patch: Option<&[u8]>, ignore_checksums: bool) -> Result<Self, GbError> {
+        let mut bin = std::fs::read(path)?;
+        if let Some(patch) = patch {
+            patch::apply(&mut bin, patch)?;
+        }
+        GameBoy::with_rom_path(Cpu::load(bin, ignore_checksums)?, path)
+    }
+
+    fn with_rom_path(mut cpu: Cpu, path: &Path) -> Result<Self, GbError> {
+        let save_path = save::save_path(path);
+        if let Some(data) = save::read_save(&save_path)? {
+            cpu.cartridge_mut().load_battery_ram(&data);
+        }
+
+        Ok(GameBoy {
+            cpu,
+            rom_path:   Some(path.to_path_buf()),
+            save_path:  Some(save_path),
+            save_enabled:   true,
+            forced_model:   None,
+            last_save:  Instant::now(),
+            rgba:       vec![0; SCREEN_WIDTH*SCREEN_HEIGHT*4],
+            rgb565:     vec![0; SCREEN_WIDTH*SCREEN_HEIGHT*2],
+            inputs:     Vec::new(),
+            queued:     Vec::new(),
+            frames:     0,
+            last_hash:  0,
+            unchanged:  0,
+            cycle_budget:   DEFAULT_CYCLE_BUDGET,
+            color_correction:   ColorCorrection::Raw,
+            palette:            PaletteChoice::Fixed(0),
+            blend:              None,
+        })
+    }
+
+    pub fn from_bytes(bin: Vec<u8>) -> Result<Self, GbError> {
+        GameBoy::load(bin, false)
+    }
+
+    pub fn load(bin: Vec<u8>, ignore_checksums: bool) -> Result<Self, GbError> {
+        Ok(GameBoy {
+            cpu:        Cpu::load(bin, ignore_checksums)?,
+            rom_path:   None,
+            save_path:  None,
+            save_enabled:   true,
+            forced_model:   None,
+            last_save:  Instant::now(),
+            rgba:       vec![0; SCREEN_WIDTH*SCREEN_HEIGHT*4],
+            rgb565:     vec![0; SCREEN_WIDTH*SCREEN_HEIGHT*2],
+            inputs:     Vec::new(),
+            queued:     Vec::new(),
+            frames:     0,
+            last_hash:  0,
+            unchanged:  0,
+            cycle_budget:   DEFAULT_CYCLE_BUDGET,
+            color_correction:   ColorCorrection::Raw,
+            palette:            PaletteChoice::Fixed(0),
+            blend:              None,
+        })
+    }
+
+    pub fn run_frame(&mut self) {
+        self.poll_inputs();
+        self.cpu.run_frame();
+        self.frame_done();
+    }
+
+    // Runs until the next VBlank, so frames line up with what the game draws.
+    // Stops with CycleBudgetExceeded if none comes within the cycle budget,
+    // e.g. when the LCD is off or the game is stuck.
+    pub fn step_frame(&mut self) -> StopReason {
+        self.poll_inputs();
+        let reason = self.cpu.step_frame(self.cycle_budget);
+        self.frame_done();
+        reason
+    }
+
+    fn frame_done(&mut self) {
+        self.frames += 1;
+        let hash = self.frame_hash();
+        match hash == self.last_hash {
+            true    =>  self.unchanged += 1,
+            false   =>  self.unchanged = 0,
+        }
+        self.last_hash = hash;
+        if self.blend.is_some() {
+            self.render_rgba();
+            if let Some(blend) = &mut self.blend {
+                blend.push(&self.rgba);
+            }
+        }
+        self.autosave();
+    }
+
+    // Frames in a row the screen has stayed the same, 0 if the last frame changed it.
+    // A test harness can tell a game waiting on a still screen from one that hung,
+    // e.g. with a blank screen, by what get_pixels() shows once this gets large.
+    pub fn frames_since_change(&self) -> u64 {
+        self.unchanged
+    }
+
+    // Clocks step_frame() may run without reaching VBlank. A frame is 70224.
+    pub fn set_cycle_budget(&mut self, cycles: u64) {
+        self.cycle_budget = cycles;
+    }
+
+    fn autosave(&mut self) {
+        if self.last_save.elapsed() >= SAVE_INTERVAL {
+            if let Err(e) = self.flush_save() {
+                error!("can't write save: {}", e);
+            }
+        }
+    }
+
+    // Writes battery backed RAM to the .sav file if it changed since the last flush.
+    pub fn flush_save(&mut self) -> Result<(), GbError> {
+        self.last_save = Instant::now();
+
+        let path = match &self.save_path {
+            Some(path) if self.save_enabled =>  path,
+            _                               =>  return Ok(()),
+        };
+        if !self.cpu.cartridge().is_dirty() {
+            return Ok(());
+        }
+        if let Some(ram) = self.cpu.cartridge().battery_ram() {
+            save::write_save(path, &ram)?;
+        }
+        self.cpu.cartridge_mut().clear_dirty();
+
+        Ok(())
+    }
+
+    // Stops the sound and writes the battery save, before the emulator exits.
+    // Dropping the GameBoy writes the save too, but can't report an error.
+    pub fn shutdown(&mut self) -> Result<(), GbError> {
+        self.cpu.stop_audio();
+        self.flush_save()
+    }
+
+    // A new GameBoy is silent, so headless runs and tests work without an audio device.
+    // This starts playing on the default one. Without a usable device the sound stays
+    // off with a warning.
+    pub fn open_audio(&mut self) {
+        self.cpu.open_audio();
+    }
+
+    pub fn has_audio(&self) -> bool {
+        self.cpu.has_audio()
+    }
+
+    // Silences the sound while the game isn't running, e.g. while the window is paused
+    pub fn set_audio_paused(&mut self, paused: bool) {
+        match paused {
+            true    =>  self.cpu.stop_audio(),
+            false   =>  self.cpu.start_audio(),
+        }
+    }
+
+    // Plays the sound at `rate` Hz if the audio device can, e.g. 44100 or 48000,
+    // otherwise at the device's highest rate. Returns the rate it plays at, which
+    // is `rate` until open_audio().
+    pub fn set_sample_rate(&mut self, rate: u32) -> u32 {
+        self.cpu.set_sample_rate(rate)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.cpu.sample_rate()
+    }
+
+    // While on, the sound is sampled at sample_rate() per emulated second and kept for
+    // take_audio_samples() instead of played, e.g. to check it in tests
+    pub fn set_audio_capture(&mut self, on: bool) {
+        self.cpu.set_audio_capture(on);
+    }
+
+    // (left, right) samples captured since the last call
+    pub fn take_audio_samples(&mut self) -> Vec<(f32, f32)> {
+        self.cpu.take_audio_samples()
+    }
+
+    // Samples the audio device has played so far. Compared to sample_rate() it shows
+    // whether the sound keeps up, e.g. when it crackles.
+    pub fn audio_samples_played(&self) -> u64 {
+        self.cpu.audio_samples_played()
+    }
+
+    // With saving off the battery save is still loaded, but never written,
+    // so a good save can't be clobbered while testing.
+    pub fn set_save_enabled(&mut self, on: bool) {
+        self.save_enabled = on;
+    }
+
+    pub fn save_enabled(&self) -> bool {
+        self.save_enabled
+    }
+
+    // Power-cycles the machine without reloading the ROM.
+    // Battery backed cartridge RAM survives the reset.
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    // Snapshot of the whole machine
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        self.cpu.save_state(&mut w);
+        w.finish()
+    }
+
+    // Restores a snapshot from save_state().
+    // The machine is left as it was if the state can't be loaded.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), GbError> {
+        let mut r = StateReader::new(data)?;
+        let backup = self.save_state();
+        if let Err(e) = self.cpu.load_state(&mut r) {
+            let mut r = StateReader::new(&backup)?;
+            self.cpu.load_state(&mut r)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Writes a save state to slot `slot` next to the ROM and returns its path
+    pub fn save_state_slot(&self, slot: u8) -> Result<PathBuf, GbError> {
+        let path = match &self.rom_path {
+            Some(rom)   =>  save::state_path(rom, slot),
+            None        =>  return Err(GbError::NoRomPath),
+        };
+        save::write_save(&path, &self.save_state())?;
+        Ok(path)
+    }
+
+    // Loads the save state in slot `slot`. Returns false if the slot is empty.
+    pub fn load_state_slot(&mut self, slot: u8) -> Result<bool, GbError> {
+        let path = match &self.rom_path {
+            Some(rom)   =>  save::state_path(rom, slot),
+            None        =>  return Err(GbError::NoRomPath),
+        };
+        match save::read_save(&path)? {
+            Some(data)  =>  self.load_state(&data).map(|_| true),
+            None        =>  Ok(false),
+        }
+    }
+
+    pub fn model(&self) -> Model {
+        self.cpu.model()
+    }
+
+    // Overrides the model picked from the cartridge header (DMG or CGB), e.g. to run
+    // CGB enhanced games in DMG mode or as on a GBA. The machine is reset.
+    pub fn set_model(&mut self, model: Model) {
+        self.forced_model = Some(model);
+        self.cpu.set_model(model);
+    }
+
+    // The model set with set_model(), e.g. to force it on the next game too
+    pub fn forced_model(&self) -> Option<Model> {
+        self.forced_model
+    }
+
+    // Plays the boot animation from `bin` instead of starting the game directly.
+    // The machine is reset.
+    pub fn set_boot_rom(&mut self, bin: Vec<u8>) -> Result<(), GbError> {
+        self.cpu.set_boot_rom(bin)
+    }
+
+    pub fn push_key(&mut self, key: Key) {
+        self.cpu.push_key(key);
+    }
+
+    pub fn release_key(&mut self, key: Key) {
+        self.cpu.release_key(key);
+    }
+
+    // Sets all eight buttons at once, e.g. to play back recorded input.
+    // A set bit is a pressed button: Start, Select, B, A, Down, Up, Left, Right
+    // from bit 7 to bit 0. New presses raise the joypad interrupt.
+    pub fn set_buttons(&mut self, mask: u8) {
+        self.cpu.set_buttons(mask);
+    }
+
+    // The held buttons in the layout of set_buttons()
+    pub fn buttons(&self) -> u8 {
+        self.cpu.buttons()
+    }
+
+    // Adds a source of button presses that is polled every frame.
+    // All sources drive the same pad.
+    pub fn add_input(&mut self, input: Box<dyn InputSource>) {
+        self.inputs.push(input);
+    }
+
+    // Presses or releases `key` before the frame `frame_offset` frames from now,
+    // e.g. to get past a title screen in a test. 0 is the next frame.
+    pub fn queue_input(&mut self, frame_offset: u64, key: Key, press: bool) {
+        let event = if press { InputEvent::Press(key) } else { InputEvent::Release(key) };
+        self.queued.push((self.frames + frame_offset, event));
+    }
+
+    // Frames run since the Game Boy was created
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    fn poll_inputs(&mut self) {
+        let frames = self.frames;
+        let (due, later) = self.queued.drain(..).partition(|(frame, _)| *frame <= frames);
+        self.queued = later;
+        for (_, event) in due {
+            self.apply_input(event);
+        }
+
+        for i in 0..self.inputs.len() {
+            while let Some(event) = self.inputs[i].poll() {
+                self.apply_input(event);
+            }
+        }
+    }
+
+    fn apply_input(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::Press(key)      =>  self.cpu.push_key(key),
+            InputEvent::Release(key)    =>  self.cpu.release_key(key),
+        }
+    }
+
+    // Auto-fire: while `key` is held it is pressed and released every turbo_rate() frames
+    pub fn set_turbo(&mut self, key: Key, on: bool) {
+        self.cpu.set_turbo(key, on);
+    }
+
+    pub fn is_turbo(&self, key: Key) -> bool {
+        self.cpu.is_turbo(key)
+    }
+
+    pub fn set_turbo_rate(&mut self, frames: u8) {
+        self.cpu.set_turbo_rate(frames);
+    }
+
+    pub fn turbo_rate(&self) -> u8 {
+        self.cpu.turbo_rate()
+    }
+
+    // The current frame, one byte per pixel, row by row. On DMG each byte is a shade
+    // from 0 (lightest) to 3 (darkest) with BGP, OBP0 and OBP1 already applied; see
+    // shade_to_intensity() and shade_to_rgba(). On CGB it is the color number (0-3)
+    // within the pixel's palette, so use framebuffer_rgba() for the actual colors.
+    pub fn get_pixels(&self) -> [u8; SCREEN_WIDTH*SCREEN_HEIGHT] {
+        self.cpu.get_pixels()
+    }
+
+    // LCDC, STAT, scroll, window and palette registers at this moment, e.g. to debug raster effects
+    pub fn ppu_registers(&self) -> PpuRegisters {
+        self.cpu.ppu_registers()
+    }
+
+    // Dots per PPU mode and sprites per line of the last complete frame, for accuracy tests
+    pub fn ppu_timing(&self) -> PpuTiming {
+        self.cpu.ppu_timing()
+    }
+
+    // Plugs a link cable into the serial port
+    pub fn connect_link(&mut self, link: Box<dyn SerialLink>) {
+        self.cpu.connect_link(link);
+    }
+
+    // Plugs a device such as a loopback plug into the serial port
+    pub fn connect_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.cpu.connect_device(device);
+    }
+
+    // Puts a device such as another Game Boy in front of the CGB infrared port.
+    // Without one the port never receives light.
+    pub fn connect_infrared(&mut self, device: Box<dyn InfraredDevice>) {
+        self.cpu.connect_infrared(device);
+    }
+
+    // Maps a device over an address range, in front of whatever is normally there.
+    // The device mapped last wins where ranges overlap. Devices aren't saved in states.
+    pub fn map_device(&mut self, range: RangeInclusive<u16>, device: Box<dyn MemDevice>) {
+        self.cpu.map_device(range, device);
+    }
+
+    pub fn unmap_devices(&mut self) {
+        self.cpu.unmap_devices();
+    }
+
+    // Super Game Boy command packets the game has sent, oldest first
+    pub fn pop_sgb_packet(&mut self) -> Option<SgbPacket> {
+        self.cpu.pop_sgb_packet()
+    }
+
+    // The current frame as RGBA, for rendering without a window.
+    // The slice borrows an internal buffer that is reused every call.
+    pub fn framebuffer_rgba(&mut self) -> &[u8] {
+        self.render_rgba();
+        if let Some(blend) = &self.blend {
+            blend.blend(&mut self.rgba);
+        }
+
+        &self.rgba
+    }
+
+    fn render_rgba(&mut self) {
+        match self.cpu.model().is_color() {
+            false   =>  {
+                let palette = self.palette();
+                let pixels = self.cpu.get_pixels();
+                let layers = self.cpu.get_layers();
+                for (rgba, (pixel, layer)) in self.rgba.chunks_mut(4).zip(pixels.iter().zip(layers)) {
+                    rgba.copy_from_slice(&palette.rgba(*layer, *pixel));
+                }
+            },
+            true    =>  {
+                for (rgba, color) in self.rgba.chunks_mut(4).zip(self.cpu.get_colors()) {
+                    rgba[..3].copy_from_slice(&cgb_to_rgb(*color, self.color_correction));
+                    rgba[3] = 0xFF;
+                }
+            },
+        }
+    }
+
+    // Averages the last `frames` frames in the framebuffer functions, so fades
+    // that flip the palette every frame look smooth. Inaccurate; 0 or 1 turns it off.
+    pub fn set_frame_blend(&mut self, frames: u8) {
+        self.blend = match frames {
+            0 | 1   =>  None,
+            n       =>  Some(FrameBlend::new(n as usize)),
+        };
+    }
+
+    pub fn frame_blend(&self) -> u8 {
+        self.blend.as_ref().map_or(0, |blend| blend.frames() as u8)
+    }
+
+    // The current frame as RGB565, e.g. for 16-bit textures.
+    // Converted from the same colors as framebuffer_rgba().
+    pub fn framebuffer_rgb565(&mut self) -> &[u8] {
+        self.framebuffer_rgba();
+        for (out, rgba) in self.rgb565.chunks_mut(2).zip(self.rgba.chunks(4)) {
+            let (r, g, b) = (rgba[0] as u16, rgba[1] as u16, rgba[2] as u16);
+            let pixel = (r >> 3) << 11 | (g >> 2) << 5 | b >> 3;
+            out.copy_from_slice(&pixel.to_le_bytes());
+        }
+
+        &self.rgb565
+    }
+
+    // How CGB colors are converted by the framebuffer functions. Raw by default.
+    // DMG shades aren't affected.
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.color_correction = correction;
+    }
+
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.color_correction
+    }
+
+    // The colors DMG games are shown in by the framebuffer functions. The classic green by default.
+    pub fn set_palette(&mut self, palette: PaletteChoice) {
+        self.palette = palette;
+    }
+
+    pub fn palette_choice(&self) -> PaletteChoice {
+        self.palette
+    }
+
+    // Switches to the next palette, see PaletteChoice::next(), and returns it
+    pub fn cycle_palette(&mut self) -> &'static Palette {
+        self.palette = self.palette.next();
+        self.palette()
+    }
+
+    // The palette in use, with Auto resolved like the CGB boot ROM does for this game
+    pub fn palette(&self) -> &'static Palette {
+        let index = match self.palette {
+            PaletteChoice::Auto     =>  {
+                let cartridge = self.cpu.cartridge();
+                boot_palette(cartridge.is_nintendo(), cartridge.title_checksum())
+            },
+            PaletteChoice::Fixed(i) =>  i,
+        };
+        PALETTES.get(index).unwrap_or(&PALETTES[0])
+    }
+
+    pub fn framebuffer(&mut self, format: PixelFormat) -> &[u8] {
+        match format {
+            PixelFormat::Rgba8888   =>  self.framebuffer_rgba(),
+            PixelFormat::Rgb565     =>  self.framebuffer_rgb565(),
+        }
+    }
+
+    // Runs until scanline `ly` (0-143) has been drawn and returns the frame so far as RGBA.
+    // Lines below `ly` are blank, so raster effects can be followed line by line.
+    pub fn framebuffer_at_line(&mut self, ly: u8) -> &[u8] {
+        let ly = ly.min(SCREEN_HEIGHT as u8 - 1);
+        self.cpu.run_to_line(ly);
+        self.render_rgba();
+        for byte in self.rgba[(ly as usize + 1) * SCREEN_WIDTH * 4..].iter_mut() {
+            *byte = 0;
+        }
+
+        &self.rgba
+    }
+
+    // Mutes or unmutes sound channel 1-4, for debugging
+    pub fn set_channel_enabled(&mut self, ch: u8, on: bool) {
+        self.cpu.set_channel_enabled(ch, on);
+    }
+
+    // The filter on the sound output. Follows the model unless set here;
+    // set_model() picks the model's filter again.
+    pub fn set_high_pass(&mut self, filter: HighPass) {
+        self.cpu.set_high_pass(filter);
+    }
+
+    // FNV-1a hash of the current frame, for comparing screens in regression tests
+    pub fn frame_hash(&self) -> u64 {
+        self.get_pixels().iter().fold(0xcbf29ce484222325, |hash, pixel| {
+            (hash ^ *pixel as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    // The memory map with the banks that are currently switched in
+    pub fn memory_regions(&self) -> Vec<MemRegion> {
+        self.cpu.memory_regions()
+    }
+
+    // Counts reads and writes per memory region, e.g. to find a game polling VRAM.
+    // Off by default.
+    pub fn set_profiling(&mut self, on: bool) {
+        self.cpu.set_profiling(on);
+    }
+
+    // Reads and writes per region since profiling was turned on
+    pub fn memory_accesses(&self) -> Option<Vec<RegionAccesses>> {
+        self.cpu.memory_accesses()
+    }
+
+    // IO registers the game used that the emulator doesn't handle, e.g. to see what
+    // a misbehaving game needs. Each is also logged at debug level the first time.
+    pub fn unhandled_io(&self) -> Vec<u16> {
+        self.cpu.unhandled_io()
+    }
+
+    // Counts how often each opcode runs and the clocks it takes, e.g. to find
+    // the hot instructions of a game. Off by default.
+    pub fn set_opcode_profiling(&mut self, on: bool) {
+        self.cpu.set_opcode_profiling(on);
+    }
+
+    // Opcodes run since profiling was turned on, the most frequent first
+    pub fn opcode_counts(&self) -> Option<Vec<OpcodeCount>> {
+        self.cpu.opcode_counts()
+    }
+
+    // The 64kB address space as the CPU sees it, for post-mortem debugging
+    pub fn dump_memory(&self) -> Vec<u8> {
+        self.cpu.dump_memory()
+    }
+
+    // Sets the interrupt's IF bit as if the hardware had raised it, e.g. to test a handler
+    // on its own. It is taken as usual once IME and its IE bit are set.
+    pub fn request_interrupt(&mut self, kind: InterruptKind) {
+        self.cpu.request_interrupt(kind);
+    }
+
+    // IME, IE, IF and whether the CPU is halted, e.g. to see why an interrupt isn't taken
+    pub fn interrupt_state(&self) -> InterruptState {
+        self.cpu.interrupt_state()
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    pub fn set_registers(&mut self, regs: Registers) {
+        self.cpu.set_registers(regs);
+    }
+
+    // Memory as the CPU sees it, for debuggers. Writes to ROM switch banks
+    // like they would from the game.
+    pub fn read_memory(&self, addr: u16) -> u8 {
+        self.cpu.read_memory(addr)
+    }
+
+    pub fn write_memory(&mut self, addr: u16, data: u8) {
+        self.cpu.write_memory(addr, data);
+    }
+
+    // step_frame() stops with StopReason::Breakpoint before running the instruction at `addr`
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.cpu.add_breakpoint(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.cpu.remove_breakpoint(addr);
+    }
+
+    pub fn breakpoints(&self) -> &[u16] {
+        self.cpu.breakpoints()
+    }
+
+    // Remembers the last 256 instructions so step_back() can undo them. Off by default,
+    // as it costs a copy of the registers per instruction.
+    pub fn set_step_back(&mut self, on: bool) {
+        self.cpu.set_step_back(on);
+    }
+
+    // Undoes the last instruction: the registers and the RAM it wrote go back.
+    // I/O registers, cartridge RAM, the PPU, timer and sound keep their state.
+    // False when there's nothing to undo.
+    pub fn step_back(&mut self) -> bool {
+        self.cpu.step_back()
+    }
+
+    // Records the address and opcode of every instruction in `trace`, which keeps
+    // the latest ones. None stops recording.
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+        self.cpu.set_trace(trace);
+    }
+
+    // A handle on the trace being recorded into
+    pub fn trace(&self) -> Option<Trace> {
+        self.cpu.trace()
+    }
+
+    // Runs the CPU `scale` times as fast as the PPU, timer and APU, e.g. 2.0 or 0.5,
+    // to see whether a bug is timing related. Inaccurate, for debugging only; 1.0 is normal.
+    pub fn set_clock_scale(&mut self, scale: f64) {
+        self.cpu.set_clock_scale(scale);
+    }
+
+    pub fn clock_scale(&self) -> f64 {
+        self.cpu.clock_scale()
+    }
+
+    // Runs a single instruction
+    pub fn step_instruction(&mut self) {
+        self.cpu.step_instruction();
+    }
+
+    // Lets `clocks` pass without running the CPU, e.g. to test timer behavior to the cycle.
+    // The timer and other registers can be set with write_memory() first.
+    pub fn advance_cycles(&mut self, clocks: u64) {
+        self.cpu.advance_cycles(clocks);
+    }
+
+    // Runs exactly `n` instructions, e.g. to compare against a reference trace by index.
+    // Interrupts are still taken but don't count as instructions. Returns the clocks
+    // that passed, and a reason if it stopped early at a breakpoint or the cycle budget.
+    // The budget grows by a machine cycle per instruction at the current clock scale,
+    // so only a long HALT or STOP runs out of it.
+    pub fn step_instructions(&mut self, n: u64) -> (u64, Option<StopReason>) {
+        let per_instruction = (4.0 / self.cpu.clock_scale()).ceil() as u64;
+        let budget = self.cycle_budget.saturating_add(n.saturating_mul(per_instruction));
+        self.cpu.step_instructions(n, budget)
+    }
+
+    // Runs headless as fast as it can until `addr` reads `value`, e.g. a flag in WRAM
+    // that says the game reached its menu. Returns the clocks that passed, and a reason
+    // if it stopped first at a breakpoint or after `max_cycles` clocks.
+    pub fn run_until_mem(&mut self, addr: u16, value: u8, max_cycles: u64) -> (u64, Option<StopReason>) {
+        self.poll_inputs();
+        self.cpu.run_until_mem(addr, value, max_cycles)
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.cpu.instructions()
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cpu.cycles()
+    }
+}
+
+// Flushes the save on exit and when the GUI swaps in another ROM
+impl Drop for GameBoy {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_save() {
+            error!("can't write save: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::cartridge::tests::{ rom, fix_header };
+    use crate::core::pad::ChannelInput;
+
+    #[test]
+    fn bytes_boot_like_a_file() {
+        // INC A; JR -3
+        let bin = rom(&[0x3C, 0x18, 0xFD]);
+        let path = std::env::temp_dir().join("gbr_bytes_boot_like_a_file.gb");
+        std::fs::write(&path, &bin).unwrap();
+        let mut from_file = GameBoy::from_path(&path).unwrap();
+        let mut from_bytes = GameBoy::from_bytes(bin).unwrap();
+        for _ in 0..3 {
+            from_file.run_frame();
+            from_bytes.run_frame();
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(from_file.registers(), from_bytes.registers());
+        assert_eq!(from_file.dump_memory(), from_bytes.dump_memory());
+        assert_eq!(from_file.frame_hash(), from_bytes.frame_hash());
+    }
+
+    // MBC1+RAM+BATTERY with 8kB of RAM. Writes 0x42 to 0xA000.
+    // LD A,0x0A; LD (0x0000),A; LD A,0x42; LD (0xA000),A; INC A; JR -3
+    fn battery_rom() -> Vec<u8> {
+        let mut bin = rom(&[0x3E, 0x0A, 0xEA, 0x00, 0x00, 0x3E, 0x42, 0xEA, 0x00, 0xA0, 0x3C, 0x18, 0xFD]);
+        bin[0x147] = 0x03;
+        bin[0x149] = 0x02;
+        fix_header(&mut bin);
+        bin
+    }
+
+    #[test]
+    fn reset_keeps_battery_ram() {
+        let mut gameboy = GameBoy::from_bytes(battery_rom()).unwrap();
+        let boot = gameboy.registers();
+
+        for _ in 0..3 {
+            gameboy.run_frame();
+        }
+        assert_ne!(gameboy.registers(), boot);
+        gameboy.reset();
+
+        assert_eq!(gameboy.registers(), boot);
+        assert_eq!(gameboy.cpu.cartridge().battery_ram().unwrap()[0], 0x42);
+    }
+
+    #[test]
+    fn swapping_rom_flushes_save() {
+        let dir = std::env::temp_dir();
+        let path = dir.join("gbr_swapping_rom_flushes_save.gb");
+        let sav = save::save_path(&path);
+        let other = dir.join("gbr_swapping_rom_flushes_save_other.gb");
+        std::fs::write(&path, battery_rom()).unwrap();
+        std::fs::write(&other, rom(&[0x18, 0xFE])).unwrap();
+        let _ = std::fs::remove_file(&sav);
+
+        // What the window does when another ROM is dropped onto it
+        let mut gameboy = GameBoy::from_path(&path).unwrap();
+        gameboy.run_frame();
+        gameboy = GameBoy::from_path(&other).unwrap();
+        gameboy.run_frame();
+
+        let data = std::fs::read(&sav).unwrap();
+        for file in [&path, &other, &sav].iter() {
+            std::fs::remove_file(file).unwrap();
+        }
+        assert_eq!(data[0], 0x42);
+    }
+
+    #[test]
+    fn no_save_leaves_sav_alone() {
+        let path = std::env::temp_dir().join("gbr_no_save_leaves_sav_alone.gb");
+        let sav = save::save_path(&path);
+        std::fs::write(&path, battery_rom()).unwrap();
+        let _ = std::fs::remove_file(&sav);
+
+        // No .sav is created
+        let mut gameboy = GameBoy::from_path(&path).unwrap();
+        gameboy.set_save_enabled(false);
+        gameboy.run_frame();
+        gameboy.flush_save().unwrap();
+        drop(gameboy);
+        let created = sav.exists();
+
+        // An existing one isn't modified
+        std::fs::write(&sav, vec![0x24; 0x2000]).unwrap();
+        let mut gameboy = GameBoy::from_path(&path).unwrap();
+        gameboy.set_save_enabled(false);
+        gameboy.run_frame();
+        drop(gameboy);
+        let data = std::fs::read(&sav).unwrap();
+
+        for file in [&path, &sav].iter() {
+            std::fs::remove_file(file).unwrap();
+        }
+        assert!(!created);
+        assert_eq!(data, vec![0x24; 0x2000]);
+    }
+
+    // Every 8th line black, the rest white, through the DMG palette
+    fn striped_rom() -> Vec<u8> {
+        rom(&[
+            0x3E, 0x00, 0xE0, 0x40,                     // LD A,0x00; LDH (LCDC),A
+            0x3E, 0xFF, 0xEA, 0x00, 0x80, 0xEA, 0x01, 0x80, // LD A,0xFF; LD (0x8000),A; LD (0x8001),A
+            0x3E, 0x91, 0xE0, 0x40,                     // LD A,0x91; LDH (LCDC),A
+            0x18, 0xFE,                                 // JR -2
+        ])
+    }
+
+    #[test]
+    fn framebuffer_rgba_applies_palette() {
+        let mut gameboy = GameBoy::from_bytes(striped_rom()).unwrap();
+        for _ in 0..3 {
+            gameboy.run_frame();
+        }
+        let rgba = gameboy.framebuffer_rgba();
+        assert_eq!(rgba.len(), SCREEN_WIDTH * SCREEN_HEIGHT * 4);
+
+        let line = |y: usize| rgba[y * SCREEN_WIDTH * 4..(y + 1) * SCREEN_WIDTH * 4].chunks(4);
+        assert!(line(0).all(|pixel| pixel == [0x0F, 0x38, 0x0F, 0xFF]));
+        assert!(line(1).all(|pixel| pixel == [0x9B, 0xBC, 0x0F, 0xFF]));
+        assert!(line(8).all(|pixel| pixel == [0x0F, 0x38, 0x0F, 0xFF]));
+    }
+
+    #[test]
+    fn frame_hash_is_stable() {
+        let run = || {
+            let mut gameboy = GameBoy::from_bytes(striped_rom()).unwrap();
+            for _ in 0..3 {
+                gameboy.run_frame();
+            }
+            gameboy
+        };
+        let mut gameboy = run();
+        let hash = gameboy.frame_hash();
+        assert_eq!(run().frame_hash(), hash);
+
+        // The first pixel of every stripe turns white
+        gameboy.write_memory(0x8000, 0x7F);
+        gameboy.write_memory(0x8001, 0x7F);
+        gameboy.run_frame();
+        assert_eq!(gameboy.get_pixels()[0], 0);
+        assert_ne!(gameboy.frame_hash(), hash);
+    }
+
+    #[test]
+    fn header_picks_model() {
+        let model = |cgb_flag: u8| {
+            let mut bin = rom(&[]);
+            bin[0x143] = cgb_flag;
+            fix_header(&mut bin);
+            GameBoy::from_bytes(bin).unwrap().model()
+        };
+        assert_eq!(model(0x00), Model::Dmg);
+        assert_eq!(model(0x80), Model::Cgb);
+        assert_eq!(model(0xC0), Model::Cgb);
+    }
+
+    #[test]
+    fn state_slot_round_trips() {
+        let path = std::env::temp_dir().join("gbr_state_slot_round_trips.gb");
+        std::fs::write(&path, striped_rom()).unwrap();
+        let mut gameboy = GameBoy::from_path(&path).unwrap();
+        gameboy.run_frame();
+        let state = gameboy.save_state_slot(1).unwrap();
+        let (registers, memory) = (gameboy.registers(), gameboy.dump_memory());
+
+        gameboy.run_frame();
+        gameboy.write_memory(0xC000, 0x42);
+        let loaded = gameboy.load_state_slot(1);
+        let empty = gameboy.load_state_slot(2);
+        std::fs::remove_file(&state).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(loaded.unwrap());
+        assert!(!empty.unwrap());
+        assert_eq!(gameboy.registers(), registers);
+        assert_eq!(gameboy.dump_memory(), memory);
+    }
+
+    #[test]
+    fn interrupt_state_reports_registers() {
+        // EI; LD A,0x04; LDH (IE),A; LD A,0x02; LDH (IF),A; HALT
+        let bin = rom(&[0xFB, 0x3E, 0x04, 0xE0, 0xFF, 0x3E, 0x02, 0xE0, 0x0F, 0x76]);
+        let mut gameboy = GameBoy::from_bytes(bin).unwrap();
+        // Up to the program at 0x150
+        for _ in 0..7 {
+            gameboy.step_instruction();
+        }
+        let state = gameboy.interrupt_state();
+        assert!(state.ime);
+        assert_eq!(state.ie, 0x04);
+        assert_eq!(state.if_ & 0x02, 0x02);
+        assert!(!state.halted);
+
+        gameboy.step_instruction();
+        assert!(gameboy.interrupt_state().halted);
+
+        // A snapshot isn't a CPU read of IE or IF
+        gameboy.set_profiling(true);
+        gameboy.interrupt_state();
+        let accesses = gameboy.memory_accesses().unwrap();
+        assert!(accesses.iter().all(|r| r.reads == 0));
+    }
+
+    #[test]
+    fn framebuffer_at_line_stops_at_line() {
+        let mut gameboy = GameBoy::from_bytes(striped_rom()).unwrap();
+        gameboy.run_frame();
+        let frame = gameboy.framebuffer_rgba().to_vec();
+        let partial = gameboy.framebuffer_at_line(72);
+
+        // The picture does not change, so the lines drawn so far match the last frame
+        let split = 73 * SCREEN_WIDTH * 4;
+        assert_eq!(&partial[..split], &frame[..split]);
+        assert!(partial[..split].chunks(4).all(|pixel| pixel[3] == 0xFF));
+        assert!(partial[split..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn input_source_presses_reach_pad() {
+        let mut gameboy = GameBoy::from_bytes(rom(&[0x18, 0xFE])).unwrap();
+        let (gamepad, input) = ChannelInput::new();
+        gameboy.add_input(Box::new(input));
+
+        gamepad.send(InputEvent::Press(Key::A)).unwrap();
+        gamepad.send(InputEvent::Press(Key::Down)).unwrap();
+        gameboy.run_frame();
+        assert_eq!(gameboy.buttons(), 0x18);
+
+        gamepad.send(InputEvent::Release(Key::A)).unwrap();
+        gameboy.run_frame();
+        assert_eq!(gameboy.buttons(), 0x08);
+    }
+
+    #[test]
+    fn memory_dump_starts_with_rom() {
+        let bin = rom(&[0x18, 0xFE]);
+        let gameboy = GameBoy::from_bytes(bin.clone()).unwrap();
+        let dump = gameboy.dump_memory();
+        assert_eq!(dump.len(), 0x10000);
+        assert_eq!(&dump[..0x8000], &bin[..0x8000]);
+    }
+
+    #[test]
+    fn profiler_counts_region_accesses() {
+        let mut gameboy = GameBoy::from_bytes(rom(&[
+            0xFA, 0x00, 0xC0,                           // LD A,(0xC000)
+            0xEA, 0x00, 0x80,                           // LD (0x8000),A
+            0xEA, 0x01, 0xC0,                           // LD (0xC001),A
+            0x18, 0xFE,                                 // JR -2
+        ])).unwrap();
+        // NOP; JP 0x150
+        gameboy.step_instruction();
+        gameboy.step_instruction();
+        assert_eq!(gameboy.memory_accesses(), None);
+
+        gameboy.set_profiling(true);
+        for _ in 0..3 {
+            gameboy.step_instruction();
+        }
+        let accesses = gameboy.memory_accesses().unwrap();
+        let region = |name: &str| accesses.iter().find(|r| r.region.name == name).unwrap();
+        assert_eq!((region("ROM bank 0").reads, region("ROM bank 0").writes), (9, 0));
+        assert_eq!((region("WRAM").reads, region("WRAM").writes), (1, 1));
+        assert_eq!((region("VRAM").reads, region("VRAM").writes), (0, 1));
+    }
+
+    #[test]
+    fn lcd_off_loop_exceeds_cycle_budget() {
+        let mut gameboy = GameBoy::from_bytes(rom(&[
+            0x3E, 0x00, 0xE0, 0x40,                     // LD A,0x00; LDH (LCDC),A
+            0x18, 0xFE,                                 // JR -2
+        ])).unwrap();
+        assert_eq!(gameboy.step_frame(), StopReason::CycleBudgetExceeded);
+
+        gameboy.set_cycle_budget(1000);
+        let start = gameboy.cycles();
+        assert_eq!(gameboy.step_frame(), StopReason::CycleBudgetExceeded);
+        assert!(gameboy.cycles() - start < 1100);
+    }
+
+    #[test]
+    fn framebuffer_formats_share_colors() {
+        let mut gameboy = GameBoy::from_bytes(striped_rom()).unwrap();
+        for _ in 0..3 {
+            gameboy.run_frame();
+        }
+        let rgba = gameboy.framebuffer(PixelFormat::Rgba8888).to_vec();
+        assert_eq!(&rgba[..4], &[0x0F, 0x38, 0x0F, 0xFF]);
+        assert_eq!(&rgba[SCREEN_WIDTH * 4..SCREEN_WIDTH * 4 + 4], &[0x9B, 0xBC, 0x0F, 0xFF]);
+
+        let rgb565 = gameboy.framebuffer(PixelFormat::Rgb565);
+        assert_eq!(rgb565.len(), SCREEN_WIDTH * SCREEN_HEIGHT * 2);
+        assert_eq!(&rgb565[..2], &0x09C1u16.to_le_bytes());
+        assert_eq!(&rgb565[SCREEN_WIDTH * 2..SCREEN_WIDTH * 2 + 2], &0x9DE1u16.to_le_bytes());
+    }
+
+    #[test]
+    fn set_buttons_shows_under_each_select_line() {
+        let mut gameboy = GameBoy::from_bytes(rom(&[0x18, 0xFE])).unwrap();
+        let read_p1 = |gameboy: &mut GameBoy, select: u8| {
+            gameboy.write_memory(0xFF00, select);
+            gameboy.read_memory(0xFF00)
+        };
+
+        gameboy.write_memory(0xFF0F, 0x00);
+        gameboy.set_buttons(0x18);
+        assert_eq!(read_p1(&mut gameboy, 0x10), 0xDE);
+        assert_eq!(read_p1(&mut gameboy, 0x20), 0xE7);
+        assert_eq!(gameboy.read_memory(0xFF0F) & 0x10, 0x10);
+
+        gameboy.write_memory(0xFF0F, 0x00);
+        gameboy.set_buttons(0x08);
+        assert_eq!(read_p1(&mut gameboy, 0x10), 0xDF);
+        assert_eq!(read_p1(&mut gameboy, 0x20), 0xE7);
+        assert_eq!(gameboy.read_memory(0xFF0F) & 0x10, 0x00);
+        assert_eq!(gameboy.buttons(), 0x08);
+    }
+
+    #[test]
+    fn queued_input_lands_on_its_frame() {
+        let mut gameboy = GameBoy::from_bytes(rom(&[0x18, 0xFE])).unwrap();
+        gameboy.queue_input(10, Key::Start, true);
+        gameboy.queue_input(12, Key::Start, false);
+
+        let mut held = vec![];
+        for _ in 0..14 {
+            gameboy.run_frame();
+            held.push(gameboy.buttons() == 0x80);
+        }
+        let pressed: Vec<usize> = (0..14).filter(|&frame| held[frame]).collect();
+        assert_eq!(pressed, vec![10, 11]);
+    }
+
+    #[test]
+    fn ppu_registers_snapshot() {
+        let mut bin = rom(&[0x18, 0xFE]);
+        bin[0x143] = 0xC0;
+        fix_header(&mut bin);
+        let mut gameboy = GameBoy::from_bytes(bin).unwrap();
+        let writes = [
+            (0xFF40, 0x93), (0xFF41, 0x40), (0xFF42, 0x12), (0xFF43, 0x34), (0xFF45, 0x56),
+            (0xFF47, 0xE4), (0xFF48, 0xD2), (0xFF49, 0x1B), (0xFF4A, 0x78), (0xFF4B, 0x9A),
+            (0xFF68, 0x85), (0xFF6A, 0x03),
+        ];
+        for &(addr, data) in writes.iter() {
+            gameboy.write_memory(addr, data);
+        }
+
+        let regs = gameboy.ppu_registers();
+        assert_eq!((regs.lcdc, regs.stat & 0x78, regs.scy, regs.scx), (0x93, 0x40, 0x12, 0x34));
+        assert_eq!((regs.ly, regs.lyc), (gameboy.read_memory(0xFF44), 0x56));
+        assert_eq!((regs.bgp, regs.obp0, regs.obp1, regs.wy, regs.wx), (0xE4, 0xD2, 0x1B, 0x78, 0x9A));
+        assert_eq!((regs.bcps, regs.ocps), (0xC5, 0x43));
+    }
+
+    #[test]
+    fn color_correction_mixes_channels() {
+        assert_eq!(cgb_to_rgb(0x001F, ColorCorrection::Raw), [0xFF, 0x00, 0x00]);
+        assert_eq!(cgb_to_rgb(0x001F, ColorCorrection::Lcd), [201, 0, 46]);
+        assert_eq!(cgb_to_rgb(0x7FFF, ColorCorrection::Raw), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(cgb_to_rgb(0x7FFF, ColorCorrection::Lcd), [240, 240, 240]);
+    }
+
+    #[test]
+    fn forced_dmg_renders_dmg_shades() {
+        let mut bin = striped_rom();
+        bin[0x143] = 0x80;
+        fix_header(&mut bin);
+        let mut gameboy = GameBoy::from_bytes(bin).unwrap();
+        gameboy.set_model(Model::Dmg);
+        assert_eq!((gameboy.model(), gameboy.forced_model()), (Model::Dmg, Some(Model::Dmg)));
+        for _ in 0..3 {
+            gameboy.run_frame();
+        }
+
+        let rgba = gameboy.framebuffer_rgba();
+        let line = |y: usize| rgba[y * SCREEN_WIDTH * 4..(y + 1) * SCREEN_WIDTH * 4].chunks(4);
+        assert!(line(0).all(|pixel| pixel == [0x0F, 0x38, 0x0F, 0xFF]));
+        assert!(line(1).all(|pixel| pixel == [0x9B, 0xBC, 0x0F, 0xFF]));
+    }
+
+    #[test]
+    fn step_instructions_lands_on_pc() {
+        // LD A,0x01; INC A; JP 0x0160
+        let mut gameboy = GameBoy::from_bytes(rom(&[0x3E, 0x01, 0x3C, 0xC3, 0x60, 0x01])).unwrap();
+        // NOP; JP 0x150
+        assert_eq!(gameboy.step_instructions(2).1, None);
+        assert_eq!(gameboy.registers().pc, 0x150);
+        let (clocks, reason) = gameboy.step_instructions(3);
+        // One instruction per machine cycle
+        assert_eq!((clocks, reason), (3 * 4, None));
+        assert_eq!((gameboy.registers().pc, gameboy.registers().a), (0x160, 0x02));
+    }
+
+    #[test]
+    fn step_instructions_outlasts_frame_budget() {
+        // JR -2, one machine cycle each: 100000 of them take over four frames
+        let mut gameboy = GameBoy::from_bytes(rom(&[0x18, 0xFE])).unwrap();
+        gameboy.step_instructions(2);
+        let start = gameboy.instructions();
+        assert_eq!(gameboy.step_instructions(100_000), (400_000, None));
+        assert_eq!(gameboy.instructions() - start, 100_000);
+    }
+
+    #[test]
+    fn clock_scale_multiplies_instructions_per_frame() {
+        let frame = |scale| {
+            // JR -2
+            let mut gameboy = GameBoy::from_bytes(rom(&[0x18, 0xFE])).unwrap();
+            gameboy.step_frame();
+            gameboy.set_clock_scale(scale);
+            let start = gameboy.instructions();
+            assert_eq!(gameboy.step_frame(), StopReason::VBlank);
+            gameboy.instructions() - start
+        };
+        let normal = frame(1.0);
+        assert_eq!(frame(2.0), 2 * normal);
+        let slow = frame(0.5);
+        assert!(slow >= normal / 2 && slow <= normal / 2 + 1);
+    }
+
+    #[test]
+    fn auto_palette_follows_title() {
+        let mut bin = rom(&[]);
+        bin[0x134..0x134 + 11].copy_from_slice(b"POKEMON RED");
+        // Old licensee code: Nintendo
+        bin[0x14B] = 0x01;
+        fix_header(&mut bin);
+        let mut gameboy = GameBoy::from_bytes(bin).unwrap();
+        gameboy.set_palette(PaletteChoice::Auto);
+        assert_eq!(gameboy.palette().name, "Red");
+        assert_eq!(gameboy.cycle_palette().name, "Green");
+    }
+
+    #[test]
+    fn advance_cycles_ticks_timer_not_cpu() {
+        let mut gameboy = GameBoy::from_bytes(rom(&[0x18, 0xFE])).unwrap();
+        let pc = gameboy.registers().pc;
+        gameboy.write_memory(0xFF04, 0x00);
+        gameboy.write_memory(0xFF06, 0x00);
+        gameboy.write_memory(0xFF05, 0x00);
+        // 262144Hz: every 16 clocks
+        gameboy.write_memory(0xFF07, 0x05);
+        gameboy.advance_cycles(12);
+        assert_eq!(gameboy.read_memory(0xFF05), 0);
+        gameboy.advance_cycles(4);
+        assert_eq!(gameboy.read_memory(0xFF05), 1);
+        gameboy.advance_cycles(12);
+        assert_eq!(gameboy.read_memory(0xFF05), 1);
+        assert_eq!(gameboy.registers().pc, pc);
+    }
+
+    #[test]
+    fn shutdown_writes_save() {
+        let path = std::env::temp_dir().join("gbr_shutdown_writes_save.gb");
+        let sav = save::save_path(&path);
+        std::fs::write(&path, battery_rom()).unwrap();
+        let _ = std::fs::remove_file(&sav);
+
+        let mut gameboy = GameBoy::from_path(&path).unwrap();
+        gameboy.run_frame();
+        let before = sav.exists();
+        gameboy.shutdown().unwrap();
+        // Still alive, so the save can't come from drop()
+        let data = std::fs::read(&sav).unwrap();
+        drop(gameboy);
+
+        for file in [&path, &sav].iter() {
+            std::fs::remove_file(file).unwrap();
+        }
+        assert!(!before);
+        assert_eq!(data[0], 0x42);
+    }
+
+    #[test]
+    fn still_screen_counts_unchanged_frames() {
+        // JR -2
+        let mut gameboy = GameBoy::from_bytes(rom(&[0x18, 0xFE])).unwrap();
+        for _ in 0..3 {
+            gameboy.run_frame();
+        }
+        let start = gameboy.frames_since_change();
+        for i in 1..=5 {
+            gameboy.run_frame();
+            assert_eq!(gameboy.frames_since_change(), start + i);
+        }
+        gameboy.write_memory(0xFF47, 0xFF);
+        gameboy.run_frame();
+        assert_eq!(gameboy.frames_since_change(), 0);
+        gameboy.run_frame();
+        assert_eq!(gameboy.frames_since_change(), 1);
+    }
+
+    #[test]
+    fn requested_vblank_jumps_to_0x40() {
+        // EI; JR -2
+        let mut gameboy = GameBoy::from_bytes(rom(&[0xFB, 0x18, 0xFE])).unwrap();
+        gameboy.step_instructions(4);
+        gameboy.write_memory(0xFF0F, 0x00);
+        gameboy.write_memory(0xFFFF, 0x01);
+        // Masked: only IF is set
+        gameboy.request_interrupt(InterruptKind::Timer);
+        gameboy.step_instructions(1);
+        assert_eq!(gameboy.registers().pc, 0x151);
+        assert_eq!(gameboy.read_memory(0xFF0F) & 0x1F, 0x04);
+
+        gameboy.add_breakpoint(0x40);
+        gameboy.request_interrupt(InterruptKind::Vblank);
+        assert_eq!(gameboy.step_frame(), StopReason::Breakpoint);
+        assert_eq!(gameboy.registers().pc, 0x40);
+        assert_eq!(gameboy.read_memory(0xFF0F) & 0x1F, 0x04);
+    }
+
+    #[test]
+    fn state_of_another_revision_is_rejected() {
+        let mut gameboy = GameBoy::from_bytes(striped_rom()).unwrap();
+        gameboy.run_frame();
+        let state = gameboy.save_state();
+        assert!(gameboy.load_state(&state).is_ok());
+
+        // Same header, one byte changed past the program
+        let mut hack = striped_rom();
+        hack[0x7000] ^= 0xFF;
+        fix_header(&mut hack);
+        let mut other = GameBoy::from_bytes(hack).unwrap();
+        let registers = other.registers();
+        match other.load_state(&state) {
+            Err(GbError::BadState(msg))     =>  assert!(msg.starts_with("saved with another rom")),
+            _                               =>  panic!("state of another rom was loaded"),
+        }
+        assert_eq!(other.registers(), registers);
+    }
+
+    #[test]
+    fn inc_dec_hl_access_memory_once() {
+        // LD HL,0xC000; INC (HL); DEC (HL); DEC (HL); JR -2
+        let mut gameboy = GameBoy::from_bytes(rom(&[0x21, 0x00, 0xC0, 0x34, 0x35, 0x35, 0x18, 0xFE])).unwrap();
+        // NOP; JP 0x150; LD HL,0xC000
+        for _ in 0..3 {
+            gameboy.step_instruction();
+        }
+        gameboy.write_memory(0xC000, 0x0F);
+        gameboy.set_profiling(true);
+        // WRAM reads and writes of the next instruction, then (HL) and the Z, N and H flags
+        let mut step = || {
+            let wram = |gameboy: &GameBoy| {
+                let accesses = gameboy.memory_accesses().unwrap();
+                let wram = accesses.iter().find(|r| r.region.name == "WRAM").unwrap();
+                (wram.reads, wram.writes)
+            };
+            let (reads, writes) = wram(&gameboy);
+            gameboy.step_instruction();
+            let (after_reads, after_writes) = wram(&gameboy);
+            assert_eq!((after_reads - reads, after_writes - writes), (1, 1));
+            (gameboy.read_memory(0xC000), gameboy.registers().f & 0xE0)
+        };
+
+        // Half carry out of bit 3
+        assert_eq!(step(), (0x10, 0x20));
+        // Borrow into bit 4
+        assert_eq!(step(), (0x0F, 0x60));
+        assert_eq!(step(), (0x0E, 0x40));
+    }
+
+    #[test]
+    fn cb_hl_ops_access_memory_minimally() {
+        for op in (0x06..=0xFE).step_by(8) {
+            // LD HL,0xC000; op (HL); JR -2
+            let mut gameboy = GameBoy::from_bytes(rom(&[0x21, 0x00, 0xC0, 0xCB, op, 0x18, 0xFE])).unwrap();
+            for _ in 0..3 {
+                gameboy.step_instruction();
+            }
+            gameboy.set_profiling(true);
+            gameboy.step_instruction();
+            let accesses = gameboy.memory_accesses().unwrap();
+            let wram = accesses.iter().find(|r| r.region.name == "WRAM").unwrap();
+            // BIT n,(HL) only reads
+            let expected = match op {
+                0x40 ..= 0x7F   =>  (1, 0),
+                _               =>  (1, 1),
+            };
+            assert_eq!((wram.reads, wram.writes), expected, "CB {:02x}", op);
+        }
+    }
+
+    #[test]
+    fn run_until_mem_stops_at_sentinel() {
+        let mut gameboy = GameBoy::from_bytes(rom(&[
+            0x3E, 0x00, 0xEA, 0x00, 0xC0,               // LD A,0x00; LD (0xC000),A
+            0x06, 0x40,                                 // LD B,0x40
+            0x05, 0x20, 0xFD,                           // DEC B; JR NZ,-3
+            0x3E, 0x5A, 0xEA, 0x00, 0xC0,               // LD A,0x5A; LD (0xC000),A
+            0x18, 0xFE,                                 // JR -2
+        ])).unwrap();
+        let (_, reason) = gameboy.run_until_mem(0xC000, 0x5A, 100_000);
+        assert_eq!(reason, None);
+        assert_eq!((gameboy.registers().pc, gameboy.registers().b), (0x15F, 0x00));
+
+        let (clocks, reason) = gameboy.run_until_mem(0xC000, 0x99, 1000);
+        assert_eq!(reason, Some(StopReason::CycleBudgetExceeded));
+        assert!((1000..1004).contains(&clocks));
+    }
+
+    #[test]
+    fn frame_blend_averages_palette_flip() {
+        let mut gameboy = GameBoy::from_bytes(striped_rom()).unwrap();
+        gameboy.set_palette(PaletteChoice::Fixed(1));
+        gameboy.set_frame_blend(2);
+        for _ in 0..3 {
+            gameboy.run_frame();
+        }
+        // Line 1 is shade 0, white in the grayscale palette
+        let pixel = |gameboy: &mut GameBoy| {
+            gameboy.framebuffer_rgba()[SCREEN_WIDTH * 4..SCREEN_WIDTH * 4 + 4].to_vec()
+        };
+        assert_eq!(pixel(&mut gameboy), vec![0xFF, 0xFF, 0xFF, 0xFF]);
+
+        // Black for a single frame
+        let bgp = gameboy.read_memory(0xFF47);
+        gameboy.write_memory(0xFF47, !bgp);
+        gameboy.run_frame();
+        assert_eq!(pixel(&mut gameboy), vec![0x80, 0x80, 0x80, 0xFF]);
+        gameboy.write_memory(0xFF47, bgp);
+        gameboy.run_frame();
+        assert_eq!(pixel(&mut gameboy), vec![0x80, 0x80, 0x80, 0xFF]);
+        gameboy.run_frame();
+        assert_eq!(pixel(&mut gameboy), vec![0xFF, 0xFF, 0xFF, 0xFF]);
+
+        gameboy.set_frame_blend(0);
+        gameboy.write_memory(0xFF47, !bgp);
+        gameboy.run_frame();
+        assert_eq!(pixel(&mut gameboy), vec![0x00, 0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn debugger_reads_are_not_profiled() {
+        let mut gameboy = GameBoy::from_bytes(rom(&[0x18, 0xFE])).unwrap();
+        gameboy.set_profiling(true);
+        gameboy.write_memory(0xC000, 0x42);
+        assert_eq!(gameboy.read_memory(0xC000), 0x42);
+        // As the GDB stub reads a block of memory
+        for addr in 0xFF00..=0xFFFF {
+            gameboy.read_memory(addr);
+        }
+        let accesses = gameboy.memory_accesses().unwrap();
+        assert!(accesses.iter().all(|r| r.reads == 0));
+    }
+}
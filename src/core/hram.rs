@@ -1,25 +1,49 @@
 use crate::core::io::Io;
+use crate::core::addr::{ HRAM_START, HRAM_END };
+use crate::core::error::GbError;
+use crate::core::state::{ StateReader, StateWriter };
 
-const HRAM_SIZE: usize   = 128;
+// 0xFF80-0xFFFE. 0xFFFF right after it is IE, not HRAM
+const HRAM_SIZE: usize   = HRAM_END - HRAM_START + 1;
 
 pub struct HRam {
     ram:    [u8; HRAM_SIZE],
 }
 
+impl Default for HRam {
+    fn default() -> Self {
+        HRam::new()
+    }
+}
+
 impl HRam {
     pub fn new() -> Self {
         HRam {
             ram:    [0; HRAM_SIZE]
         }
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        r.bytes_into(&mut self.ram)
+    }
 }
 
+// Takes the bus address. Anything outside HRAM reads 0xFF and ignores writes.
 impl Io for HRam {
     fn read8(&self, addr: usize) -> u8 {
-        self.ram[addr]
+        match self.ram.get(addr.wrapping_sub(HRAM_START)) {
+            Some(data)  =>  *data,
+            None        =>  0xFF,
+        }
     }
 
     fn write8(&mut self, addr: usize, data: u8) {
-        self.ram[addr] = data;
+        if let Some(byte) = self.ram.get_mut(addr.wrapping_sub(HRAM_START)) {
+            *byte = data;
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,121 @@
+use crate::core::io::Io;
+use crate::core::error::GbError;
+use crate::core::state::{ StateReader, StateWriter };
+
+// RP: bit 0 drives the LED, bit 1 is 0 while light is received,
+// bits 6-7 must both be set to read bit 1
+const LED_ON:       u8  = 0b00000001;
+const NO_LIGHT:     u8  = 0b00000010;
+const READ_ENABLE:  u8  = 0b11000000;
+
+// Whatever faces the CGB's infrared port, e.g. another Game Boy
+pub trait InfraredDevice {
+    // Called when the game turns its LED on or off
+    fn set_led(&mut self, _on: bool) {}
+    // True while light reaches our sensor
+    fn light(&self) -> bool;
+}
+
+pub struct Infrared {
+    rp:         u8,
+    device:     Option<Box<dyn InfraredDevice>>,
+}
+
+impl Default for Infrared {
+    fn default() -> Self {
+        Infrared::new()
+    }
+}
+
+impl Infrared {
+    pub fn new() -> Self {
+        Infrared {
+            rp:         0,
+            device:     None,
+        }
+    }
+
+    // Clears the register but keeps the device
+    pub fn reset(&mut self) {
+        self.rp = 0;
+    }
+
+    pub fn connect(&mut self, device: Box<dyn InfraredDevice>) {
+        self.device = Some(device);
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.rp);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        self.rp = r.u8()? & (READ_ENABLE | LED_ON);
+        Ok(())
+    }
+}
+
+impl Io for Infrared {
+    // Without a device no light is ever received
+    fn read8(&self, _addr: usize) -> u8 {
+        let light = match &self.device {
+            Some(device)    =>  self.rp & READ_ENABLE == READ_ENABLE && device.light(),
+            None            =>  false,
+        };
+        let signal = if light { 0 } else { NO_LIGHT };
+
+        self.rp | 0x3C | signal
+    }
+
+    fn write8(&mut self, _addr: usize, data: u8) {
+        let led = self.rp & LED_ON;
+        self.rp = data & (READ_ENABLE | LED_ON);
+        if let Some(device) = &mut self.device {
+            if self.rp & LED_ON != led {
+                device.set_led(self.rp & LED_ON != 0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Shines light at us and remembers our LED
+    struct Beam {
+        led:    Rc<Cell<bool>>,
+    }
+
+    impl InfraredDevice for Beam {
+        fn set_led(&mut self, on: bool) {
+            self.led.set(on);
+        }
+
+        fn light(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn no_light_without_device() {
+        let mut infrared = Infrared::new();
+        infrared.write8(0xFF56, READ_ENABLE);
+        assert_eq!(infrared.read8(0xFF56), 0xFE);
+    }
+
+    #[test]
+    fn device_light_reaches_sensor() {
+        let led = Rc::new(Cell::new(false));
+        let mut infrared = Infrared::new();
+        infrared.connect(Box::new(Beam { led: led.clone() }));
+
+        // Bit 1 only reports light with reading enabled
+        infrared.write8(0xFF56, 0x00);
+        assert_eq!(infrared.read8(0xFF56), 0x3E);
+        infrared.write8(0xFF56, READ_ENABLE | LED_ON);
+        assert_eq!(infrared.read8(0xFF56), 0xFD);
+        assert!(led.get());
+    }
+}
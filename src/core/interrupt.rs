@@ -1,6 +1,10 @@
 use bitflags::*;
+use log::{trace, log_enabled, Level};
 
+use crate::core::addr::{ IF, IE };
 use crate::core::io::Io;
+use crate::core::error::GbError;
+use crate::core::state::{ StateReader, StateWriter };
 
 bitflags! {
     struct If: u8 {
@@ -30,7 +34,7 @@ const TIMER_ISR_ADDR:       usize = 0x0050;
 const SERIAL_ISR_ADDR:      usize = 0x0058;
 const JOYPAD_ISR_ADDR:      usize = 0x0060;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InterruptKind {
     Vblank,
     LcdcStatus,
@@ -39,10 +43,33 @@ pub enum InterruptKind {
     Joypad,
 }
 
+// Every interrupt, highest priority first
+pub const INTERRUPT_KINDS: [InterruptKind; 5] = [
+    InterruptKind::Vblank, InterruptKind::LcdcStatus, InterruptKind::Timer,
+    InterruptKind::Serial, InterruptKind::Joypad,
+];
+
+// The interrupt registers and halt state, for debugging interrupts that never fire
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InterruptState {
+    pub ime:    bool,
+    pub ie:     u8,
+    pub if_:    u8,
+    pub halted: bool,
+}
+
 pub struct Interrupt {
     ime:    bool,
     irqf:   If,
     irqe:   Ie,
+    // IE bits 5-7 have no interrupt but still hold what was written
+    ie_unused:  u8,
+}
+
+impl Default for Interrupt {
+    fn default() -> Self {
+        Interrupt::new()
+    }
 }
 
 impl Interrupt {
@@ -51,6 +78,7 @@ impl Interrupt {
             ime:    false,
             irqf:   If::empty(),
             irqe:   Ie::empty(),
+            ie_unused:  0,
         }
     }
 
@@ -70,6 +98,20 @@ impl Interrupt {
         self.ime
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.ime);
+        w.u8(self.irqf.bits());
+        w.u8(self.read8(IE));
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        self.ime    = r.bool()?;
+        self.irqf   = If::from_bits_truncate(r.u8()?);
+        let ie = r.u8()?;
+        self.write8(IE, ie);
+        Ok(())
+    }
+
     fn interrupt_kind(&mut self) -> Option<InterruptKind> {
         if !self.ime {
             return None;
@@ -101,6 +143,9 @@ impl Interrupt {
 
     pub fn isr_addr(&mut self) -> Option<usize> {
         let kind = self.interrupt_kind()?;
+        if log_enabled!(Level::Trace) {
+            trace!("dispatching {:?} interrupt", kind);
+        }
         match kind {
             InterruptKind::Vblank       =>  {
                 self.remove_irq(InterruptKind::Vblank);
@@ -149,17 +194,40 @@ impl Interrupt {
 impl Io for Interrupt {
     fn read8(&self, addr: usize) -> u8 {
         match addr {
-            0xFF0F  =>  self.irqf.bits() as u8,
-            0xFFFF  =>  self.irqe.bits() as u8,
+            IF      =>  self.irqf.bits() | 0xE0,
+            IE      =>  self.irqe.bits() | self.ie_unused,
             _       =>  panic!("can't read from: {:04x}", addr),
         }
     }
 
     fn write8(&mut self, addr: usize, data: u8) {
         match addr {
-            0xFF0F  =>  self.irqf = If::from_bits_truncate(data),
-            0xFFFF  =>  self.irqe = Ie::from_bits_truncate(data),
+            IF      =>  self.irqf = If::from_bits_truncate(data),
+            IE      =>  {
+                self.irqe = Ie::from_bits_truncate(data);
+                self.ie_unused = data & 0xE0;
+            },
             _       =>  panic!("can't write to: {:04x}", addr),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dispatch_follows_interrupt_kinds() {
+        let mut interrupt = Interrupt::new();
+        interrupt.enable();
+        interrupt.write8(IE, 0x1F);
+        for kind in INTERRUPT_KINDS.iter() {
+            interrupt.set_irq(*kind);
+        }
+        for (i, kind) in INTERRUPT_KINDS.iter().enumerate() {
+            assert_eq!(interrupt.interrupt_kind(), Some(*kind));
+            assert_eq!(interrupt.isr_addr(), Some(VBLANK_ISR_ADDR + i * 8));
+        }
+        assert_eq!(interrupt.read8(IF), 0xE0);
+    }
+}
@@ -1,4 +1,11 @@
 pub trait Io {
     fn read8(&self, addr: usize) -> u8;
     fn write8(&mut self, addr: usize, data: u8);
-}
\ No newline at end of file
+}
+
+// Something mapped over part of the address space, e.g. a debugging port or
+// extra cartridge hardware. The bus hands it the full address.
+pub trait MemDevice {
+    fn read(&self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, data: u8);
+}
@@ -8,4 +8,18 @@ pub mod pad;
 pub mod ppu;
 pub mod hram;
 pub mod apu;
-pub mod timer;
\ No newline at end of file
+pub mod timer;
+pub mod error;
+pub mod save;
+pub mod state;
+pub mod sgb;
+pub mod serial;
+pub mod infrared;
+pub mod model;
+pub mod palette;
+pub mod blend;
+pub mod patch;
+pub mod trace;
+pub mod rtc;
+pub mod gameboy;
+pub mod addr;
\ No newline at end of file
@@ -0,0 +1,22 @@
+// The hardware being emulated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    // Original Game Boy
+    Dmg,
+    // Game Boy Pocket. A DMG apart from the boot state
+    Mgb,
+    // Game Boy Color
+    Cgb,
+    // Game Boy Advance running Game Boy Color games
+    Agb,
+}
+
+impl Model {
+    // CGB and AGB have the color palettes, the second VRAM bank, WRAM banking and double speed
+    pub fn is_color(&self) -> bool {
+        match self {
+            Model::Dmg | Model::Mgb =>  false,
+            Model::Cgb | Model::Agb =>  true,
+        }
+    }
+}
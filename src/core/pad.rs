@@ -1,6 +1,10 @@
 use bitflags::*;
+use std::sync::mpsc::{channel, Sender, Receiver};
 
 use crate::core::io::Io;
+use crate::core::error::GbError;
+use crate::core::state::{ StateReader, StateWriter };
+use crate::core::sgb::{ SgbReceiver, SgbPacket };
 
 bitflags!{
     struct P1: u8 {
@@ -26,7 +30,7 @@ bitflags!{
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Key {
     Right,
     Left,
@@ -38,9 +42,60 @@ pub enum Key {
     Start,
 }
 
+// Every button, e.g. to copy settings from one pad to another
+pub const KEYS: [Key; 8] = [
+    Key::Right, Key::Left, Key::Up, Key::Down, Key::A, Key::B, Key::Select, Key::Start,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputEvent {
+    Press(Key),
+    Release(Key),
+}
+
+// Something that presses buttons, e.g. a keyboard, a gamepad or a peer on the network.
+// The Game Boy polls its sources once per frame.
+pub trait InputSource {
+    fn poll(&mut self) -> Option<InputEvent>;
+}
+
+// Input sent from another thread or another part of the frontend
+pub struct ChannelInput {
+    rx: Receiver<InputEvent>,
+}
+
+impl ChannelInput {
+    pub fn new() -> (Sender<InputEvent>, ChannelInput) {
+        let (tx, rx) = channel();
+        (tx, ChannelInput { rx })
+    }
+}
+
+impl InputSource for ChannelInput {
+    fn poll(&mut self) -> Option<InputEvent> {
+        self.rx.try_recv().ok()
+    }
+}
+
+// Auto-fire toggles a held button every 2 frames unless told otherwise
+const DEFAULT_TURBO_RATE: u8 = 2;
+
 pub struct Pad {
     register:   P1,
+    // What the game sees. A cleared bit is a pressed button
     state:      KeyState,
+    sgb:        SgbReceiver,
+    // Buttons held on the host and buttons that auto-fire while held
+    held:       KeyState,
+    turbo:      KeyState,
+    turbo_rate:     u8,
+    turbo_frames:   u8,
+}
+
+impl Default for Pad {
+    fn default() -> Self {
+        Pad::new()
+    }
 }
 
 impl Pad {
@@ -51,51 +106,171 @@ impl Pad {
                         KeyState::SELECT    | KeyState::START   |
                         KeyState::RIGHT     | KeyState::LEFT    |
                         KeyState::UP        | KeyState::DOWN,
+            sgb:        SgbReceiver::new(),
+            held:       KeyState::empty(),
+            turbo:      KeyState::empty(),
+            turbo_rate:     DEFAULT_TURBO_RATE,
+            turbo_frames:   0,
         }
     }
 
-    pub fn push_key(&mut self, key: Key) {
+    // Releases every button. The turbo settings are kept.
+    pub fn reset(&mut self) {
+        let pad = Pad::new();
+        *self = Pad {
+            turbo:      self.turbo,
+            turbo_rate: self.turbo_rate,
+            ..pad
+        };
+    }
+
+    fn key_state(key: Key) -> KeyState {
         match key {
-            Key::Right  =>  self.state.remove(KeyState::RIGHT),
-            Key::A      =>  self.state.remove(KeyState::A),
-            Key::Left   =>  self.state.remove(KeyState::LEFT),
-            Key::B      =>  self.state.remove(KeyState::B),
-            Key::Up     =>  self.state.remove(KeyState::UP),
-            Key::Select =>  self.state.remove(KeyState::SELECT),
-            Key::Down   =>  self.state.remove(KeyState::DOWN),
-            Key::Start  =>  self.state.remove(KeyState::START),
+            Key::Right  =>  KeyState::RIGHT,
+            Key::A      =>  KeyState::A,
+            Key::Left   =>  KeyState::LEFT,
+            Key::B      =>  KeyState::B,
+            Key::Up     =>  KeyState::UP,
+            Key::Select =>  KeyState::SELECT,
+            Key::Down   =>  KeyState::DOWN,
+            Key::Start  =>  KeyState::START,
         }
     }
+
+    pub fn push_key(&mut self, key: Key) {
+        self.held.insert(Pad::key_state(key));
+        self.state.remove(Pad::key_state(key));
+    }
     
     pub fn release_key(&mut self, key: Key) {
-        match key {
-            Key::Right  =>  self.state.insert(KeyState::RIGHT),
-            Key::A      =>  self.state.insert(KeyState::A),
-            Key::Left   =>  self.state.insert(KeyState::LEFT),
-            Key::B      =>  self.state.insert(KeyState::B),
-            Key::Up     =>  self.state.insert(KeyState::UP),
-            Key::Select =>  self.state.insert(KeyState::SELECT),
-            Key::Down   =>  self.state.insert(KeyState::DOWN),
-            Key::Start  =>  self.state.insert(KeyState::START),
+        self.held.remove(Pad::key_state(key));
+        self.state.insert(Pad::key_state(key));
+    }
+
+    // Sets every button at once. A set bit is a pressed button:
+    // Start, Select, B, A, Down, Up, Left, Right from bit 7 to bit 0.
+    // Returns true if a button went down.
+    pub fn set_buttons(&mut self, mask: u8) -> bool {
+        let mut pressed = false;
+        for key in KEYS.iter() {
+            let down = mask & Pad::key_state(*key).bits() != 0;
+            match (self.held.contains(Pad::key_state(*key)), down) {
+                (false, true)   =>  {
+                    self.push_key(*key);
+                    pressed = true;
+                },
+                (true, false)   =>  self.release_key(*key),
+                _               =>  (),
+            }
+        }
+        pressed
+    }
+
+    // The buttons held on the host, in the layout of set_buttons()
+    pub fn buttons(&self) -> u8 {
+        self.held.bits()
+    }
+
+    // Makes `key` auto-fire while it is held
+    pub fn set_turbo(&mut self, key: Key, on: bool) {
+        self.turbo.set(Pad::key_state(key), on);
+    }
+
+    pub fn is_turbo(&self, key: Key) -> bool {
+        self.turbo.contains(Pad::key_state(key))
+    }
+
+    // Auto-fire buttons are pressed for `frames` frames, then released for as long
+    pub fn set_turbo_rate(&mut self, frames: u8) {
+        self.turbo_rate = frames.max(1);
+    }
+
+    pub fn turbo_rate(&self) -> u8 {
+        self.turbo_rate
+    }
+
+    // Advances auto-fire by a frame.
+    // Returns true when a button goes down, which raises the joypad interrupt.
+    pub fn next_frame(&mut self) -> bool {
+        let firing = self.turbo & self.held;
+        if firing.is_empty() {
+            self.turbo_frames = 0;
+            return false;
+        }
+
+        self.turbo_frames += 1;
+        if self.turbo_frames < self.turbo_rate {
+            return false;
         }
+        self.turbo_frames = 0;
+        self.state.toggle(firing);
+
+        !(firing - self.state).is_empty()
+    }
+
+    pub fn pop_sgb_packet(&mut self) -> Option<SgbPacket> {
+        self.sgb.pop_packet()
     }
 
+    // The keys held on the host are left alone
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.register.bits());
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        self.register = P1::from_bits_truncate(r.u8()?);
+        Ok(())
+    }
 }
 
 impl Io for Pad {
     fn read8(&self, _addr: usize) -> u8 {
+        // Bits 6-7 are unused and read as 1
+        let select = 0xC0 | self.register.bits() & 0x30;
+
         if !self.register.contains(P1::P15) {
-            return self.register.bits() & 0xF0 | (self.state.bits() >> 4) & 0x0F;
+            return select | (self.state.bits() >> 4) & 0x0F;
         }
 
         if !self.register.contains(P1::P14) {
-            return self.register.bits() & 0xF0 | self.state.bits() & 0x0F;
+            return select | self.state.bits() & 0x0F;
         }
 
-        self.register.bits() & 0x0F
+        select | 0x0F
     }
 
     fn write8(&mut self, _addr: usize, data: u8) {
         self.register = P1::from_bits_truncate(data);
+        self.sgb.write(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads the A button through P1 with the action buttons selected
+    fn a_pressed(pad: &mut Pad) -> bool {
+        pad.write8(0xFF00, 0x10);
+        pad.read8(0xFF00) & 0x01 == 0
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn turbo_button_alternates_while_held() {
+        let mut pad = Pad::new();
+        pad.set_turbo(Key::A, true);
+        pad.set_turbo_rate(1);
+        pad.push_key(Key::A);
+
+        let mut seen = vec![];
+        for _ in 0..4 {
+            seen.push(a_pressed(&mut pad));
+            pad.next_frame();
+        }
+        assert_eq!(seen, vec![true, false, true, false]);
+
+        pad.release_key(Key::A);
+        pad.next_frame();
+        assert!(!a_pressed(&mut pad));
+    }
+}
@@ -0,0 +1,145 @@
+// Colors a DMG game is shown in, as 0xRRGGBB for shades 0-3 of BGP, OBP0 and OBP1
+pub struct Palette {
+    pub name:   &'static str,
+    pub bg:     [u32; 4],
+    pub obj0:   [u32; 4],
+    pub obj1:   [u32; 4],
+}
+
+impl Palette {
+    // The RGBA of a shade that went through BGP (layer 0), OBP0 (1) or OBP1 (2)
+    pub fn rgba(&self, layer: u8, shade: u8) -> [u8; 4] {
+        let colors = match layer {
+            1   =>  &self.obj0,
+            2   =>  &self.obj1,
+            _   =>  &self.bg,
+        };
+        let rgb = colors[(shade & 0x03) as usize];
+        [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8, 0xFF]
+    }
+}
+
+const fn same(name: &'static str, colors: [u32; 4]) -> Palette {
+    Palette { name, bg: colors, obj0: colors, obj1: colors }
+}
+
+// The classic green screen first, then the ones the CGB boot ROM lets you pick
+// with a direction and A/B held
+pub const PALETTES: [Palette; 14] = [
+    same("Green",       [0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F]),
+    same("Grayscale",   [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]),
+    same("Brown",       [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000]),
+    Palette {
+        name:   "Red",
+        bg:     [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000],
+        obj0:   [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000],
+        obj1:   [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000],
+    },
+    same("Dark brown",  [0xFFE6C5, 0xCE9C84, 0x846B29, 0x5A3108]),
+    Palette {
+        name:   "Blue",
+        bg:     [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000],
+        obj0:   [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000],
+        obj1:   [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000],
+    },
+    Palette {
+        name:   "Dark blue",
+        bg:     [0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000],
+        obj0:   [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000],
+        obj1:   [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000],
+    },
+    same("Gray",        [0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000]),
+    same("Pale yellow", [0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000]),
+    same("Orange",      [0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000]),
+    Palette {
+        name:   "Yellow",
+        bg:     [0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000],
+        obj0:   [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000],
+        obj1:   [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000],
+    },
+    same("Light green", [0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000]),
+    Palette {
+        name:   "Dark green",
+        bg:     [0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000],
+        obj0:   [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000],
+        obj1:   [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000],
+    },
+    same("Inverted",    [0x000000, 0x008484, 0xFFDE00, 0xFFFFFF]),
+];
+
+const RED:          usize = 3;
+const BLUE:         usize = 5;
+// What the CGB boot ROM shows games it doesn't know
+const DARK_GREEN:   usize = 12;
+
+// Title checksums of Nintendo games the CGB boot ROM has colors for. Only a few
+// well known ones so far; the rest get the default like other publishers' games do.
+const TITLE_PALETTES: [(u8, usize); 2] = [
+    (0x14,  RED),   // POKEMON RED
+    (0x61,  BLUE),  // POKEMON BLUE
+];
+
+// Which palette DMG games are shown in: a fixed one, or the one the CGB boot ROM picks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaletteChoice {
+    Auto,
+    Fixed(usize),
+}
+
+impl PaletteChoice {
+    // Auto, then every palette in order, then Auto again
+    pub fn next(self) -> Self {
+        match self {
+            PaletteChoice::Auto                                 =>  PaletteChoice::Fixed(0),
+            PaletteChoice::Fixed(i) if i + 1 < PALETTES.len()   =>  PaletteChoice::Fixed(i + 1),
+            PaletteChoice::Fixed(_)                             =>  PaletteChoice::Auto,
+        }
+    }
+
+    // "auto" or a palette name, in any case
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name.eq_ignore_ascii_case("auto") {
+            return Some(PaletteChoice::Auto);
+        }
+        PALETTES.iter()
+            .position(|palette| palette.name.eq_ignore_ascii_case(name))
+            .map(PaletteChoice::Fixed)
+    }
+}
+
+// The palette the CGB boot ROM gives a DMG game, from its licensee and title checksum
+pub fn boot_palette(nintendo: bool, title_checksum: u8) -> usize {
+    if !nintendo {
+        return DARK_GREEN;
+    }
+    TITLE_PALETTES.iter()
+        .find(|(checksum, _)| *checksum == title_checksum)
+        .map(|(_, palette)| *palette)
+        .unwrap_or(DARK_GREEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn title_checksum_picks_boot_palette() {
+        assert_eq!(PALETTES[boot_palette(true, 0x14)].name, "Red");
+        assert_eq!(PALETTES[boot_palette(true, 0x61)].name, "Blue");
+        // Unknown titles and other publishers get the default
+        assert_eq!(PALETTES[boot_palette(true, 0x00)].name, "Dark green");
+        assert_eq!(PALETTES[boot_palette(false, 0x14)].name, "Dark green");
+    }
+
+    #[test]
+    fn choices_cycle_back_to_auto() {
+        let mut choice = PaletteChoice::Auto;
+        for _ in 0..PALETTES.len() {
+            choice = choice.next();
+        }
+        assert_eq!(choice, PaletteChoice::Fixed(PALETTES.len() - 1));
+        assert_eq!(choice.next(), PaletteChoice::Auto);
+        assert_eq!(PaletteChoice::from_name("dark BROWN"), Some(PaletteChoice::Fixed(4)));
+        assert_eq!(PaletteChoice::from_name("purple"), None);
+    }
+}
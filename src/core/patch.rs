@@ -0,0 +1,275 @@
+use crate::core::error::GbError;
+
+const IPS_MAGIC:    &[u8] = b"PATCH";
+const IPS_EOF:      usize = 0x454F46;
+const BPS_MAGIC:    &[u8] = b"BPS1";
+// Source, target and patch CRC32
+const BPS_FOOTER:   usize = 12;
+// The biggest ROM a cartridge header can describe
+const MAX_ROM_SIZE: usize = 0x800000;
+
+fn bad(msg: &str) -> GbError {
+    GbError::BadPatch(msg.to_string())
+}
+
+// Applies an IPS or BPS patch to the ROM, told apart by their magic
+pub fn apply(rom: &mut Vec<u8>, patch: &[u8]) -> Result<(), GbError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, &patch[IPS_MAGIC.len()..])
+    } else if patch.starts_with(BPS_MAGIC) {
+        *rom = apply_bps(rom, patch)?;
+        Ok(())
+    } else {
+        Err(bad("not an IPS or BPS patch"))
+    }
+}
+
+// Reads the patch front to back and fails on anything past its end
+struct Reader<'a> {
+    data:   &'a [u8],
+    pos:    usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], GbError> {
+        if self.data.len() - self.pos < len {
+            return Err(bad("unexpected end of patch"));
+        }
+        let bytes = &self.data[self.pos..self.pos+len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, GbError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    // Big endian, like every IPS number
+    fn be(&mut self, len: usize) -> Result<usize, GbError> {
+        Ok(self.bytes(len)?.iter().fold(0, |n, b| n << 8 | *b as usize))
+    }
+
+    // BPS numbers: 7 bits per byte, the last one has bit 7 set
+    fn varint(&mut self) -> Result<usize, GbError> {
+        let mut n: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let b = self.u8()?;
+            n = (b as usize & 0x7F).checked_mul(shift)
+                .and_then(|x| x.checked_add(n))
+                .ok_or_else(|| bad("number too big"))?;
+            if b & 0x80 != 0 {
+                return Ok(n);
+            }
+            shift = shift.checked_mul(0x80).ok_or_else(|| bad("number too big"))?;
+            n = n.checked_add(shift).ok_or_else(|| bad("number too big"))?;
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+// Records of 24-bit offset, 16-bit size and data, or size 0 and a byte repeated
+// a 16-bit count of times, up to "EOF". It can grow the ROM and, after "EOF",
+// a 24-bit size truncates it.
+fn apply_ips(rom: &mut Vec<u8>, records: &[u8]) -> Result<(), GbError> {
+    let mut r = Reader::new(records);
+    loop {
+        let offset = r.be(3)?;
+        if offset == IPS_EOF {
+            break;
+        }
+        let size = r.be(2)?;
+        let (data, len) = match size {
+            0   =>  {
+                let len = r.be(2)?;
+                (None, len)
+            },
+            _   =>  (Some(r.bytes(size)?), size),
+        };
+        let end = offset + len;
+        if end > MAX_ROM_SIZE {
+            return Err(GbError::BadPatch(format!("writes past 8MB at 0x{:06x}", offset)));
+        }
+        if rom.len() < end {
+            rom.resize(end, 0x00);
+        }
+        match data {
+            Some(data)  =>  rom[offset..end].copy_from_slice(data),
+            None        =>  {
+                let value = r.u8()?;
+                for b in rom[offset..end].iter_mut() {
+                    *b = value;
+                }
+            },
+        }
+    }
+
+    if !r.at_end() {
+        let size = r.be(3)?;
+        if !r.at_end() {
+            return Err(bad("data after EOF"));
+        }
+        rom.truncate(size);
+    }
+    Ok(())
+}
+
+// Builds the patched ROM from actions that copy from the original ROM, the patch
+// or the output so far. CRC32s of all three at the end catch the wrong ROM.
+fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, GbError> {
+    if patch.len() < BPS_MAGIC.len() + BPS_FOOTER {
+        return Err(bad("unexpected end of patch"));
+    }
+    let (body, footer) = patch.split_at(patch.len() - BPS_FOOTER);
+    let crc = |i: usize| u32::from_le_bytes([footer[i], footer[i+1], footer[i+2], footer[i+3]]);
+    if crc32(&patch[..patch.len()-4]) != crc(8) {
+        return Err(bad("patch checksum mismatch"));
+    }
+
+    let mut r = Reader::new(&body[BPS_MAGIC.len()..]);
+    let source_size = r.varint()?;
+    let target_size = r.varint()?;
+    let metadata_size = r.varint()?;
+    r.bytes(metadata_size)?;
+    if source_size != source.len() || crc32(source) != crc(0) {
+        return Err(bad("made for a different ROM"));
+    }
+    if target_size > MAX_ROM_SIZE {
+        return Err(GbError::BadPatch(format!("patched ROM is over 8MB: {} bytes", target_size)));
+    }
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    while !r.at_end() {
+        let action = r.varint()?;
+        let len = (action >> 2) + 1;
+        if target_size - target.len() < len {
+            return Err(bad("writes past the patched ROM's size"));
+        }
+        match action & 0x03 {
+            // Same bytes as in the original at the same offset
+            0   =>  {
+                let start = target.len();
+                let bytes = source.get(start..start+len).ok_or_else(|| bad("reads past the ROM"))?;
+                target.extend_from_slice(bytes);
+            },
+            // Bytes stored in the patch
+            1   =>  target.extend_from_slice(r.bytes(len)?),
+            // Bytes from elsewhere in the original
+            2   =>  {
+                source_offset = relative(source_offset, r.varint()?)?;
+                let bytes = source.get(source_offset..).and_then(|s| s.get(..len)).ok_or_else(|| bad("reads past the ROM"))?;
+                target.extend_from_slice(bytes);
+                source_offset += len;
+            },
+            // Bytes already written, one at a time since the copy may overlap itself
+            _   =>  {
+                target_offset = relative(target_offset, r.varint()?)?;
+                for _ in 0..len {
+                    let b = *target.get(target_offset).ok_or_else(|| bad("reads past the patched ROM"))?;
+                    target.push(b);
+                    target_offset += 1;
+                }
+            },
+        }
+    }
+
+    if target.len() != target_size {
+        return Err(bad("patched ROM is shorter than the patch says"));
+    }
+    if crc32(&target) != crc(4) {
+        return Err(bad("patched ROM checksum mismatch"));
+    }
+    Ok(target)
+}
+
+// BPS copy offsets are relative to the last one: bit 0 is the sign, the rest the distance
+fn relative(offset: usize, delta: usize) -> Result<usize, GbError> {
+    let distance = delta >> 1;
+    match delta & 0x01 {
+        0   =>  offset.checked_add(distance),
+        _   =>  offset.checked_sub(distance),
+    }.ok_or_else(|| bad("copy offset out of range"))
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                0   =>  crc >> 1,
+                _   =>  (crc >> 1) ^ 0xEDB88320,
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ips_records_and_truncation() {
+        let mut rom = vec![0x00; 8];
+        let mut patch = b"PATCH".to_vec();
+        // 0x000002: one byte
+        patch.extend_from_slice(&[0x00, 0x00, 0x02, 0x00, 0x01, 0x42]);
+        // 0x000004: 0x99 three times
+        patch.extend_from_slice(&[0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x03, 0x99]);
+        patch.extend_from_slice(b"EOF");
+        patch.extend_from_slice(&[0x00, 0x00, 0x06]);
+        apply(&mut rom, &patch).unwrap();
+        assert_eq!(rom, vec![0x00, 0x00, 0x42, 0x00, 0x99, 0x99]);
+    }
+
+    #[test]
+    fn bad_patches_are_rejected() {
+        let mut rom = vec![0x00; 8];
+        assert!(matches!(apply(&mut rom, b"NOT A PATCH"), Err(GbError::BadPatch(_))));
+        // The record's data is cut off
+        assert!(matches!(apply(&mut rom, b"PATCH\x00\x00\x02\x00\x04\x42"), Err(GbError::BadPatch(_))));
+        // Past 8MB
+        assert!(matches!(apply(&mut rom, b"PATCH\x7F\xFF\xFF\x00\x02\x42\x42EOF"), Err(GbError::BadPatch(_))));
+        assert_eq!(rom, vec![0x00; 8]);
+    }
+
+    // Turns 1 2 3 4 into 1 2 9 4
+    fn bps_patch() -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        // Source and target size 4, no metadata
+        patch.extend_from_slice(&[0x84, 0x84, 0x80]);
+        // 2 bytes from the source, 0x09 from the patch, 1 byte from the source
+        patch.extend_from_slice(&[0x84, 0x81, 0x09, 0x80]);
+        patch.extend_from_slice(&crc32(&[1, 2, 3, 4]).to_le_bytes());
+        patch.extend_from_slice(&crc32(&[1, 2, 9, 4]).to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn bps_actions_and_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+
+        let mut rom = vec![1, 2, 3, 4];
+        apply(&mut rom, &bps_patch()).unwrap();
+        assert_eq!(rom, vec![1, 2, 9, 4]);
+
+        // Made for 1 2 3 4
+        let mut other = vec![1, 2, 3, 5];
+        assert!(matches!(apply(&mut other, &bps_patch()), Err(GbError::BadPatch(_))));
+        let mut corrupt = bps_patch();
+        corrupt[8] ^= 0x01;
+        assert!(matches!(apply(&mut vec![1, 2, 3, 4], &corrupt), Err(GbError::BadPatch(_))));
+    }
+}
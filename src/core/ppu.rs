@@ -1,8 +1,14 @@
 use bitflags::*;
+use log::debug;
+use std::collections::VecDeque;
 
+use crate::core::addr::*;
 use crate::core::io::Io;
 use crate::core::ram::Ram;
 use crate::core::interrupt::InterruptKind;
+use crate::core::model::Model;
+use crate::core::error::GbError;
+use crate::core::state::{ StateReader, StateWriter };
 
 bitflags! {
     struct Lcdc: u8 {
@@ -103,6 +109,7 @@ enum PpuMode {
 pub const SCREEN_WIDTH:     usize   = 160;
 pub const SCREEN_HEIGHT:    usize   = 144;
 const LCD_BLANK_HEIGHT: u8 = 10;
+
 // const VRAM_SIZE:        usize   = 8192;
 const OAM_SPRITES:      usize   = 40;
 // const OAM_OFFSET:       usize   = 0xFE00;
@@ -113,10 +120,130 @@ const TILEMAP0_OFFSET: usize = 0x9800;
 const TILEMAP1_OFFSET: usize = 0x9C00;
 const TILEDATA0_OFFSET: usize = 0x8800;
 const TILEDATA1_OFFSET: usize = 0x8000;
+const OAM_SEARCH_CLOCKS: u16 = 80;
+const SPRITES_PER_LINE: usize = 10;
+// Dots the pixel pipeline is paused while a sprite is fetched
+const SPRITE_FETCH_STALL: u8 = 6;
+const VRAM_BANK_SIZE: usize = 0x2000;
+
+// RGBA of each shade, plus the LCD off color
+pub const COLORS: [[u8; 4]; 5] = [
+    [0x9B, 0xBC, 0x0F, 0xFF],   // Lightest Green (#9BBC0F)
+    [0x8B, 0xAC, 0x0F, 0xFF],   // Light Green (#8BAC0F)
+    [0x30, 0x62, 0x30, 0xFF],   // Dark Green (#306230)
+    [0x0F, 0x38, 0x0F, 0xFF],   // Darkest Green (#0F380F)
+    [0x8F, 0x7B, 0x13, 0xFF],   // LCD OFF
+];
+
+// The registers at 0xFF40-0xFF4B as the CPU reads them, plus the CGB palette indices
+// (BCPS/OCPS, 0xFF on DMG)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PpuRegisters {
+    pub lcdc:   u8,
+    pub stat:   u8,
+    pub scy:    u8,
+    pub scx:    u8,
+    pub ly:     u8,
+    pub lyc:    u8,
+    pub bgp:    u8,
+    pub obp0:   u8,
+    pub obp1:   u8,
+    pub wy:     u8,
+    pub wx:     u8,
+    pub bcps:   u8,
+    pub ocps:   u8,
+}
+
+// Where the dots of the last complete frame went, to check PPU timing against hardware
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PpuTiming {
+    // Dots spent in mode 0 (HBlank), 1 (VBlank), 2 (OAM search) and 3 (pixel transfer)
+    pub mode_dots:      [u32; 4],
+    // 70224 on hardware
+    pub frame_dots:     u32,
+    // Sprites fetched on each visible line
+    pub line_sprites:   [u8; SCREEN_HEIGHT],
+}
+
+impl PpuTiming {
+    fn new() -> Self {
+        PpuTiming {
+            mode_dots:      [0; 4],
+            frame_dots:     0,
+            line_sprites:   [0; SCREEN_HEIGHT],
+        }
+    }
+}
+
+// Gray level of a shade from get_pixels(), 0xFF for shade 0 (white) down to 0x00 for shade 3
+pub fn shade_to_intensity(shade: u8) -> u8 {
+    0xFF - (shade & 0x03) * 0x55
+}
+
+// The color a DMG screen shows for a shade from get_pixels()
+pub fn shade_to_rgba(shade: u8) -> [u8; 4] {
+    COLORS[(shade & 0x03) as usize]
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FetcherState {
+    TileId,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+// Background/window tile fetcher. Every step takes two dots.
+struct Fetcher {
+    state:  FetcherState,
+    dot:    bool,
+    tilex:  u8,
+    tileid: u8,
+    low:    u8,
+    high:   u8,
+    // CGB tile attributes from VRAM bank 1
+    attrs:  u8,
+    window: bool,
+}
+
+impl Fetcher {
+    fn new(window: bool) -> Self {
+        Fetcher {
+            state:  FetcherState::TileId,
+            dot:    false,
+            tilex:  0,
+            tileid: 0,
+            low:    0,
+            high:   0,
+            attrs:  0,
+            window,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct BgPixel {
+    color:      u8,
+    palette:    u8,
+    priority:   bool,
+}
+
+#[derive(Clone, Copy)]
+struct ObjPixel {
+    color:  u8,
+    flags:  OamFlags,
+    // Position of the sprite in OAM
+    index:  u8,
+}
 
 pub struct Ppu {
+    model:  Model,
     clock: u16,
     pixels: [u8; SCREEN_WIDTH*SCREEN_HEIGHT],
+    // RGB555 output in CGB mode
+    colors: Vec<u16>,
+    // DMG: the palette each pixel went through, 0 = BGP, 1 = OBP0, 2 = OBP1
+    layers: Vec<u8>,
     lcdc:   Lcdc,
     stat:   Stat,
     scy:    u8,
@@ -130,34 +257,61 @@ pub struct Ppu {
     wy:     u8,
     wx:     u8,
     vram:   Ram,
+    vbk:    u8,
     oam:    [Oam; OAM_SPRITES],
     oam_dma_started:    bool,
+    stat_write_irq:     bool,
+    // CGB color palettes: 8 palettes of 4 little endian RGB555 colors
+    bcps:   u8,
+    ocps:   u8,
+    bg_palettes:    [u8; 64],
+    obj_palettes:   [u8; 64],
+    // Pixel FIFO state of the current line
+    lx:             u8,
+    discard:        u8,
+    stall:          u8,
+    window_active:  bool,
+    window_line:    u8,
+    fetcher:        Fetcher,
+    bg_fifo:        VecDeque<BgPixel>,
+    obj_fifo:       VecDeque<ObjPixel>,
+    // Sprites on the current line with their OAM index
+    line_sprites:   Vec<(u8, Oam)>,
+    // Object priority mode: 0 = OAM index (CGB), 1 = X coordinate (DMG)
+    opri:   u8,
+    // Counters of the frame being drawn and of the last complete one
+    timing:         PpuTiming,
+    last_timing:    PpuTiming,
 }
 
 impl Io for Ppu {
     fn read8(&self, addr: usize) -> u8 {
         match addr {
             // 8kB Video RAM
-            0x8000 ..= 0x9FFF   =>  self.vram.read8(addr&0x1FFF),
+            VRAM_START ..= VRAM_END =>  self.read_vram(self.vbk as usize, addr),
             // Sprite Attribute Memory (OAM)
-            0xFE00 ..= 0xFE9F   =>  self.oam[(addr&0xFF)/4].read8(addr%4),
+            OAM_START ..= OAM_END   =>  self.oam[(addr&0xFF)/4].read8(addr%4),
             // Registers
-            0xFF40  =>  self.lcdc.bits,
-            0xFF41  =>  self.stat.bits,
-            0xFF42  =>  self.scy,
-            0xFF43  =>  self.scx,
-            0xFF44  =>  self.ly ,
-            0xFF45  =>  self.lyc,
-            0xFF46  =>  self.dma,
-            0xFF47  =>  self.bgp.to_u8(),
-            0xFF48  =>  self.obp0.to_u8(),
-            0xFF49  =>  self.obp1.to_u8(),
-            0xFF4A  =>  self.wy,
-            0xFF4B  =>  self.wx,
-            // ToDo: LCD Color Palettes (CGB only)
-            // 0xFF68
-            // 0xFF69
-            // 0xFF6A
+            LCDC    =>  self.lcdc.bits,
+            STAT    =>  self.stat.bits | 0x80,
+            SCY     =>  self.scy,
+            SCX     =>  self.scx,
+            LY      =>  self.ly ,
+            LYC     =>  self.lyc,
+            DMA     =>  self.dma,
+            BGP     =>  self.bgp.to_u8(),
+            OBP0    =>  self.obp0.to_u8(),
+            OBP1    =>  self.obp1.to_u8(),
+            WY      =>  self.wy,
+            WX      =>  self.wx,
+            // VRAM Bank and LCD Color Palettes (CGB only)
+            VBK | BCPS ..= OPRI  if !self.model.is_color()  =>  0xFF,
+            VBK     =>  self.vbk | 0xFE,
+            BCPS    =>  self.bcps | 0x40,
+            BCPD    =>  self.bg_palettes[(self.bcps & 0x3F) as usize],
+            OCPS    =>  self.ocps | 0x40,
+            OCPD    =>  self.obj_palettes[(self.ocps & 0x3F) as usize],
+            OPRI    =>  self.opri | 0xFE,
             _       =>  panic!(),
         }
     }
@@ -165,39 +319,72 @@ impl Io for Ppu {
     fn write8(&mut self, addr: usize, data: u8) {
         match addr {
             // 8kB Video RAM
-            0x8000 ..= 0x9FFF   =>  self.vram.write8(addr&0x1FFF, data),
+            VRAM_START ..= VRAM_END =>  {
+                let addr = self.vram_addr(self.vbk as usize, addr);
+                self.vram.write8(addr, data);
+            },
             // Sprite Attribute Memory (OAM)
-            0xFE00 ..= 0xFE9F   =>  self.oam[(addr&0xFF)/4].write8(addr%4, data),
+            OAM_START ..= OAM_END   =>  self.oam[(addr&0xFF)/4].write8(addr%4, data),
             // Registers
-            0xFF40  =>  self.lcdc   = Lcdc::from_bits_truncate(data),
-            0xFF41  =>  self.stat   = Stat::from_bits_truncate(data),
-            0xFF42  =>  self.scy    = data,
-            0xFF43  =>  self.scx    = data,
-            0xFF44  =>  self.ly     = data,
-            0xFF45  =>  self.lyc    = data,
-            0xFF46  =>  {
+            LCDC    =>  {
+                let was_on = self.lcdc.contains(Lcdc::LCD_EN);
+                self.lcdc   = Lcdc::from_bits_truncate(data);
+                // Switching the LCD off stops the PPU at the start of line 0 in HBlank
+                if was_on && !self.lcdc.contains(Lcdc::LCD_EN) {
+                    self.ly = 0;
+                    self.clock = 0;
+                    self.window_line = 0;
+                    self.timing = PpuTiming::new();
+                    self.switch_mode(PpuMode::HBlank);
+                }
+            },
+            STAT    =>  {
+                // The mode and LYC flags are read only
+                self.stat   = Stat::from_bits_truncate(data & 0x78 | self.stat.bits & 0x07);
+                // DMG bug: a STAT write briefly enables every STAT source,
+                // so it fires in HBlank, VBlank or on an LY=LYC match
+                if !self.model.is_color() {
+                    self.stat_write_irq = match self.mode() {
+                        PpuMode::HBlank | PpuMode::VBlank   =>  true,
+                        _                                   =>  self.stat.contains(Stat::LYC_STAT),
+                    };
+                }
+            },
+            SCY     =>  self.scy    = data,
+            SCX     =>  self.scx    = data,
+            LY      =>  self.ly     = data,
+            LYC     =>  self.lyc    = data,
+            DMA     =>  {
+                debug!("OAM DMA from 0x{:02x}00", data);
                 self.dma    = data;
                 self.oam_dma_started = true;
             },
-            0xFF47  =>  self.bgp    = Palette::from(data),
-            0xFF48  =>  self.obp0   = Palette::from(data),
-            0xFF49  =>  self.obp1   = Palette::from(data),
-            0xFF4A  =>  self.wy     = data,
-            0xFF4B  =>  self.wx     = data,
-            // ToDo: LCD Color Palettes (CGB only)
-            // 0xFF68
-            // 0xFF69
-            // 0xFF6A
+            BGP     =>  self.bgp    = Palette::from(data),
+            OBP0    =>  self.obp0   = Palette::from(data),
+            OBP1    =>  self.obp1   = Palette::from(data),
+            WY      =>  self.wy     = data,
+            WX      =>  self.wx     = data,
+            // VRAM Bank and LCD Color Palettes (CGB only)
+            VBK | BCPS ..= OPRI  if !self.model.is_color()  =>  (),
+            VBK     =>  self.vbk    = data & 0x01,
+            BCPS    =>  self.bcps   = data & 0xBF,
+            BCPD    =>  write_palette(&mut self.bg_palettes, &mut self.bcps, data),
+            OCPS    =>  self.ocps   = data & 0xBF,
+            OCPD    =>  write_palette(&mut self.obj_palettes, &mut self.ocps, data),
+            OPRI    =>  self.opri   = data & 0x01,
             _       =>  panic!(),
         }
     }
 }
 
 impl Ppu {
-    pub fn new() -> Self {
+    pub fn new(model: Model) -> Self {
         Ppu {
+            model,
             clock: 0,
             pixels: [0; SCREEN_WIDTH*SCREEN_HEIGHT],
+            colors: vec![0x7FFF; SCREEN_WIDTH*SCREEN_HEIGHT],
+            layers: vec![0; SCREEN_WIDTH*SCREEN_HEIGHT],
             lcdc:   Lcdc::from_bits_truncate(0x91),
             stat:   Stat::empty(),
             scy:    0,
@@ -210,38 +397,118 @@ impl Ppu {
             obp1:   Palette::from(0xFF),
             wy:     0,
             wx:     0,
-            vram:   Ram::new(),
+            vram:   Ram::with_size(match model.is_color() {
+                false   =>  VRAM_BANK_SIZE,
+                true    =>  2 * VRAM_BANK_SIZE,
+            }),
+            vbk:    0,
             oam:    [Oam::new(); OAM_SPRITES],
             oam_dma_started:    false,
+            stat_write_irq:     false,
+            bcps:   0,
+            ocps:   0,
+            bg_palettes:    [0xFF; 64],
+            obj_palettes:   [0xFF; 64],
+            lx:             0,
+            discard:        0,
+            stall:          0,
+            window_active:  false,
+            window_line:    0,
+            fetcher:        Fetcher::new(false),
+            bg_fifo:        VecDeque::with_capacity(16),
+            obj_fifo:       VecDeque::with_capacity(8),
+            line_sprites:   Vec::with_capacity(SPRITES_PER_LINE),
+            opri:   match model.is_color() {
+                false   =>  0x01,
+                true    =>  0x00,
+            },
+            timing:         PpuTiming::new(),
+            last_timing:    PpuTiming::new(),
         }
     }
 
+    // DMG: shades 0-3 after BGP/OBP0/OBP1. CGB: color numbers 0-3 before the palette.
     pub fn get_pixels(&self) -> [u8; SCREEN_WIDTH*SCREEN_HEIGHT] {
         self.pixels
     }
 
+    pub fn get_colors(&self) -> &[u16] {
+        &self.colors
+    }
+
+    pub fn get_layers(&self) -> &[u8] {
+        &self.layers
+    }
+
+    // Counters of the last frame the LCD drew from line 0 to the end of VBlank
+    pub fn timing_stats(&self) -> PpuTiming {
+        self.last_timing
+    }
+
+    pub fn registers(&self) -> PpuRegisters {
+        PpuRegisters {
+            lcdc:   self.read8(LCDC),
+            stat:   self.read8(STAT),
+            scy:    self.read8(SCY),
+            scx:    self.read8(SCX),
+            ly:     self.read8(LY),
+            lyc:    self.read8(LYC),
+            bgp:    self.read8(BGP),
+            obp0:   self.read8(OBP0),
+            obp1:   self.read8(OBP1),
+            wy:     self.read8(WY),
+            wx:     self.read8(WX),
+            bcps:   self.read8(BCPS),
+            ocps:   self.read8(OCPS),
+        }
+    }
+
+    fn vram_addr(&self, bank: usize, addr: usize) -> usize {
+        bank * VRAM_BANK_SIZE + (addr & 0x1FFF)
+    }
+
+    fn read_vram(&self, bank: usize, addr: usize) -> u8 {
+        self.vram.read8(self.vram_addr(bank, addr))
+    }
+
     pub fn tick(&mut self) -> (Option<InterruptKind>, Option<InterruptKind>) {
+        if !self.lcdc.contains(Lcdc::LCD_EN) {
+            return (None, None);
+        }
+
         let mut vblank_irq = false;
         let mut lcdc_irq = self.update_mode();
+        if self.stat_write_irq {
+            self.stat_write_irq = false;
+            lcdc_irq = true;
+        }
+        self.timing.mode_dots[(self.stat.bits & 0x03) as usize] += 4;
+        self.timing.frame_dots += 4;
+
+        if self.ly < SCREEN_HEIGHT as u8 && self.clock >= OAM_SEARCH_CLOCKS {
+            for _ in 0..4 {
+                self.step_dot();
+            }
+        }
         self.clock = self.clock.wrapping_add(4);
 
         if self.clock >= CLOCKS_PER_LINE {
+            if self.window_active {
+                self.window_line = self.window_line.wrapping_add(1);
+            }
+            self.ly = self.ly.wrapping_add(1);
+            self.clock = self.clock.wrapping_sub(CLOCKS_PER_LINE);
+
             if self.ly == SCREEN_HEIGHT as u8 {
                 vblank_irq = true;
-                if self.sprite_on() {
-                    self.build_sprite();
-                }
                 if self.stat.contains(Stat::INTR_M1) {
                     lcdc_irq = true;
                 }
             } else if self.ly >= (SCREEN_HEIGHT as u8 + LCD_BLANK_HEIGHT) {
                 self.ly = 0;
-                self.build_bg();
-            } else if self.ly < SCREEN_HEIGHT as u8 {
-                self.build_bg();
-                if self.window_on() {
-                    self.build_window();
-                }
+                self.window_line = 0;
+                self.last_timing = self.timing;
+                self.timing = PpuTiming::new();
             }
 
             if self.ly == self.lyc {
@@ -250,10 +517,8 @@ impl Ppu {
                     lcdc_irq = true;
                 }
             } else {
-                self.switch_mode(PpuMode::HBlank);
+                self.stat.remove(Stat::LYC_STAT);
             }
-            self.ly = self.ly.wrapping_add(1);
-            self.clock = self.clock.wrapping_sub(CLOCKS_PER_LINE);
         }
 
         match (vblank_irq, lcdc_irq) {
@@ -265,6 +530,11 @@ impl Ppu {
         }
     }
 
+    // True once every pixel of line `ly` has been pushed out
+    pub fn line_done(&self, ly: u8) -> bool {
+        self.ly == ly && self.lx >= SCREEN_WIDTH as u8
+    }
+
     pub fn dma_started(&self) -> bool {
         self.oam_dma_started
     }
@@ -273,6 +543,185 @@ impl Ppu {
         self.oam_dma_started = false;
     }
 
+    pub fn oam_accessible(&self) -> bool {
+        // OAM is locked during mode 2 (searching OAM) and mode 3 (transferring pixels)
+        !self.stat.contains(Stat::MODE_FLAG1)
+    }
+
+    // OAM bug: OAM is scanned as 20 rows of 8 bytes, one row per M-cycle of mode 2.
+    // A write to the bus corrupts the row being scanned with the one before it.
+    pub fn corrupt_oam(&mut self) {
+        if !self.lcdc.contains(Lcdc::LCD_EN) {
+            return;
+        }
+        if let PpuMode::SearchingOAM = self.mode() {
+            // The first row is never corrupted
+            let row = (self.clock / 4) as usize;
+            if row == 0 || row >= OAM_SPRITES / 2 {
+                return;
+            }
+
+            let a = self.oam_word(row, 0);
+            let b = self.oam_word(row - 1, 0);
+            let c = self.oam_word(row - 1, 2);
+            self.set_oam_word(row, 0, ((a ^ c) & (b ^ c)) ^ c);
+            for word in 1..4 {
+                let prev = self.oam_word(row - 1, word);
+                self.set_oam_word(row, word, prev);
+            }
+        }
+    }
+
+    fn oam_word(&self, row: usize, word: usize) -> u16 {
+        let addr = row*8 + word*2;
+        let low = self.oam[addr/4].read8(addr%4) as u16;
+        let high = self.oam[(addr+1)/4].read8((addr+1)%4) as u16;
+        (high << 8) | low
+    }
+
+    fn set_oam_word(&mut self, row: usize, word: usize, data: u16) {
+        let addr = row*8 + word*2;
+        self.oam[addr/4].write8(addr%4, data as u8);
+        self.oam[(addr+1)/4].write8((addr+1)%4, (data >> 8) as u8);
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.clock);
+        w.bytes(&self.pixels);
+        for color in self.colors.iter() {
+            w.u16(*color);
+        }
+        w.u8(self.lcdc.bits);
+        w.u8(self.stat.bits);
+        w.u8(self.scy);
+        w.u8(self.scx);
+        w.u8(self.ly);
+        w.u8(self.lyc);
+        w.u8(self.dma);
+        w.u8(self.bgp.to_u8());
+        w.u8(self.obp0.to_u8());
+        w.u8(self.obp1.to_u8());
+        w.u8(self.wy);
+        w.u8(self.wx);
+        self.vram.save_state(w);
+        w.u8(self.vbk);
+        for attr in self.oam.iter() {
+            save_oam(attr, w);
+        }
+        w.bool(self.oam_dma_started);
+        w.bool(self.stat_write_irq);
+        w.u8(self.bcps);
+        w.u8(self.ocps);
+        w.bytes(&self.bg_palettes);
+        w.bytes(&self.obj_palettes);
+
+        // The line being drawn
+        w.u8(self.lx);
+        w.u8(self.discard);
+        w.u8(self.stall);
+        w.bool(self.window_active);
+        w.u8(self.window_line);
+        w.u8(self.fetcher.state as u8);
+        w.bool(self.fetcher.dot);
+        w.u8(self.fetcher.tilex);
+        w.u8(self.fetcher.tileid);
+        w.u8(self.fetcher.low);
+        w.u8(self.fetcher.high);
+        w.u8(self.fetcher.attrs);
+        w.bool(self.fetcher.window);
+        w.u8(self.bg_fifo.len() as u8);
+        for pixel in self.bg_fifo.iter() {
+            w.u8(pixel.color);
+            w.u8(pixel.palette);
+            w.bool(pixel.priority);
+        }
+        w.u8(self.obj_fifo.len() as u8);
+        for pixel in self.obj_fifo.iter() {
+            w.u8(pixel.color);
+            w.u8(pixel.flags.bits);
+            w.u8(pixel.index);
+        }
+        w.u8(self.line_sprites.len() as u8);
+        for (index, attr) in self.line_sprites.iter() {
+            w.u8(*index);
+            save_oam(attr, w);
+        }
+        w.u8(self.opri);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        self.clock = r.u16()?;
+        r.bytes_into(&mut self.pixels)?;
+        for color in self.colors.iter_mut() {
+            *color = r.u16()?;
+        }
+        self.lcdc   = Lcdc::from_bits_truncate(r.u8()?);
+        self.stat   = Stat::from_bits_truncate(r.u8()?);
+        self.scy    = r.u8()?;
+        self.scx    = r.u8()?;
+        self.ly     = r.u8()?;
+        self.lyc    = r.u8()?;
+        self.dma    = r.u8()?;
+        self.bgp    = Palette::from(r.u8()?);
+        self.obp0   = Palette::from(r.u8()?);
+        self.obp1   = Palette::from(r.u8()?);
+        self.wy     = r.u8()?;
+        self.wx     = r.u8()?;
+        self.vram.load_state(r)?;
+        self.vbk    = r.u8()?;
+        for attr in self.oam.iter_mut() {
+            *attr = load_oam(r)?;
+        }
+        self.oam_dma_started    = r.bool()?;
+        self.stat_write_irq     = r.bool()?;
+        self.bcps   = r.u8()?;
+        self.ocps   = r.u8()?;
+        r.bytes_into(&mut self.bg_palettes)?;
+        r.bytes_into(&mut self.obj_palettes)?;
+
+        self.lx             = r.u8()?;
+        self.discard        = r.u8()?;
+        self.stall          = r.u8()?;
+        self.window_active  = r.bool()?;
+        self.window_line    = r.u8()?;
+        self.fetcher.state = match r.u8()? {
+            0   =>  FetcherState::TileId,
+            1   =>  FetcherState::DataLow,
+            2   =>  FetcherState::DataHigh,
+            _   =>  FetcherState::Push,
+        };
+        self.fetcher.dot    = r.bool()?;
+        self.fetcher.tilex  = r.u8()?;
+        self.fetcher.tileid = r.u8()?;
+        self.fetcher.low    = r.u8()?;
+        self.fetcher.high   = r.u8()?;
+        self.fetcher.attrs  = r.u8()?;
+        self.fetcher.window = r.bool()?;
+        self.bg_fifo.clear();
+        for _ in 0..r.u8()? {
+            self.bg_fifo.push_back(BgPixel {
+                color:      r.u8()?,
+                palette:    r.u8()?,
+                priority:   r.bool()?,
+            });
+        }
+        self.obj_fifo.clear();
+        for _ in 0..r.u8()? {
+            self.obj_fifo.push_back(ObjPixel {
+                color:  r.u8()?,
+                flags:  OamFlags::from_bits_truncate(r.u8()?),
+                index:  r.u8()?,
+            });
+        }
+        self.line_sprites.clear();
+        for _ in 0..r.u8()? {
+            let index = r.u8()?;
+            self.line_sprites.push((index, load_oam(r)?));
+        }
+        self.opri = r.u8()?;
+        Ok(())
+    }
+
     fn bg_tilemap_offset(&self) -> usize {
         match self.lcdc.contains(Lcdc::BG_MAP) {
             false   =>  TILEMAP0_OFFSET,
@@ -315,19 +764,32 @@ impl Ppu {
         }
     }
 
+    fn mode(&self) -> PpuMode {
+        match (self.stat.contains(Stat::MODE_FLAG1), self.stat.contains(Stat::MODE_FLAG0)) {
+            (false, false)  =>  PpuMode::HBlank,
+            (false, true)   =>  PpuMode::VBlank,
+            (true, false)   =>  PpuMode::SearchingOAM,
+            (true, true)    =>  PpuMode::TransferPixels,
+        }
+    }
+
     fn update_mode(&mut self) -> bool {
         let mut lcdc_irq = false;
-        if self.ly > SCREEN_HEIGHT as u8 {
+        if self.ly >= SCREEN_HEIGHT as u8 {
             self.switch_mode(PpuMode::VBlank);
-        } else if self.clock <= 80 {
+        } else if self.clock < OAM_SEARCH_CLOCKS {
             self.switch_mode(PpuMode::SearchingOAM);
-        } else if self.clock >= 167 && self.clock <= 291 {
+        } else if self.clock == OAM_SEARCH_CLOCKS {
+            self.start_line();
             self.switch_mode(PpuMode::TransferPixels);
-        } else {
-            self.switch_mode(PpuMode::HBlank);
-            if self.stat.contains(Stat::INTR_M0) {
-                lcdc_irq = true;
+        } else if self.lx >= SCREEN_WIDTH as u8 {
+            // Mode 3 lasts until the last pixel of the line is pushed out
+            if let PpuMode::TransferPixels = self.mode() {
+                if self.stat.contains(Stat::INTR_M0) {
+                    lcdc_irq = true;
+                }
             }
+            self.switch_mode(PpuMode::HBlank);
         }
 
         lcdc_irq
@@ -348,79 +810,271 @@ impl Ppu {
         self.lcdc.contains(Lcdc::WIN_EN)
     }
 
-    fn build_bg(&mut self) {
-        for x in 0..SCREEN_WIDTH as u8 {
-            let y = self.ly.wrapping_add(self.scy) as u16 / 8 * 32;
-            let index = x.wrapping_add(self.scx) as u16 / 8 % 32 + y;
-            let tileid = self.get_bg_tileid(index);
-            let color = self.get_bg_color(tileid, 
-                            x.wrapping_add(self.scx)%8, 
-                            self.ly.wrapping_add(self.scy)%8);
-            let base = (self.ly as usize * SCREEN_WIDTH + x as usize)%(SCREEN_HEIGHT*SCREEN_WIDTH);
-            self.pixels[base] = self.get_bg_palette()[color as usize];
+    fn start_line(&mut self) {
+        self.lx = 0;
+        self.discard = self.scx & 0x07;
+        self.stall = 0;
+        self.window_active = false;
+        self.fetcher = Fetcher::new(false);
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.search_oam();
+    }
+
+    // Picks the first 10 sprites in OAM that are on the current line, in OAM order.
+    // OAM Y is the sprite's top line + 16, and the height (8 or 16) is LCDC's at the scan.
+    // Sprites off screen horizontally still count towards the 10.
+    fn search_oam(&mut self) {
+        let height = self.sprite_size() as u16;
+        let line = self.ly as u16 + 16;
+
+        self.line_sprites.clear();
+        for (index, attr) in self.oam.iter().enumerate() {
+            if self.line_sprites.len() >= SPRITES_PER_LINE {
+                break;
+            }
+            if line >= attr.y as u16 && line < attr.y as u16 + height {
+                self.line_sprites.push((index as u8, *attr));
+            }
         }
     }
 
-    fn build_sprite(&mut self) {
-        let height = self.sprite_size();
-        for attr in self.oam.iter() {
-            if attr.x == 0 {
-                continue;
+    fn step_dot(&mut self) {
+        if self.lx >= SCREEN_WIDTH as u8 {
+            return;
+        }
+        if self.stall > 0 {
+            self.stall -= 1;
+            return;
+        }
+
+        self.check_window();
+        self.step_fetcher();
+        self.shift_pixel();
+    }
+
+    // The window restarts the fetcher once the line reaches WX-7
+    fn check_window(&mut self) {
+        if self.window_active || !self.window_on() || self.ly < self.wy {
+            return;
+        }
+        if (self.lx as u16 + 7) < self.wx as u16 {
+            return;
+        }
+
+        self.window_active = true;
+        self.fetcher = Fetcher::new(true);
+        self.bg_fifo.clear();
+        self.discard = 7u8.saturating_sub(self.wx);
+    }
+
+    fn step_fetcher(&mut self) {
+        // Every step but the push takes two dots
+        if self.fetcher.state != FetcherState::Push {
+            self.fetcher.dot = !self.fetcher.dot;
+            if self.fetcher.dot {
+                return;
             }
-            for x in 0..8 as u8 {
-                for y in 0.. height {
-                    let mut posx = x;
-                    let mut posy = y;
-
-                    if attr.is_xflip() {
-                        posx = 7 - x;
-                    }
-                    if attr.is_yflip() {
-                        posy = 7 - y;
-                    }
-
-                    if posx.wrapping_add(attr.offsetx()) >= SCREEN_WIDTH as u8 {
-                        continue;
-                    }
-                    if posy.wrapping_add(attr.offsety()) >= SCREEN_HEIGHT as u8 {
-                        continue;
-                    }
-
-                    let color = self.get_sprite_color(attr.tileid(), x%8, y%height, height);
-                    let base = ((posx.wrapping_add(attr.offsetx()) as usize
-                                + (posy.wrapping_add(attr.offsety()) as usize * SCREEN_WIDTH)))
-                                %(SCREEN_HEIGHT*SCREEN_WIDTH);
-                    if color != 0 {
-                        self.pixels[base] = self.get_sprite_palette(*attr)[color as usize];
-                    }
+        }
+
+        match self.fetcher.state {
+            FetcherState::TileId    =>  {
+                let addr = self.fetch_map_addr();
+                self.fetcher.tileid = self.read_vram(0, addr);
+                self.fetcher.attrs = match self.model.is_color() {
+                    false   =>  0,
+                    true    =>  self.read_vram(1, addr),
+                };
+                self.fetcher.state = FetcherState::DataLow;
+            },
+            FetcherState::DataLow   =>  {
+                self.fetcher.low = self.read_vram(self.fetch_bank(), self.fetch_tile_addr());
+                self.fetcher.state = FetcherState::DataHigh;
+            },
+            FetcherState::DataHigh  =>  {
+                self.fetcher.high = self.read_vram(self.fetch_bank(), self.fetch_tile_addr() + 1);
+                self.fetcher.state = FetcherState::Push;
+            },
+            FetcherState::Push      =>  {
+                // The fetcher waits until the FIFO has room for a whole tile
+                if !self.bg_fifo.is_empty() {
+                    return;
                 }
-            }
+                let attrs = self.fetcher.attrs;
+                for x in 0..8 {
+                    let bit = match attrs & 0x20 != 0 {
+                        false   =>  7 - x,
+                        true    =>  x,
+                    };
+                    let lsb = (self.fetcher.low >> bit) & 0x01;
+                    let msb = (self.fetcher.high >> bit) & 0x01;
+                    self.bg_fifo.push_back(BgPixel {
+                        color:      (msb<<1)+lsb,
+                        palette:    attrs & 0x07,
+                        priority:   attrs & 0x80 != 0,
+                    });
+                }
+                self.fetcher.tilex = self.fetcher.tilex.wrapping_add(1);
+                self.fetcher.state = FetcherState::TileId;
+            },
         }
     }
 
-    fn build_window(&mut self) {
-        if (self.wx >= 167) && (self.wy >= 144) {
+    // SCX and SCY are read on every tile fetch, so writes during a frame (or even a line)
+    // take effect from the next tile on. Only the fine X scroll is latched at the start of the line.
+    fn fetch_map_addr(&self) -> usize {
+        let (offset, x, y) = match self.fetcher.window {
+            false   =>  (self.bg_tilemap_offset(),
+                         (self.scx / 8).wrapping_add(self.fetcher.tilex) & 0x1F,
+                         self.ly.wrapping_add(self.scy) / 8),
+            true    =>  (self.window_tilemap_offset(),
+                         self.fetcher.tilex & 0x1F,
+                         self.window_line / 8),
+        };
+
+        offset + y as usize * 32 + x as usize
+    }
+
+    fn fetch_bank(&self) -> usize {
+        (self.fetcher.attrs as usize >> 3) & 0x01
+    }
+
+    fn fetch_tile_addr(&self) -> usize {
+        let mut row = match self.fetcher.window {
+            false   =>  self.ly.wrapping_add(self.scy) % 8,
+            true    =>  self.window_line % 8,
+        };
+        if self.fetcher.attrs & 0x40 != 0 {
+            row = 7 - row;
+        }
+
+        self.get_tile_addr(self.fetcher.tileid) + row as usize * 2
+    }
+
+    fn shift_pixel(&mut self) {
+        if self.bg_fifo.is_empty() {
             return;
         }
-        if self.ly < self.wy {
+
+        // Fine scroll: the first SCX&7 pixels of the line are thrown away
+        if self.discard > 0 {
+            self.bg_fifo.pop_front();
+            self.discard -= 1;
             return;
         }
 
-        for x in 0..SCREEN_WIDTH as u8 {
-            let posx = self.wx.wrapping_sub(7);
-            if x < posx {
-                continue;
+        if self.sprite_on() && self.fetch_sprites() {
+            self.stall = SPRITE_FETCH_STALL;
+            return;
+        }
+
+        let bg = match self.bg_fifo.pop_front() {
+            Some(bg)    =>  bg,
+            None        =>  return,
+        };
+        let obj = self.obj_fifo.pop_front();
+        let base = self.ly as usize * SCREEN_WIDTH + self.lx as usize;
+
+        match self.model {
+            Model::Dmg | Model::Mgb =>  {
+                // Color numbers go through BGP/OBP0/OBP1. With LCDC bit 0 clear the
+                // background is blank white whatever BGP says.
+                let (bg, shade) = match self.lcdc.contains(Lcdc::BG_EN) {
+                    true    =>  (bg.color, self.get_bg_palette()[bg.color as usize]),
+                    false   =>  (0, 0),
+                };
+                let (layer, shade) = match obj {
+                    Some(obj) if obj.color != 0 && !(obj.flags.contains(OamFlags::PRIORITY) && bg != 0)
+                            =>  (1 + obj.flags.contains(OamFlags::PALETTE_NO) as u8,
+                                 self.get_sprite_palette(obj.flags)[obj.color as usize]),
+                    _       =>  (0, shade),
+                };
+                self.pixels[base] = shade;
+                self.layers[base] = layer;
+            },
+            Model::Cgb | Model::Agb =>  {
+                // LCDC bit 0 takes away the background's priority instead of hiding it
+                let bg_on_top = |obj: &ObjPixel| {
+                    self.lcdc.contains(Lcdc::BG_EN) && bg.color != 0 &&
+                    (bg.priority || obj.flags.contains(OamFlags::PRIORITY))
+                };
+                let (palettes, palette, color) = match obj {
+                    Some(obj) if obj.color != 0 && !bg_on_top(&obj)
+                            =>  (&self.obj_palettes, obj.flags.bits & 0x07, obj.color),
+                    _       =>  (&self.bg_palettes, bg.palette, bg.color),
+                };
+                self.colors[base] = cgb_color(palettes, palette, color);
+                self.pixels[base] = color;
+            },
+        }
+        self.lx += 1;
+    }
+
+    // Loads every sprite starting at the current pixel into the sprite FIFO.
+    // Returns true if any sprite was fetched.
+    fn fetch_sprites(&mut self) -> bool {
+        let lx = self.lx as i16;
+        let mut fetched = false;
+
+        let mut i = 0;
+        while i < self.line_sprites.len() {
+            let (index, attr) = self.line_sprites[i];
+            let x = attr.x as i16 - 8;
+            // Sprites partly off the left edge are fetched at the first pixel
+            if x == lx || (lx == 0 && x < 0 && x > -8) {
+                self.line_sprites.remove(i);
+                self.merge_sprite(index, attr, (lx - x) as u8);
+                if let Some(count) = self.timing.line_sprites.get_mut(self.ly as usize) {
+                    *count += 1;
+                }
+                fetched = true;
+            } else {
+                i += 1;
+            }
+        }
+
+        fetched
+    }
+
+    fn merge_sprite(&mut self, index: u8, attr: Oam, skip: u8) {
+        let height = self.sprite_size();
+        let mut row = self.ly.wrapping_add(16).wrapping_sub(attr.y) & (height - 1);
+        if attr.is_yflip() {
+            row = height - 1 - row;
+        }
+        let tileid = match height {
+            16  =>  attr.tileid() & 0xFE,
+            _   =>  attr.tileid(),
+        };
+        let bank = match self.model.is_color() {
+            true if attr.flags.contains(OamFlags::VRAM_BANK)    =>  1,
+            _                                                   =>  0,
+        };
+        let addr = TILEDATA1_OFFSET + tileid as usize * 0x10 + row as usize * 2;
+        let low = self.read_vram(bank, addr);
+        let high = self.read_vram(bank, addr+1);
+
+        for x in skip..8 {
+            let bit = match attr.is_xflip() {
+                false   =>  7 - x,
+                true    =>  x,
+            };
+            let pixel = ObjPixel {
+                color:  (((high >> bit) & 0x01) << 1) + ((low >> bit) & 0x01),
+                flags:  attr.flags,
+                index,
+            };
+
+            // In X coordinate mode the sprite fetched first (further left) wins.
+            // In OAM index mode the lower index wins wherever the sprites start.
+            let i = (x - skip) as usize;
+            if i >= self.obj_fifo.len() {
+                self.obj_fifo.push_back(pixel);
+            } else if self.obj_fifo[i].color == 0 ||
+                      (self.opri & 0x01 == 0 && pixel.color != 0 && index < self.obj_fifo[i].index) {
+                self.obj_fifo[i] = pixel;
             }
-            let y = self.ly.wrapping_sub(self.wy) as u16 / 8 * 32;
-            let index = x.wrapping_sub(posx) as u16 / 8 % 32 + y;
-            let tileid = self.get_window_tileid(index);
-            let color = self.get_bg_color(tileid, 
-                            x.wrapping_sub(posx)%8, 
-                            self.ly.wrapping_sub(self.wy)%8);
-            let base = self.ly as usize * SCREEN_WIDTH + x as usize;
-            self.pixels[base] = self.get_bg_palette()[color as usize];
         }
-        
     }
 
     fn get_bg_palette(&self) -> [u8; 4] {
@@ -428,8 +1082,8 @@ impl Ppu {
             self.bgp.dot_10.to_u8(), self.bgp.dot_11.to_u8()]
     }
 
-    fn get_sprite_palette(&self, oam: Oam) -> [u8; 4] {
-        if oam.flags.contains(OamFlags::PALETTE_NO) {
+    fn get_sprite_palette(&self, flags: OamFlags) -> [u8; 4] {
+        if flags.contains(OamFlags::PALETTE_NO) {
             return [self.obp1.dot_00.to_u8(), self.obp1.dot_01.to_u8(),
                     self.obp1.dot_10.to_u8(), self.obp1.dot_11.to_u8()]
         }
@@ -438,16 +1092,6 @@ impl Ppu {
             self.obp0.dot_10.to_u8(), self.obp0.dot_11.to_u8()]
     }
 
-    fn get_bg_tileid(&self, index: u16) -> u8 {
-        let addr = index as usize + self.bg_tilemap_offset();
-        self.read8(addr)
-    }
-
-    fn get_window_tileid(&self, index: u16) -> u8 {
-        let addr = index as usize + self.window_tilemap_offset();
-        self.read8(addr)
-    }
-
     fn get_tile_addr(&self, tileid: u8) -> usize {
         let offset = self.tiledata_offset();
 
@@ -457,40 +1101,33 @@ impl Ppu {
 
         offset + (tileid as usize * 0x10)
     }
+}
 
-    fn get_bg_color(&self, tileid: u8, x: u8, y: u8) -> u8 {
-        let addr = self.get_tile_addr(tileid);
-        let mut pixels = Vec::new();
+// Writes a CGB palette data register, advancing the index if auto-increment (bit 7) is set
+fn write_palette(palettes: &mut [u8; 64], index: &mut u8, data: u8) {
+    palettes[(*index & 0x3F) as usize] = data;
+    if *index & 0x80 != 0 {
+        *index = 0x80 | (index.wrapping_add(1) & 0x3F);
+    }
+}
 
-        for i in 0..8 as usize {
-            let line1 = self.read8(addr+i*2);
-            let line2 = self.read8(addr+i*2+1);
-            for j in 0..8 {
-                let lsb = (line1 >> (7-j)) & 0x01;
-                let msb = (line2 >> (7-j)) & 0x01;
-                pixels.push((msb<<1)+lsb);
-            }
-        }
+fn cgb_color(palettes: &[u8; 64], palette: u8, color: u8) -> u16 {
+    let i = (palette as usize * 4 + color as usize) * 2;
+    palettes[i] as u16 | (palettes[i+1] as u16) << 8
+}
 
-        pixels[(x+y*8) as usize]
+fn save_oam(attr: &Oam, w: &mut StateWriter) {
+    for i in 0..4 {
+        w.u8(attr.read8(i));
     }
-    
-    fn get_sprite_color(&self, tileid: u8, x: u8, y: u8, height: u8) -> u8 {
-        let addr = tileid as usize * 0x10 + TILEDATA1_OFFSET;
-        let mut pixels = Vec::new();
-
-        for i in 0..height as usize {
-            let line1 = self.read8(addr+i*2);
-            let line2 = self.read8(addr+i*2+1);
-            for j in 0..8 {
-                let lsb = (line1 >> (7-j)) & 0x01;
-                let msb = (line2 >> (7-j)) & 0x01;
-                pixels.push((msb<<1)+lsb);
-            }
-        }
+}
 
-        pixels[(x+y*8) as usize]
+fn load_oam(r: &mut StateReader) -> Result<Oam, GbError> {
+    let mut attr = Oam::new();
+    for i in 0..4 {
+        attr.write8(i, r.u8()?);
     }
+    Ok(attr)
 }
 
 bitflags! {
@@ -535,14 +1172,6 @@ impl Oam {
     pub fn tileid(&self) -> u8 {
         self.tile
     }
-
-    pub fn offsetx(&self) -> u8 {
-        self.x.wrapping_sub(8)
-    }
-    
-    pub fn offsety(&self) -> u8 {
-        self.y.wrapping_sub(16)
-    }
 }
 
 impl Io for Oam {
@@ -567,3 +1196,170 @@ impl Io for Oam {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Line 0 with every tile showing only its leftmost pixel, in shade 3
+    fn first_line(scx: u8) -> Vec<u8> {
+        let mut ppu = Ppu::new(Model::Dmg);
+        for row in 0..8 {
+            ppu.write8(VRAM_START + row * 2, 0x80);
+            ppu.write8(VRAM_START + 1 + row * 2, 0x80);
+        }
+        ppu.write8(SCX, scx);
+        for _ in 0..CLOCKS_PER_LINE / 4 {
+            ppu.tick();
+        }
+        ppu.get_pixels()[..SCREEN_WIDTH].to_vec()
+    }
+
+    #[test]
+    fn fine_scroll_shifts_background() {
+        let unscrolled = first_line(0);
+        let scrolled = first_line(3);
+        assert_eq!(unscrolled[0], 3);
+        assert_eq!(&scrolled[..SCREEN_WIDTH - 3], &unscrolled[3..]);
+        assert_eq!(scrolled.iter().position(|&shade| shade == 3), Some(5));
+    }
+
+    // Line 0 on CGB with sprite 0 in color 1 and sprite 1 in color 2 at the given X
+    fn overlapping_sprites(opri: u8, x0: u8, x1: u8) -> Vec<u8> {
+        let mut ppu = Ppu::new(Model::Cgb);
+        for row in 0..8 {
+            ppu.write8(VRAM_START + 0x10 + row * 2, 0xFF);
+            ppu.write8(VRAM_START + 0x21 + row * 2, 0xFF);
+        }
+        for (i, &(x, tile)) in [(x0, 1), (x1, 2)].iter().enumerate() {
+            ppu.write8(OAM_START + i * 4, 16);
+            ppu.write8(OAM_START + i * 4 + 1, x);
+            ppu.write8(OAM_START + i * 4 + 2, tile);
+        }
+        ppu.write8(LCDC, 0x93);
+        ppu.write8(OPRI, opri);
+        for _ in 0..CLOCKS_PER_LINE / 4 {
+            ppu.tick();
+        }
+        ppu.get_pixels()[..16].to_vec()
+    }
+
+    #[test]
+    fn opri_selects_sprite_priority() {
+        // At the same X the lower OAM index wins in both modes
+        for &opri in [0, 1].iter() {
+            let line = overlapping_sprites(opri, 8, 8);
+            assert_eq!(&line[..8], &[1; 8]);
+        }
+
+        // Sprite 1 starts further left: it wins the overlap only in X coordinate mode
+        let line = overlapping_sprites(0, 12, 8);
+        assert_eq!(&line[..8], &[2, 2, 2, 2, 1, 1, 1, 1]);
+        let line = overlapping_sprites(1, 12, 8);
+        assert_eq!(&line[..12], &[2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1]);
+    }
+
+    // Line 0 with every tile showing colors 0, 1, 2, 3, 0, 1, 2, 3 through `bgp`
+    fn bg_line(bgp: u8) -> Vec<u8> {
+        let mut ppu = Ppu::new(Model::Dmg);
+        for row in 0..8 {
+            ppu.write8(VRAM_START + row * 2, 0x55);
+            ppu.write8(VRAM_START + 1 + row * 2, 0x33);
+        }
+        ppu.write8(BGP, bgp);
+        for _ in 0..CLOCKS_PER_LINE / 4 {
+            ppu.tick();
+        }
+        ppu.get_pixels()[..SCREEN_WIDTH].to_vec()
+    }
+
+    #[test]
+    fn pixels_are_shades_after_bgp() {
+        let line = bg_line(0xE4);
+        assert!(line.iter().all(|&shade| shade <= 3));
+        assert_eq!(&line[..8], &[0, 1, 2, 3, 0, 1, 2, 3]);
+        assert_eq!(&bg_line(0xFC)[..4], &[0, 3, 3, 3]);
+
+        let intensities: Vec<u8> = (0..4).map(shade_to_intensity).collect();
+        assert_eq!(intensities, vec![0xFF, 0xAA, 0x55, 0x00]);
+        assert_eq!(shade_to_rgba(3), COLORS[3]);
+    }
+
+    #[test]
+    fn inverted_bgp_flips_shades() {
+        let normal = bg_line(0xE4);
+        let inverted = bg_line(0x1B);
+        assert!(normal.iter().zip(inverted.iter()).all(|(a, b)| *b == 3 - *a));
+
+        // With the background off it is white whatever BGP says
+        let mut ppu = Ppu::new(Model::Dmg);
+        ppu.write8(BGP, 0x1B);
+        ppu.write8(LCDC, 0x90);
+        for _ in 0..CLOCKS_PER_LINE / 4 {
+            ppu.tick();
+        }
+        assert!(ppu.get_pixels()[..SCREEN_WIDTH].iter().all(|&shade| shade == 0));
+    }
+
+    #[test]
+    fn oam_scan_keeps_first_ten_tall_sprites() {
+        let mut ppu = Ppu::new(Model::Dmg);
+        // Tile 3, the lower half of 8x16 tile 2, is solid color 3
+        for row in 0..16 {
+            ppu.write8(VRAM_START + 0x30 + row, 0xFF);
+        }
+        // 12 sprites side by side whose lower half covers line 0
+        for i in 0..12 {
+            ppu.write8(OAM_START + i * 4, 8);
+            ppu.write8(OAM_START + i * 4 + 1, 8 + 8 * i as u8);
+            ppu.write8(OAM_START + i * 4 + 2, 2);
+        }
+        ppu.write8(BGP, 0xE4);
+        ppu.write8(OBP0, 0xE4);
+        ppu.write8(LCDC, 0x97);
+        for _ in 0..CLOCKS_PER_LINE / 4 {
+            ppu.tick();
+        }
+        let line = &ppu.get_pixels()[..96];
+        assert!(line[..80].iter().all(|&pixel| pixel == 3));
+        assert!(line[80..].iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn scy_written_in_hblank_moves_later_lines() {
+        let mut ppu = Ppu::new(Model::Dmg);
+        // Only the top row of tile 0 is set
+        ppu.write8(VRAM_START, 0xFF);
+        ppu.write8(VRAM_START + 1, 0xFF);
+        while !(ppu.read8(LY) == 1 && ppu.read8(STAT) & 0x03 == 0) {
+            ppu.tick();
+        }
+        ppu.write8(SCY, 1);
+        while ppu.read8(LY) < 10 {
+            ppu.tick();
+        }
+        let pixels = ppu.get_pixels();
+        let lit: Vec<usize> = (0..9).filter(|y| pixels[y * SCREEN_WIDTH] != 0).collect();
+        assert_eq!(lit, vec![0, 7]);
+    }
+
+    #[test]
+    fn frame_timing_matches_hardware() {
+        let mut ppu = Ppu::new(Model::Dmg);
+        // Three sprites on lines 0-7
+        for i in 0..3 {
+            ppu.write8(OAM_START + i * 4, 16);
+            ppu.write8(OAM_START + i * 4 + 1, 8 + 20 * i as u8);
+        }
+        ppu.write8(LCDC, 0x93);
+        for _ in 0..2 * 70224 / 4 + 10 {
+            ppu.tick();
+        }
+        let timing = ppu.timing_stats();
+        assert_eq!(timing.frame_dots, 70224);
+        assert_eq!(timing.mode_dots[2], 80 * SCREEN_HEIGHT as u32);
+        assert_eq!(timing.mode_dots[1], 10 * 456);
+        assert_eq!(timing.mode_dots[0] + timing.mode_dots[3], 376 * SCREEN_HEIGHT as u32);
+        assert_eq!(&timing.line_sprites[..9], &[3, 3, 3, 3, 3, 3, 3, 3, 0]);
+    }
+}
@@ -1,17 +1,37 @@
 use crate::core::io::Io;
+use crate::core::error::GbError;
+use crate::core::state::{ StateReader, StateWriter };
 
 const RAM_SIZE: usize   = 8192;
 
 pub struct Ram {
-    ram:    [u8; RAM_SIZE],
+    ram:    Vec<u8>,
+}
+
+impl Default for Ram {
+    fn default() -> Self {
+        Ram::new()
+    }
 }
 
 impl Ram {
     pub fn new() -> Self {
+        Ram::with_size(RAM_SIZE)
+    }
+
+    pub fn with_size(size: usize) -> Self {
         Ram {
-            ram:    [0; RAM_SIZE]
+            ram:    vec![0; size]
         }
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        r.bytes_into(&mut self.ram)
+    }
 }
 
 impl Io for Ram {
@@ -22,4 +42,4 @@ impl Io for Ram {
     fn write8(&mut self, addr: usize, data: u8) {
         self.ram[addr] = data;
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,218 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::core::state::{ StateReader, StateWriter };
+use crate::core::error::GbError;
+
+// The RTC registers as the game selects them through 0x4000-0x5FFF
+pub const RTC_SECONDS:  u8 = 0x08;
+pub const RTC_MINUTES:  u8 = 0x09;
+pub const RTC_HOURS:    u8 = 0x0A;
+pub const RTC_DAY_LOW:  u8 = 0x0B;
+pub const RTC_DAY_HIGH: u8 = 0x0C;
+
+const DAY_HIGH_BIT: u8 = 0x01;
+const HALT:         u8 = 0x40;
+const DAY_CARRY:    u8 = 0x80;
+
+// Registers, latched registers (4 bytes each) and a 64-bit UNIX timestamp,
+// the layout BGB and VBA-M append to the battery save
+pub const RTC_SAVE_SIZE: usize = 48;
+
+// Seconds since the UNIX epoch. It doesn't depend on the time zone, so moving
+// between zones or DST changes don't make the clock jump.
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// The MBC3 real-time clock. It follows the wall clock rather than emulated time,
+// so it keeps counting while the emulator is closed, like the cartridge's battery does.
+// Every method takes the current time to keep it testable.
+pub struct Rtc {
+    seconds:    u8,
+    minutes:    u8,
+    hours:      u8,
+    // 9-bit day counter
+    days:       u16,
+    halt:       bool,
+    carry:      bool,
+    // What the game reads, copied from the counters by latching
+    latched:    [u8; 5],
+    // When the counters were last brought up to date
+    updated:    u64,
+}
+
+impl Rtc {
+    pub fn new(now: u64) -> Self {
+        Rtc {
+            seconds:    0,
+            minutes:    0,
+            hours:      0,
+            days:       0,
+            halt:       false,
+            carry:      false,
+            latched:    [0; 5],
+            updated:    now,
+        }
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            (self.days >> 8) as u8 & DAY_HIGH_BIT
+                | if self.halt { HALT } else { 0 }
+                | if self.carry { DAY_CARRY } else { 0 },
+        ]
+    }
+
+    // Counts the seconds since the last update, unless the halt bit stops the clock
+    pub fn update(&mut self, now: u64) {
+        if !self.halt {
+            self.advance(now.saturating_sub(self.updated));
+        }
+        self.updated = now;
+    }
+
+    // Day 511 rolls over to 0 and sets the carry bit, which stays set until the game clears it
+    fn advance(&mut self, secs: u64) {
+        let seconds = self.seconds as u64 + secs;
+        self.seconds = (seconds % 60) as u8;
+        let minutes = self.minutes as u64 + seconds / 60;
+        self.minutes = (minutes % 60) as u8;
+        let hours = self.hours as u64 + minutes / 60;
+        self.hours = (hours % 24) as u8;
+        let days = self.days as u64 + hours / 24;
+        if days > 0x1FF {
+            self.carry = true;
+        }
+        self.days = (days % 0x200) as u16;
+    }
+
+    // Writing 0x00 then 0x01 to 0x6000-0x7FFF copies the counters to what the game reads
+    pub fn latch(&mut self, now: u64) {
+        self.update(now);
+        self.latched = self.registers();
+    }
+
+    pub fn read(&self, reg: u8) -> u8 {
+        match reg {
+            RTC_SECONDS ..= RTC_DAY_HIGH    =>  self.latched[(reg - RTC_SECONDS) as usize],
+            _                               =>  0xFF,
+        }
+    }
+
+    pub fn write(&mut self, reg: u8, data: u8, now: u64) {
+        self.update(now);
+        match reg {
+            RTC_SECONDS     =>  self.seconds = data & 0x3F,
+            RTC_MINUTES     =>  self.minutes = data & 0x3F,
+            RTC_HOURS       =>  self.hours = data & 0x1F,
+            RTC_DAY_LOW     =>  self.days = self.days & 0x100 | data as u16,
+            RTC_DAY_HIGH    =>  {
+                self.days = ((data & DAY_HIGH_BIT) as u16) << 8 | self.days & 0xFF;
+                self.halt = data & HALT != 0;
+                self.carry = data & DAY_CARRY != 0;
+            },
+            _               =>  (),
+        }
+    }
+
+    // The counters as of the last update, with that time, so loading can catch up from it
+    pub fn save(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(RTC_SAVE_SIZE);
+        for reg in self.registers().iter().chain(self.latched.iter()) {
+            data.extend_from_slice(&(*reg as u32).to_le_bytes());
+        }
+        data.extend_from_slice(&self.updated.to_le_bytes());
+        data
+    }
+
+    // Restores save() and counts the time that passed since, e.g. while the emulator was closed
+    pub fn load(&mut self, data: &[u8], now: u64) {
+        if data.len() < RTC_SAVE_SIZE {
+            return;
+        }
+        let reg = |i: usize| data[i * 4];
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&data[40..48]);
+
+        *self = Rtc::new(u64::from_le_bytes(timestamp));
+        for i in 0..5 {
+            self.write(RTC_SECONDS + i as u8, reg(i), self.updated);
+            self.latched[i] = reg(5 + i);
+        }
+        self.update(now);
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.registers());
+        w.bytes(&self.latched);
+        w.u64(self.updated);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader, now: u64) -> Result<(), GbError> {
+        let mut registers = [0; 5];
+        r.bytes_into(&mut registers)?;
+        r.bytes_into(&mut self.latched)?;
+        self.updated = r.u64()?;
+        for (i, data) in registers.iter().enumerate() {
+            self.write(RTC_SECONDS + i as u8, *data, self.updated);
+        }
+        self.update(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T0: u64 = 1_600_000_000;
+
+    fn latched(rtc: &mut Rtc, now: u64) -> Vec<u8> {
+        rtc.latch(now);
+        (RTC_SECONDS..=RTC_DAY_HIGH).map(|reg| rtc.read(reg)).collect()
+    }
+
+    #[test]
+    fn reload_counts_elapsed_time() {
+        let mut rtc = Rtc::new(T0);
+        rtc.write(RTC_MINUTES, 59, T0);
+        rtc.write(RTC_SECONDS, 50, T0);
+        let save = rtc.save();
+
+        let mut reloaded = Rtc::new(0);
+        reloaded.load(&save, T0 + 75);
+        assert_eq!(latched(&mut reloaded, T0 + 75), vec![5, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn halted_clock_stays_put() {
+        let mut rtc = Rtc::new(T0);
+        rtc.write(RTC_SECONDS, 10, T0);
+        rtc.write(RTC_DAY_HIGH, HALT, T0);
+        let save = rtc.save();
+
+        let mut reloaded = Rtc::new(0);
+        reloaded.load(&save, T0 + 3600);
+        assert_eq!(latched(&mut reloaded, T0 + 7200), vec![10, 0, 0, 0, HALT]);
+        // Clearing the halt bit starts it again from there
+        reloaded.write(RTC_DAY_HIGH, 0, T0 + 7200);
+        assert_eq!(latched(&mut reloaded, T0 + 7201), vec![11, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn day_counter_wrap_sets_carry() {
+        let mut rtc = Rtc::new(T0);
+        rtc.write(RTC_DAY_LOW, 0xFF, T0);
+        rtc.write(RTC_DAY_HIGH, DAY_HIGH_BIT, T0);
+        rtc.write(RTC_HOURS, 23, T0);
+        rtc.write(RTC_MINUTES, 59, T0);
+        rtc.write(RTC_SECONDS, 59, T0);
+        assert_eq!(latched(&mut rtc, T0 + 1), vec![0, 0, 0, 0, DAY_CARRY]);
+        // The carry stays until the game clears it
+        assert_eq!(latched(&mut rtc, T0 + 86400 + 1)[4], DAY_CARRY);
+    }
+}
@@ -0,0 +1,70 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Path of the battery save that belongs to a ROM: "game.gb" -> "game.sav"
+pub fn save_path(rom: &Path) -> PathBuf {
+    rom.with_extension("sav")
+}
+
+// Path of a save state slot: "game.gb" -> "game.ss1"
+pub fn state_path(rom: &Path, slot: u8) -> PathBuf {
+    rom.with_extension(format!("ss{}", slot))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+pub fn read_save(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data)                                            =>  Ok(Some(data)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound   =>  Ok(None),
+        Err(e)                                              =>  Err(e),
+    }
+}
+
+// Writes the save to "<path>.tmp" first and renames it over the target,
+// so the previous save stays intact if we die halfway through.
+// The previous save is kept as "<path>.bak".
+pub fn write_save(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = with_suffix(path, ".tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+
+    if path.exists() {
+        fs::copy(path, with_suffix(path, ".bak"))?;
+    }
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupted_write_keeps_save() {
+        let path = std::env::temp_dir().join("gbr_interrupted_write_keeps_save.sav");
+        let (tmp, bak) = (with_suffix(&path, ".tmp"), with_suffix(&path, ".bak"));
+        write_save(&path, b"good").unwrap();
+
+        // Killed while writing: only the temporary file is half written
+        fs::write(&tmp, b"ha").unwrap();
+        assert_eq!(read_save(&path).unwrap().unwrap(), b"good");
+
+        write_save(&path, b"newer").unwrap();
+        let (save, backup) = (fs::read(&path).unwrap(), fs::read(&bak).unwrap());
+        let tmp_left = tmp.exists();
+        for file in [&path, &bak].iter() {
+            fs::remove_file(file).unwrap();
+        }
+        assert_eq!(save, b"newer");
+        assert_eq!(backup, b"good");
+        assert!(!tmp_left);
+    }
+}
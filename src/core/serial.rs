@@ -0,0 +1,401 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+
+use log::warn;
+
+use crate::core::addr::{ SB, SC };
+use crate::core::io::Io;
+use crate::core::error::GbError;
+use crate::core::model::Model;
+use crate::core::state::{ StateReader, StateWriter };
+
+// 8 bits at 8192Hz, in ticks of 4 clocks
+const TRANSFER_TICKS:       u32 = 1024;
+// 8 bits at 262144Hz, the fast clock of the CGB
+const FAST_TRANSFER_TICKS:  u32 = 32;
+// How long the internal clock side waits for the peer before giving up
+const LINK_TIMEOUT:     u32 = TRANSFER_TICKS * 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkMessage {
+    // Sent by the side driving the clock
+    Transfer(u8),
+    // The other side's byte, sent back in answer to a Transfer
+    Reply(u8),
+}
+
+// Connection to the Game Boy on the other end of the cable.
+// Errors mean the peer is gone.
+pub trait SerialLink {
+    fn send(&mut self, message: LinkMessage) -> io::Result<()>;
+    fn try_recv(&mut self) -> io::Result<Option<LinkMessage>>;
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "link cable disconnected")
+}
+
+// Links two emulators running in the same process
+pub struct ChannelLink {
+    tx: Sender<LinkMessage>,
+    rx: Receiver<LinkMessage>,
+}
+
+impl ChannelLink {
+    pub fn pair() -> (ChannelLink, ChannelLink) {
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+
+        (ChannelLink { tx: tx1, rx: rx2 }, ChannelLink { tx: tx2, rx: rx1 })
+    }
+}
+
+impl SerialLink for ChannelLink {
+    fn send(&mut self, message: LinkMessage) -> io::Result<()> {
+        self.tx.send(message).map_err(|_| disconnected())
+    }
+
+    fn try_recv(&mut self) -> io::Result<Option<LinkMessage>> {
+        match self.rx.try_recv() {
+            Ok(message)                         =>  Ok(Some(message)),
+            Err(TryRecvError::Empty)            =>  Ok(None),
+            Err(TryRecvError::Disconnected)     =>  Err(disconnected()),
+        }
+    }
+}
+
+// Links two emulators over TCP. Every message is two bytes: kind and data.
+pub struct TcpLink {
+    stream:     TcpStream,
+    pending:    Vec<u8>,
+}
+
+impl TcpLink {
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        TcpLink::from_stream(stream)
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        TcpLink::from_stream(TcpStream::connect(addr)?)
+    }
+
+    fn from_stream(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+
+        Ok(TcpLink {
+            stream,
+            pending:    Vec::new(),
+        })
+    }
+}
+
+impl SerialLink for TcpLink {
+    fn send(&mut self, message: LinkMessage) -> io::Result<()> {
+        let packet = match message {
+            LinkMessage::Transfer(data) =>  [0x00, data],
+            LinkMessage::Reply(data)    =>  [0x01, data],
+        };
+        self.stream.write_all(&packet)
+    }
+
+    fn try_recv(&mut self) -> io::Result<Option<LinkMessage>> {
+        let mut buf = [0; 2];
+        while self.pending.len() < 2 {
+            match self.stream.read(&mut buf[..2 - self.pending.len()]) {
+                Ok(0)   =>  return Err(disconnected()),
+                Ok(n)   =>  self.pending.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>  return Ok(None),
+                Err(e)  =>  return Err(e),
+            }
+        }
+
+        let packet: Vec<u8> = self.pending.drain(..).collect();
+        match packet[0] {
+            0x00    =>  Ok(Some(LinkMessage::Transfer(packet[1]))),
+            0x01    =>  Ok(Some(LinkMessage::Reply(packet[1]))),
+            _       =>  Err(io::Error::new(io::ErrorKind::InvalidData, "bad link message")),
+        }
+    }
+}
+
+// Something plugged into the serial port that answers every byte by itself.
+// Used instead of a second Game Boy when no link cable is connected.
+pub trait SerialDevice {
+    // Takes the byte the Game Boy shifts out and returns the one shifted in
+    fn exchange(&mut self, data: u8) -> u8;
+
+    // Called when the Game Boy starts sending `data` on its own clock.
+    // Some test ROMs start the next byte before the transfer could have finished.
+    fn started(&mut self, _data: u8) {}
+}
+
+// Echoes every byte back
+pub struct Loopback;
+
+impl SerialDevice for Loopback {
+    fn exchange(&mut self, data: u8) -> u8 {
+        data
+    }
+}
+
+// Answers with the scripted bytes in order, then 0xFF
+pub struct Scripted(pub Vec<u8>);
+
+impl SerialDevice for Scripted {
+    fn exchange(&mut self, _data: u8) -> u8 {
+        match self.0.is_empty() {
+            true    =>  0xFF,
+            false   =>  self.0.remove(0),
+        }
+    }
+}
+
+// Writes every byte the game sends on its own clock to `out`, e.g. a file a test script
+// can tail, and answers like an empty port. Stops writing after an error.
+pub struct SerialLog<W: Write> {
+    out:    Option<W>,
+}
+
+impl<W: Write> SerialLog<W> {
+    pub fn new(out: W) -> Self {
+        SerialLog { out: Some(out) }
+    }
+}
+
+impl<W: Write> SerialDevice for SerialLog<W> {
+    fn exchange(&mut self, _data: u8) -> u8 {
+        0xFF
+    }
+
+    // Logged when sent rather than when done, like the test ROM runner does
+    fn started(&mut self, data: u8) {
+        if let Some(out) = &mut self.out {
+            if let Err(e) = out.write_all(&[data]).and_then(|_| out.flush()) {
+                warn!("can't log serial output: {}", e);
+                self.out = None;
+            }
+        }
+    }
+}
+
+pub struct Serial {
+    model:      Model,
+    sb:         u8,
+    sc:         u8,
+    link:       Option<Box<dyn SerialLink>>,
+    device:     Option<Box<dyn SerialDevice>>,
+    // Ticks since the internal clock side started the transfer
+    elapsed:    Option<u32>,
+}
+
+impl Serial {
+    pub fn new(model: Model) -> Self {
+        Serial {
+            model,
+            sb:         0,
+            sc:         0,
+            link:       None,
+            device:     None,
+            elapsed:    None,
+        }
+    }
+
+    // Clears the registers but keeps the cable plugged in
+    pub fn reset(&mut self) {
+        self.sb = 0;
+        self.sc = 0;
+        self.elapsed = None;
+    }
+
+    // The cable and device stay plugged in
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.bool(self.elapsed.is_some());
+        w.u32(self.elapsed.unwrap_or(0));
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        self.sb = r.u8()?;
+        self.sc = r.u8()?;
+        let transferring = r.bool()?;
+        let elapsed = r.u32()?;
+        self.elapsed = if transferring { Some(elapsed) } else { None };
+        Ok(())
+    }
+
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+    }
+
+    pub fn connect(&mut self, link: Box<dyn SerialLink>) {
+        self.link = Some(link);
+    }
+
+    pub fn connect_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = Some(device);
+    }
+
+    fn transferring(&self) -> bool {
+        self.sc & 0x80 != 0
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.sc & 0x01 != 0
+    }
+
+    // Bit 1 picks the fast clock, on CGB only
+    fn transfer_ticks(&self) -> u32 {
+        match self.model.is_color() && self.sc & 0x02 != 0 {
+            true    =>  FAST_TRANSFER_TICKS,
+            false   =>  TRANSFER_TICKS,
+        }
+    }
+
+    fn start(&mut self) {
+        self.elapsed = Some(0);
+        if self.internal_clock() {
+            let sb = self.sb;
+            self.send(LinkMessage::Transfer(sb));
+            if let (None, Some(device)) = (&self.link, &mut self.device) {
+                device.started(sb);
+            }
+        }
+    }
+
+    fn finish(&mut self, data: u8) {
+        self.sb = data;
+        self.sc &= !0x80;
+        self.elapsed = None;
+    }
+
+    fn send(&mut self, message: LinkMessage) {
+        if let Some(link) = &mut self.link {
+            if link.send(message).is_err() {
+                self.link = None;
+            }
+        }
+    }
+
+    fn recv(&mut self) -> Option<LinkMessage> {
+        let result = match &mut self.link {
+            Some(link)  =>  link.try_recv(),
+            None        =>  return None,
+        };
+
+        match result {
+            Ok(message) =>  message,
+            Err(_)      =>  {
+                self.link = None;
+                None
+            },
+        }
+    }
+
+    // Returns true when a transfer completes
+    pub fn tick(&mut self) -> bool {
+        let mut done = false;
+
+        while let Some(message) = self.recv() {
+            match message {
+                // The peer drives the clock: both sides latch each other's byte
+                LinkMessage::Transfer(data) =>  {
+                    let sb = self.sb;
+                    self.send(LinkMessage::Reply(sb));
+                    if self.transferring() {
+                        self.finish(data);
+                        done = true;
+                    }
+                },
+                LinkMessage::Reply(data)    =>  if self.elapsed.is_some() {
+                    self.finish(data);
+                    done = true;
+                },
+            }
+        }
+
+        if let Some(elapsed) = self.elapsed {
+            let timeout = match self.link {
+                Some(_) =>  LINK_TIMEOUT,
+                None    =>  self.transfer_ticks(),
+            };
+            if elapsed + 1 >= timeout {
+                // Without a device nothing on the other end shifts in 1s
+                let sb = self.sb;
+                let data = match (&self.link, &mut self.device) {
+                    (None, Some(device))    =>  device.exchange(sb),
+                    _                       =>  0xFF,
+                };
+                self.finish(data);
+                done = true;
+            } else {
+                self.elapsed = Some(elapsed + 1);
+            }
+        }
+
+        done
+    }
+}
+
+impl Io for Serial {
+    fn read8(&self, addr: usize) -> u8 {
+        match addr {
+            SB      =>  self.sb,
+            SC      =>  match self.model.is_color() {
+                true    =>  self.sc | 0x7C,
+                false   =>  self.sc | 0x7E,
+            },
+            _       =>  panic!(),
+        }
+    }
+
+    fn write8(&mut self, addr: usize, data: u8) {
+        match addr {
+            SB      =>  self.sb = data,
+            SC      =>  {
+                self.sc = data;
+                // A device drives the clock itself when the Game Boy doesn't
+                let external = self.link.is_none() && self.device.is_some();
+                if self.transferring() && (self.internal_clock() || external) {
+                    self.start();
+                }
+            },
+            _       =>  panic!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linked_instances_exchange_bytes() {
+        let (link1, link2) = ChannelLink::pair();
+        let mut master = Serial::new(Model::Dmg);
+        let mut slave = Serial::new(Model::Dmg);
+        master.connect(Box::new(link1));
+        slave.connect(Box::new(link2));
+
+        slave.write8(SB, 0x99);
+        slave.write8(SC, 0x80);
+        master.write8(SB, 0x42);
+        master.write8(SC, 0x81);
+        assert!(slave.tick());
+        assert!(master.tick());
+        assert_eq!(master.read8(SB), 0x99);
+        assert_eq!(slave.read8(SB), 0x42);
+        assert_eq!(master.read8(SC) & 0x80, 0x00);
+        assert_eq!(slave.read8(SC) & 0x80, 0x00);
+
+        // Nothing shifts in once the other side is gone
+        drop(slave);
+        master.write8(SC, 0x81);
+        let ticks = (0..TRANSFER_TICKS).take_while(|_| !master.tick()).count();
+        assert_eq!(ticks as u32, TRANSFER_TICKS - 1);
+        assert_eq!(master.read8(SB), 0xFF);
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+
+// One packet is 16 bytes sent LSB first, followed by a 0 stop bit
+const PACKET_BYTES: usize   = 16;
+const PACKET_BITS:  usize   = PACKET_BYTES * 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SgbPacket {
+    pub command:    u8,
+    // Every byte after the header, across all packets of the command
+    pub data:       Vec<u8>,
+}
+
+// Receives Super Game Boy command packets that games send by pulsing P14/P15.
+//   P14=0 P15=0:   reset, starts a packet
+//   P14=0 P15=1:   bit 0
+//   P14=1 P15=0:   bit 1
+//   P14=1 P15=1:   ends the current pulse
+pub struct SgbReceiver {
+    receiving:  bool,
+    pulse:      Option<bool>,
+    bits:       usize,
+    packet:     [u8; PACKET_BYTES],
+    raw:        Vec<u8>,
+    remaining:  u8,
+    packets:    VecDeque<SgbPacket>,
+}
+
+impl Default for SgbReceiver {
+    fn default() -> Self {
+        SgbReceiver::new()
+    }
+}
+
+impl SgbReceiver {
+    pub fn new() -> Self {
+        SgbReceiver {
+            receiving:  false,
+            pulse:      None,
+            bits:       0,
+            packet:     [0; PACKET_BYTES],
+            raw:        Vec::new(),
+            remaining:  0,
+            packets:    VecDeque::new(),
+        }
+    }
+
+    pub fn write(&mut self, p1: u8) {
+        match (p1 >> 4) & 0x03 {
+            0b00    =>  {
+                self.receiving = true;
+                self.pulse = None;
+                self.bits = 0;
+                self.packet = [0; PACKET_BYTES];
+            },
+            0b10    =>  self.pulse = Some(false),
+            0b01    =>  self.pulse = Some(true),
+            _       =>  if let Some(bit) = self.pulse.take() {
+                if self.receiving {
+                    self.push_bit(bit);
+                }
+            },
+        }
+    }
+
+    pub fn pop_packet(&mut self) -> Option<SgbPacket> {
+        self.packets.pop_front()
+    }
+
+    fn push_bit(&mut self, bit: bool) {
+        if self.bits < PACKET_BITS {
+            if bit {
+                self.packet[self.bits / 8] |= 1 << (self.bits % 8);
+            }
+            self.bits += 1;
+            return;
+        }
+
+        // Stop bit
+        self.receiving = false;
+        if !bit {
+            self.finish_packet();
+        }
+    }
+
+    fn finish_packet(&mut self) {
+        if self.remaining == 0 {
+            // The low 3 bits of the header hold the number of packets
+            self.raw.clear();
+            self.remaining = (self.packet[0] & 0x07).max(1);
+        }
+        self.raw.extend_from_slice(&self.packet);
+        self.remaining -= 1;
+
+        if self.remaining == 0 {
+            self.packets.push_back(SgbPacket {
+                command:    self.raw[0] >> 3,
+                data:       self.raw[1..].to_vec(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pulses one packet onto P14/P15 the way a game does
+    fn send(sgb: &mut SgbReceiver, packet: &[u8; PACKET_BYTES]) {
+        sgb.write(0x00);
+        sgb.write(0x30);
+        for i in 0..PACKET_BITS {
+            let bit = packet[i / 8] >> (i % 8) & 0x01;
+            sgb.write(match bit {
+                0   =>  0x20,
+                _   =>  0x10,
+            });
+            sgb.write(0x30);
+        }
+        // Stop bit
+        sgb.write(0x20);
+        sgb.write(0x30);
+    }
+
+    #[test]
+    fn decodes_packet() {
+        let mut sgb = SgbReceiver::new();
+        // MLT_REQ (0x11), one packet, two players
+        let mut packet = [0; PACKET_BYTES];
+        packet[0] = 0x11 << 3 | 1;
+        packet[1] = 0x01;
+        send(&mut sgb, &packet);
+
+        let mut data = vec![0; PACKET_BYTES - 1];
+        data[0] = 0x01;
+        assert_eq!(sgb.pop_packet(), Some(SgbPacket { command: 0x11, data }));
+        assert_eq!(sgb.pop_packet(), None);
+    }
+}
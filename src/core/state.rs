@@ -0,0 +1,135 @@
+use crate::core::error::GbError;
+
+const MAGIC:    &[u8; 4]    = b"GBRS";
+const VERSION:  u8          = 9;
+
+// Save states are the fields of every component, written in a fixed order
+// as little endian integers. Each component reads back what it wrote.
+pub struct StateWriter {
+    buf:    Vec<u8>,
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        StateWriter::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut buf = MAGIC.to_vec();
+        buf.push(VERSION);
+        StateWriter {
+            buf,
+        }
+    }
+
+    pub fn u8(&mut self, data: u8) {
+        self.buf.push(data);
+    }
+
+    pub fn bool(&mut self, data: bool) {
+        self.u8(data as u8);
+    }
+
+    pub fn u16(&mut self, data: u16) {
+        self.buf.extend_from_slice(&data.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, data: u32) {
+        self.buf.extend_from_slice(&data.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, data: u64) {
+        self.buf.extend_from_slice(&data.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, data: f32) {
+        self.u32(data.to_bits());
+    }
+
+    // Length prefixed
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    buf:    &'a [u8],
+    pos:    usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Result<Self, GbError> {
+        if buf.len() < MAGIC.len() + 1 || &buf[..MAGIC.len()] != MAGIC {
+            return Err(GbError::BadState("not a save state".to_string()));
+        }
+        if buf[MAGIC.len()] != VERSION {
+            return Err(GbError::BadState(format!("unsupported version: {}", buf[MAGIC.len()])));
+        }
+
+        Ok(StateReader {
+            buf,
+            pos:    MAGIC.len() + 1,
+        })
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], GbError> {
+        if self.buf.len() - self.pos < len {
+            return Err(GbError::BadState("unexpected end of file".to_string()));
+        }
+        let data = &self.buf[self.pos..self.pos+len];
+        self.pos += len;
+        Ok(data)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, GbError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, GbError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, GbError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, GbError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, GbError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, GbError> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], GbError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    // Reads bytes written by StateWriter::bytes() into a buffer of the same size
+    pub fn bytes_into(&mut self, buf: &mut [u8]) -> Result<(), GbError> {
+        let data = self.bytes()?;
+        if data.len() != buf.len() {
+            return Err(GbError::BadState(format!("expected {} bytes, found {}", buf.len(), data.len())));
+        }
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+}
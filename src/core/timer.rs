@@ -1,6 +1,9 @@
 use bitflags::*;
 
+use crate::core::addr::{ DIV, TIMA, TMA, TAC };
 use crate::core::io::Io;
+use crate::core::error::GbError;
+use crate::core::state::{ StateReader, StateWriter };
 
 bitflags! {
     struct Tac: u8 {
@@ -10,101 +13,157 @@ bitflags! {
     }
 }
 
-const TAC00_DIV: u16    = 1024;
-const TAC01_DIV: u16    = 16;
-const TAC10_DIV: u16    = 64;
-const TAC11_DIV: u16    = 256;
-const DIV: u16          = 256;
+// TIMA counts on the falling edge of one bit of the internal counter
+const TAC00_BIT: u16    = 1 << 9;   // 4096Hz
+const TAC01_BIT: u16    = 1 << 3;   // 262144Hz
+const TAC10_BIT: u16    = 1 << 5;   // 65536Hz
+const TAC11_BIT: u16    = 1 << 7;   // 16384Hz
 
 #[derive(Debug)]
 pub struct Timer {
-    div:    u8,
-    tima:   u8,
-    tma:    u8,
-    tac:    Tac,
-    count:  u16
+    // Counts clocks. DIV is the upper byte
+    counter:    u16,
+    tima:       u8,
+    tma:        u8,
+    tac:        Tac,
+    // TIMA overflowed on a register write, reported by the next tick()
+    overflow:   bool,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
 }
 
 impl Timer {
     pub fn new() -> Self {
         Timer {
-            div:    0,
-            tima:   0,
-            tma:    0,
-            tac:    Tac::empty(),
-            count:  0,
+            counter:    0,
+            tima:       0,
+            tma:        0,
+            tac:        Tac::empty(),
+            overflow:   false,
         }
     }
 
-    pub fn tick(&mut self) -> bool {
-        let mut overflow = false;
-        self.count = self.count.wrapping_add(4);
-        if self.tac.contains(Tac::TIMER_EN) {
-            match self.tac.bits() & 0b11 {
-                0b00    =>  {
-                    if self.count % TAC00_DIV == 0 {
-                        self.tima = self.tima.wrapping_add(1);
-                        if self.tima == 0 {
-                            self.tima = self.tma;
-                            overflow = true;
-                        }
-                    }
-                },
-                0b01    =>  {
-                    if self.count % TAC01_DIV == 0 {
-                        self.tima = self.tima.wrapping_add(1);
-                        if self.tima == 0 {
-                            self.tima = self.tma;
-                            overflow = true;
-                        }
-                    }
-                },
-                0b10    =>  {
-                    if self.count % TAC10_DIV == 0 {
-                        self.tima = self.tima.wrapping_add(1);
-                        if self.tima == 0 {
-                            self.tima = self.tma;
-                            overflow = true;
-                        }
-                    }
-                },
-                0b11    =>  {
-                    if self.count % TAC11_DIV == 0 {
-                        self.tima = self.tima.wrapping_add(1);
-                        if self.tima == 0 {
-                            self.tima = self.tma;
-                            overflow = true;
-                        }
-                    }
-                },
-                _       =>  panic!(),
+    // DIV can only be cleared through the register
+    pub fn set_div(&mut self, div: u8) {
+        self.counter = (div as u16) << 8;
+    }
+
+    // The signal TIMA counts on: the selected counter bit while the timer is enabled
+    fn input(&self) -> bool {
+        let bit = match self.tac.bits() & 0b11 {
+            0b00    =>  TAC00_BIT,
+            0b01    =>  TAC01_BIT,
+            0b10    =>  TAC10_BIT,
+            _       =>  TAC11_BIT,
+        };
+        self.tac.contains(Tac::TIMER_EN) && self.counter & bit != 0
+    }
+
+    // Applies a change to the counter or TAC. Resetting DIV or switching TAC
+    // while the input is high is a falling edge too, and ticks TIMA.
+    fn update(&mut self, change: impl FnOnce(&mut Timer)) {
+        let before = self.input();
+        change(self);
+        if before && !self.input() {
+            self.tima = self.tima.wrapping_add(1);
+            if self.tima == 0 {
+                self.tima = self.tma;
+                self.overflow = true;
             }
         }
-        if self.count % DIV == 0 { self.div = self.div.wrapping_add(1); }
+    }
+
+    // Returns true when TIMA overflows
+    pub fn tick(&mut self) -> bool {
+        self.update(|timer| timer.counter = timer.counter.wrapping_add(4));
 
+        let overflow = self.overflow;
+        self.overflow = false;
         overflow
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.counter);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac.bits());
+        w.bool(self.overflow);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), GbError> {
+        self.counter    = r.u16()?;
+        self.tima       = r.u8()?;
+        self.tma        = r.u8()?;
+        self.tac        = Tac::from_bits_truncate(r.u8()?);
+        self.overflow   = r.bool()?;
+        Ok(())
+    }
     
 }
 
 impl Io for Timer {
     fn read8(&self, addr: usize) -> u8 {
         match addr {
-            0xFF04  =>  self.div,
-            0xFF05  =>  self.tima,
-            0xFF06  =>  self.tma,
-            0xFF07  =>  self.tac.bits(),
+            DIV     =>  (self.counter >> 8) as u8,
+            TIMA    =>  self.tima,
+            TMA     =>  self.tma,
+            TAC     =>  self.tac.bits() | 0xF8,
             _       =>  panic!("can't read from: {:04x}", addr),
         }
     }
 
     fn write8(&mut self, addr: usize, data: u8) {
         match addr {
-            0xFF04  =>  self.div    = 0,
-            0xFF05  =>  self.tima   = data,
-            0xFF06  =>  self.tma    = data,
-            0xFF07  =>  self.tac    = Tac::from_bits_truncate(data),
+            // Clears the whole counter
+            DIV     =>  self.update(|timer| timer.counter = 0),
+            TIMA    =>  self.tima   = data,
+            TMA     =>  self.tma    = data,
+            TAC     =>  self.update(|timer| timer.tac = Tac::from_bits_truncate(data)),
             _       =>  panic!("can't write to: {:04x}", addr),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn div_is_counter_upper_byte() {
+        let mut timer = Timer::new();
+        for _ in 0..64 {
+            timer.tick();
+        }
+        assert_eq!(timer.read8(DIV), 0x01);
+        timer.write8(DIV, 0x55);
+        assert_eq!(timer.read8(DIV), 0x00);
+    }
+
+    #[test]
+    fn div_write_ticks_tima_on_falling_edge() {
+        let mut timer = Timer::new();
+        // 262144Hz: TIMA follows bit 3 of the counter
+        timer.write8(TAC, 0x05);
+
+        // Bit 3 is low: resetting the counter is no edge
+        timer.tick();
+        timer.write8(DIV, 0);
+        assert_eq!(timer.read8(TIMA), 0);
+
+        // Bit 3 is high: resetting the counter ticks TIMA early
+        timer.tick();
+        timer.tick();
+        timer.write8(DIV, 0);
+        assert_eq!(timer.read8(TIMA), 1);
+
+        // A full period after the reset ticks it once more
+        for _ in 0..4 {
+            timer.tick();
+        }
+        assert_eq!(timer.read8(TIMA), 2);
+    }
+}
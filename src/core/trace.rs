@@ -0,0 +1,71 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::panic;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+// An executed instruction: its address and first opcode byte (0xCB for prefixed ones)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceEntry {
+    pub pc:     u16,
+    pub opcode: u8,
+}
+
+// The last instructions the CPU ran, oldest first. Clones share the same ring,
+// so a panic hook can keep one to write out what led to a crash.
+#[derive(Clone)]
+pub struct Trace {
+    ring:       Arc<Mutex<VecDeque<TraceEntry>>>,
+    capacity:   usize,
+}
+
+impl Trace {
+    pub fn new(capacity: usize) -> Self {
+        Trace {
+            ring:       Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn record(&self, pc: u16, opcode: u8) {
+        if let Ok(mut ring) = self.ring.lock() {
+            if ring.len() == self.capacity {
+                ring.pop_front();
+            }
+            ring.push_back(TraceEntry { pc, opcode });
+        }
+    }
+
+    pub fn entries(&self) -> Vec<TraceEntry> {
+        // Still readable after a panic while it was locked
+        let ring = self.ring.lock().unwrap_or_else(|e| e.into_inner());
+        ring.iter().copied().collect()
+    }
+
+    // One "pc: opcode" line per instruction, oldest first
+    pub fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        for entry in self.entries() {
+            writeln!(w, "{:04x}: {:02x}", entry.pc, entry.opcode)?;
+        }
+        Ok(())
+    }
+
+    // Writes the trace to `path` if the emulator panics, e.g. on an unsupported opcode
+    pub fn dump_on_panic(&self, path: PathBuf) {
+        let trace = self.clone();
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let result = File::create(&path).and_then(|file| {
+                let mut w = BufWriter::new(file);
+                trace.write_to(&mut w)?;
+                w.flush()
+            });
+            match result {
+                Ok(_)   =>  eprintln!("last instructions written to {}", path.display()),
+                Err(e)  =>  eprintln!("{}: {}", path.display(), e),
+            }
+            default_hook(info);
+        }));
+    }
+}
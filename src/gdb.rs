@@ -0,0 +1,294 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::core::cpu::{ Registers, StopReason };
+use crate::core::gameboy::GameBoy;
+
+// GDB remote serial protocol, just enough to debug game code: registers, memory,
+// breakpoints, continue, single step and reverse single step. GDB has no Game Boy target, so the
+// registers are sent in the order of its z80 target (set architecture z80):
+// AF BC DE HL SP PC, then IX IY AF' BC' DE' HL' IR which are always 0.
+
+const NREGS:            usize   = 13;
+// Stopped by SIGTRAP, after a breakpoint, a step or Ctrl-C
+const STOPPED:          &str    = "S05";
+// Reverse stepping reached the oldest instruction we kept
+const HISTORY_START:    &str    = "T05replaylog:begin;";
+// Sent by GDB to interrupt a running target
+const INTERRUPT:        u8      = 0x03;
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+// "$<data>#<checksum>"
+pub fn encode(data: &str) -> Vec<u8> {
+    format!("${}#{:02x}", data, checksum(data.as_bytes())).into_bytes()
+}
+
+// Returns the data of a "$<data>#<checksum>" packet, or None if it is malformed
+pub fn decode(packet: &[u8]) -> Option<&[u8]> {
+    if packet.len() < 4 || packet[0] != b'$' || packet[packet.len()-3] != b'#' {
+        return None;
+    }
+    let data = &packet[1..packet.len()-3];
+    let sum = std::str::from_utf8(&packet[packet.len()-2..]).ok()?;
+    match u8::from_str_radix(sum, 16) {
+        Ok(sum) if sum == checksum(data)    =>  Some(data),
+        _                                   =>  None,
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i+2)?, 16).ok()).collect()
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+// Register pairs as GDB numbers them, little endian
+fn register_pairs(r: &Registers) -> [u16; NREGS] {
+    let pair = |hi: u8, lo: u8| (hi as u16) << 8 | lo as u16;
+    let mut regs = [0; NREGS];
+    regs[..6].copy_from_slice(&[
+        pair(r.a, r.f), pair(r.b, r.c), pair(r.d, r.e), pair(r.h, r.l), r.sp, r.pc,
+    ]);
+    regs
+}
+
+fn set_register_pair(r: &mut Registers, n: usize, value: u16) {
+    let (hi, lo) = ((value >> 8) as u8, value as u8);
+    match n {
+        0   =>  { r.a = hi; r.f = lo & 0xF0; },
+        1   =>  { r.b = hi; r.c = lo; },
+        2   =>  { r.d = hi; r.e = lo; },
+        3   =>  { r.h = hi; r.l = lo; },
+        4   =>  r.sp = value,
+        5   =>  r.pc = value,
+        _   =>  (),
+    }
+}
+
+// Reply to the 'g' packet
+pub fn read_registers(r: &Registers) -> String {
+    register_pairs(r).iter().map(|reg| to_hex(&reg.to_le_bytes())).collect()
+}
+
+// Handles a packet that doesn't resume the game. None for packets we don't support,
+// which GDB expects an empty reply to.
+fn handle(gameboy: &mut GameBoy, packet: &str) -> Option<String> {
+    let (cmd, args) = packet.split_at(1);
+    match cmd {
+        "?" =>  Some(STOPPED.to_string()),
+        "g" =>  Some(read_registers(&gameboy.registers())),
+        "G" =>  {
+            let data = from_hex(args)?;
+            let mut regs = gameboy.registers();
+            for (n, bytes) in data.chunks(2).take(6).enumerate() {
+                if bytes.len() == 2 {
+                    set_register_pair(&mut regs, n, u16::from_le_bytes([bytes[0], bytes[1]]));
+                }
+            }
+            gameboy.set_registers(regs);
+            Some("OK".to_string())
+        },
+        "p" =>  {
+            let n = parse_hex(args)? as usize;
+            let reg = register_pairs(&gameboy.registers()).get(n).copied()?;
+            Some(to_hex(&reg.to_le_bytes()))
+        },
+        "P" =>  {
+            let mut parts = args.splitn(2, '=');
+            let n = parse_hex(parts.next()?)? as usize;
+            let bytes = from_hex(parts.next()?)?;
+            let mut regs = gameboy.registers();
+            set_register_pair(&mut regs, n, u16::from_le_bytes([*bytes.first()?, *bytes.get(1)?]));
+            gameboy.set_registers(regs);
+            Some("OK".to_string())
+        },
+        "m" =>  {
+            let mut parts = args.splitn(2, ',');
+            let addr = parse_hex(parts.next()?)?;
+            let len = parse_hex(parts.next()?)?;
+            let data: Vec<u8> = (0..len).map(|i| gameboy.read_memory(addr.wrapping_add(i))).collect();
+            Some(to_hex(&data))
+        },
+        "M" =>  {
+            let mut parts = args.splitn(2, ':');
+            let addr = parse_hex(parts.next()?.split(',').next()?)?;
+            for (i, data) in from_hex(parts.next()?)?.iter().enumerate() {
+                gameboy.write_memory(addr.wrapping_add(i as u16), *data);
+            }
+            Some("OK".to_string())
+        },
+        // Software and hardware breakpoints are the same to us
+        "Z" | "z"   =>  {
+            let mut parts = args.splitn(3, ',');
+            let kind = parts.next()?;
+            if kind != "0" && kind != "1" {
+                return None;
+            }
+            let addr = parse_hex(parts.next()?)?;
+            match cmd {
+                "Z" =>  gameboy.add_breakpoint(addr),
+                _   =>  gameboy.remove_breakpoint(addr),
+            }
+            Some("OK".to_string())
+        },
+        "q" if args.starts_with("Supported")    =>  Some("PacketSize=4000;ReverseStep+".to_string()),
+        "q" if args == "Attached"               =>  Some("1".to_string()),
+        _   =>  None,
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0   =>  Ok(None),
+            _   =>  Ok(Some(byte[0])),
+        }
+    }
+
+    // Waits for the next packet and acknowledges it. None when GDB hangs up.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acks and anything else between packets
+            match self.read_byte()? {
+                Some(b'$')  =>  (),
+                Some(_)     =>  continue,
+                None        =>  return Ok(None),
+            }
+
+            let mut packet = vec![b'$'];
+            loop {
+                match self.read_byte()? {
+                    Some(b'#')  =>  break,
+                    Some(byte)  =>  packet.push(byte),
+                    None        =>  return Ok(None),
+                }
+            }
+            packet.push(b'#');
+            for _ in 0..2 {
+                match self.read_byte()? {
+                    Some(byte)  =>  packet.push(byte),
+                    None        =>  return Ok(None),
+                }
+            }
+
+            match decode(&packet) {
+                Some(data)  =>  {
+                    self.stream.write_all(b"+")?;
+                    return Ok(Some(String::from_utf8_lossy(data).into_owned()));
+                },
+                None        =>  self.stream.write_all(b"-")?,
+            }
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        self.stream.write_all(&encode(data))
+    }
+
+    // True if GDB sent Ctrl-C. Doesn't wait.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let result = self.read_byte();
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(byte)                                            =>  Ok(byte == Some(INTERRUPT)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>  Ok(false),
+            Err(e)                                              =>  Err(e),
+        }
+    }
+}
+
+// Runs the game headless until it hits a breakpoint or GDB interrupts it
+fn resume(gameboy: &mut GameBoy, conn: &mut Connection) -> io::Result<()> {
+    loop {
+        if gameboy.step_frame() == StopReason::Breakpoint || conn.interrupted()? {
+            return Ok(());
+        }
+    }
+}
+
+// Waits for GDB to connect on `port` and serves it until it detaches
+pub fn serve(gameboy: &mut GameBoy, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for GDB on port {}", port);
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    gameboy.set_step_back(true);
+    let mut conn = Connection { stream };
+
+    while let Some(packet) = conn.read_packet()? {
+        if packet.is_empty() || !packet.is_ascii() {
+            conn.write_packet("")?;
+            continue;
+        }
+        match &packet[..1] {
+            "c" =>  {
+                resume(gameboy, &mut conn)?;
+                conn.write_packet(STOPPED)?;
+            },
+            "s" =>  {
+                gameboy.step_instruction();
+                conn.write_packet(STOPPED)?;
+            },
+            // reverse-stepi, as far back as the step back history goes
+            "b" if packet == "bs"   =>  match gameboy.step_back() {
+                true    =>  conn.write_packet(STOPPED)?,
+                false   =>  conn.write_packet(HISTORY_START)?,
+            },
+            "D" =>  {
+                conn.write_packet("OK")?;
+                return Ok(());
+            },
+            "k" =>  return Ok(()),
+            _   =>  {
+                let reply = handle(gameboy, &packet).unwrap_or_default();
+                conn.write_packet(&reply)?;
+            },
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_framing() {
+        assert_eq!(encode("OK"), b"$OK#9a".to_vec());
+        assert_eq!(decode(b"$OK#9a"), Some(&b"OK"[..]));
+        assert_eq!(decode(b"$OK#9b"), None);
+        assert_eq!(decode(b"OK#9a"), None);
+        assert_eq!(decode(&encode("m150,2")), Some(&b"m150,2"[..]));
+    }
+
+    #[test]
+    fn register_read_reply() {
+        let regs = Registers {
+            a: 0x01, f: 0xB0, b: 0x00, c: 0x13, d: 0x00, e: 0xD8,
+            h: 0x01, l: 0x4D, sp: 0xFFFE, pc: 0x0100,
+        };
+        let reply = read_registers(&regs);
+        assert_eq!(reply.len(), NREGS * 4);
+        assert_eq!(&reply[..24], "b0011300d8004d01feff0001");
+        assert!(reply[24..].chars().all(|c| c == '0'));
+    }
+}
@@ -0,0 +1,84 @@
+// Post-processing for the scaled up screen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    None,
+    // Darkens the bottom row of every Game Boy pixel, like the gaps between CRT scanlines
+    Scanlines,
+    // Darkens the bottom row and right column of every Game Boy pixel, like an LCD's grid
+    LcdGrid,
+}
+
+impl Filter {
+    // How many screen pixels a Game Boy pixel needs at least for the filter to show
+    pub fn min_scale(self) -> u32 {
+        match self {
+            Filter::None    =>  1,
+            _               =>  2,
+        }
+    }
+}
+
+// Brightness left on the darkened rows and columns, out of 256
+const DARKEN: u16 = 160;
+
+fn darken(rgba: &mut [u8]) {
+    for c in rgba[..3].iter_mut() {
+        *c = ((*c as u16 * DARKEN) >> 8) as u8;
+    }
+}
+
+// Scales the RGBA image `width` x `height` up by `scale` with nearest neighbor into `out`,
+// then applies the filter
+pub fn apply(filter: Filter, rgba: &[u8], width: usize, height: usize, scale: usize, out: &mut Vec<u8>) {
+    let out_width = width * scale;
+    out.resize(out_width * height * scale * 4, 0);
+
+    for (y, line) in out.chunks_mut(out_width * 4).enumerate() {
+        let sy = y / scale;
+        let last_row = y % scale == scale - 1;
+        for (x, pixel) in line.chunks_mut(4).enumerate() {
+            let sx = x / scale;
+            let src = (sy * width + sx) * 4;
+            pixel.copy_from_slice(&rgba[src..src+4]);
+
+            let last_column = x % scale == scale - 1;
+            let dark = match filter {
+                Filter::None        =>  false,
+                Filter::Scanlines   =>  last_row,
+                Filter::LcdGrid     =>  last_row || last_column,
+            };
+            if dark && scale > 1 {
+                darken(pixel);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scanlines_darken_every_other_row() {
+        // 2x1 white image scaled by 2
+        let rgba = [0xFF; 2 * 4];
+        let mut out = Vec::new();
+        apply(Filter::Scanlines, &rgba, 2, 1, 2, &mut out);
+        let rows: Vec<&[u8]> = out.chunks(4 * 4).collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].iter().all(|&c| c == 0xFF));
+        assert!(rows[1].chunks(4).all(|pixel| pixel == [159, 159, 159, 0xFF]));
+
+        apply(Filter::None, &rgba, 2, 1, 2, &mut out);
+        assert!(out.iter().all(|&c| c == 0xFF));
+    }
+
+    #[test]
+    fn lcd_grid_darkens_last_column_too() {
+        let rgba = [0xFF; 4];
+        let mut out = Vec::new();
+        apply(Filter::LcdGrid, &rgba, 1, 1, 2, &mut out);
+        let bright: Vec<bool> = out.chunks(4).map(|pixel| pixel[0] == 0xFF).collect();
+        assert_eq!(bright, vec![true, false, false, false]);
+    }
+}
@@ -0,0 +1,61 @@
+// Whether the game runs, given the window's focus and --pause-on-blur
+pub struct RunState {
+    pause_on_blur:  bool,
+    paused:         bool,
+}
+
+// What a focus change asks the window to do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transition {
+    Pause,
+    Resume,
+}
+
+impl RunState {
+    pub fn new(pause_on_blur: bool) -> Self {
+        RunState {
+            pause_on_blur,
+            paused:         false,
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    // Losing focus pauses and getting it back resumes, but only with --pause-on-blur.
+    // Repeated events of the same kind change nothing.
+    pub fn focus(&mut self, focused: bool) -> Option<Transition> {
+        if !self.pause_on_blur || self.paused != focused {
+            return None;
+        }
+        self.paused = !focused;
+        match focused {
+            false   =>  Some(Transition::Pause),
+            true    =>  Some(Transition::Resume),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blur_pauses_and_focus_resumes() {
+        let mut state = RunState::new(true);
+        assert_eq!(state.focus(true), None);
+        assert_eq!(state.focus(false), Some(Transition::Pause));
+        assert!(state.paused());
+        assert_eq!(state.focus(false), None);
+        assert_eq!(state.focus(true), Some(Transition::Resume));
+        assert!(!state.paused());
+    }
+
+    #[test]
+    fn focus_is_ignored_without_option() {
+        let mut state = RunState::new(false);
+        assert_eq!(state.focus(false), None);
+        assert!(!state.paused());
+    }
+}
@@ -0,0 +1,56 @@
+// Which frames --frameskip draws. The Game Boy runs every frame either way,
+// so timing and sound don't change.
+pub struct FrameSkip {
+    // Only every (frameskip+1)th frame is drawn
+    frameskip:  u32,
+    skipped:    u32,
+}
+
+impl FrameSkip {
+    pub fn new(frameskip: u32) -> Self {
+        FrameSkip {
+            frameskip,
+            skipped:    0,
+        }
+    }
+
+    // Called once per frame. Returns whether to draw it.
+    pub fn draw(&mut self) -> bool {
+        if self.skipped < self.frameskip {
+            self.skipped += 1;
+            return false;
+        }
+        self.skipped = 0;
+        true
+    }
+
+    // Draws the next frame, e.g. the first one after a pause
+    pub fn restart(&mut self) {
+        self.skipped = self.frameskip;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gbr::core::gameboy::GameBoy;
+
+    #[test]
+    fn draws_every_third_frame() {
+        // 32kB of NOPs, so no header checksum either
+        let mut gameboy = GameBoy::load(vec![0; 0x8000], true).unwrap();
+        let mut skip = FrameSkip::new(2);
+        let mut drawn = 0;
+        for _ in 0..9 {
+            gameboy.run_frame();
+            if skip.draw() {
+                drawn += 1;
+            }
+        }
+        assert_eq!(drawn, 3);
+        assert_eq!(gameboy.frames(), 9);
+
+        skip.restart();
+        assert!(skip.draw());
+    }
+}
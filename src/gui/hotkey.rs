@@ -0,0 +1,144 @@
+use ggez::event::{ KeyCode, KeyMods };
+
+use crate::core::pad::Key;
+
+// Held down for the emulator's letter and number hotkeys (R, P, 1-4), so they can't
+// get in the way of the game. Function keys never need it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Modifier {
+    None,
+    Ctrl,
+    Alt,
+}
+
+impl Modifier {
+    fn held(self, keymod: KeyMods) -> bool {
+        match self {
+            Modifier::None  =>  true,
+            Modifier::Ctrl  =>  keymod.contains(KeyMods::CTRL),
+            Modifier::Alt   =>  keymod.contains(KeyMods::ALT),
+        }
+    }
+}
+
+// Something the emulator does rather than the game
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hotkey {
+    Reset,
+    ToggleChannel(u8),
+    SaveState(u8),
+    LoadState(u8),
+    CyclePalette,
+    ToggleStats,
+    ToggleBlend,
+    DumpMemory,
+}
+
+// Where a key press goes. A key is either a Game Boy button or a hotkey, never both.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Pad(Key),
+    Hotkey(Hotkey),
+}
+
+fn pad_key(keycode: KeyCode) -> Option<Key> {
+    match keycode {
+        KeyCode::Left       =>  Some(Key::Left),
+        KeyCode::Right      =>  Some(Key::Right),
+        KeyCode::Up         =>  Some(Key::Up),
+        KeyCode::Down       =>  Some(Key::Down),
+        KeyCode::Z          =>  Some(Key::A),
+        KeyCode::X          =>  Some(Key::B),
+        KeyCode::Return     =>  Some(Key::Start),
+        KeyCode::Back       =>  Some(Key::Select),
+        _                   =>  None,
+    }
+}
+
+// Shift+F1-F4 loads the slot F1-F4 saves to
+fn state_hotkey(slot: u8, keymod: KeyMods) -> Hotkey {
+    match keymod.contains(KeyMods::SHIFT) {
+        true    =>  Hotkey::LoadState(slot),
+        false   =>  Hotkey::SaveState(slot),
+    }
+}
+
+fn function_hotkey(keycode: KeyCode, keymod: KeyMods) -> Option<Hotkey> {
+    match keycode {
+        KeyCode::F1     =>  Some(state_hotkey(1, keymod)),
+        KeyCode::F2     =>  Some(state_hotkey(2, keymod)),
+        KeyCode::F3     =>  Some(state_hotkey(3, keymod)),
+        KeyCode::F4     =>  Some(state_hotkey(4, keymod)),
+        KeyCode::F9     =>  Some(Hotkey::ToggleBlend),
+        KeyCode::F10    =>  Some(Hotkey::ToggleStats),
+        KeyCode::F12    =>  Some(Hotkey::DumpMemory),
+        _               =>  None,
+    }
+}
+
+fn modified_hotkey(keycode: KeyCode) -> Option<Hotkey> {
+    match keycode {
+        KeyCode::R      =>  Some(Hotkey::Reset),
+        KeyCode::Key1   =>  Some(Hotkey::ToggleChannel(1)),
+        KeyCode::Key2   =>  Some(Hotkey::ToggleChannel(2)),
+        KeyCode::Key3   =>  Some(Hotkey::ToggleChannel(3)),
+        KeyCode::Key4   =>  Some(Hotkey::ToggleChannel(4)),
+        KeyCode::P      =>  Some(Hotkey::CyclePalette),
+        _               =>  None,
+    }
+}
+
+// Sorts keyboard input into Game Boy buttons and emulator hotkeys
+pub struct KeyMap {
+    modifier:   Modifier,
+}
+
+impl KeyMap {
+    pub fn new(modifier: Modifier) -> Self {
+        KeyMap {
+            modifier,
+        }
+    }
+
+    pub fn press(&self, keycode: KeyCode, keymod: KeyMods) -> Option<Action> {
+        if let Some(key) = pad_key(keycode) {
+            return Some(Action::Pad(key));
+        }
+        if let Some(hotkey) = function_hotkey(keycode, keymod) {
+            return Some(Action::Hotkey(hotkey));
+        }
+        match self.modifier.held(keymod) {
+            true    =>  modified_hotkey(keycode).map(Action::Hotkey),
+            false   =>  None,
+        }
+    }
+
+    // Hotkeys act on the press, so only buttons care about the release
+    pub fn release(&self, keycode: KeyCode) -> Option<Key> {
+        pad_key(keycode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hotkeys_and_buttons_stay_apart() {
+        let keymap = KeyMap::new(Modifier::Ctrl);
+        assert_eq!(keymap.press(KeyCode::Z, KeyMods::NONE), Some(Action::Pad(Key::A)));
+        assert_eq!(keymap.press(KeyCode::F1, KeyMods::NONE), Some(Action::Hotkey(Hotkey::SaveState(1))));
+        assert_eq!(keymap.press(KeyCode::F1, KeyMods::SHIFT), Some(Action::Hotkey(Hotkey::LoadState(1))));
+        assert_eq!(keymap.release(KeyCode::F1), None);
+        // Letter hotkeys need the modifier
+        assert_eq!(keymap.press(KeyCode::R, KeyMods::NONE), None);
+        assert_eq!(keymap.press(KeyCode::R, KeyMods::CTRL), Some(Action::Hotkey(Hotkey::Reset)));
+        assert_eq!(keymap.press(KeyCode::R, KeyMods::ALT), None);
+        // Buttons work with the modifier held too
+        assert_eq!(keymap.press(KeyCode::Return, KeyMods::CTRL), Some(Action::Pad(Key::Start)));
+
+        let keymap = KeyMap::new(Modifier::None);
+        assert_eq!(keymap.press(KeyCode::Key3, KeyMods::NONE), Some(Action::Hotkey(Hotkey::ToggleChannel(3))));
+        assert_eq!(keymap.press(KeyCode::A, KeyMods::NONE), None);
+    }
+}
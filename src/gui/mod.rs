@@ -1 +1,6 @@
+pub mod filter;
+pub mod focus;
+pub mod frameskip;
+pub mod hotkey;
+pub mod stats;
 pub mod window;
\ No newline at end of file
@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::core::cpu::CLOCK_HZ;
+
+// About a second at full speed
+pub const STATS_FRAMES: usize = 60;
+
+// What a frame took and did, for the performance overlay
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Frame {
+    real:       Duration,
+    cycles:     u64,
+    samples:    u64,
+}
+
+// Rolling averages over the last frames
+pub struct FrameStats {
+    frames: VecDeque<Frame>,
+    len:    usize,
+}
+
+impl FrameStats {
+    pub fn new(len: usize) -> Self {
+        FrameStats {
+            frames: VecDeque::with_capacity(len),
+            len:    len.max(1),
+        }
+    }
+
+    // `real` is the wall time since the last frame, `cycles` the clocks emulated
+    // and `samples` the audio samples the device played meanwhile
+    pub fn push(&mut self, real: Duration, cycles: u64, samples: u64) {
+        if self.frames.len() == self.len {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(Frame { real, cycles, samples });
+    }
+
+    // E.g. after a pause, which would drag the averages down
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    fn real_secs(&self) -> f64 {
+        self.frames.iter().map(|f| f.real.as_secs_f64()).sum()
+    }
+
+    pub fn frame_time(&self) -> Duration {
+        match self.frames.len() {
+            0   =>  Duration::from_secs(0),
+            n   =>  Duration::from_secs_f64(self.real_secs() / n as f64),
+        }
+    }
+
+    pub fn fps(&self) -> f64 {
+        match self.real_secs() {
+            secs if secs > 0.0  =>  self.frames.len() as f64 / secs,
+            _                   =>  0.0,
+        }
+    }
+
+    // Emulated time per real time, 1.0 is full speed
+    pub fn speed(&self) -> f64 {
+        let cycles: u64 = self.frames.iter().map(|f| f.cycles).sum();
+        match self.real_secs() {
+            secs if secs > 0.0  =>  cycles as f64 / CLOCK_HZ as f64 / secs,
+            _                   =>  0.0,
+        }
+    }
+
+    // Samples played per real time against the sample rate. Under 1.0 the device
+    // ran dry and the sound crackles.
+    pub fn audio(&self, sample_rate: u32) -> f64 {
+        let samples: u64 = self.frames.iter().map(|f| f.samples).sum();
+        match self.real_secs() {
+            secs if secs > 0.0 && sample_rate > 0   =>  samples as f64 / sample_rate as f64 / secs,
+            _                                       =>  0.0,
+        }
+    }
+
+    pub fn text(&self, sample_rate: u32) -> String {
+        format!(
+            "{:.1} fps\n{:.2} ms/frame\nspeed {:.0}%\naudio {:.0}%",
+            self.fps(),
+            self.frame_time().as_secs_f64() * 1000.0,
+            self.speed() * 100.0,
+            self.audio(sample_rate) * 100.0,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_roll_over_last_frames() {
+        let mut stats = FrameStats::new(4);
+        assert_eq!((stats.fps(), stats.speed()), (0.0, 0.0));
+
+        // Four frames of 20ms with a second's worth of clocks and samples over 80ms
+        for _ in 0..4 {
+            stats.push(Duration::from_millis(20), CLOCK_HZ / 4, 12000);
+        }
+        assert_eq!(stats.frame_time(), Duration::from_millis(20));
+        assert!((stats.fps() - 50.0).abs() < 1e-9);
+        assert!((stats.speed() - 12.5).abs() < 1e-9);
+        assert!((stats.audio(48000) - 12.5).abs() < 1e-9);
+
+        // The 20ms frames roll out for 10ms ones
+        for _ in 0..4 {
+            stats.push(Duration::from_millis(10), 0, 0);
+        }
+        assert_eq!(stats.frame_time(), Duration::from_millis(10));
+        assert!((stats.fps() - 100.0).abs() < 1e-9);
+        assert_eq!(stats.speed(), 0.0);
+
+        stats.clear();
+        assert_eq!(stats.frame_time(), Duration::from_secs(0));
+    }
+}
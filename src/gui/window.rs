@@ -1,68 +1,265 @@
 use ggez::{Context, ContextBuilder, GameResult};
-use ggez::event::{self, EventHandler, KeyCode, KeyMods};
+use ggez::event::{self, Button, EventHandler, KeyCode, KeyMods};
+use ggez::event::winit_event::{Event, WindowEvent, KeyboardInput, ElementState};
 use ggez::graphics;
+use ggez::input::gamepad::gilrs::{self, EventType};
+use ggez::input::keyboard;
 use ggez::nalgebra::Point2;
 use ggez::timer;
-use std::path::Path;
+use log::info;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
 
-use crate::core::cpu::Cpu;
-use crate::core::pad::Key;
+use crate::core::gameboy::GameBoy;
+use crate::gui::filter::{ self, Filter };
+use crate::gui::focus::{ RunState, Transition };
+use crate::gui::frameskip::FrameSkip;
+use crate::gui::hotkey::{ Action, Hotkey, KeyMap, Modifier };
+use crate::gui::stats::{ FrameStats, STATS_FRAMES };
+use crate::core::palette::PaletteChoice;
+use crate::core::pad::{ Key, KEYS, InputEvent, ChannelInput };
 
 const SCREEN_WIDTH:     u32 = 160;
 const SCREEN_HEIGHT:    u32 = 144;
-const NLINES: usize = 154;
-const CYCLE_PER_LINE: usize = 114;
+// How long an on-screen message stays visible
+const MESSAGE_FRAMES:   u32 = 180;
+// How long the event loop sleeps between polls while paused
+const PAUSED_POLL:      Duration = Duration::from_millis(50);
+// Frames F9 averages unless --frame-blend says otherwise
+const BLEND_FRAMES:     u8 = 3;
 
-const COLORS: [[u8; 4]; 5] = [
-    [0x9B, 0xBC, 0x0F, 0xFF],   // Lightest Green (#9BBC0F)
-    [0x8B, 0xAC, 0x0F, 0xFF],   // Light Green (#8BAC0F)
-    [0x30, 0x62, 0x30, 0xFF],   // Dark Green (#306230)
-    [0x0F, 0x38, 0x0F, 0xFF],   // Darkest Green (#0F380F)
-    [0x8F, 0x7B, 0x13, 0xFF],   // LCD OFF
-];
+// How the command line sets up the window
+pub struct WindowOptions {
+    pub frameskip:      u32,
+    pub dump_path:      Option<PathBuf>,
+    // Screen pixels per Game Boy pixel, raised to what the filter needs
+    pub scale:          u32,
+    pub filter:         Filter,
+    pub pause_on_blur:  bool,
+    pub modifier:       Modifier,
+    // Dropped ROMs load like the first one
+    pub ignore_checksums:   bool,
+}
 
 pub struct MainWindow {
-    cpu:        Cpu,
-    palette:    Vec<graphics::spritebatch::SpriteBatch>,
-    pixels:     [u8; (SCREEN_WIDTH*SCREEN_HEIGHT) as usize],
+    gameboy:    GameBoy,
+    message:    Option<(String, u32)>,
+    channels:   [bool; 4],
+    frameskip:  FrameSkip,
+    // Gamepad buttons are fed to the Game Boy as an input source
+    gamepad:    Sender<InputEvent>,
+    // Where F12 and quitting write a memory dump
+    dump_path:  Option<PathBuf>,
+    // Screen pixels per Game Boy pixel, and the post-processing on top
+    scale:      u32,
+    filter:     Filter,
+    scaled:     Vec<u8>,
+    run_state:  RunState,
+    // Performance overlay, toggled with F10
+    stats:      FrameStats,
+    show_stats: bool,
+    // Clocks and audio samples as of the last frame
+    last_cycles:    u64,
+    last_samples:   u64,
+    keymap:     KeyMap,
+    // What F9 turns frame blending on with
+    blend_frames:   u8,
+    ignore_checksums:   bool,
 }
 
 
 impl MainWindow {
-    pub fn new(path: &Path, ctx: &mut Context) -> MainWindow {        
+    pub fn new(mut gameboy: GameBoy, options: WindowOptions, _ctx: &mut Context) -> MainWindow {
+        let (gamepad, input) = ChannelInput::new();
+        gameboy.add_input(Box::new(input));
+        gameboy.open_audio();
+        let blend_frames = match gameboy.frame_blend() {
+            0       =>  BLEND_FRAMES,
+            frames  =>  frames,
+        };
+        // The game runs anyway, silently
+        let message = match gameboy.has_audio() {
+            true    =>  None,
+            false   =>  Some(("No audio output".to_string(), MESSAGE_FRAMES)),
+        };
+
         MainWindow {
-            cpu:        Cpu::from_path(path),
-            palette:    MainWindow::get_init_palette(ctx),
-            pixels:     [4; (SCREEN_WIDTH*SCREEN_HEIGHT) as usize],
+            gameboy,
+            message,
+            channels:   [true; 4],
+            frameskip:  FrameSkip::new(options.frameskip),
+            gamepad,
+            dump_path:  options.dump_path,
+            scale:      options.scale,
+            filter:     options.filter,
+            scaled:     Vec::new(),
+            run_state:  RunState::new(options.pause_on_blur),
+            stats:      FrameStats::new(STATS_FRAMES),
+            show_stats: false,
+            last_cycles:    0,
+            last_samples:   0,
+            keymap:     KeyMap::new(options.modifier),
+            blend_frames,
+            ignore_checksums:   options.ignore_checksums,
+        }
+    }
+
+    fn show_message(&mut self, message: String) {
+        info!("{}", message);
+        self.message = Some((message, MESSAGE_FRAMES));
+    }
+
+    // Swaps the running game for the ROM at `path`.
+    // The current game keeps running if the new ROM can't be loaded.
+    pub fn load_rom(&mut self, path: &Path) {
+        if !is_rom_file(path) {
+            self.show_message(format!("Not a Game Boy ROM:\n{}", path.display()));
+            return;
+        }
+
+        match GameBoy::open(path, None, self.ignore_checksums) {
+            Ok(mut gameboy) =>  {
+                for key in KEYS.iter() {
+                    gameboy.set_turbo(*key, self.gameboy.is_turbo(*key));
+                }
+                gameboy.set_turbo_rate(self.gameboy.turbo_rate());
+                gameboy.set_save_enabled(self.gameboy.save_enabled());
+                gameboy.set_color_correction(self.gameboy.color_correction());
+                gameboy.set_trace(self.gameboy.trace());
+                gameboy.set_clock_scale(self.gameboy.clock_scale());
+                gameboy.set_palette(self.gameboy.palette_choice());
+                gameboy.set_frame_blend(self.gameboy.frame_blend());
+                gameboy.set_sample_rate(self.gameboy.sample_rate());
+                if let Some(model) = self.gameboy.forced_model() {
+                    gameboy.set_model(model);
+                }
+                gameboy.open_audio();
+                let (gamepad, input) = ChannelInput::new();
+                gameboy.add_input(Box::new(input));
+                self.gamepad = gamepad;
+                self.gameboy = gameboy;
+                for (i, on) in self.channels.iter().enumerate() {
+                    self.gameboy.set_channel_enabled(i as u8 + 1, *on);
+                }
+                if self.run_state.paused() {
+                    self.gameboy.set_audio_paused(true);
+                }
+                self.stats.clear();
+                self.show_message(format!("Loaded {}", path.display()));
+            },
+            Err(e)          =>  self.show_message(format!("Can't load ROM:\n{}", e)),
+        }
+    }
+
+    // Slots are files next to the ROM
+    fn save_state(&mut self, slot: u8) {
+        match self.gameboy.save_state_slot(slot) {
+            Ok(_)       =>  self.show_message(format!("Saved state {}", slot)),
+            Err(e)      =>  self.show_message(format!("Can't save state {}:\n{}", slot, e)),
+        }
+    }
+
+    fn load_state(&mut self, slot: u8) {
+        match self.gameboy.load_state_slot(slot) {
+            Ok(true)    =>  self.show_message(format!("Loaded state {}", slot)),
+            Ok(false)   =>  self.show_message(format!("State {} is empty", slot)),
+            Err(e)      =>  self.show_message(format!("Can't load state {}:\n{}", slot, e)),
+        }
+    }
+
+    fn hotkey(&mut self, hotkey: Hotkey) {
+        match hotkey {
+            Hotkey::Reset               =>  self.gameboy.reset(),
+            Hotkey::ToggleChannel(ch)   =>  self.toggle_channel(ch),
+            Hotkey::SaveState(slot)     =>  self.save_state(slot),
+            Hotkey::LoadState(slot)     =>  self.load_state(slot),
+            Hotkey::CyclePalette        =>  self.cycle_palette(),
+            Hotkey::ToggleStats         =>  self.show_stats = !self.show_stats,
+            Hotkey::ToggleBlend         =>  self.toggle_blend(),
+            Hotkey::DumpMemory          =>  self.dump_memory(),
+        }
+    }
+
+    fn gamepad_button(&mut self, button: Button, pressed: bool) {
+        let key = match gamepad_key(button) {
+            Some(key)   =>  key,
+            None        =>  return,
+        };
+        let event = if pressed { InputEvent::Press(key) } else { InputEvent::Release(key) };
+        // The receiver lives as long as the Game Boy, so this can't fail
+        let _ = self.gamepad.send(event);
+    }
+
+    fn dump_memory(&mut self) {
+        let path = match &self.dump_path {
+            Some(path)  =>  path.clone(),
+            None        =>  {
+                self.show_message("Pass --dump-mem FILE\nto dump memory".to_string());
+                return;
+            },
+        };
+        match std::fs::write(&path, self.gameboy.dump_memory()) {
+            Ok(_)   =>  self.show_message(format!("Dumped memory to\n{}", path.display())),
+            Err(e)  =>  self.show_message(format!("Can't dump memory:\n{}", e)),
         }
     }
 
-    fn get_init_palette(ctx: &mut Context) -> Vec<graphics::spritebatch::SpriteBatch> {
-        let mut palette = Vec::new();
+    fn cycle_palette(&mut self) {
+        let palette = self.gameboy.cycle_palette();
+        let message = match self.gameboy.palette_choice() {
+            PaletteChoice::Auto     =>  format!("Palette: auto ({})", palette.name),
+            PaletteChoice::Fixed(_) =>  format!("Palette: {}", palette.name),
+        };
+        self.show_message(message);
+    }
 
-        for color in &COLORS {
-            let green = graphics::Image::from_rgba8(
-                ctx,
-                1,
-                1,
-                color,
-            ).unwrap();
-            palette.push(graphics::spritebatch::SpriteBatch::new(green));
+    fn focus_changed(&mut self, focused: bool) {
+        match self.run_state.focus(focused) {
+            Some(Transition::Pause)     =>  self.gameboy.set_audio_paused(true),
+            Some(Transition::Resume)    =>  {
+                self.gameboy.set_audio_paused(false);
+                self.stats.clear();
+                self.frameskip.restart();
+            },
+            None                        =>  (),
         }
-        palette
     }
 
-    pub fn update_pixels(&mut self, pixels: [u8;(SCREEN_WIDTH*SCREEN_HEIGHT) as usize]) {
-        self.pixels = pixels;
+    fn record_stats(&mut self, real: Duration) {
+        let cycles = self.gameboy.cycles();
+        let samples = self.gameboy.audio_samples_played();
+        // A new ROM starts both over
+        self.stats.push(real, cycles.saturating_sub(self.last_cycles), samples.saturating_sub(self.last_samples));
+        self.last_cycles = cycles;
+        self.last_samples = samples;
+    }
+
+    fn toggle_blend(&mut self) {
+        match self.gameboy.frame_blend() {
+            0   =>  {
+                self.gameboy.set_frame_blend(self.blend_frames);
+                self.show_message(format!("Frame blending on ({} frames)", self.blend_frames));
+            },
+            _   =>  {
+                self.gameboy.set_frame_blend(0);
+                self.show_message("Frame blending off".to_string());
+            },
+        }
+    }
+
+    fn toggle_channel(&mut self, ch: u8) {
+        let on = !self.channels[ch as usize - 1];
+        self.channels[ch as usize - 1] = on;
+        self.gameboy.set_channel_enabled(ch, on);
+        self.show_message(format!("Sound channel {} {}", ch, if on { "on" } else { "off" }));
     }
 }
 
 impl EventHandler for MainWindow {
     fn update(&mut self, ctx: &mut Context) -> GameResult {
-        for _ in 0..NLINES*CYCLE_PER_LINE {
-            self.cpu.tick();
-        }
-        self.update_pixels(self.cpu.get_pixels());
+        self.gameboy.run_frame();
+        self.record_stats(timer::delta(ctx));
 
         if timer::ticks(ctx) % 100 == 0 {
             println!("Delta frame time: {:?} ", timer::delta(ctx));
@@ -73,27 +270,44 @@ impl EventHandler for MainWindow {
     }
 
     fn draw(&mut self, ctx: &mut Context) -> GameResult {
+        if !self.frameskip.draw() {
+            return Ok(());
+        }
+
         graphics::clear(ctx, graphics::WHITE);
 
-        self.palette = MainWindow::get_init_palette(ctx);
+        let scale = self.scale as usize;
+        let frame = self.gameboy.framebuffer_rgba();
+        let pixels = match (scale, self.filter) {
+            (1, Filter::None)   =>  frame,
+            _                   =>  {
+                filter::apply(self.filter, frame, SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize, scale, &mut self.scaled);
+                &self.scaled
+            },
+        };
+        let mut screen = graphics::Image::from_rgba8(
+            ctx,
+            (SCREEN_WIDTH * self.scale) as u16,
+            (SCREEN_HEIGHT * self.scale) as u16,
+            pixels,
+        )?;
+        screen.set_filter(graphics::FilterMode::Nearest);
+        graphics::draw(ctx, &screen, (Point2::new(0.0, 0.0),))?;
 
-        for i in 0..self.pixels.len() as u32 {
-            let x = (i % SCREEN_WIDTH) as f32;
-            let y = (i / SCREEN_WIDTH % SCREEN_HEIGHT) as f32;
-            let p = graphics::DrawParam::new()
-                .dest(Point2::new(x, y));
-                
-            self.palette[self.pixels[i as usize] as usize].add(p);
+        if self.show_stats {
+            let text = graphics::Text::new(self.stats.text(self.gameboy.sample_rate()));
+            let y = (SCREEN_HEIGHT * self.scale).saturating_sub(text.height(ctx)) as f32;
+            graphics::draw(ctx, &text, (Point2::new(2.0, y - 2.0), graphics::BLACK))?;
         }
-        let param = graphics::DrawParam::new()
-            .dest(Point2::new(0.0, 0.0));
 
-        for gray in &self.palette {
-            graphics::draw(ctx, gray, param)?;
+        if let Some((message, frames)) = self.message.take() {
+            let text = graphics::Text::new(message.as_str());
+            graphics::draw(ctx, &text, (Point2::new(2.0, 2.0), graphics::BLACK))?;
+            if frames > 1 {
+                self.message = Some((message, frames - 1));
+            }
         }
 
-        self.palette.clear();
-
         graphics::present(ctx)
     }
 
@@ -101,19 +315,13 @@ impl EventHandler for MainWindow {
         &mut self,
         _ctx: &mut Context,
         keycode: KeyCode,
-        _keymod: KeyMods,
-        _repeat: bool
+        keymod: KeyMods,
+        repeat: bool
     ) {
-        match keycode {
-            KeyCode::Left       =>  self.cpu.push_key(Key::Left),
-            KeyCode::Right      =>  self.cpu.push_key(Key::Right),
-            KeyCode::Up         =>  self.cpu.push_key(Key::Up),
-            KeyCode::Down       =>  self.cpu.push_key(Key::Down),
-            KeyCode::Z          =>  self.cpu.push_key(Key::A),
-            KeyCode::X          =>  self.cpu.push_key(Key::B),
-            KeyCode::Return     =>  self.cpu.push_key(Key::Start),
-            KeyCode::Back       =>  self.cpu.push_key(Key::Select),
-            _                   =>  (),
+        match self.keymap.press(keycode, keymod) {
+            Some(Action::Pad(key))                  =>  self.gameboy.push_key(key),
+            Some(Action::Hotkey(hotkey)) if !repeat =>  self.hotkey(hotkey),
+            _                                       =>  (),
         }
     }
     
@@ -123,33 +331,120 @@ impl EventHandler for MainWindow {
         keycode: KeyCode,
         _keymod: KeyMods
     ) {
-        match keycode {
-            KeyCode::Left       =>  self.cpu.release_key(Key::Left),
-            KeyCode::Right      =>  self.cpu.release_key(Key::Right),
-            KeyCode::Up         =>  self.cpu.release_key(Key::Up),
-            KeyCode::Down       =>  self.cpu.release_key(Key::Down),
-            KeyCode::Z          =>  self.cpu.release_key(Key::A),
-            KeyCode::X          =>  self.cpu.release_key(Key::B),
-            KeyCode::Return     =>  self.cpu.release_key(Key::Start),
-            KeyCode::Back       =>  self.cpu.release_key(Key::Select),
-            _                   =>  (),
+        if let Some(key) = self.keymap.release(keycode) {
+            self.gameboy.release_key(key);
         }
     }
 }
 
-pub fn run(path: &Path) {
+// Xbox style layout: the bottom face button is A, the right one is B
+fn gamepad_key(button: Button) -> Option<Key> {
+    match button {
+        Button::DPadLeft    =>  Some(Key::Left),
+        Button::DPadRight   =>  Some(Key::Right),
+        Button::DPadUp      =>  Some(Key::Up),
+        Button::DPadDown    =>  Some(Key::Down),
+        Button::South       =>  Some(Key::A),
+        Button::East        =>  Some(Key::B),
+        Button::Start       =>  Some(Key::Start),
+        Button::Select      =>  Some(Key::Select),
+        _                   =>  None,
+    }
+}
+
+fn is_rom_file(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext)   =>  ext.eq_ignore_ascii_case("gb") || ext.eq_ignore_ascii_case("gbc"),
+        None        =>  false,
+    }
+}
+
+// Same as ggez::event::run(), but also handles files dropped onto the window
+// and sends gamepad buttons to the Game Boy
+fn event_loop(ctx: &mut Context, event_loop: &mut event::EventsLoop, window: &mut MainWindow) -> GameResult {
+    while ctx.continuing {
+        ctx.timer_context.tick();
+
+        let mut dropped = None;
+        event_loop.poll_events(|event| {
+            ctx.process_event(&event);
+            if let Event::WindowEvent { event, .. } = event {
+                match event {
+                    WindowEvent::CloseRequested         =>  event::quit(ctx),
+                    WindowEvent::DroppedFile(path)      =>  dropped = Some(path),
+                    WindowEvent::Focused(focused)       =>  window.focus_changed(focused),
+                    WindowEvent::KeyboardInput {
+                        input: KeyboardInput {
+                            state:              ElementState::Pressed,
+                            virtual_keycode:    Some(keycode),
+                            modifiers,
+                            ..
+                        },
+                        ..
+                    }   =>  {
+                        let repeat = keyboard::is_key_repeated(ctx);
+                        window.key_down_event(ctx, keycode, modifiers.into(), repeat);
+                    },
+                    WindowEvent::KeyboardInput {
+                        input: KeyboardInput {
+                            state:              ElementState::Released,
+                            virtual_keycode:    Some(keycode),
+                            modifiers,
+                            ..
+                        },
+                        ..
+                    }   =>  window.key_up_event(ctx, keycode, modifiers.into()),
+                    _   =>  (),
+                }
+            }
+        });
+
+        while let Some(gilrs::Event { event, .. }) = ctx.gamepad_context.next_event() {
+            match event {
+                EventType::ButtonPressed(button, _)     =>  window.gamepad_button(button, true),
+                EventType::ButtonReleased(button, _)    =>  window.gamepad_button(button, false),
+                _                                       =>  (),
+            }
+        }
+
+        if let Some(path) = dropped {
+            window.load_rom(&path);
+        }
+
+        if window.run_state.paused() {
+            timer::sleep(PAUSED_POLL);
+            continue;
+        }
+
+        window.update(ctx)?;
+        window.draw(ctx)?;
+    }
+
+    Ok(())
+}
+
+pub fn run(gameboy: GameBoy, mut options: WindowOptions) {
+    options.scale = options.scale.max(options.filter.min_scale());
+    let (width, height) = ((SCREEN_WIDTH * options.scale) as f32, (SCREEN_HEIGHT * options.scale) as f32);
     let (mut ctx, mut event_loop) =
        ContextBuilder::new("GBR", "Noboru")
             .window_setup(ggez::conf::WindowSetup::default().vsync(false))
-            .window_mode(ggez::conf::WindowMode::default().dimensions(SCREEN_WIDTH as f32, SCREEN_HEIGHT as f32))
+            .window_mode(ggez::conf::WindowMode::default().dimensions(width, height))
             .build()
             .unwrap();
 
-    let mut window = MainWindow::new(path, &mut ctx);
+    let mut window = MainWindow::new(gameboy, options, &mut ctx);
 
     // Run!
-    match event::run(&mut ctx, &mut event_loop, &mut window) {
+    match self::event_loop(&mut ctx, &mut event_loop, &mut window) {
         Ok(_)   => println!("Exited cleanly."),
         Err(e)  => println!("Error occured: {}", e)
     }
-}
\ No newline at end of file
+
+    if window.dump_path.is_some() {
+        window.dump_memory();
+    }
+    if let Err(e) = window.gameboy.shutdown() {
+        eprintln!("Can't write save: {}", e);
+    }
+}
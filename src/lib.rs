@@ -0,0 +1,2 @@
+// The emulator core, usable without the window, e.g. headless or from tests
+pub mod core;
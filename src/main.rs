@@ -1,21 +1,486 @@
-mod core;
+use gbr::core;
+mod gdb;
 mod gui;
+mod logger;
+mod testrom;
 
-use gui::window::run;
+use gui::filter::Filter;
+use gui::hotkey::Modifier;
+use gui::window::{ run, WindowOptions };
+use crate::core::cartridge::Header;
+use crate::core::cpu::CLOCK_HZ;
+use crate::core::gameboy::{ ColorCorrection, GameBoy };
+use crate::core::error::GbError;
+use crate::core::serial::{ SerialLog, TcpLink };
+use crate::core::model::Model;
+use crate::core::palette::PaletteChoice;
+use crate::core::patch;
+use crate::core::trace::Trace;
+use crate::core::pad::Key;
 
-use std::path::Path;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
+use log::LevelFilter;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 struct Opt {
+    /// ROM file to load, or "-" to read it from stdin. With --test, a directory of test ROMs
     #[structopt(short, long)]
     pub rom: String,
+
+    /// IPS or BPS patch to apply to the ROM, e.g. a translation. The ROM file is left as is
+    #[structopt(long, parse(from_os_str))]
+    pub patch: Option<PathBuf>,
+
+    /// Save state to start from, e.g. game.ss1. It must have been saved with the same ROM
+    #[structopt(long, parse(from_os_str))]
+    pub state: Option<PathBuf>,
+
+    /// Boot ROM to run first, to see the boot animation
+    #[structopt(long)]
+    pub boot_rom: Option<String>,
+
+    /// Log level: off, error, warn, info, debug or trace
+    #[structopt(long, default_value = "warn")]
+    pub log_level: LevelFilter,
+
+    /// Print the cartridge header and exit
+    #[structopt(long)]
+    pub info: bool,
+
+    /// Run test ROMs (Blargg, Mooneye) headless, print pass/fail and exit
+    #[structopt(long)]
+    pub test: bool,
+
+    /// With --test, emulated seconds a test ROM may run before it counts as hung
+    // No `requires = "test"`: clap counts the default as given and would always ask for --test
+    #[structopt(long, default_value = "120")]
+    pub test_timeout: u64,
+
+    /// Append every byte the game sends over the serial port to this file, e.g. Blargg's test results
+    #[structopt(long, parse(from_os_str))]
+    pub log_serial: Option<PathBuf>,
+
+    /// Boot ROMs with a wrong header or global checksum, e.g. homebrew that never fixed them. Only warns
+    #[structopt(long)]
+    pub ignore_checksums: bool,
+
+    /// Never write the battery save (.sav). An existing one is still loaded
+    #[structopt(long)]
+    pub no_save: bool,
+
+    /// Run Game Boy Color enhanced games in original Game Boy mode
+    #[structopt(long)]
+    pub force_dmg: bool,
+
+    /// Emulate this model instead of the one the header asks for: dmg, mgb, cgb or agb
+    #[structopt(long, conflicts_with = "force-dmg", parse(try_from_str = parse_model))]
+    pub model: Option<Model>,
+
+    /// Make Game Boy Color games look like they do on the real LCD, less saturated
+    #[structopt(long)]
+    pub color_correction: bool,
+
+    /// Colors for original Game Boy games: auto (what a Game Boy Color picks), green, grayscale, red, blue...
+    #[structopt(long, parse(try_from_str = parse_palette))]
+    pub palette: Option<PaletteChoice>,
+
+    /// Buttons that auto-fire while held, e.g. a,b
+    #[structopt(long, use_delimiter = true, parse(try_from_str = parse_key))]
+    pub turbo: Vec<Key>,
+
+    /// Frames an auto-fire button stays pressed (and then released)
+    #[structopt(long, default_value = "2")]
+    pub turbo_rate: u8,
+
+    /// Run the CPU this many times as fast as the rest of the Game Boy, e.g. 0.5. Inaccurate, for debugging
+    #[structopt(long, default_value = "1.0", parse(try_from_str = parse_clock_scale))]
+    pub clock_scale: f64,
+
+    /// Window size, in screen pixels per Game Boy pixel
+    #[structopt(long, default_value = "1", parse(try_from_str = parse_scale))]
+    pub scale: u32,
+
+    /// Post-processing for the window: none, scanlines or lcd-grid. The window is at least doubled
+    #[structopt(long, default_value = "none", parse(try_from_str = parse_filter))]
+    pub filter: Filter,
+
+    /// Sound output sample rate in Hz, e.g. 44100 or 48000. Defaults to the audio device's highest
+    #[structopt(long)]
+    pub sample_rate: Option<u32>,
+
+    /// Average the last N frames (e.g. 3) so fast palette fades look smooth. Inaccurate, off by default. F9 toggles it
+    #[structopt(long, default_value = "0")]
+    pub frame_blend: u8,
+
+    /// Only draw every (N+1)th frame. Emulation and sound still run at full speed
+    #[structopt(long, default_value = "0")]
+    pub frameskip: u32,
+
+    /// Pause the game and its sound while the window isn't focused
+    #[structopt(long)]
+    pub pause_on_blur: bool,
+
+    /// Modifier to hold for the R, P and 1-4 hotkeys: none, ctrl or alt. Function keys never need it
+    #[structopt(long, default_value = "none", parse(try_from_str = parse_modifier))]
+    pub hotkey_modifier: Modifier,
+
+    /// Keep the last instructions run and write them to this file if the emulator crashes
+    #[structopt(long, parse(from_os_str))]
+    pub trace: Option<PathBuf>,
+
+    /// How many instructions --trace keeps
+    #[structopt(long, default_value = "10000")]
+    pub trace_len: usize,
+
+    /// Write the 64kB address space to this file on exit. F12 writes it too
+    #[structopt(long, parse(from_os_str))]
+    pub dump_mem: Option<PathBuf>,
+
+    /// Run headless for N frames and print performance stats (e.g. frames=600)
+    #[structopt(long, parse(try_from_str = parse_bench))]
+    pub bench: Option<u64>,
+
+    /// With --bench, exit with an error unless the last frame has this hash (e.g. 0x1234abcd)
+    #[structopt(long, requires = "bench", parse(try_from_str = parse_hash))]
+    pub expect_hash: Option<u64>,
+
+    /// With --bench, print how often each memory region was read and written
+    #[structopt(long, requires = "bench")]
+    pub profile_mem: bool,
+
+    /// With --bench, print how often each opcode ran and the clocks it took
+    #[structopt(long, requires = "bench")]
+    pub profile_ops: bool,
+
+    /// Wait for GDB to connect on this port and run the game under it, without a window
+    #[structopt(long)]
+    pub gdb: Option<u16>,
+
+    /// Wait for another instance to connect a link cable on this address (e.g. 0.0.0.0:5555)
+    #[structopt(long)]
+    pub link_listen: Option<String>,
+
+    /// Connect a link cable to an instance listening on this address
+    #[structopt(long, conflicts_with = "link-listen")]
+    pub link_connect: Option<String>,
+}
+
+fn parse_bench(s: &str) -> Result<u64, String> {
+    let frames = s.strip_prefix("frames=").unwrap_or(s);
+    frames.parse::<u64>().map_err(|e| format!("invalid frame count '{}': {}", frames, e))
+}
+
+fn parse_key(s: &str) -> Result<Key, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "right"     =>  Ok(Key::Right),
+        "left"      =>  Ok(Key::Left),
+        "up"        =>  Ok(Key::Up),
+        "down"      =>  Ok(Key::Down),
+        "a"         =>  Ok(Key::A),
+        "b"         =>  Ok(Key::B),
+        "select"    =>  Ok(Key::Select),
+        "start"     =>  Ok(Key::Start),
+        _           =>  Err(format!("unknown button '{}'", s)),
+    }
+}
+
+fn parse_model(s: &str) -> Result<Model, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "dmg"   =>  Ok(Model::Dmg),
+        "mgb"   =>  Ok(Model::Mgb),
+        "cgb"   =>  Ok(Model::Cgb),
+        "agb"   =>  Ok(Model::Agb),
+        _       =>  Err(format!("unknown model '{}'", s)),
+    }
+}
+
+fn parse_clock_scale(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(scale) if scale > 0.0 && scale.is_finite()   =>  Ok(scale),
+        _                                               =>  Err(format!("invalid clock scale '{}'", s)),
+    }
+}
+
+fn parse_palette(s: &str) -> Result<PaletteChoice, String> {
+    PaletteChoice::from_name(s).ok_or_else(|| format!("unknown palette '{}'", s))
 }
 
+fn parse_scale(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(scale) if (1..=8).contains(&scale)   =>  Ok(scale),
+        _                                       =>  Err(format!("scale must be 1 to 8: '{}'", s)),
+    }
+}
+
+fn parse_filter(s: &str) -> Result<Filter, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "none"      =>  Ok(Filter::None),
+        "scanlines" =>  Ok(Filter::Scanlines),
+        "lcd-grid"  =>  Ok(Filter::LcdGrid),
+        _           =>  Err(format!("unknown filter '{}'", s)),
+    }
+}
+
+fn parse_modifier(s: &str) -> Result<Modifier, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "none"  =>  Ok(Modifier::None),
+        "ctrl"  =>  Ok(Modifier::Ctrl),
+        "alt"   =>  Ok(Modifier::Alt),
+        _       =>  Err(format!("unknown modifier '{}'", s)),
+    }
+}
+
+fn parse_hash(s: &str) -> Result<u64, String> {
+    let hex = s.trim_start_matches("0x");
+    u64::from_str_radix(hex, 16).map_err(|e| format!("invalid hash '{}': {}", s, e))
+}
+
+fn read_stdin() -> io::Result<Vec<u8>> {
+    let mut bin = Vec::new();
+    io::stdin().read_to_end(&mut bin)?;
+    Ok(bin)
+}
+
+fn load(rom: &str, patch: Option<&[u8]>, ignore_checksums: bool) -> Result<GameBoy, GbError> {
+    match (rom, patch) {
+        ("-", None)         =>  GameBoy::load(read_stdin()?, ignore_checksums),
+        ("-", Some(patch))  =>  {
+            let mut bin = read_stdin()?;
+            patch::apply(&mut bin, patch)?;
+            GameBoy::load(bin, ignore_checksums)
+        },
+        (_, patch)          =>  GameBoy::open(Path::new(rom), patch, ignore_checksums),
+    }
+}
+
+fn info(rom: &str, patch: Option<&[u8]>) -> Result<Header, GbError> {
+    let mut bin = match rom {
+        "-" =>  read_stdin()?,
+        _   =>  std::fs::read(rom)?,
+    };
+    if let Some(patch) = patch {
+        patch::apply(&mut bin, patch)?;
+    }
+    Header::parse(&bin)
+}
+
+// Serial output is appended, so a script can keep tailing it across runs
+fn open_serial_log(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn print_memory_accesses(gameboy: &GameBoy) {
+    let regions = match gameboy.memory_accesses() {
+        Some(regions)   =>  regions,
+        None            =>  return,
+    };
+    println!("{:<16} {:>12} {:>12}", "region", "reads", "writes");
+    for r in regions {
+        println!("{:<16} {:>12} {:>12}", r.region.name, r.reads, r.writes);
+    }
+}
+
+fn print_opcode_counts(gameboy: &GameBoy) {
+    let counts = match gameboy.opcode_counts() {
+        Some(counts)    =>  counts,
+        None            =>  return,
+    };
+    println!("{:<6} {:<20} {:>12} {:>12}", "opcode", "name", "count", "cycles");
+    for c in counts {
+        println!("{:<6} {:<20} {:>12} {:>12}", format!("{:02x}", c.opcode), c.name, c.count, c.cycles);
+    }
+}
+
+fn bench(gameboy: &mut GameBoy, frames: u64) {
+    let start = Instant::now();
+    for _ in 0..frames {
+        gameboy.run_frame();
+    }
+    let wall = start.elapsed().as_secs_f64();
+    let emulated = gameboy.cycles() as f64 / CLOCK_HZ as f64;
+
+    println!("frames:       {}", frames);
+    println!("instructions: {}", gameboy.instructions());
+    println!("cycles:       {}", gameboy.cycles());
+    println!("wall time:    {:.3}s", wall);
+    println!("speed:        {:.2}x realtime", emulated / wall);
+    println!("frame hash:   0x{:016x}", gameboy.frame_hash());
+    let r = gameboy.registers();
+    println!("registers:    af={:02x}{:02x} bc={:02x}{:02x} de={:02x}{:02x} hl={:02x}{:02x} sp={:04x} pc={:04x}",
+             r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l, r.sp, r.pc);
+}
 
 fn main() {
     let opt = Opt::from_args();
-    let path = Path::new(&opt.rom);
+    logger::init(opt.log_level).expect("logger is already set");
+
+    let patch = match &opt.patch {
+        Some(path)  =>  match std::fs::read(path) {
+            Ok(patch)   =>  Some(patch),
+            Err(e)      =>  {
+                eprintln!("{}: {}", path.display(), e);
+                process::exit(1);
+            },
+        },
+        None        =>  None,
+    };
+
+    if opt.info {
+        match info(&opt.rom, patch.as_deref()) {
+            Ok(header)  =>  println!("{}", header),
+            Err(e)      =>  {
+                eprintln!("{}: {}", opt.rom, e);
+                process::exit(1);
+            },
+        }
+        return;
+    }
+
+    let serial_log = match &opt.log_serial {
+        Some(path)  =>  match open_serial_log(path) {
+            Ok(file)    =>  Some(file),
+            Err(e)      =>  {
+                eprintln!("{}: {}", path.display(), e);
+                process::exit(1);
+            },
+        },
+        None        =>  None,
+    };
+
+    if opt.test {
+        match testrom::run_all(Path::new(&opt.rom), opt.test_timeout, opt.ignore_checksums, serial_log.as_ref()) {
+            Ok(true)    =>  return,
+            Ok(false)   =>  process::exit(1),
+            Err(e)      =>  {
+                eprintln!("{}: {}", opt.rom, e);
+                process::exit(1);
+            },
+        }
+    }
+
+    let mut gameboy = match load(&opt.rom, patch.as_deref(), opt.ignore_checksums) {
+        Ok(gameboy) =>  gameboy,
+        Err(e)  =>  {
+            eprintln!("{}: {}", opt.rom, e);
+            process::exit(1);
+        },
+    };
+
+    gameboy.set_save_enabled(!opt.no_save);
+
+    if let Some(path) = &opt.trace {
+        let trace = Trace::new(opt.trace_len);
+        trace.dump_on_panic(path.clone());
+        gameboy.set_trace(Some(trace));
+    }
+
+    // A link cable takes the port over if there is one
+    if let Some(file) = serial_log {
+        gameboy.connect_device(Box::new(SerialLog::new(file)));
+    }
+
+    if opt.force_dmg {
+        gameboy.set_model(Model::Dmg);
+    }
+    if let Some(model) = opt.model {
+        gameboy.set_model(model);
+    }
+
+    if opt.color_correction {
+        gameboy.set_color_correction(ColorCorrection::Lcd);
+    }
+    if let Some(palette) = opt.palette {
+        gameboy.set_palette(palette);
+    }
+
+    for key in opt.turbo.iter() {
+        gameboy.set_turbo(*key, true);
+    }
+    gameboy.set_turbo_rate(opt.turbo_rate);
+    gameboy.set_clock_scale(opt.clock_scale);
+    gameboy.set_frame_blend(opt.frame_blend);
+    if let Some(rate) = opt.sample_rate {
+        gameboy.set_sample_rate(rate);
+    }
+
+    if let Some(path) = &opt.boot_rom {
+        let result = std::fs::read(path)
+            .map_err(GbError::from)
+            .and_then(|bin| gameboy.set_boot_rom(bin));
+        if let Err(e) = result {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(path) = &opt.state {
+        let result = std::fs::read(path)
+            .map_err(GbError::from)
+            .and_then(|data| gameboy.load_state(&data));
+        if let Err(e) = result {
+            eprintln!("{}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+
+    let link = match (&opt.link_listen, &opt.link_connect) {
+        (Some(addr), _) =>  {
+            println!("Waiting for link cable on {}", addr);
+            Some((addr, TcpLink::listen(addr.as_str())))
+        },
+        (_, Some(addr)) =>  Some((addr, TcpLink::connect(addr.as_str()))),
+        _               =>  None,
+    };
+    match link {
+        Some((_, Ok(link)))     =>  gameboy.connect_link(Box::new(link)),
+        Some((addr, Err(e)))    =>  {
+            eprintln!("{}: {}", addr, e);
+            process::exit(1);
+        },
+        None                    =>  (),
+    }
+
+    if let Some(port) = opt.gdb {
+        if let Err(e) = gdb::serve(&mut gameboy, port) {
+            eprintln!("gdb: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(frames) = opt.bench {
+        gameboy.set_profiling(opt.profile_mem);
+        gameboy.set_opcode_profiling(opt.profile_ops);
+        bench(&mut gameboy, frames);
+        print_memory_accesses(&gameboy);
+        print_opcode_counts(&gameboy);
+        if let Some(expected) = opt.expect_hash {
+            if gameboy.frame_hash() != expected {
+                eprintln!("frame hash mismatch: expected 0x{:016x}", expected);
+                process::exit(1);
+            }
+        }
+        if let Some(path) = &opt.dump_mem {
+            if let Err(e) = std::fs::write(path, gameboy.dump_memory()) {
+                eprintln!("{}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
 
-    run(path);
+    run(gameboy, WindowOptions {
+        frameskip:      opt.frameskip,
+        dump_path:      opt.dump_mem,
+        scale:          opt.scale,
+        filter:         opt.filter,
+        pause_on_blur:  opt.pause_on_blur,
+        modifier:       opt.hotkey_modifier,
+        ignore_checksums:   opt.ignore_checksums,
+    });
 }
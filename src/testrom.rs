@@ -0,0 +1,159 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::core::cpu::CLOCK_HZ;
+use crate::core::error::GbError;
+use crate::core::gameboy::GameBoy;
+use crate::core::serial::{ SerialDevice, SerialLog };
+
+// Mooneye tests load the Fibonacci numbers into B, C, D, E, H and L when they pass
+// and 0x42 into all of them when they fail
+const MOONEYE_PASSED:   [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAILED:   [u8; 6] = [0x42; 6];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Passed,
+    // With what the ROM printed, if anything
+    Failed(String),
+    Timeout,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Passed         =>  write!(f, "passed"),
+            Verdict::Failed(output) =>  write!(f, "FAILED\n{}", output.trim_end()),
+            Verdict::Timeout        =>  write!(f, "TIMEOUT"),
+        }
+    }
+}
+
+// Keeps what the game prints over the serial port, and copies it to the log if there is one.
+// Blargg's tests print their results there.
+struct Capture {
+    output: Rc<RefCell<Vec<u8>>>,
+    log:    Option<SerialLog<File>>,
+}
+
+impl SerialDevice for Capture {
+    fn exchange(&mut self, _data: u8) -> u8 {
+        0xFF
+    }
+
+    // Blargg's tests send the next character before the last transfer is done
+    fn started(&mut self, data: u8) {
+        self.output.borrow_mut().push(data);
+        if let Some(log) = &mut self.log {
+            log.started(data);
+        }
+    }
+}
+
+// Runs the ROM headless until it reports a result or `timeout_secs` of emulated time pass.
+// What it prints is appended to `serial_log` too.
+pub fn run(path: &Path, timeout_secs: u64, ignore_checksums: bool, serial_log: Option<&File>) -> Result<Verdict, GbError> {
+    let mut gameboy = GameBoy::open(path, None, ignore_checksums)?;
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let log = match serial_log {
+        Some(file)  =>  Some(SerialLog::new(file.try_clone()?)),
+        None        =>  None,
+    };
+    gameboy.connect_device(Box::new(Capture { output: output.clone(), log }));
+
+    while gameboy.cycles() < timeout_secs * CLOCK_HZ {
+        gameboy.run_frame();
+
+        let text = String::from_utf8_lossy(&output.borrow()).into_owned();
+        if text.contains("Passed") {
+            return Ok(Verdict::Passed);
+        }
+        if text.contains("Failed") {
+            return Ok(Verdict::Failed(text));
+        }
+
+        let r = gameboy.registers();
+        let regs = [r.b, r.c, r.d, r.e, r.h, r.l];
+        if regs == MOONEYE_PASSED {
+            return Ok(Verdict::Passed);
+        }
+        if regs == MOONEYE_FAILED {
+            return Ok(Verdict::Failed(text));
+        }
+    }
+
+    Ok(Verdict::Timeout)
+}
+
+// Every .gb and .gbc file under `path`, or `path` itself if it is a ROM
+pub fn find_roms(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut roms = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        if path.is_dir() {
+            roms.extend(find_roms(&path)?);
+        } else if ext.eq_ignore_ascii_case("gb") || ext.eq_ignore_ascii_case("gbc") {
+            roms.push(path);
+        }
+    }
+    roms.sort();
+
+    Ok(roms)
+}
+
+// Runs every test ROM under `path` and prints a report.
+// Returns true if all of them passed. Having no ROMs at all isn't a failure,
+// since most test suites can't be checked in.
+pub fn run_all(path: &Path, timeout_secs: u64, ignore_checksums: bool, serial_log: Option<&File>) -> io::Result<bool> {
+    let roms = find_roms(path)?;
+    if roms.is_empty() {
+        println!("no test ROMs in {}", path.display());
+        return Ok(true);
+    }
+
+    let mut failed = 0;
+    for rom in roms.iter() {
+        let verdict = run(rom, timeout_secs, ignore_checksums, serial_log).unwrap_or_else(|e| Verdict::Failed(e.to_string()));
+        println!("{}: {}", rom.display(), verdict);
+        if verdict != Verdict::Passed {
+            failed += 1;
+        }
+    }
+    println!("{} passed, {} failed", roms.len() - failed, failed);
+
+    Ok(failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roms() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms")
+    }
+
+    #[test]
+    fn synthetic_rom_passes() {
+        let rom = roms().join("serial_passed.gb");
+        assert!(find_roms(&roms()).unwrap().contains(&rom));
+        assert_eq!(run(&rom, 1, false, None).unwrap(), Verdict::Passed);
+    }
+
+    #[test]
+    fn missing_roms_are_skipped() {
+        assert!(find_roms(&roms().join("blargg")).unwrap().is_empty());
+        assert!(run_all(&roms().join("blargg"), 1, false, None).unwrap());
+    }
+}
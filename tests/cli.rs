@@ -0,0 +1,34 @@
+// Command line tests that only need the synthetic ROM in tests/roms, so they run
+// without the testroms feature
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+mod common;
+use common::{ bench_output, bench_value };
+
+#[test]
+fn bench_counts_instructions() {
+    let output = bench_output(&["--bench", "frames=10"], None);
+
+    assert_eq!(bench_value(&output, "frames"), "10");
+    assert!(bench_value(&output, "instructions").parse::<u64>().unwrap() > 0);
+    assert!(bench_value(&output, "cycles").parse::<u64>().unwrap() > 0);
+}
+
+#[test]
+fn log_serial() {
+    let rom = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms").join("serial_passed.gb");
+    let log = Path::new(env!("CARGO_TARGET_TMPDIR")).join("serial_passed.log");
+    let _ = fs::remove_file(&log);
+    let status = Command::new(env!("CARGO_BIN_EXE_GBR"))
+        .arg("--rom").arg(&rom)
+        .arg("--bench").arg("frames=10")
+        .arg("--log-serial").arg(&log)
+        .status()
+        .expect("can't run GBR");
+
+    assert!(status.success());
+    assert_eq!(fs::read_to_string(&log).unwrap(), "serial_passed\n\nPassed\n");
+}
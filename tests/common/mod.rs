@@ -0,0 +1,32 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{ Command, Stdio };
+
+// Runs GBR on the synthetic test ROM, or on `stdin` if given, and returns what it printed
+pub fn bench_output(args: &[&str], stdin: Option<&[u8]>) -> String {
+    let rom = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms").join("serial_passed.gb");
+    let mut command = Command::new(env!("CARGO_BIN_EXE_GBR"));
+    match stdin {
+        Some(_) =>  command.arg("--rom").arg("-").stdin(Stdio::piped()),
+        None    =>  command.arg("--rom").arg(&rom).stdin(Stdio::null()),
+    };
+    let mut child = command
+        .args(args)
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("can't run GBR");
+    if let Some(bin) = stdin {
+        child.stdin.take().unwrap().write_all(bin).unwrap();
+    }
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+pub fn bench_value<'a>(output: &'a str, name: &str) -> &'a str {
+    output.lines()
+        .find_map(|line| line.strip_prefix(name))
+        .map(|value| value.trim_start_matches(':').trim())
+        .unwrap_or_else(|| panic!("no {} in {}", name, output))
+}
@@ -0,0 +1,36 @@
+; -----------------------------------------------------------------------------
+; Synthetic test ROM: prints "Passed" over the serial port like Blargg's tests,
+; so the test ROM runner has something to pass without the real suites.
+; -----------------------------------------------------------------------------
+
+	INCLUDE "hardware.inc"			; system defines
+
+	SECTION	"Start",ROM0[$100]		; start vector, followed by header data applied by rgbfix.exe
+	nop
+	jp	start
+
+	SECTION "Test",ROM0[$150]
+
+start:
+	di
+	ld	sp,$E000
+	ld	hl,message
+
+.next						; send the message a byte at a time
+	ld	a,[hl+]
+	and	a
+	jr	z,.done
+	ldh	[rSB],a
+	ld	a,$81				; start a transfer on the internal clock
+	ldh	[rSC],a
+.wait
+	ldh	a,[rSC]
+	bit	7,a
+	jr	nz,.wait
+	jr	.next
+
+.done
+	jr	.done
+
+message:
+	db	"serial_passed\n\nPassed\n",0
@@ -0,0 +1,106 @@
+#![cfg(feature = "testroms")]
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use gbr::core::gameboy::GameBoy;
+
+mod common;
+use common::{ bench_output, bench_value };
+
+// Drop Blargg or Mooneye test ROMs into tests/roms to run them too.
+// They can't be redistributed, so only the synthetic ROM is checked in.
+#[test]
+fn test_roms() {
+    let roms = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms");
+    let status = Command::new(env!("CARGO_BIN_EXE_GBR"))
+        .arg("--rom").arg(&roms)
+        .arg("--test")
+        .status()
+        .expect("can't run GBR");
+
+    assert!(status.success());
+}
+
+#[test]
+fn patch() {
+    let rom = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms").join("serial_passed.gb");
+    let patch = Path::new(env!("CARGO_TARGET_TMPDIR")).join("serial_passed.ips");
+    // One record: a single byte 'X' over the first letter of the title at 0x134
+    fs::write(&patch, b"PATCH\x00\x01\x34\x00\x01XEOF").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_GBR"))
+        .arg("--rom").arg(&rom)
+        .arg("--patch").arg(&patch)
+        .arg("--info")
+        .output()
+        .expect("can't run GBR");
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("XERIALPASSED"));
+}
+
+#[test]
+fn state_not_a_save_state() {
+    let rom = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms").join("serial_passed.gb");
+    let output = Command::new(env!("CARGO_BIN_EXE_GBR"))
+        .arg("--rom").arg(&rom)
+        .arg("--state").arg(&rom)
+        .arg("--bench").arg("frames=1")
+        .output()
+        .expect("can't run GBR");
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("bad save state"));
+}
+
+#[test]
+fn state_restores_registers() {
+    let rom = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms").join("serial_passed.gb");
+    let state = Path::new(env!("CARGO_TARGET_TMPDIR")).join("serial_passed.ss1");
+    let mut gameboy = GameBoy::from_bytes(fs::read(&rom).unwrap()).unwrap();
+    gameboy.run_frame();
+    // JR -2 in WRAM with interrupts off, so nothing moves after loading
+    gameboy.write_memory(0xC100, 0x18);
+    gameboy.write_memory(0xC101, 0xFE);
+    gameboy.write_memory(0xFFFF, 0x00);
+    let mut regs = gameboy.registers();
+    regs.a = 0x12;
+    regs.f = 0xB0;
+    regs.b = 0x34;
+    regs.c = 0x56;
+    regs.d = 0x78;
+    regs.e = 0x9A;
+    regs.h = 0xBC;
+    regs.l = 0xDE;
+    regs.sp = 0xDFF0;
+    regs.pc = 0xC100;
+    gameboy.set_registers(regs);
+    fs::write(&state, gameboy.save_state()).unwrap();
+
+    let output = bench_output(&["--state", state.to_str().unwrap(), "--bench", "frames=1"], None);
+    assert_eq!(bench_value(&output, "registers"), "af=12b0 bc=3456 de=789a hl=bcde sp=dff0 pc=c100");
+}
+
+#[test]
+fn info_logo() {
+    let rom = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms").join("serial_passed.gb");
+    let output = Command::new(env!("CARGO_BIN_EXE_GBR"))
+        .arg("--rom").arg(&rom)
+        .arg("--info")
+        .output()
+        .expect("can't run GBR");
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Nintendo logo:    ok"));
+}
+
+#[test]
+fn rom_from_stdin() {
+    let rom = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms").join("serial_passed.gb");
+    let from_file = bench_output(&["--bench", "frames=30"], None);
+    let from_stdin = bench_output(&["--bench", "frames=30"], Some(&fs::read(&rom).unwrap()));
+
+    assert_eq!(bench_value(&from_file, "frame hash"), bench_value(&from_stdin, "frame hash"));
+    assert_eq!(bench_value(&from_file, "instructions"), bench_value(&from_stdin, "instructions"));
+}